@@ -0,0 +1,149 @@
+// Copyright 2023 André Cipriani Bandarra
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// Tracks whether the emoji directory can be read, from the outcome of loading emoji,
+/// eg: to tell a dropped network mount apart from emoji that are missing.
+///
+/// The directory is unavailable after `threshold` loads in a row fail while it can't be
+/// listed, and available again after the next load or check that succeeds.
+#[derive(Debug)]
+pub struct AssetHealth {
+    threshold: u32,
+    failures: u32,
+    available: bool,
+}
+
+impl AssetHealth {
+    pub fn new(threshold: u32) -> Self {
+        Self {
+            threshold,
+            failures: 0,
+            available: true,
+        }
+    }
+
+    pub fn is_available(&self) -> bool {
+        self.available
+    }
+
+    /// Number of loads that failed in a row while the directory couldn't be listed.
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    /// Records a failed load, where `directory_available` is whether the directory could
+    /// still be listed, as emoji missing from a readable directory don't count. Returns
+    /// whether this made the directory unavailable.
+    pub fn record_failure(&mut self, directory_available: bool) -> bool {
+        if directory_available {
+            self.failures = 0;
+            return false;
+        }
+        self.failures = self.failures.saturating_add(1);
+        if !self.available || self.failures < self.threshold {
+            return false;
+        }
+        self.available = false;
+        true
+    }
+
+    /// Records a successful load or check. Returns whether the directory was unavailable
+    /// until now.
+    pub fn record_success(&mut self) -> bool {
+        self.failures = 0;
+        let recovered = !self.available;
+        self.available = true;
+        recovered
+    }
+}
+
+/// Returns whether `directory` can be listed and has any entries. An unmounted network
+/// share usually leaves an empty mount point behind, or fails to list.
+pub fn directory_available(directory: &Path) -> bool {
+    fs::read_dir(directory).is_ok_and(|mut entries| entries.next().is_some())
+}
+
+/// Like `directory_available`, but listing `directory` on a blocking thread, as listing a
+/// dead network mount can hang. The directory is unavailable when listing it takes
+/// longer than `timeout`.
+pub async fn check_directory(directory: PathBuf, timeout: Duration) -> bool {
+    let listing = tokio::task::spawn_blocking(move || directory_available(&directory));
+    match tokio::time::timeout(timeout, listing).await {
+        Ok(available) => available.unwrap_or(false),
+        Err(_) => {
+            log::warn!("Listing the emoji directory took over {:?}", timeout);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AssetHealth;
+
+    #[test]
+    fn reports_unavailable_directory_then_recovery() {
+        let mut health = AssetHealth::new(3);
+        assert!(!health.record_failure(false));
+        assert!(!health.record_failure(false));
+        assert!(health.record_failure(false));
+        assert!(!health.is_available());
+        // Only reported once.
+        assert!(!health.record_failure(false));
+        assert_eq!(health.failures(), 4);
+
+        assert!(health.record_success());
+        assert!(health.is_available());
+        assert!(!health.record_success());
+    }
+
+    #[test]
+    fn missing_emoji_dont_make_directory_unavailable() {
+        let mut health = AssetHealth::new(2);
+        for _ in 0..10 {
+            assert!(!health.record_failure(true));
+        }
+        assert!(health.is_available());
+        // The count starts over after a success.
+        assert!(!health.record_failure(false));
+        health.record_success();
+        assert!(!health.record_failure(false));
+        assert!(health.record_failure(false));
+    }
+
+    #[test]
+    fn checks_directory_listing() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(!super::directory_available(dir.path()));
+        std::fs::write(dir.path().join("emoji_u1f44d.png"), b"").unwrap();
+        assert!(super::directory_available(dir.path()));
+        assert!(!super::directory_available(&dir.path().join("missing")));
+    }
+
+    #[tokio::test]
+    async fn checks_directory_listing_off_the_runtime() {
+        let dir = tempfile::tempdir().unwrap();
+        let timeout = std::time::Duration::from_secs(5);
+        assert!(!super::check_directory(dir.path().to_path_buf(), timeout).await);
+        std::fs::write(dir.path().join("emoji_u1f44d.png"), b"").unwrap();
+        assert!(super::check_directory(dir.path().to_path_buf(), timeout).await);
+    }
+}
@@ -0,0 +1,224 @@
+// Copyright 2023 André Cipriani Bandarra
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::{str::FromStr, time::Duration};
+
+use crate::clock::Clock;
+
+// Delay before the first reconnect, and the unit the growing strategies scale.
+const BASE_DELAY: Duration = Duration::from_secs(1);
+
+// Longest delay between reconnects for the growing strategies.
+const MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// Decides how long to wait before each reconnect attempt.
+pub trait BackoffStrategy {
+    /// Delay before the next attempt. Each call counts as one more failed attempt.
+    fn next_delay(&mut self) -> Duration;
+
+    /// Starts over from the first delay, after a successful connection.
+    fn reset(&mut self);
+}
+
+/// Sleeps on `clock` for the next delay of `strategy`, eg: before reconnecting, returning
+/// the delay waited.
+pub async fn wait_next_delay<S: BackoffStrategy + ?Sized>(
+    strategy: &mut S,
+    clock: &dyn Clock,
+) -> Duration {
+    let delay = strategy.next_delay();
+    clock.sleep(delay).await;
+    delay
+}
+
+/// Waits the same delay before every attempt.
+#[derive(Debug, Clone)]
+pub struct Fixed {
+    delay: Duration,
+}
+
+impl Fixed {
+    pub fn new(delay: Duration) -> Self {
+        Self { delay }
+    }
+}
+
+impl BackoffStrategy for Fixed {
+    fn next_delay(&mut self) -> Duration {
+        self.delay
+    }
+
+    fn reset(&mut self) {}
+}
+
+/// Doubles the delay after every attempt, up to `max`.
+#[derive(Debug, Clone)]
+pub struct Exponential {
+    initial: Duration,
+    max: Duration,
+    next: Duration,
+}
+
+impl Exponential {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self {
+            initial,
+            max,
+            next: initial,
+        }
+    }
+}
+
+impl BackoffStrategy for Exponential {
+    fn next_delay(&mut self) -> Duration {
+        let delay = self.next.min(self.max);
+        self.next = delay.saturating_mul(2);
+        delay
+    }
+
+    fn reset(&mut self) {
+        self.next = self.initial;
+    }
+}
+
+/// Waits `unit` times the Fibonacci sequence (1, 1, 2, 3, 5...), up to `max`.
+#[derive(Debug, Clone)]
+pub struct Fibonacci {
+    unit: Duration,
+    max: Duration,
+    current: Duration,
+    next: Duration,
+}
+
+impl Fibonacci {
+    pub fn new(unit: Duration, max: Duration) -> Self {
+        Self {
+            unit,
+            max,
+            current: unit,
+            next: unit,
+        }
+    }
+}
+
+impl BackoffStrategy for Fibonacci {
+    fn next_delay(&mut self) -> Duration {
+        let delay = self.current.min(self.max);
+        let following = self.current.saturating_add(self.next);
+        self.current = self.next;
+        self.next = following;
+        delay
+    }
+
+    fn reset(&mut self) {
+        self.current = self.unit;
+        self.next = self.unit;
+    }
+}
+
+/// Backoff strategy selected in the configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BackoffKind {
+    #[default]
+    Fixed,
+    Exponential,
+    Fibonacci,
+}
+
+impl FromStr for BackoffKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "fixed" => Ok(BackoffKind::Fixed),
+            "exponential" => Ok(BackoffKind::Exponential),
+            "fibonacci" => Ok(BackoffKind::Fibonacci),
+            _ => Err(format!("Invalid backoff strategy: {}", s)),
+        }
+    }
+}
+
+impl BackoffKind {
+    /// Creates a new strategy of this kind, starting from its first delay.
+    pub fn strategy(&self) -> Box<dyn BackoffStrategy + Send> {
+        match self {
+            BackoffKind::Fixed => Box::new(Fixed::new(BASE_DELAY)),
+            BackoffKind::Exponential => Box::new(Exponential::new(BASE_DELAY, MAX_DELAY)),
+            BackoffKind::Fibonacci => Box::new(Fibonacci::new(BASE_DELAY, MAX_DELAY)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{BackoffKind, BackoffStrategy, Exponential, Fibonacci, Fixed};
+    use crate::clock::FakeClock;
+
+    fn delays(strategy: &mut impl BackoffStrategy, count: usize) -> Vec<u64> {
+        (0..count)
+            .map(|_| strategy.next_delay().as_secs())
+            .collect()
+    }
+
+    #[test]
+    fn fixed_repeats_delay() {
+        let mut fixed = Fixed::new(Duration::from_secs(2));
+        assert_eq!(delays(&mut fixed, 4), vec![2, 2, 2, 2]);
+    }
+
+    #[test]
+    fn exponential_doubles_up_to_max() {
+        let mut exponential = Exponential::new(Duration::from_secs(1), Duration::from_secs(10));
+        assert_eq!(delays(&mut exponential, 6), vec![1, 2, 4, 8, 10, 10]);
+
+        exponential.reset();
+        assert_eq!(delays(&mut exponential, 2), vec![1, 2]);
+    }
+
+    #[test]
+    fn fibonacci_follows_sequence_up_to_max() {
+        let mut fibonacci = Fibonacci::new(Duration::from_secs(1), Duration::from_secs(10));
+        assert_eq!(delays(&mut fibonacci, 8), vec![1, 1, 2, 3, 5, 8, 10, 10]);
+
+        fibonacci.reset();
+        assert_eq!(delays(&mut fibonacci, 3), vec![1, 1, 2]);
+    }
+
+    #[tokio::test]
+    async fn waits_on_the_clock() {
+        let clock = FakeClock::default();
+        let mut backoff = BackoffKind::Exponential.strategy();
+        for _ in 0..3 {
+            super::wait_next_delay(backoff.as_mut(), &clock).await;
+        }
+        assert_eq!(clock.elapsed(), Duration::from_secs(1 + 2 + 4));
+
+        backoff.reset();
+        assert_eq!(
+            super::wait_next_delay(backoff.as_mut(), &clock).await,
+            Duration::from_secs(1)
+        );
+    }
+
+    #[test]
+    fn parses_backoff_kind() {
+        assert_eq!("fixed".parse(), Ok(BackoffKind::Fixed));
+        assert_eq!("Exponential".parse(), Ok(BackoffKind::Exponential));
+        assert_eq!("fibonacci".parse(), Ok(BackoffKind::Fibonacci));
+        assert!("linear".parse::<BackoffKind>().is_err());
+    }
+}
@@ -0,0 +1,130 @@
+// Copyright 2023 André Cipriani Bandarra
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Measures the throughput of the render pipeline: renders and encodes an emoji from
+//! `EMOJI_DIRECTORY` in a loop, for each size, and prints the timings.
+//!
+//! Usage: `bench ITERATIONS [WIDTHxHEIGHT...]`, with the default sizes when none are
+//! given. `BENCH_EMOJI` picks the emoji, 😀 by default. Prints one JSON object per size,
+//! with the frames per second of rendering and encoding a frame, and the average
+//! microseconds spent in `render_emoji` and encoding its frame. The stages can't be timed
+//! from inside `render_emoji`, so decoding, resizing and blending are timed in a separate
+//! pass repeating them, reported under `separate_pass_us`.
+
+use std::{
+    error::Error,
+    hint::black_box,
+    time::{Duration, Instant},
+};
+
+use mqtt_image_writer::{
+    config::Config,
+    emoji::load_emoji_image,
+    imageutils,
+    render::{self, parse_size, BACKGROUND},
+};
+use serde_json::json;
+
+static ENV_EMOJI_DIRECTORY: &str = "EMOJI_DIRECTORY";
+static ENV_BENCH_EMOJI: &str = "BENCH_EMOJI";
+static DEFAULT_BENCH_EMOJI: &str = "😀";
+
+// Time spent in each stage of the pipeline over every iteration.
+#[derive(Debug, Default)]
+struct Timings {
+    decode: Duration,
+    resize: Duration,
+    blend: Duration,
+    encode: Duration,
+    render: Duration,
+}
+
+impl Timings {
+    /// Times `stage`, adding the time spent to `total`.
+    fn time<T>(total: &mut Duration, stage: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = stage();
+        *total += start.elapsed();
+        result
+    }
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let mut args = std::env::args().skip(1);
+    let iterations: u32 = args
+        .next()
+        .ok_or("Usage: bench ITERATIONS [WIDTHxHEIGHT...]")?
+        .parse()
+        .map_err(|e| format!("Invalid iteration count: {}", e))?;
+    if iterations == 0 {
+        return Err("ITERATIONS must be at least 1".into());
+    }
+    let mut sizes = args
+        .map(|size| parse_size(&size))
+        .collect::<Result<Vec<_>, _>>()?;
+    let emoji_directory = std::env::var(ENV_EMOJI_DIRECTORY)
+        .map_err(|_| format!("{} is not set", ENV_EMOJI_DIRECTORY))?;
+    let emoji = std::env::var(ENV_BENCH_EMOJI).unwrap_or_else(|_| DEFAULT_BENCH_EMOJI.to_string());
+    if sizes.is_empty() {
+        sizes = Config::default().sizes;
+    }
+
+    for (width, height) in sizes {
+        let config = Config {
+            emoji_directory: emoji_directory.clone(),
+            sizes: vec![(width, height)],
+            ..Config::default()
+        };
+        let encoder = config.output_format.encoder();
+        let mut timings = Timings::default();
+        for _ in 0..iterations {
+            let img = Timings::time(&mut timings.decode, || {
+                load_emoji_image(&config.emoji_directory, &emoji, None)
+            })?;
+            let resized = Timings::time(&mut timings.resize, || {
+                render::resize(&img, width, height, config.resize_mode, config.scale_filter)
+            });
+            // Keeps the compiler from dropping work whose result is never used.
+            black_box(Timings::time(&mut timings.blend, || {
+                imageutils::blend_onto(black_box(&resized), &BACKGROUND, config.blend_space)
+            }));
+            let frames = Timings::time(&mut timings.render, || {
+                imageutils::render_emoji(&config, &emoji)
+            })?;
+            black_box(Timings::time(&mut timings.encode, || {
+                encoder.encode(black_box(&frames[0].2), width, height)
+            }));
+        }
+
+        let average = |total: Duration| total.as_secs_f64() * 1e6 / iterations as f64;
+        let per_frame = timings.render + timings.encode;
+        let report = json!({
+            "emoji": emoji,
+            "width": width,
+            "height": height,
+            "iterations": iterations,
+            "fps": iterations as f64 / per_frame.as_secs_f64(),
+            "render_us": average(timings.render),
+            "encode_us": average(timings.encode),
+            "separate_pass_us": {
+                "decode": average(timings.decode),
+                "resize": average(timings.resize),
+                "blend": average(timings.blend),
+            },
+        });
+        println!("{}", report);
+    }
+    Ok(())
+}
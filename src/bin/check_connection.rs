@@ -0,0 +1,97 @@
+// Copyright 2023 André Cipriani Bandarra
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Connects to the MQTT broker configured in the environment and exits, without
+//! publishing anything. Useful to verify connectivity and credentials before deploying.
+//! Connects with the protocol version in `MQTT_VERSION`, falling back from v5 to 3.1.1
+//! like the daemon does.
+//!
+//! Exits with 0 when the broker accepts the connection, 1 when the connection fails
+//! or is refused, and 2 when no answer arrives before the timeout.
+
+use std::{process::ExitCode, time::Duration};
+
+use mqtt_image_writer::{
+    config::MqttConfig,
+    logging,
+    mqtt::{wait_for_connack, Client, ConnectError, EventStream, MqttVersion, V5EventLoop},
+};
+use rumqttc::{v5, AsyncClient};
+
+// Seconds to wait for the broker to acknowledge the connection.
+static ENV_CHECK_TIMEOUT_SECS: &str = "CHECK_TIMEOUT_SECS";
+static DEFAULT_CHECK_TIMEOUT_SECS: u64 = 10;
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    logging::init("check_connection=info");
+
+    let config = match MqttConfig::from_env() {
+        Ok(config) => config,
+        Err(e) => {
+            log::error!("Invalid configuration: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let timeout = match std::env::var(ENV_CHECK_TIMEOUT_SECS) {
+        Ok(secs) => match secs.parse() {
+            Ok(secs) => Duration::from_secs(secs),
+            Err(e) => {
+                log::error!("Invalid {}: {}", ENV_CHECK_TIMEOUT_SECS, e);
+                return ExitCode::FAILURE;
+            }
+        },
+        Err(_) => Duration::from_secs(DEFAULT_CHECK_TIMEOUT_SECS),
+    };
+
+    log::info!("Connecting to {}:{}...", config.server, config.port);
+    let (client, result) = match config.version {
+        MqttVersion::V4 => {
+            let (client, mut eventloop) = AsyncClient::new(config.options(), 10);
+            let result = wait_for_connack(&mut eventloop, timeout).await;
+            (Client::V4(client), result)
+        }
+        MqttVersion::V5 => {
+            let (client, eventloop) = v5::AsyncClient::new(config.options_v5(), 10);
+            let mut stream = V5EventLoop::new(eventloop, config.options());
+            let mut result = wait_for_connack(&mut stream, timeout).await;
+            match stream.replaced_client() {
+                // The broker doesn't support v5, so the check is repeated with 3.1.1.
+                Some(client) => {
+                    result = wait_for_connack(&mut stream, timeout).await;
+                    (client, result)
+                }
+                None => (Client::V5(client), result),
+            }
+        }
+    };
+    let _ = client.try_disconnect();
+
+    match result {
+        Ok(()) => {
+            log::info!("Connected to {}:{}", config.server, config.port);
+            ExitCode::SUCCESS
+        }
+        Err(e @ ConnectError::TimedOut(_)) => {
+            log::error!("{}", e);
+            ExitCode::from(2)
+        }
+        Err(e) => {
+            log::error!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
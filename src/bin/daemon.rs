@@ -13,214 +13,2319 @@
 // limitations under the License.
 //
 
-use std::{error::Error, io, path::Path, thread, time::Duration};
-
-use env_logger::Env;
-use image::DynamicImage;
-use reqwest::ClientBuilder;
-use rumqttc::{AsyncClient, Event, Incoming, MqttOptions, Outgoing, QoS};
-use serde::Deserialize;
-use tokio::task;
-
-const SIZES: [(u32, u32); 2] = [(32, 32), (128, 128)];
-
-// Path to Noto Emoji font directory (https://github.com/googlefonts/noto-emoji)
-static ENV_EMOJI_DIRECTORY: &str = "EMOJI_DIRECTORY";
-
-// URL to the firebase database record to listen to.
-// eg: 'https://my-firebase-project.firebaseio.com/ledgrids/1.json'
-static ENV_FIREBASE_URL: &str = "FIREBASE_URL";
-
-// MQTT client ID to use.
-static ENV_MQTT_CLIENT_ID: &str = "MQTT_CLIENT_ID";
-static ENV_MQTT_HOST: &str = "MQTT_HOST";
-static ENV_MQTT_PORT: &str = "MQTT_PORT";
-static DEFAULT_MQTT_PORT: u16 = 1883;
-
-#[derive(Debug, Deserialize)]
-struct Config {
-    pub emoji_directory: String,
-    pub firebase_url: String,
-    pub mqtt_client_id: String,
-    pub mqtt_server: String,
-    pub mqtt_port: u16,
-}
-
-impl Config {
-    pub fn from_env() -> Result<Self, Box<dyn Error>> {
-        let mqtt_port = match std::env::var(ENV_MQTT_PORT) {
-            Ok(port) => port.parse()?,
-            Err(_) => DEFAULT_MQTT_PORT,
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap},
+    error::Error,
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use chrono::Timelike;
+use image::{Rgb, RgbImage};
+#[cfg(feature = "file-source")]
+use mqtt_image_writer::file_source;
+use mqtt_image_writer::{
+    assets::{self, AssetHealth},
+    budget::{ByteBudget, Spend},
+    cache::EmojiCache,
+    clock::{Clock, SystemClock},
+    config::{Config, Fade, Icon, RuntimeFlavor, BYTES_PER_PIXEL},
+    control::ControlCommand,
+    emoji::count_emoji_assets,
+    encoder::OutputEncoder,
+    error::DaemonError,
+    frame_fifo::{FrameFifo, FIFO_SOURCE_ID},
+    freeze::{FreezeGate, FrozenPrefixes},
+    history::{DuplicateFilter, FrameHistory, RepeatFilter},
+    imageutils::{self, BlankFramePolicy, Transition},
+    logging::{self, LogSampler},
+    meta::Info,
+    mqtt::{
+        check_frame_packet_sizes, control_topic, frame_topic, publish_packet_size,
+        query_response_topic, query_topic, replay_topic, request_topic, MqttPublisher, MqttVersion,
+    },
+    pixels::{self, OutputMode, PixelHistory},
+    playlist::{LiveOverride, PlaylistSource},
+    priority::CommandQueue,
+    query::{query_response, ShownEmoji},
+    render::parse_size,
+    render_api,
+    router::Router,
+    sink::{FrameSink, FramebufferSink, SinkKind},
+    source::{EventSource, SourceError, SourceEvent},
+    startup,
+    stats::EmojiStats,
+    stdin_source::StdinSource,
+    telemetry::Span,
+};
+#[cfg(feature = "firebase")]
+use mqtt_image_writer::{firebase, watchdog::Progress};
+use rumqttc::{Publish, QoS};
+use tokio::{
+    sync::mpsc,
+    task::JoinHandle,
+    time::{Instant, MissedTickBehavior},
+};
+
+const TEXT_COLOR: Rgb<u8> = Rgb([255, 255, 255]);
+const BACKGROUND_COLOR: Rgb<u8> = Rgb([0, 0, 0]);
+// Shown instead of emoji that render blank, see BLANK_FRAME_POLICY.
+const PLACEHOLDER_TEXT: &str = "?";
+
+// Exit code used when giving up reconnecting, so supervisors can tell it apart from
+// configuration errors. EX_TEMPFAIL from sysexits.h.
+const EXIT_RECONNECT_LIMIT: i32 = 75;
+
+// Steps and speed of transitions requested in the payload, when no fade is configured.
+const DEFAULT_TRANSITION_TIMING: Fade = Fade { frames: 8, fps: 16 };
+
+// Samples the log lines of published frames, see PUBLISH_LOG_SAMPLE.
+static PUBLISH_LOG: LogSampler = LogSampler::new();
+
+// Window of the bandwidth budget, see MAX_BYTES_PER_MIN.
+const BANDWIDTH_WINDOW: Duration = Duration::from_secs(60);
+
+// How often the frames shown are checked for being due a keyframe. Keyframes are sent
+// at most this late, see KEYFRAME_INTERVAL_SECS.
+const KEYFRAME_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+// Steps in a full turn of the hue cycle, 3 degrees each, see HUE_CYCLE.
+const HUE_CYCLE_STEPS: u32 = 120;
+
+// How often sources are checked for progress, see WATCHDOG_SECS.
+#[cfg(feature = "firebase")]
+const WATCHDOG_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+// Longest the emoji directory is listed for, to check whether it's available, see
+// ASSET_CHECK_INTERVAL_SECS.
+const DIRECTORY_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How long each frame is shown when replaying the frame history.
+const REPLAY_PAUSE: Duration = Duration::from_secs(1);
+
+// How long to wait after publishing a frame over MQTT, before publishing anything else.
+// Animations pace their frames themselves, see `publish_frame_now`.
+const PUBLISH_PAUSE: Duration = Duration::from_millis(100);
+
+fn main() -> Result<(), Box<dyn Error>> {
+    logging::init("daemon=info,mqtt_image_writer=info");
+
+    let config = Arc::new(Config::from_env()?);
+    build_runtime(config.runtime_flavor)?.block_on(run(config, Arc::new(SystemClock)))
+}
+
+/// Builds the tokio runtime the daemon runs on, see `TOKIO_FLAVOR`.
+fn build_runtime(flavor: RuntimeFlavor) -> io::Result<tokio::runtime::Runtime> {
+    let mut runtime = match flavor {
+        RuntimeFlavor::MultiThread => tokio::runtime::Builder::new_multi_thread(),
+        RuntimeFlavor::CurrentThread => tokio::runtime::Builder::new_current_thread(),
+    };
+    runtime.enable_all().build()
+}
+
+async fn run(config: Arc<Config>, clock: Arc<dyn Clock>) -> Result<(), Box<dyn Error>> {
+    // Flushes the exported spans when the daemon stops.
+    #[cfg(feature = "otel")]
+    let _telemetry = config
+        .otel_enabled
+        .then(mqtt_image_writer::telemetry::init)
+        .transpose()?;
+    if let Some(delay) = config.startup_delay {
+        log::info!("Waiting {:?} before starting", delay);
+        tokio::time::sleep(delay).await;
+    }
+    if let (SinkKind::Mqtt, Some(timeout)) = (&config.sink, config.startup_wait_timeout) {
+        let mqtt = &config.mqtt;
+        // The client keeps trying to connect, so start anyway.
+        if let Err(e) = startup::wait_for_tcp(&mqtt.server, mqtt.port, timeout).await {
+            log::warn!("{}. Starting anyway...", e);
+        }
+    }
+
+    let destination = match &config.sink {
+        SinkKind::Mqtt if config.mqtt.version == MqttVersion::V5 => {
+            Destination::Mqtt(MqttPublisher::new_v5(
+                config.mqtt_options_v5(),
+                config.mqtt_options(),
+                10,
+                config.backoff.strategy(),
+                config.max_reconnect_attempts,
+                config.max_publish_failures,
+                clock.clone(),
+            ))
+        }
+        SinkKind::Mqtt => Destination::Mqtt(MqttPublisher::new(
+            config.mqtt_options(),
+            10,
+            config.backoff.strategy(),
+            config.max_reconnect_attempts,
+            config.max_publish_failures,
+            clock.clone(),
+        )),
+        SinkKind::Framebuffer(device) => {
+            let sink = FramebufferSink::open(device)
+                .map_err(|e| format!("Failed to open {}: {}", device.display(), e))?;
+            let (width, height) = sink.size();
+            if !config.sizes.contains(&(width, height)) {
+                return Err(format!(
+                    "SIZES must include the {}x{} resolution of {}",
+                    width,
+                    height,
+                    device.display()
+                )
+                .into());
+            }
+            log::info!(
+                "Drawing {}x{} frames on {}",
+                width,
+                height,
+                device.display()
+            );
+            Destination::Local(Mutex::new(Box::new(sink)))
+        }
+    };
+    let output = Arc::new(Output::new(destination, &config));
+
+    if let (Destination::Mqtt(mqtt_client), Some(attempts)) =
+        (&output.destination, config.startup_connect_attempts)
+    {
+        log::info!("Waiting for the MQTT broker to accept the connection...");
+        mqtt_client
+            .wait_first_connection(config.startup_connect_timeout, attempts)
+            .await
+            .map_err(|e| format!("Failed to connect to the MQTT broker: {}", e))?;
+        log::info!("Connected to the MQTT broker");
+    }
+
+    if let Destination::Mqtt(_) = output.destination {
+        tokio::spawn(publish_info(output.clone(), config.clone()));
+    }
+
+    let splash = config
+        .splash_image
+        .as_deref()
+        .and_then(|path| load_splash(&config, path));
+    let startup_targets = panel_targets(&config.router.all_prefixes(), &config.sizes);
+    if let Some(pause) = config.selftest_pause {
+        run_selftest(&output, &config, &*clock, &startup_targets, pause).await;
+    } else if config.blank_on_startup && splash.is_none() {
+        // Replace the frames retained from a previous run before listening for events.
+        publish_blank(&output, &config, &*clock, &startup_targets).await;
+    }
+    if let Some(splash) = &splash {
+        publish_splash(&output, &config, &*clock, splash, &startup_targets).await;
+    }
+    // Shown instead of blanking when a record is deleted.
+    let clear_splash = splash.filter(|_| config.splash_on_clear);
+    let mut freeze = FreezeGate::new(config.freeze_policy);
+    // Background tasks skip the panels frozen after they started.
+    let frozen = freeze.frozen();
+    // Replaced by the first emoji, countdown or clear.
+    let mut loading = config.loading_fps.map(|fps| {
+        tokio::spawn(run_loading_animation(
+            output.clone(),
+            config.clone(),
+            clock.clone(),
+            frozen.clone(),
+            startup_targets.clone(),
+            fps,
+        ))
+    });
+
+    let stats = match &config.stats_file {
+        Some(path) => {
+            let stats = EmojiStats::load(path)
+                .map_err(|e| format!("Failed to load {}: {}", path.display(), e))?;
+            let stats = Arc::new(Mutex::new(stats));
+            tokio::spawn(save_stats(
+                stats.clone(),
+                path.clone(),
+                config.stats_interval,
+            ));
+            Some(stats)
+        }
+        None => None,
+    };
+
+    if let Some(port) = config.render_api_port {
+        let config = config.clone();
+        let stats = stats.clone();
+        tokio::spawn(async move {
+            if let Err(e) = render_api::serve(port, config, stats).await {
+                log::error!("Render API failed: {}", e);
+            }
+        });
+    }
+
+    // Listen for events from every source.
+    let (events_tx, mut events) = mpsc::channel(16);
+    let (gave_up_tx, mut gave_up) = mpsc::channel(1);
+    #[cfg(feature = "firebase")]
+    for source in &config.firebase_sources {
+        let listener = firebase::FirebaseListener::new(source.clone(), &config, clock.clone());
+        match config.watchdog_timeout {
+            Some(threshold) => spawn_watched_source(
+                move |progress| listener.clone().with_progress(progress),
+                threshold,
+                clock.clone(),
+                events_tx.clone(),
+                gave_up_tx.clone(),
+            ),
+            None => spawn_source(listener, events_tx.clone(), gave_up_tx.clone()),
+        }
+    }
+    #[cfg(feature = "file-source")]
+    if let Some(path) = &config.event_file {
+        let source = file_source::FileSource::new(path.clone(), config.payload_format);
+        spawn_source(source, events_tx.clone(), gave_up_tx.clone());
+    }
+    if let Some(on_eof) = config.stdin_source {
+        let source = StdinSource::new(on_eof);
+        spawn_source(source, events_tx.clone(), gave_up_tx.clone());
+    }
+    if let Some(playlist) = &config.playlist {
+        let source = PlaylistSource::new(playlist.playlist.clone(), playlist.interval);
+        spawn_source(source, events_tx.clone(), gave_up_tx.clone());
+    }
+    drop(gave_up_tx);
+
+    // Pre-rendered frames from custom renderers.
+    let (fifo_tx, mut fifo_frames) = mpsc::channel(4);
+    if let Some(path) = &config.frame_fifo {
+        let (width, height) = config.frame_fifo_size;
+        let fifo = FrameFifo::new(path.clone(), width, height);
+        // Holding a sender keeps the loop running while the pipe is read.
+        let events_tx = events_tx.clone();
+        tokio::spawn(async move {
+            if let Err(e) = fifo.run(fifo_tx).await {
+                log::error!("Frame FIFO failed: {}", e);
+            }
+            drop(events_tx);
+        });
+    }
+    drop(events_tx);
+
+    // Subscribers can ask for the current emoji at sizes not in SIZES, or which it is.
+    let (requests_tx, mut requests) = mpsc::unbounded_channel();
+    // Operators can freeze, refresh or reload the panels, see ControlCommand.
+    let (controls_tx, mut controls) = mpsc::unbounded_channel();
+    if let Destination::Mqtt(mqtt_client) = &output.destination {
+        for prefix in config.router.all_prefixes() {
+            mqtt_client
+                .subscribe(&control_topic(prefix), controls_tx.clone())
+                .await?;
+            mqtt_client
+                .subscribe(&request_topic(prefix), requests_tx.clone())
+                .await?;
+            mqtt_client
+                .subscribe(&query_topic(prefix), requests_tx.clone())
+                .await?;
+            if config.frame_history > 0 {
+                mqtt_client
+                    .subscribe(&replay_topic(prefix), requests_tx.clone())
+                    .await?;
+            }
+        }
+    }
+
+    let mut countdown: Option<JoinHandle<()>> = None;
+    let mut replay: Option<JoinHandle<()>> = None;
+    let mut hue_cycle: Option<JoinHandle<()>> = None;
+    let mut previous_frames: HashMap<String, RgbImage> = HashMap::new();
+    // The frames of `previous_frames` before `apply_corrections`, for the hue cycle.
+    let mut uncorrected_frames: HashMap<String, RgbImage> = HashMap::new();
+    // Emoji shown under each topic prefix, for rendering requested sizes and queries.
+    let mut current_emoji: HashMap<String, ShownEmoji> = HashMap::new();
+    // Recently published emoji frames, by topic.
+    let mut history: HashMap<String, FrameHistory> = HashMap::new();
+    let mut duplicates = DuplicateFilter::new(config.keyframe_interval);
+    let mut keyframe_checks = tokio::time::interval(KEYFRAME_CHECK_INTERVAL);
+    let mut repeats = RepeatFilter::default();
+    let mut commands = CommandQueue::default();
+    let mut live = config
+        .playlist
+        .as_ref()
+        .map(|playlist| LiveOverride::new(playlist.resume_after));
+    // Ticks at the start of every minute, to update the clock overlay.
+    let until_next_minute = Duration::from_secs(60 - clock.wall_time().second() as u64);
+    let mut clock_ticks =
+        tokio::time::interval_at(Instant::now() + until_next_minute, Duration::from_secs(60));
+    // The clock and night mode change the frames shown on the minute.
+    let minute_ticks = config.clock_format.is_some() || config.night_mode.is_some();
+    let mut night = night_brightness(&config, &*clock);
+    let mut cache = EmojiCache::new(config.emoji_cache_size, config.emoji_cache_bytes);
+    let mut assets = AssetHealth::new(config.asset_failure_threshold);
+    let mut asset_checks = tokio::time::interval(config.asset_check_interval);
+    // Emoji found in the emoji directory or font, for logging what a reload changed.
+    let mut emoji_count = count_emoji(&config).ok();
+    loop {
+        // Prefixes showing the command, the ones it's routed to that aren't frozen.
+        let (SourceEvent { source, payload }, prefixes) = tokio::select! {
+            event = next_command(&mut events, &mut commands, &config.router) => match event {
+                Some(event) => {
+                    let prefixes = freeze.admit(&event, config.router.route(&event.source));
+                    if prefixes.is_empty() {
+                        continue;
+                    }
+                    (event, prefixes)
+                }
+                None => break,
+            },
+            Some(control) = controls.recv() => {
+                let command = String::from_utf8_lossy(&control.payload).parse::<ControlCommand>();
+                // Controls only apply to the panels under their prefix.
+                let Some(prefix) = config
+                    .router
+                    .all_prefixes()
+                    .into_iter()
+                    .find(|prefix| control.topic == control_topic(prefix))
+                else {
+                    continue;
+                };
+                match command {
+                    Ok(ControlCommand::Freeze) => {
+                        freeze.freeze(prefix);
+                        continue;
+                    }
+                    Ok(ControlCommand::Unfreeze) => match freeze.unfreeze(prefix) {
+                        Some(event) => (event, vec![prefix]),
+                        None => continue,
+                    },
+                    Ok(ControlCommand::Reload) => {
+                        reload_emoji(&output, &config, &mut cache, &mut emoji_count);
+                        continue;
+                    }
+                    Ok(ControlCommand::Refresh) => {
+                        log::info!("Refreshing {} frames", previous_frames.len());
+                        stop_hue_cycle(&mut hue_cycle);
+                        republish_frames(&output, &config, &*clock, &frozen, &previous_frames)
+                            .await;
+                        hue_cycle =
+                            start_hue_cycle(&output, &config, &clock, &frozen, &uncorrected_frames);
+                        continue;
+                    }
+                    Err(e) => {
+                        log::warn!("Rejected command on {}: {}", control.topic, e);
+                        continue;
+                    }
+                }
+            }
+            Some(frame) = fifo_frames.recv() => {
+                stop_loading_animation(&mut loading);
+                stop_hue_cycle(&mut hue_cycle);
+                for prefix in config.router.route(FIFO_SOURCE_ID) {
+                    if freeze.is_frozen(prefix) {
+                        continue;
+                    }
+                    let topic = frame_topic(prefix, frame.width(), frame.height());
+                    publish_frame(
+                        &output, &config, &*clock, &topic, &frame, "FIFO frame", true,
+                    )
+                    .await;
+                }
+                continue;
+            }
+            _ = clock_ticks.tick(), if minute_ticks => {
+                // Without a clock, frames only change when night mode starts or ends.
+                let dimmed = night_brightness(&config, &*clock);
+                let changed = config.clock_format.is_some() || dimmed != night;
+                night = dimmed;
+                // The hue cycle shows the clock and dimming on its next step.
+                if changed && hue_cycle.is_none() {
+                    republish_frames(&output, &config, &*clock, &frozen, &previous_frames).await;
+                }
+                continue;
+            }
+            // The hue cycle keeps republishing the frames, shifted, so they don't go stale.
+            _ = keyframe_checks.tick(),
+                if config.keyframe_interval.is_some() && hue_cycle.is_none() =>
+            {
+                let connection = output.connection_count();
+                for (topic, frame) in &previous_frames {
+                    if frozen.contains_topic(topic)
+                        || duplicates.is_duplicate(topic, frame, connection, &*clock)
+                    {
+                        continue;
+                    }
+                    if config.pixel_delta {
+                        // The panel may have missed pixels, so every pixel is sent again.
+                        output.state.lock().unwrap().pixels.forget(topic);
+                    }
+                    let shown = with_clock(&config, &*clock, frame);
+                    let published = publish_frame(
+                        &output, &config, &*clock, topic, &shown, "keyframe", true,
+                    )
+                    .await;
+                    if published {
+                        duplicates.record(topic, frame, connection, &*clock);
+                    }
+                }
+                continue;
+            }
+            _ = asset_checks.tick(), if !assets.is_available() => {
+                let directory = PathBuf::from(&config.emoji_directory);
+                if assets::check_directory(directory, DIRECTORY_CHECK_TIMEOUT).await {
+                    record_asset_load(&config, &mut assets, &Ok(())).await;
+                }
+                continue;
+            }
+            Some(reason) = gave_up.recv() => {
+                log::error!("Exiting after too many reconnect attempts. {}", reason);
+                std::process::exit(EXIT_RECONNECT_LIMIT);
+            }
+            _ = output.wait_failed() => {
+                log::error!("Exiting after too many reconnect attempts to the MQTT broker");
+                std::process::exit(EXIT_RECONNECT_LIMIT);
+            }
+            Some(request) = requests.recv() => {
+                if let Some(prefix) = request.topic.strip_suffix("/replay") {
+                    stop_replay(&mut replay);
+                    stop_hue_cycle(&mut hue_cycle);
+                    let panels = replay_panels(&config, &history, prefix);
+                    replay = Some(tokio::spawn(run_replay(
+                        output.clone(),
+                        config.clone(),
+                        clock.clone(),
+                        frozen.clone(),
+                        prefix.to_string(),
+                        panels,
+                    )));
+                } else if let Some(prefix) = request.topic.strip_suffix("/query") {
+                    let shown = current_emoji.get(prefix);
+                    publish_query_response(&output, &config, prefix, shown).await;
+                } else {
+                    publish_requested_size(
+                        &output,
+                        &config,
+                        &*clock,
+                        &mut cache,
+                        &mut assets,
+                        &current_emoji,
+                        &request,
+                    )
+                    .await;
+                }
+                continue;
+            }
         };
+        if live
+            .as_mut()
+            .is_some_and(|live| !live.admit(&source, &*clock))
+        {
+            log::debug!("Live command shown. Holding back the playlist...");
+            continue;
+        }
+        let event_span = Span::receive_event(&source, payload.emoji.as_deref());
+        if config.skip_repeated_events
+            && repeats.is_repeat(&source, &payload, output.connection_count())
+        {
+            log::debug!("Command from {} repeats the last one. Skipping...", source);
+            continue;
+        }
 
-        Ok(Self {
-            emoji_directory: std::env::var(ENV_EMOJI_DIRECTORY).unwrap_or_else(|_| {
-                panic!("{} environment variable not set", ENV_EMOJI_DIRECTORY);
-            }),
-            firebase_url: std::env::var(ENV_FIREBASE_URL).unwrap_or_else(|_| {
-                panic!("{} environment variable not set", ENV_FIREBASE_URL);
-            }),
-            mqtt_client_id: std::env::var(ENV_MQTT_CLIENT_ID).unwrap_or_else(|_| {
-                panic!("{} environment variable not set", ENV_MQTT_CLIENT_ID);
-            }),
-            mqtt_server: std::env::var(ENV_MQTT_HOST).unwrap_or_else(|_| {
-                panic!("{} environment variable not set", ENV_MQTT_HOST);
-            }),
-            mqtt_port: mqtt_port,
-        })
+        // Any new command interrupts an active countdown or replay.
+        if let Some(countdown) = countdown.take() {
+            countdown.abort();
+        }
+        stop_replay(&mut replay);
+
+        if payload.countdown_secs.is_some() || payload.clear {
+            stop_loading_animation(&mut loading);
+            stop_hue_cycle(&mut hue_cycle);
+        }
+        if let Some(secs) = payload.countdown_secs {
+            // The countdown replaces the panel contents, so don't fade from them.
+            previous_frames.retain(|topic, _| !shown_on(topic, &prefixes));
+            uncorrected_frames.retain(|topic, _| !shown_on(topic, &prefixes));
+            duplicates.clear();
+            for prefix in &prefixes {
+                current_emoji.remove(*prefix);
+            }
+            countdown = Some(tokio::spawn(run_countdown(
+                output.clone(),
+                config.clone(),
+                clock.clone(),
+                frozen.clone(),
+                panel_targets(&prefixes, &config.sizes),
+                secs,
+            )));
+            continue;
+        }
+
+        if payload.clear {
+            log::info!("Record from {} was deleted. Clearing...", source);
+            previous_frames.retain(|topic, _| !shown_on(topic, &prefixes));
+            uncorrected_frames.retain(|topic, _| !shown_on(topic, &prefixes));
+            duplicates.clear();
+            for prefix in &prefixes {
+                current_emoji.remove(*prefix);
+            }
+            let targets = panel_targets(&prefixes, &config.sizes);
+            match &clear_splash {
+                Some(splash) => {
+                    event_span
+                        .instrument(publish_splash(&output, &config, &*clock, splash, &targets))
+                        .await
+                }
+                None => {
+                    event_span
+                        .instrument(publish_blank(&output, &config, &*clock, &targets))
+                        .await
+                }
+            }
+            continue;
+        }
+
+        let background = payload.background_color().unwrap_or_else(|background| {
+            log::warn!(
+                "Invalid background {:?} from {}. Using the default...",
+                background,
+                source
+            );
+            None
+        });
+        let split = payload
+            .split_emoji()
+            .map(|(left, right)| (left.to_string(), right.to_string()));
+        // Split panels are labeled with both emoji, for logging and queries.
+        let emoji = match (&split, payload.emoji) {
+            (Some((left, right)), _) => format!("{} {}", left, right),
+            (None, Some(emoji)) => emoji,
+            (None, None) => {
+                log::error!("Payload has no emoji. Skipping...");
+                continue;
+            }
+        };
+
+        let render_span = event_span.in_scope(|| Span::render(&emoji));
+        // The uncorrected frames are kept for the hue cycle.
+        let render = {
+            let config = config.clone();
+            let emoji = emoji.clone();
+            let split = split.clone();
+            let opacity = payload.opacity;
+            move |cache: &mut EmojiCache| -> Result<_, DaemonError> {
+                let uncorrected = render_shown_uncorrected(
+                    &config,
+                    cache,
+                    &emoji,
+                    split.as_ref(),
+                    &config.sizes,
+                    background,
+                    opacity,
+                )?;
+                Ok((
+                    imageutils::corrected(&config, uncorrected.clone()),
+                    uncorrected,
+                ))
+            }
+        };
+        let rendered = render_blocking(&mut cache, render_span, render).await;
+        let logged = record_asset_load(&config, &mut assets, &rendered).await;
+        let (rendered, uncorrected) = match rendered {
+            Ok((mut rendered, mut uncorrected)) => {
+                stop_loading_animation(&mut loading);
+                // Fully transparent emoji were asked for.
+                if payload.opacity != Some(0) {
+                    check_blank_frames(
+                        &config,
+                        &emoji,
+                        background,
+                        &mut rendered,
+                        &mut uncorrected,
+                    );
+                }
+                (rendered, uncorrected)
+            }
+            Err(e @ DaemonError::InvalidEmoji(_)) => {
+                log::warn!("Rejected emoji from {}: {}", source, e);
+                continue;
+            }
+            Err(e) => {
+                if logged {
+                    log::error!("Failed to render {}: {}", emoji, e);
+                }
+                continue;
+            }
+        };
+
+        // Every panel under the prefixes shows the same frames.
+        let mut frames = prefixes
+            .iter()
+            .flat_map(|prefix| {
+                rendered.iter().map(move |(width, height, buf)| {
+                    let frame = RgbImage::from_raw(*width, *height, buf.clone()).unwrap();
+                    (frame_topic(prefix, *width, *height), frame)
+                })
+            })
+            .collect::<Vec<_>>();
+        config
+            .publish_order
+            .sort(&mut frames, |(_, frame)| frame.dimensions());
+
+        if config.skip_duplicates {
+            let connection = output.connection_count();
+            frames.retain(|(topic, frame)| {
+                !duplicates.is_duplicate(topic, frame, connection, &*clock)
+            });
+            if frames.is_empty() {
+                log::info!("{} is already shown. Skipping...", emoji);
+                continue;
+            }
+        }
+
+        // Nothing may shift the hues of the frames about to be shown.
+        stop_hue_cycle(&mut hue_cycle);
+        match (payload.transition, config.fade) {
+            (Some(transition), fade) => {
+                let timing = fade.unwrap_or(DEFAULT_TRANSITION_TIMING);
+                event_span
+                    .instrument(publish_transition(
+                        &output,
+                        &config,
+                        &*clock,
+                        transition,
+                        timing,
+                        &previous_frames,
+                        &frames,
+                    ))
+                    .await;
+            }
+            (None, Some(fade)) => {
+                event_span
+                    .instrument(publish_fade(
+                        &output,
+                        &config,
+                        &*clock,
+                        fade,
+                        &previous_frames,
+                        &frames,
+                    ))
+                    .await;
+            }
+            (None, None) => {}
+        }
+
+        let uncorrected = uncorrected
+            .into_iter()
+            .map(|(width, height, buf)| {
+                let frame = RgbImage::from_raw(width, height, buf).unwrap();
+                ((width, height), frame)
+            })
+            .collect::<HashMap<_, _>>();
+        for (topic, frame) in frames {
+            let shown = with_clock(&config, &*clock, &frame);
+            let published = event_span
+                .instrument(publish_frame(
+                    &output, &config, &*clock, &topic, &shown, &emoji, true,
+                ))
+                .await;
+            // Frames that were throttled or failed aren't duplicates of what's shown.
+            if published {
+                duplicates.record(&topic, &frame, output.connection_count(), &*clock);
+            }
+            if config.frame_history > 0 {
+                history
+                    .entry(topic.clone())
+                    .or_insert_with(|| {
+                        FrameHistory::new(config.frame_history, config.frame_history_bytes)
+                    })
+                    .push(frame.clone());
+            }
+            uncorrected_frames.insert(topic.clone(), uncorrected[&frame.dimensions()].clone());
+            previous_frames.insert(topic, frame);
+        }
+        hue_cycle = start_hue_cycle(&output, &config, &clock, &frozen, &uncorrected_frames);
+        for prefix in &prefixes {
+            let shown = ShownEmoji {
+                emoji: emoji.clone(),
+                split: split.clone(),
+                background,
+                opacity: payload.opacity,
+                shown_at: clock.wall_time(),
+            };
+            current_emoji.insert(prefix.to_string(), shown);
+        }
+        // The icon is too small to split, so it shows the left emoji.
+        let shown = match &split {
+            Some((left, right)) => vec![left.as_str(), right.as_str()],
+            None => vec![emoji.as_str()],
+        };
+        if let Some(icon) = &config.icon {
+            publish_icon(&output, &config, &mut cache, icon, shown[0], background).await;
+        }
+        if let Some(stats) = &stats {
+            let mut stats = stats.lock().unwrap();
+            for emoji in shown {
+                stats.record(emoji);
+            }
+        }
     }
+
+    Ok(())
 }
 
-#[derive(Debug, Deserialize)]
-struct PayloadData {
-    emoji: String,
+/// Waits for the next command to handle, after queueing every command the sources sent
+/// while the previous one was handled, so high priority ones go first, see
+/// `CommandQueue`. `router` tells the panels each command is shown on. `None` once every
+/// source stopped and the queue is empty.
+async fn next_command(
+    events: &mut mpsc::Receiver<SourceEvent>,
+    commands: &mut CommandQueue,
+    router: &Router,
+) -> Option<SourceEvent> {
+    if commands.is_empty() {
+        commands.push(events.recv().await?, router);
+    }
+    while let Ok(event) = events.try_recv() {
+        commands.push(event, router);
+    }
+    commands.pop()
 }
 
-#[derive(Debug, Deserialize)]
-struct Payload {
-    data: PayloadData,
+/// Where the rendered frames go, see `SINK`.
+enum Destination {
+    Mqtt(MqttPublisher),
+    Local(Mutex<Box<dyn FrameSink>>),
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    env_logger::Builder::from_env(Env::default().default_filter_or("daemon=info")).init();
+/// What was published to an `Output`, for the publishes depending on it.
+#[derive(Debug, Default)]
+struct PublishState {
+    // Number of frames published to each topic, see DEBUG_FRAME_COUNTER.
+    frame_counters: BTreeMap<String, u32>,
+    // Last frames published pixel by pixel, see PIXEL_DELTA.
+    pixels: PixelHistory,
+    // Bytes of frames published over the last minute, see MAX_BYTES_PER_MIN.
+    bandwidth: Option<ByteBudget>,
+}
+
+/// Destination of the rendered frames, with what was published to it.
+struct Output {
+    destination: Destination,
+    state: Mutex<PublishState>,
+}
 
-    let config: Config = Config::from_env()?;
+impl Output {
+    fn new(destination: Destination, config: &Config) -> Self {
+        let bandwidth = config
+            .max_bytes_per_min
+            .map(|max| ByteBudget::new(max, BANDWIDTH_WINDOW));
+        Self {
+            destination,
+            state: Mutex::new(PublishState {
+                bandwidth,
+                ..Default::default()
+            }),
+        }
+    }
+
+    /// Number of times the MQTT client connected, see `MqttPublisher::connection_count`.
+    fn connection_count(&self) -> u64 {
+        match &self.destination {
+            Destination::Mqtt(mqtt_client) => mqtt_client.connection_count(),
+            Destination::Local(_) => 0,
+        }
+    }
 
-    let mut mqttoptions =
-        MqttOptions::new(config.mqtt_client_id, config.mqtt_server, config.mqtt_port);
-    mqttoptions.set_max_packet_size(usize::MAX, usize::MAX);
-    mqttoptions.set_keep_alive(Duration::from_secs(5));
+    /// Waits until the MQTT client gives up reconnecting. Never returns for local sinks.
+    async fn wait_failed(&self) {
+        match &self.destination {
+            Destination::Mqtt(mqtt_client) => mqtt_client.wait_failed().await,
+            Destination::Local(_) => std::future::pending().await,
+        }
+    }
+}
 
-    let (mqtt_client, mut eventloop) = AsyncClient::new(mqttoptions, 10);
+/// Runs `source` on its own task, reporting on `gave_up` when it stops reconnecting.
+fn spawn_source<S: EventSource>(
+    source: S,
+    events: mpsc::Sender<SourceEvent>,
+    gave_up: mpsc::Sender<String>,
+) {
+    tokio::spawn(async move {
+        let id = source.id().to_string();
+        let result = source.run(events).await;
+        report_source_result(&id, result, &gave_up).await;
+    });
+}
 
-    // Spawn a task to run the eventloop and ensure tasks progress.
-    task::spawn(async move {
+/// Like `spawn_source`, but aborting the source and running a new one from `source` when
+/// it makes no progress for `threshold`, see `WATCHDOG_SECS`.
+#[cfg(feature = "firebase")]
+fn spawn_watched_source<S: EventSource>(
+    source: impl Fn(Arc<Progress>) -> S + Send + 'static,
+    threshold: Duration,
+    clock: Arc<dyn Clock>,
+    events: mpsc::Sender<SourceEvent>,
+    gave_up: mpsc::Sender<String>,
+) {
+    tokio::spawn(async move {
         loop {
-            let notification = eventloop.poll().await;
-            match notification {
-                Ok(Event::Incoming(Incoming::PingResp) | Event::Outgoing(Outgoing::PingReq)) => {
-                    continue
+            let progress = Arc::new(Progress::new(&*clock));
+            let source = source(progress.clone());
+            let id = source.id().to_string();
+            let mut task = tokio::spawn(source.run(events.clone()));
+            let mut checks = tokio::time::interval(WATCHDOG_CHECK_INTERVAL);
+            let result = loop {
+                tokio::select! {
+                    result = &mut task => break result,
+                    _ = checks.tick() => {
+                        if progress.is_stalled(threshold, &*clock) {
+                            task.abort();
+                        }
+                    }
+                }
+            };
+            match result {
+                Ok(result) => return report_source_result(&id, result, &gave_up).await,
+                Err(e) if e.is_cancelled() => {
+                    log::error!(
+                        "Source {} made no progress for {:?}. Restarting...",
+                        id,
+                        threshold
+                    );
+                }
+                Err(e) => {
+                    log::error!("Source {} panicked: {}", id, e);
+                    return;
                 }
-                Ok(notification) => log::info!("Notification = {:?}", notification),
-                Err(e) => log::error!("Error = {:?}", e),
             }
         }
     });
+}
+
+async fn report_source_result(
+    id: &str,
+    result: Result<(), SourceError>,
+    gave_up: &mpsc::Sender<String>,
+) {
+    match result {
+        Ok(()) => {}
+        Err(e @ SourceError::ReconnectLimit { .. }) => {
+            let _ = gave_up.send(format!("Source {}: {}", id, e)).await;
+        }
+        Err(e) => log::error!("Source {} failed: {}", id, e),
+    }
+}
 
-    // Listen for events from Firebase.
-    let http_client = ClientBuilder::new().build()?;
+/// Saves `stats` to `path` every `interval`.
+async fn save_stats(stats: Arc<Mutex<EmojiStats>>, path: PathBuf, interval: Duration) {
+    let mut ticks = tokio::time::interval(interval);
+    // The first tick completes immediately, and there's nothing new to save yet.
+    ticks.tick().await;
     loop {
-        let Ok(mut response) = http_client
-            .get(&config.firebase_url)
-            .header("Accept", "text/event-stream")
-            .send()
-            .await
-        else {
-            log::error!("Failed to get Firebase URL");
-            continue;
-        };
+        ticks.tick().await;
+        let stats = stats.lock().unwrap().clone();
+        if let Err(e) = stats.save(&path) {
+            log::error!("Failed to save statistics to {}: {}", path.display(), e);
+        }
+    }
+}
 
-        loop {
-            let Ok(chunk) = tokio::time::timeout(Duration::from_secs(60), response.chunk()).await
-            else {
-                log::error!("Timed out getting chunk");
-                break;
-            };
+/// Publishes the number of available emoji to the info topic, so operators can check
+/// the right asset pack is mounted.
+async fn publish_info(output: Arc<Output>, config: Arc<Config>) {
+    let Destination::Mqtt(mqtt_client) = &output.destination else {
+        return;
+    };
+    let emoji_count = match count_emoji(&config) {
+        Ok(count) => count,
+        Err(e) => {
+            log::error!("Failed to read {}: {}", emoji_assets(&config), e);
+            return;
+        }
+    };
+    log::info!("Found {} emoji in {}", emoji_count, emoji_assets(&config));
 
-            let Ok(Some(chunk)) = chunk else {
-                log::error!("Failed to get chunk");
-                break;
-            };
+    let info = Info {
+        emoji_count,
+        emoji_directory: config.emoji_directory.clone(),
+        emoji_font: config
+            .emoji_font
+            .as_ref()
+            .map(|font| font.path().to_string()),
+    };
+    let result = mqtt_client
+        .publish(
+            &config.info_topic,
+            QoS::AtLeastOnce,
+            true,
+            config.meta_encoding.encode(&info),
+        )
+        .await;
+    if let Err(e) = result {
+        log::error!("Failed to publish info to {}: {}", config.info_topic, e);
+    }
+}
+
+/// Publishes a PNG thumbnail of `emoji` to the icon topic, see `ICON_TOPIC`.
+async fn publish_icon(
+    output: &Output,
+    config: &Arc<Config>,
+    cache: &mut EmojiCache,
+    icon: &Icon,
+    emoji: &str,
+    background: Option<Rgb<u8>>,
+) {
+    let Destination::Mqtt(mqtt_client) = &output.destination else {
+        return;
+    };
+    let size = icon.size;
+    let render = {
+        let config = config.clone();
+        let emoji = emoji.to_string();
+        move |cache: &mut EmojiCache| {
+            // Boxed errors can't be sent back from the blocking thread.
+            imageutils::render_icon(&config, cache, &emoji, size, background)
+                .map_err(|e| DaemonError::Image(e.to_string().into()))
+        }
+    };
+    let png = match render_blocking(cache, Span::render(emoji), render).await {
+        Ok(png) => png,
+        Err(e) => {
+            log::error!("Failed to render icon for {}: {}", emoji, e);
+            return;
+        }
+    };
+    let result = mqtt_client
+        .publish(&icon.topic, QoS::AtLeastOnce, true, png)
+        .await;
+    if let Err(e) = result {
+        log::error!("Failed to publish icon to {}: {}", icon.topic, e);
+    }
+}
+
+/// Counts the emoji available in the emoji font when set, otherwise in the emoji directory.
+fn count_emoji(config: &Config) -> io::Result<usize> {
+    match &config.emoji_font {
+        Some(font) => Ok(font.count()),
+        None => count_emoji_assets(Path::new(&config.emoji_directory)),
+    }
+}
+
+/// Where the emoji are loaded from, for logging.
+fn emoji_assets(config: &Config) -> &str {
+    match &config.emoji_font {
+        Some(font) => font.path(),
+        None => &config.emoji_directory,
+    }
+}
+
+/// Picks up the emoji added to or updated in the emoji directory, publishing the new
+/// count to the info topic. Glyphs rasterized from the emoji font are rasterized again.
+fn reload_emoji(
+    output: &Arc<Output>,
+    config: &Arc<Config>,
+    cache: &mut EmojiCache,
+    emoji_count: &mut Option<usize>,
+) {
+    cache.clear();
+    let count = match count_emoji(config) {
+        Ok(count) => count,
+        Err(e) => {
+            log::error!("Failed to read {}: {}", emoji_assets(config), e);
+            return;
+        }
+    };
+    match emoji_count.replace(count) {
+        Some(before) => log::info!(
+            "Reloaded {}: {} emoji, {} before",
+            emoji_assets(config),
+            count,
+            before
+        ),
+        None => log::info!("Reloaded {}: {} emoji", emoji_assets(config), count),
+    }
+    if let Destination::Mqtt(_) = output.destination {
+        tokio::spawn(publish_info(output.clone(), config.clone()));
+    }
+}
+
+/// Renders the emoji shown under the prefix of a size request at the requested size, and
+/// publishes it once to the frame topic for that size.
+async fn publish_requested_size(
+    output: &Output,
+    config: &Arc<Config>,
+    clock: &dyn Clock,
+    cache: &mut EmojiCache,
+    assets: &mut AssetHealth,
+    current_emoji: &HashMap<String, ShownEmoji>,
+    request: &Publish,
+) {
+    let Some(prefix) = request.topic.strip_suffix("/request") else {
+        return;
+    };
+    let (width, height) = match requested_size(config, prefix, &request.payload) {
+        Ok(size) => size,
+        Err(e) => {
+            log::warn!("Rejected size requested on {}: {}", request.topic, e);
+            return;
+        }
+    };
+
+    let Some(ShownEmoji {
+        emoji,
+        split,
+        background,
+        opacity,
+        ..
+    }) = current_emoji.get(prefix)
+    else {
+        log::info!("No emoji shown on {} yet. Skipping size request...", prefix);
+        return;
+    };
+    let rendered = render_shown_blocking(
+        config,
+        cache,
+        Span::render(emoji),
+        emoji,
+        split.as_ref(),
+        &[(width, height)],
+        *background,
+        *opacity,
+    )
+    .await;
+    let logged = record_asset_load(config, assets, &rendered).await;
+    let buf = match rendered {
+        Ok(mut rendered) => rendered.remove(0).2,
+        Err(e) => {
+            if logged {
+                log::error!("Failed to render {}: {}", emoji, e);
+            }
+            return;
+        }
+    };
+
+    // Requested sizes aren't updated when the emoji changes, so they are not retained.
+    let topic = frame_topic(prefix, width, height);
+    let frame = RgbImage::from_raw(width, height, buf).unwrap();
+    publish_frame(output, config, clock, &topic, &frame, emoji, false).await;
+}
+
+/// Parses the size requested for the panels under `prefix`, checking that it's within
+/// `Config::render_pixel_limit` before checking its packet size, which encodes a frame
+/// of that size.
+fn requested_size(config: &Config, prefix: &str, payload: &[u8]) -> Result<(u32, u32), String> {
+    let (width, height) =
+        parse_size(&String::from_utf8_lossy(payload)).map_err(|e| e.to_string())?;
+    config.check_render_size(width, height)?;
+    check_frame_packet_sizes(
+        &[(width, height)],
+        &[prefix],
+        config.output_format.encoder(),
+        config.max_packet_bytes,
+    )
+    .map_err(|e| e.to_string())?;
+    Ok((width, height))
+}
+
+/// Renders `emoji` for `sizes`, or the left and right emoji of `split` side by side when
+/// the panels are split, faded to `opacity` when set.
+fn render_shown(
+    config: &Config,
+    cache: &mut EmojiCache,
+    emoji: &str,
+    split: Option<&(String, String)>,
+    sizes: &[(u32, u32)],
+    background: Option<Rgb<u8>>,
+    opacity: Option<u8>,
+) -> Result<Vec<(u32, u32, Vec<u8>)>, DaemonError> {
+    let frames = render_shown_uncorrected(config, cache, emoji, split, sizes, background, opacity)?;
+    Ok(imageutils::corrected(config, frames))
+}
 
-            let chunk_vec = chunk.to_vec();
-            let chunk_str = String::from_utf8_lossy(&chunk_vec);
-            let lines = chunk_str.lines().collect::<Vec<_>>();
-            if lines.len() < 2 {
-                log::error!("Not enough lines. Skipping...");
+/// Like `render_shown`, but leaving the frames uncorrected, see `imageutils::corrected`.
+fn render_shown_uncorrected(
+    config: &Config,
+    cache: &mut EmojiCache,
+    emoji: &str,
+    split: Option<&(String, String)>,
+    sizes: &[(u32, u32)],
+    background: Option<Rgb<u8>>,
+    opacity: Option<u8>,
+) -> Result<Vec<(u32, u32, Vec<u8>)>, DaemonError> {
+    match split {
+        Some((left, right)) => imageutils::render_split_sizes_uncorrected(
+            config, cache, left, right, sizes, background, opacity,
+        ),
+        None => imageutils::render_emoji_sizes_uncorrected(
+            config, cache, emoji, sizes, background, opacity,
+        ),
+    }
+}
+
+/// Renders like `render_shown`, on a blocking thread, see `render_blocking`.
+#[allow(clippy::too_many_arguments)]
+async fn render_shown_blocking(
+    config: &Arc<Config>,
+    cache: &mut EmojiCache,
+    span: Span,
+    emoji: &str,
+    split: Option<&(String, String)>,
+    sizes: &[(u32, u32)],
+    background: Option<Rgb<u8>>,
+    opacity: Option<u8>,
+) -> Result<Vec<(u32, u32, Vec<u8>)>, DaemonError> {
+    let config = config.clone();
+    let emoji = emoji.to_string();
+    let split = split.cloned();
+    let sizes = sizes.to_vec();
+    render_blocking(cache, span, move |cache| {
+        render_shown(
+            &config,
+            cache,
+            &emoji,
+            split.as_ref(),
+            &sizes,
+            background,
+            opacity,
+        )
+    })
+    .await
+}
+
+/// Runs `render` within `span` on a blocking thread so that resizing and blending large
+/// panels doesn't stall the other tasks. `cache` is moved to that thread for the duration
+/// of the render, and starts empty again when it panics.
+async fn render_blocking<T: Send + 'static>(
+    cache: &mut EmojiCache,
+    span: Span,
+    render: impl FnOnce(&mut EmojiCache) -> Result<T, DaemonError> + Send + 'static,
+) -> Result<T, DaemonError> {
+    let mut owned = std::mem::take(cache);
+    let (owned, rendered) = tokio::task::spawn_blocking(move || {
+        let rendered = span.in_scope(|| render(&mut owned));
+        (owned, rendered)
+    })
+    .await
+    .map_err(|e| DaemonError::Panicked(e.to_string()))?;
+    *cache = owned;
+    rendered
+}
+
+/// Logs the frames rendered for `emoji` that show nothing but background, replacing them
+/// with a placeholder with `BLANK_FRAME_POLICY=fallback`, and their `uncorrected` frames
+/// too.
+fn check_blank_frames(
+    config: &Config,
+    emoji: &str,
+    background: Option<Rgb<u8>>,
+    rendered: &mut [(u32, u32, Vec<u8>)],
+    uncorrected: &mut [(u32, u32, Vec<u8>)],
+) {
+    for ((width, height, buf), (_, _, uncorrected)) in rendered.iter_mut().zip(uncorrected) {
+        if !imageutils::shows_only_background(config, emoji, background, *width, *height, buf) {
+            continue;
+        }
+        log::warn!(
+            "{} renders blank at {}x{}. Check its asset",
+            emoji,
+            width,
+            height
+        );
+        if config.blank_frame_policy == BlankFramePolicy::Fallback {
+            *uncorrected = imageutils::render_text(
+                PLACEHOLDER_TEXT,
+                *width,
+                *height,
+                TEXT_COLOR,
+                BACKGROUND_COLOR,
+            );
+            *buf = uncorrected.clone();
+            imageutils::apply_corrections(buf, *width, *height, config);
+        }
+    }
+}
+
+/// Tracks whether the emoji directory is available from the outcome of loading an emoji,
+/// logging when it becomes unavailable and when it is back. Returns whether a failure
+/// should still be logged, as failures aren't logged one by one while it's unavailable.
+async fn record_asset_load<T>(
+    config: &Config,
+    assets: &mut AssetHealth,
+    result: &Result<T, DaemonError>,
+) -> bool {
+    // Emoji fonts are loaded into memory.
+    if config.emoji_font.is_some() {
+        return true;
+    }
+    let directory = &config.emoji_directory;
+    match result {
+        Ok(_) => {
+            if assets.record_success() {
+                log::info!("Emoji directory {} is available again", directory);
             }
+            true
+        }
+        Err(DaemonError::NotFound(_) | DaemonError::Image(_)) => {
+            let directory_path = PathBuf::from(directory);
+            let available = assets::check_directory(directory_path, DIRECTORY_CHECK_TIMEOUT).await;
+            if assets.record_failure(available) {
+                log::warn!(
+                    "Asset source unavailable: {} can't be read after {} failed loads. \
+                     Checking again every {:?}...",
+                    directory,
+                    assets.failures(),
+                    config.asset_check_interval
+                );
+            }
+            assets.is_available()
+        }
+        Err(_) => true,
+    }
+}
 
-            let Ok((_, command)) = parse_chunk_line(lines[0]) else {
-                log::error!("Failed to parse command: {:?}. Skipping...", lines);
+/// Answers a query for the emoji shown under `prefix`, see `query::query_response`.
+async fn publish_query_response(
+    output: &Output,
+    config: &Config,
+    prefix: &str,
+    shown: Option<&ShownEmoji>,
+) {
+    let Destination::Mqtt(mqtt_client) = &output.destination else {
+        return;
+    };
+    let topic = query_response_topic(prefix);
+    let response = query_response(shown, config.meta_encoding);
+    if let Err(e) = mqtt_client
+        .publish(&topic, QoS::AtLeastOnce, false, response)
+        .await
+    {
+        log::error!("Failed to publish query response to {}: {}", topic, e);
+    }
+}
+
+/// Frame history of every panel under `prefix`, by topic, for replaying it.
+fn replay_panels(
+    config: &Config,
+    history: &HashMap<String, FrameHistory>,
+    prefix: &str,
+) -> Vec<(String, FrameHistory)> {
+    config
+        .sizes
+        .iter()
+        .filter_map(|&(width, height)| {
+            let topic = frame_topic(prefix, width, height);
+            let history = history.get(&topic)?.clone();
+            Some((topic, history))
+        })
+        .collect()
+}
+
+/// Re-publishes the frame history of `panels`, oldest first, showing each step for
+/// `REPLAY_PAUSE`, until done or aborted. Replayed frames are not retained, so
+/// subscribers that reconnect get the current frame again.
+async fn run_replay(
+    output: Arc<Output>,
+    config: Arc<Config>,
+    clock: Arc<dyn Clock>,
+    frozen: FrozenPrefixes,
+    prefix: String,
+    panels: Vec<(String, FrameHistory)>,
+) {
+    let steps = panels
+        .iter()
+        .map(|(_, history)| history.len())
+        .max()
+        .unwrap_or(0);
+    log::info!("Replaying {} frames on {}", steps, prefix);
+
+    for step in 0..steps {
+        for (topic, history) in &panels {
+            if frozen.contains_topic(topic) {
                 continue;
-            };
+            }
+            if let Some(frame) = history.frames().nth(step) {
+                publish_frame(&output, &config, &*clock, topic, frame, "replay", false).await;
+            }
+        }
+        tokio::time::sleep(REPLAY_PAUSE).await;
+    }
+}
 
-            match command {
-                "put" => {
-                    log::info!("Received command {}", command);
-                    let (_, data) = parse_chunk_line(lines[1])?;
-                    let emoji = serde_json::from_str::<Payload>(data).unwrap().data.emoji;
+/// Publishes the frames shown again, by topic, with the clock overlay and night mode
+/// brought up to date. Frozen panels keep what they show.
+async fn republish_frames(
+    output: &Output,
+    config: &Config,
+    clock: &dyn Clock,
+    frozen: &FrozenPrefixes,
+    frames: &HashMap<String, RgbImage>,
+) {
+    for (topic, frame) in frames {
+        if frozen.contains_topic(topic) {
+            continue;
+        }
+        let shown = with_clock(config, clock, frame);
+        publish_frame(output, config, clock, topic, &shown, "refresh", true).await;
+    }
+}
 
-                    let Ok(img) = load_emoji_image(&config.emoji_directory, &emoji) else {
-                        log::error!("Failed to load emoji image for {}", emoji);
-                        continue;
-                    };
-
-                    for (width, height) in SIZES {
-                        let out = img
-                            .resize(width, height, image::imageops::FilterType::Nearest)
-                            .to_rgb8()
-                            .to_vec();
-                        let topic = format!("ledmoji/{}x{}", width, height);
-                        let result = mqtt_client
-                            .publish(&topic, QoS::AtLeastOnce, true, out)
-                            .await;
-                        match result {
-                            Ok(_) => log::info!("Published {emoji} to {topic}"),
-                            Err(e) => {
-                                log::error!("Failed to publish {} to {}: {}", emoji, topic, e)
-                            }
-                        };
-                        thread::sleep(Duration::from_millis(100));
-                    }
-                }
-                "keep-alive" => {
-                    log::debug!("Received keep-alive command");
-                    continue;
+/// Spends `bytes` of the bandwidth budget for publishing to `topic`, returning whether
+/// they fit, see `MAX_BYTES_PER_MIN`.
+fn within_bandwidth(
+    state: &Mutex<PublishState>,
+    config: &Config,
+    clock: &dyn Clock,
+    topic: &str,
+    bytes: usize,
+) -> bool {
+    let mut state = state.lock().unwrap();
+    let Some(budget) = state.bandwidth.as_mut() else {
+        return true;
+    };
+    match budget.spend(bytes as u64, clock) {
+        Spend::Allowed { skipped: None } => true,
+        Spend::Allowed {
+            skipped: Some(skipped),
+        } => {
+            log::info!("Stopped throttling after skipping {} bytes", skipped);
+            true
+        }
+        Spend::Skipped { engaged } => {
+            if engaged {
+                log::warn!(
+                    "Over {} bytes per minute. Throttling publishes...",
+                    config.max_bytes_per_min.unwrap_or_default()
+                );
+            }
+            log::debug!("Skipped {} bytes to {}", bytes, topic);
+            false
+        }
+    }
+}
+
+/// Returns the brightness frames are published with right now, when night mode dims them.
+fn night_brightness(config: &Config, clock: &dyn Clock) -> Option<f32> {
+    let night_mode = config.night_mode.as_ref()?;
+    night_mode.brightness_at(clock.local_time())
+}
+
+/// Returns `frame` with the current time drawn in its corner when the clock overlay is
+/// enabled. The frame is already corrected, so the time is drawn as is.
+fn with_clock(config: &Config, clock: &dyn Clock, frame: &RgbImage) -> RgbImage {
+    let mut frame = frame.clone();
+    if let Some(format) = &config.clock_format {
+        let text = clock
+            .wall_time()
+            .with_timezone(&chrono::Local)
+            .format(format)
+            .to_string();
+        let (width, height) = frame.dimensions();
+        imageutils::draw_corner_text(
+            &mut frame,
+            width,
+            height,
+            &text,
+            TEXT_COLOR,
+            BACKGROUND_COLOR,
+        );
+    }
+    frame
+}
+
+/// Returns whether `topic` is the topic of a panel under one of `prefixes`.
+fn shown_on(topic: &str, prefixes: &[&str]) -> bool {
+    topic
+        .rsplit_once('/')
+        .is_some_and(|(prefix, _)| prefixes.contains(&prefix))
+}
+
+/// Topics and sizes of the frames published for the panels under `prefixes`.
+fn panel_targets(prefixes: &[&str], sizes: &[(u32, u32)]) -> Vec<(String, (u32, u32))> {
+    prefixes
+        .iter()
+        .flat_map(|prefix| {
+            sizes
+                .iter()
+                .map(move |&(width, height)| (frame_topic(prefix, width, height), (width, height)))
+        })
+        .collect()
+}
+
+/// Publishes `frame` to `topic` with `publish_frame_now`, then waits for `PUBLISH_PAUSE`
+/// when publishing over MQTT.
+async fn publish_frame(
+    output: &Output,
+    config: &Config,
+    clock: &dyn Clock,
+    topic: &str,
+    frame: &RgbImage,
+    description: &str,
+    retain: bool,
+) -> bool {
+    let published =
+        publish_frame_now(output, config, clock, topic, frame, description, retain).await;
+    if let Destination::Mqtt(_) = output.destination {
+        tokio::time::sleep(PUBLISH_PAUSE).await;
+    }
+    published
+}
+
+/// Publishes `frame` to `topic`, after reordering it for the matrix layout. The frame
+/// must already be corrected, see `imageutils::apply_corrections`.
+///
+/// Uses the publish settings for the frame size. Frames are only retained when both
+/// `retain` and the settings allow it. With a local sink, the frame is written to it
+/// as is instead. Returns whether the frame was published, ie: it was neither throttled
+/// nor failed.
+///
+/// Unlike `publish_frame`, returns right after publishing, for the frames of animations,
+/// which are paced by their frame rate rather than by how many panels they play on.
+async fn publish_frame_now(
+    output: &Output,
+    config: &Config,
+    clock: &dyn Clock,
+    topic: &str,
+    frame: &RgbImage,
+    description: &str,
+    retain: bool,
+) -> bool {
+    let mut frame = Cow::Borrowed(frame);
+    if let Some(brightness) = night_brightness(config, clock) {
+        imageutils::scale_brightness(frame.to_mut(), brightness);
+    }
+    if config.debug_frame_counter {
+        let mut state = output.state.lock().unwrap();
+        let counter = state.frame_counters.entry(topic.to_string()).or_default();
+        let width = frame.width();
+        imageutils::draw_frame_counter(frame.to_mut(), width, *counter);
+        *counter = counter.wrapping_add(1);
+    }
+    // Last, so that nothing changes the pixels after the limits.
+    if imageutils::has_output_limits(config) {
+        imageutils::apply_output_limits(frame.to_mut(), config);
+    }
+    let buf = frame.as_raw();
+    if config.stdout_preview {
+        print!(
+            "{topic}: {description}\n{}",
+            imageutils::to_ansi(buf, frame.width(), frame.height())
+        );
+    }
+
+    let mqtt_client = match &output.destination {
+        Destination::Mqtt(mqtt_client) => mqtt_client,
+        Destination::Local(sink) => {
+            let mut sink = sink.lock().unwrap();
+            if sink.size() != frame.dimensions() {
+                return false;
+            }
+            return match sink.write_frame(&frame) {
+                Ok(()) => {
+                    log::info!("Drew {description}");
+                    true
                 }
-                command => {
-                    log::info!("Ignoring unknown command {}", command);
-                    continue;
+                Err(e) => {
+                    log::error!("Failed to draw {}: {}", description, e);
+                    false
                 }
+            };
+        }
+    };
+
+    let settings = config.publish_settings(frame.width(), frame.height());
+    let (width, height) = frame.dimensions();
+    let out = imageutils::remap(buf, width, height, config.matrix_layout);
+    if config.output_mode == OutputMode::PerPixel {
+        let retain = retain && settings.retain;
+        return publish_pixels(
+            mqtt_client,
+            &output.state,
+            config,
+            clock,
+            topic,
+            &out,
+            description,
+            settings.qos,
+            retain,
+        )
+        .await;
+    }
+    let mut out = config.output_format.encoder().encode(&out, width, height);
+    let mut topic = topic.to_string();
+    if let Some(compression) = config.compression {
+        out = compression.encode(&out, width, height);
+        topic = format!("{}/{}", topic, compression.name());
+    }
+    let bytes = out.len();
+    // Compressed frames can grow past the size checked on startup.
+    let packet_size = publish_packet_size(&topic, bytes);
+    if packet_size > config.max_packet_bytes {
+        log::error!(
+            "Failed to publish {} to {}: {} byte packet, more than the maximum of {} bytes",
+            description,
+            topic,
+            packet_size,
+            config.max_packet_bytes
+        );
+        return false;
+    }
+    if !within_bandwidth(&output.state, config, clock, &topic, bytes) {
+        return false;
+    }
+    let span = Span::publish(&topic, description);
+    let result = span
+        .instrument(mqtt_client.publish_with_properties(
+            &topic,
+            settings.qos,
+            retain && settings.retain,
+            out,
+            &config.mqtt.publish_properties,
+        ))
+        .await;
+    span.record_publish(bytes, result.as_ref().err().map(|e| e as _));
+    let published = result.is_ok();
+    match result {
+        Ok(_) if PUBLISH_LOG.sample(config.publish_log_sample) => {
+            log::info!("Published {description} to {topic}")
+        }
+        Ok(_) => {}
+        Err(e) => log::error!("Failed to publish {} to {}: {}", description, topic, e),
+    };
+    published
+}
+
+/// Publishes every pixel of a remapped frame as its own message, see `OUTPUT_MODE`, or
+/// only the pixels that changed since the previous frame on `topic` with `PIXEL_DELTA`.
+/// Returns whether every pixel was published, like `publish_frame`.
+#[allow(clippy::too_many_arguments)]
+async fn publish_pixels(
+    mqtt_client: &MqttPublisher,
+    state: &Mutex<PublishState>,
+    config: &Config,
+    clock: &dyn Clock,
+    topic: &str,
+    buf: &[u8],
+    description: &str,
+    qos: QoS,
+    retain: bool,
+) -> bool {
+    let connection = mqtt_client.connection_count();
+    let pixels = if config.pixel_delta {
+        state.lock().unwrap().pixels.changes(topic, buf, connection)
+    } else {
+        pixels::changed_pixels(None, buf)
+    };
+    let bytes = pixels.len() * BYTES_PER_PIXEL;
+    if pixels.is_empty() {
+        return true;
+    }
+    if !within_bandwidth(state, config, clock, topic, bytes) {
+        return false;
+    }
+    let span = Span::publish(topic, description);
+    let publish_all = async {
+        let mut result = Ok(());
+        for (index, color) in &pixels {
+            let pixel_topic = pixels::pixel_topic(topic, *index);
+            let published = mqtt_client
+                .publish(&pixel_topic, qos, retain, color.to_vec())
+                .await;
+            if published.is_err() {
+                result = published;
             }
         }
+        result
+    };
+    let result = span.instrument(publish_all).await;
+    span.record_publish(bytes, result.as_ref().err().map(|e| e as _));
+    let published = result.is_ok();
+    if config.pixel_delta {
+        // Some pixels may have been published, so the panel could show either buffer.
+        let history = &mut state.lock().unwrap().pixels;
+        match published {
+            true => history.commit(topic, buf, connection),
+            false => history.forget(topic),
+        }
     }
+    match result {
+        Ok(_) if PUBLISH_LOG.sample(config.publish_log_sample) => {
+            log::info!(
+                "Published {} pixels of {description} to {topic}",
+                pixels.len()
+            )
+        }
+        Ok(_) => {}
+        Err(e) => log::error!(
+            "Failed to publish pixels of {} to {}: {}",
+            description,
+            topic,
+            e
+        ),
+    };
+    published
 }
 
-fn load_emoji_image(emoji_directory: &str, emoji: &str) -> Result<DynamicImage, Box<dyn Error>> {
-    let unicode = emoji
-        .escape_unicode()
-        .to_string()
-        .replacen("\\u", "emoji_u", 1)
-        .replace("\\u", "_")
-        .replace(['{', '}'], "");
+/// Publishes the intermediate frames of a crossfade from the previously published frames.
+///
+/// Sizes without a previous frame are skipped, so the first emoji appears instantly.
+async fn publish_fade(
+    output: &Output,
+    config: &Config,
+    clock: &dyn Clock,
+    fade: Fade,
+    previous_frames: &HashMap<String, RgbImage>,
+    frames: &[(String, RgbImage)],
+) {
+    let fades = frames
+        .iter()
+        .filter_map(|(topic, frame)| {
+            let previous = previous_frames.get(topic)?;
+            (previous.dimensions() == frame.dimensions()).then_some((topic, previous, frame))
+        })
+        .collect::<Vec<_>>();
+    if fades.is_empty() {
+        return;
+    }
 
-    let mut filename = emoji_directory.to_string() + "/" + &unicode + ".png";
-    if !Path::new(&filename).exists() {
-        let previous_unicode = unicode.rsplitn(2, '_').last().unwrap();
-        filename = emoji_directory.to_string() + "/" + previous_unicode + ".png";
+    let mut ticks = tokio::time::interval(Duration::from_secs(1) / fade.fps);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    for step in 1..fade.frames {
+        ticks.tick().await;
+        let t = step as f32 / fade.frames as f32;
+        for (topic, previous, frame) in &fades {
+            let buf = imageutils::crossfade(previous, frame, t);
+            let faded = RgbImage::from_raw(frame.width(), frame.height(), buf).unwrap();
+            publish_frame_now(output, config, clock, topic, &faded, "fade", true).await;
+        }
+    }
+    ticks.tick().await;
+}
+
+/// Publishes the intermediate frames of a directional transition from the previously
+/// published frames, with the steps and speed of `timing`.
+///
+/// Like fades, sizes without a previous frame cut directly to the new emoji.
+async fn publish_transition(
+    output: &Output,
+    config: &Config,
+    clock: &dyn Clock,
+    transition: Transition,
+    timing: Fade,
+    previous_frames: &HashMap<String, RgbImage>,
+    frames: &[(String, RgbImage)],
+) {
+    let transitions = frames
+        .iter()
+        .filter_map(|(topic, frame)| {
+            let previous = previous_frames.get(topic)?;
+            let steps = imageutils::transition_frames(previous, frame, transition, timing.frames);
+            (!steps.is_empty()).then_some((topic, steps))
+        })
+        .collect::<Vec<_>>();
+    if transitions.is_empty() {
+        return;
     }
 
-    Ok(image::open(filename)?)
+    let mut ticks = tokio::time::interval(Duration::from_secs(1) / timing.fps);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    for step in 0..timing.frames as usize - 1 {
+        ticks.tick().await;
+        for (topic, steps) in &transitions {
+            let frame = &steps[step];
+            publish_frame_now(output, config, clock, topic, frame, "transition", true).await;
+        }
+    }
+    ticks.tick().await;
 }
 
-fn parse_chunk_line(input: &str) -> io::Result<(&str, &str)> {
-    let parts = input.splitn(2, ':').map(|s| s.trim()).collect::<Vec<_>>();
+/// Shows a number counting down from `secs` to 1, one per second, then blanks the panel.
+async fn run_countdown(
+    output: Arc<Output>,
+    config: Arc<Config>,
+    clock: Arc<dyn Clock>,
+    frozen: FrozenPrefixes,
+    targets: Vec<(String, (u32, u32))>,
+    secs: u64,
+) {
+    let mut ticks = tokio::time::interval(Duration::from_secs(1));
+    for remaining in (1..=secs).rev() {
+        ticks.tick().await;
+        let text = remaining.to_string();
+        for (topic, (width, height)) in &targets {
+            if frozen.contains_topic(topic) {
+                continue;
+            }
+            let mut buf =
+                imageutils::render_text(&text, *width, *height, TEXT_COLOR, BACKGROUND_COLOR);
+            imageutils::apply_corrections(&mut buf, *width, *height, &config);
+            let frame = RgbImage::from_raw(*width, *height, buf).unwrap();
+            publish_frame(&output, &config, &*clock, topic, &frame, &text, true).await;
+        }
+    }
+
+    ticks.tick().await;
+    let targets = targets
+        .into_iter()
+        .filter(|(topic, _)| !frozen.contains_topic(topic))
+        .collect::<Vec<_>>();
+    publish_blank(&output, &config, &*clock, &targets).await;
+}
 
-    if parts.len() < 2 {
-        return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid input"));
+/// Shows the loading spinner on the panels at `targets`, taking `fps` steps per second,
+/// until aborted.
+async fn run_loading_animation(
+    output: Arc<Output>,
+    config: Arc<Config>,
+    clock: Arc<dyn Clock>,
+    frozen: FrozenPrefixes,
+    targets: Vec<(String, (u32, u32))>,
+    fps: u32,
+) {
+    log::info!("Showing the loading animation until the first emoji...");
+    let mut ticks = tokio::time::interval(Duration::from_secs(1) / fps);
+    for step in (0..imageutils::SPINNER_STEPS).cycle() {
+        ticks.tick().await;
+        for (topic, (width, height)) in &targets {
+            // Frozen panels keep the step they show.
+            if frozen.contains_topic(topic) {
+                continue;
+            }
+            let mut buf =
+                imageutils::render_spinner(step, *width, *height, TEXT_COLOR, BACKGROUND_COLOR);
+            imageutils::apply_corrections(&mut buf, *width, *height, &config);
+            let frame = RgbImage::from_raw(*width, *height, buf).unwrap();
+            // Not retained, so a panel connecting later doesn't start on a spinner.
+            publish_frame_now(&output, &config, &*clock, topic, &frame, "loading", false).await;
+        }
     }
+}
 
-    Ok(((parts[0]), (parts[1])))
+/// Frame shown at `step` of the hue cycle of the uncorrected `frame`, see `HUE_CYCLE`.
+/// The hues are rotated before `apply_corrections`, so the LUT, gamma and the rest apply
+/// to the colors shown, and the frame doesn't need to be rendered again.
+fn hue_cycle_frame(config: &Config, frame: &RgbImage, step: u32) -> RgbImage {
+    let mut frame = frame.clone();
+    let degrees = (step % HUE_CYCLE_STEPS) as f32 * 360.0 / HUE_CYCLE_STEPS as f32;
+    imageutils::shift_hue(&mut frame, degrees);
+    let (width, height) = frame.dimensions();
+    imageutils::apply_corrections(&mut frame, width, height, config);
+    frame
+}
+
+/// Starts the hue cycle of every panel from the uncorrected frame it shows, including
+/// those of other prefixes, when `HUE_CYCLE` is set.
+fn start_hue_cycle(
+    output: &Arc<Output>,
+    config: &Arc<Config>,
+    clock: &Arc<dyn Clock>,
+    frozen: &FrozenPrefixes,
+    uncorrected_frames: &HashMap<String, RgbImage>,
+) -> Option<JoinHandle<()>> {
+    let period = config.hue_cycle_period?;
+    Some(tokio::spawn(run_hue_cycle(
+        output.clone(),
+        config.clone(),
+        clock.clone(),
+        frozen.clone(),
+        uncorrected_frames.clone(),
+        period,
+    )))
+}
+
+/// Rotates the hues of the uncorrected `frames`, taking `HUE_CYCLE_STEPS` steps every
+/// `period`, until aborted.
+async fn run_hue_cycle(
+    output: Arc<Output>,
+    config: Arc<Config>,
+    clock: Arc<dyn Clock>,
+    frozen: FrozenPrefixes,
+    frames: HashMap<String, RgbImage>,
+    period: Duration,
+) {
+    let mut ticks = tokio::time::interval(period / HUE_CYCLE_STEPS);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // The first tick completes right away, and the frames are already shown.
+    ticks.tick().await;
+    for step in (0..HUE_CYCLE_STEPS).cycle().skip(1) {
+        ticks.tick().await;
+        for (topic, frame) in &frames {
+            if frozen.contains_topic(topic) {
+                continue;
+            }
+            let shown = with_clock(&config, &*clock, &hue_cycle_frame(&config, frame, step));
+            // Not retained, so a panel connecting later starts from the emoji's colors.
+            publish_frame_now(&output, &config, &*clock, topic, &shown, "hue cycle", false).await;
+        }
+    }
+}
+
+/// Stops replaying the frame history, if it's still replaying.
+fn stop_replay(replay: &mut Option<JoinHandle<()>>) {
+    if let Some(replay) = replay.take() {
+        replay.abort();
+    }
+}
+
+/// Stops the hue cycle, if it's running.
+fn stop_hue_cycle(hue_cycle: &mut Option<JoinHandle<()>>) {
+    if let Some(hue_cycle) = hue_cycle.take() {
+        hue_cycle.abort();
+    }
+}
+
+/// Stops the loading animation, if it's still running.
+fn stop_loading_animation(loading: &mut Option<JoinHandle<()>>) {
+    if let Some(loading) = loading.take() {
+        log::info!("Stopping the loading animation");
+        loading.abort();
+    }
+}
+
+/// Shows solid red, green and blue on the panels at `targets`, `pause` apart, then blanks
+/// them, so installers can check the colors and wiring.
+async fn run_selftest(
+    output: &Output,
+    config: &Config,
+    clock: &dyn Clock,
+    targets: &[(String, (u32, u32))],
+    pause: Duration,
+) {
+    log::info!("Running self-test...");
+    let colors = [
+        ("red", [255, 0, 0]),
+        ("green", [0, 255, 0]),
+        ("blue", [0, 0, 255]),
+    ];
+    for (name, color) in colors {
+        for (topic, (width, height)) in targets {
+            let mut frame = RgbImage::from_pixel(*width, *height, Rgb(color));
+            imageutils::apply_corrections(&mut frame, *width, *height, config);
+            publish_frame(output, config, clock, topic, &frame, name, true).await;
+        }
+        tokio::time::sleep(pause).await;
+    }
+    publish_blank(output, config, clock, targets).await;
+}
+
+/// Blanks the panels at `targets`.
+async fn publish_blank(
+    output: &Output,
+    config: &Config,
+    clock: &dyn Clock,
+    targets: &[(String, (u32, u32))],
+) {
+    for (topic, (width, height)) in targets {
+        let mut frame = RgbImage::from_pixel(*width, *height, BACKGROUND_COLOR);
+        imageutils::apply_corrections(&mut frame, *width, *height, config);
+        publish_frame(output, config, clock, topic, &frame, "blank", true).await;
+    }
+}
+
+/// Frames of the `SPLASH_IMAGE` for every size.
+type Splash = HashMap<(u32, u32), RgbImage>;
+
+/// Renders the splash image at `path` for every size, or `None` when it can't be loaded,
+/// so panels are blanked instead.
+fn load_splash(config: &Config, path: &Path) -> Option<Splash> {
+    match imageutils::render_splash(config, path, &config.sizes) {
+        Ok(splash) => {
+            log::info!("Loaded splash image {}", path.display());
+            Some(splash)
+        }
+        Err(e) => {
+            log::warn!(
+                "Failed to load splash image {}: {}. Blanking instead...",
+                path.display(),
+                e
+            );
+            None
+        }
+    }
+}
+
+/// Shows the splash image on the panels at `targets`.
+async fn publish_splash(
+    output: &Output,
+    config: &Config,
+    clock: &dyn Clock,
+    splash: &Splash,
+    targets: &[(String, (u32, u32))],
+) {
+    for (topic, size) in targets {
+        if let Some(frame) = splash.get(size) {
+            publish_frame(output, config, clock, topic, frame, "splash", true).await;
+        }
+    }
 }
 
 #[cfg(test)]
-mod test {
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+        time::{Duration, Instant},
+    };
+
+    use chrono::{NaiveTime, Utc};
+    use image::{Rgb, RgbImage};
+    use mqtt_image_writer::{
+        cache::EmojiCache,
+        clock::{Clock, Sleep, SystemClock},
+        config::{Config, Fade, RuntimeFlavor},
+        error::DaemonError,
+        freeze::{FreezeGate, FreezePolicy, FrozenPrefixes},
+        schedule::NightMode,
+        sink::{FrameSink, SinkError},
+        telemetry::Span,
+    };
+
+    use super::{Destination, Output};
+
+    // Clock stopped at `local_time`, for the behavior depending on the time of day.
+    #[derive(Debug)]
+    struct StoppedClock {
+        local_time: NaiveTime,
+    }
+
+    impl Clock for StoppedClock {
+        fn now(&self) -> Instant {
+            Instant::now()
+        }
+
+        fn local_time(&self) -> NaiveTime {
+            self.local_time
+        }
+
+        fn wall_time(&self) -> chrono::DateTime<Utc> {
+            chrono::DateTime::UNIX_EPOCH
+        }
+
+        fn sleep(&self, duration: Duration) -> Sleep<'_> {
+            Box::pin(tokio::time::sleep(duration))
+        }
+    }
+
+    // Sink keeping the frames written to it.
+    struct RecordingSink(Arc<Mutex<Vec<RgbImage>>>);
+
+    impl FrameSink for RecordingSink {
+        fn size(&self) -> (u32, u32) {
+            (2, 2)
+        }
+
+        fn write_frame(&mut self, frame: &RgbImage) -> Result<(), SinkError> {
+            self.0.lock().unwrap().push(frame.clone());
+            Ok(())
+        }
+    }
+
+    fn recording_output(written: &Arc<Mutex<Vec<RgbImage>>>) -> Output {
+        let sink = RecordingSink(written.clone());
+        Output::new(
+            Destination::Local(Mutex::new(Box::new(sink))),
+            &Config::default(),
+        )
+    }
+
+    #[test]
+    fn builds_every_runtime_flavor() {
+        for flavor in [RuntimeFlavor::MultiThread, RuntimeFlavor::CurrentThread] {
+            let runtime = super::build_runtime(flavor).unwrap();
+            // Timers are enabled, and spawned tasks run.
+            let answer = runtime.block_on(async {
+                tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+                tokio::spawn(async { 42 }).await.unwrap()
+            });
+            assert_eq!(answer, 42);
+        }
+    }
+
+    #[tokio::test]
+    async fn refresh_republishes_frames_unchanged() {
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let output = recording_output(&written);
+        let frame = RgbImage::from_fn(2, 2, |x, y| Rgb([x as u8 * 100, y as u8 * 100, 7]));
+        let frames = HashMap::from([("ledmoji/2x2".to_string(), frame.clone())]);
+
+        let frozen = FrozenPrefixes::default();
+        let config = Config::default();
+        super::republish_frames(&output, &config, &SystemClock, &frozen, &frames).await;
+        assert_eq!(*written.lock().unwrap(), vec![frame]);
+    }
+
+    #[tokio::test]
+    async fn keeps_frozen_panels_when_republishing() {
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let output = recording_output(&written);
+        let frame = RgbImage::from_pixel(2, 2, Rgb([0, 0, 255]));
+        let frames = HashMap::from([
+            ("ledmoji/2x2".to_string(), frame.clone()),
+            ("kitchen/2x2".to_string(), frame.clone()),
+        ]);
+        let mut freeze = FreezeGate::new(FreezePolicy::Drop);
+        freeze.freeze("kitchen");
+
+        let frozen = freeze.frozen();
+        let config = Config::default();
+        super::republish_frames(&output, &config, &SystemClock, &frozen, &frames).await;
+        assert_eq!(*written.lock().unwrap(), vec![frame]);
+    }
+
+    #[tokio::test]
+    async fn applies_output_limits_when_publishing() {
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let output = recording_output(&written);
+        let config = Config {
+            min_brightness: Some(10),
+            channel_max: [255, 150, 255],
+            ..Default::default()
+        };
+        let frame = RgbImage::from_pixel(2, 2, Rgb([0, 200, 5]));
+
+        super::publish_frame(
+            &output,
+            &config,
+            &SystemClock,
+            "ledmoji/2x2",
+            &frame,
+            "test",
+            true,
+        )
+        .await;
+        assert_eq!(
+            *written.lock().unwrap(),
+            vec![RgbImage::from_pixel(2, 2, Rgb([10, 150, 10]))]
+        );
+    }
+
+    #[tokio::test]
+    async fn dims_frames_at_night_on_the_given_clock() {
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let output = recording_output(&written);
+        let config = Config {
+            night_mode: Some(NightMode {
+                window: "22:00-06:00".parse().unwrap(),
+                brightness: 0.5,
+            }),
+            ..Default::default()
+        };
+        let frame = RgbImage::from_pixel(2, 2, Rgb([200, 100, 50]));
+
+        for hour in [12, 23] {
+            let clock = StoppedClock {
+                local_time: NaiveTime::from_hms_opt(hour, 0, 0).unwrap(),
+            };
+            super::publish_frame(
+                &output,
+                &config,
+                &clock,
+                "ledmoji/2x2",
+                &frame,
+                "test",
+                true,
+            )
+            .await;
+        }
+        assert_eq!(
+            *written.lock().unwrap(),
+            vec![frame, RgbImage::from_pixel(2, 2, Rgb([100, 50, 25]))]
+        );
+    }
+
+    #[test]
+    fn hue_cycle_steps_shift_hues() {
+        let config = Config::default();
+        let frame = RgbImage::from_pixel(2, 2, Rgb([255, 0, 0]));
+        let steps = (0..4)
+            .map(|step| super::hue_cycle_frame(&config, &frame, step))
+            .collect::<Vec<_>>();
+        assert_eq!(steps[0], frame);
+        // Every step shifts the hue further from the emoji's colors.
+        for (previous, next) in steps.iter().zip(&steps[1..]) {
+            assert_ne!(previous, next);
+            assert!(next.get_pixel(0, 0)[1] > previous.get_pixel(0, 0)[1]);
+        }
+        // The cycle comes back around to the original frame.
+        assert_eq!(
+            super::hue_cycle_frame(&config, &frame, super::HUE_CYCLE_STEPS),
+            frame
+        );
+    }
+
+    #[test]
+    fn corrects_every_hue_cycle_step() {
+        // The LUT turns off red, so the corrected frame of a red emoji is black.
+        let mut identity = [0; 256];
+        for (i, value) in identity.iter_mut().enumerate() {
+            *value = i as u8;
+        }
+        let config = Config {
+            lut: Some([[0; 256], identity, identity]),
+            ..Default::default()
+        };
+        let frame = RgbImage::from_pixel(2, 2, Rgb([255, 0, 0]));
+
+        assert_eq!(
+            super::hue_cycle_frame(&config, &frame, 0),
+            RgbImage::from_pixel(2, 2, Rgb([0, 0, 0]))
+        );
+        // A third of the way, red turns green, which the LUT lets through.
+        let green = super::hue_cycle_frame(&config, &frame, super::HUE_CYCLE_STEPS / 3);
+        assert_eq!(green.get_pixel(0, 0)[0], 0);
+        assert!(green.get_pixel(0, 0)[1] > 200);
+    }
+
     #[test]
-    fn test_parse_chunk_line() {
-        let input = "event: put\ndata: {\"emoji\":\"👍\"}\n\n";
-        let (command, data) = super::parse_chunk_line(input).unwrap();
-        assert_eq!(command, "event");
-        assert_eq!(data, "put\ndata: {\"emoji\":\"👍\"}");
+    fn refuses_oversized_size_requests() {
+        let config = Config::default();
+        assert_eq!(
+            super::requested_size(&config, "ledmoji", b"64x64"),
+            Ok((64, 64))
+        );
+        for payload in [&b"129x128"[..], b"60000x60000", b"64"] {
+            assert!(super::requested_size(&config, "ledmoji", payload).is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn keeps_serving_events_while_rendering() {
+        // Counts the events served by the runtime, here a tick every 10ms.
+        let served = Arc::new(Mutex::new(0));
+        let ticker = tokio::spawn({
+            let served = served.clone();
+            async move {
+                loop {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                    *served.lock().unwrap() += 1;
+                }
+            }
+        });
+
+        let mut cache = EmojiCache::new(None, None);
+        let rendered = super::render_blocking(&mut cache, Span::render("🐢"), |_| {
+            std::thread::sleep(Duration::from_millis(200));
+            Ok(42)
+        })
+        .await;
+        ticker.abort();
+        assert_eq!(rendered.unwrap(), 42);
+        assert!(*served.lock().unwrap() >= 5);
+    }
+
+    #[tokio::test]
+    async fn reports_renders_panicking() {
+        let mut cache = EmojiCache::new(None, None);
+        let rendered: Result<(), _> =
+            super::render_blocking(&mut cache, Span::render("💥"), |_| panic!("boom")).await;
+        assert!(matches!(rendered, Err(DaemonError::Panicked(_))));
+    }
+
+    #[tokio::test]
+    async fn publishes_splash_to_every_panel() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("logo.png");
+        image::RgbaImage::from_pixel(4, 4, image::Rgba([10, 20, 30, 255]))
+            .save(&path)
+            .unwrap();
+        let config = Config {
+            sizes: vec![(2, 2), (4, 4)],
+            ..Default::default()
+        };
+        let splash = super::load_splash(&config, &path).unwrap();
+        assert_eq!(splash.len(), 2);
+
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let output = recording_output(&written);
+        let targets = super::panel_targets(&["ledmoji", "kitchen"], &config.sizes);
+        super::publish_splash(&output, &config, &SystemClock, &splash, &targets).await;
+        // The sink only shows the 2x2 frames, one per panel.
+        let splash_2x2 = RgbImage::from_pixel(2, 2, Rgb([10, 20, 30]));
+        assert_eq!(
+            *written.lock().unwrap(),
+            vec![splash_2x2.clone(), splash_2x2]
+        );
+
+        assert!(super::load_splash(&config, &dir.path().join("missing.png")).is_none());
+    }
+
+    #[tokio::test]
+    async fn counts_frames_of_each_output() {
+        let config = Config {
+            debug_frame_counter: true,
+            ..Default::default()
+        };
+        let frame = RgbImage::new(2, 2);
+        let (first, second) = (Default::default(), Default::default());
+        let outputs = [recording_output(&first), recording_output(&second)];
+        for output in &outputs {
+            super::publish_frame(
+                output,
+                &config,
+                &SystemClock,
+                "ledmoji/2x2",
+                &frame,
+                "",
+                true,
+            )
+            .await;
+        }
+        super::publish_frame(
+            &outputs[1],
+            &config,
+            &SystemClock,
+            "ledmoji/2x2",
+            &frame,
+            "",
+            true,
+        )
+        .await;
+
+        // Each output starts counting from 0.
+        let second = second.lock().unwrap();
+        assert_eq!(*first.lock().unwrap(), second[..1]);
+        assert_ne!(second[0], second[1]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fades_at_the_configured_frame_rate() {
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let output = recording_output(&written);
+        let topic = "ledmoji/2x2".to_string();
+        let previous_frames = HashMap::from([(topic.clone(), RgbImage::new(2, 2))]);
+        let frames = [(topic, RgbImage::from_pixel(2, 2, Rgb([200, 100, 0])))];
+        let fade = Fade {
+            frames: 10,
+            fps: 20,
+        };
+
+        let start = tokio::time::Instant::now();
+        let config = Config::default();
+        super::publish_fade(
+            &output,
+            &config,
+            &SystemClock,
+            fade,
+            &previous_frames,
+            &frames,
+        )
+        .await;
+        // Every frame but the last, which shows the new emoji, 50ms apart.
+        assert_eq!(written.lock().unwrap().len(), 9);
+        assert_eq!(start.elapsed(), Duration::from_millis(450));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn loading_animation_runs_at_the_configured_frame_rate() {
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let output = recording_output(&written);
+        let targets = super::panel_targets(&["ledmoji"], &[(2, 2)]);
+        let loading = super::run_loading_animation(
+            Arc::new(output),
+            Arc::new(Config::default()),
+            Arc::new(SystemClock),
+            FrozenPrefixes::default(),
+            targets,
+            10,
+        );
+
+        let _ = tokio::time::timeout(Duration::from_millis(950), loading).await;
+        // The first step right away, then one every 100ms.
+        assert_eq!(written.lock().unwrap().len(), 10);
     }
 }
@@ -15,25 +15,24 @@
 
 use std::{error::Error, thread, time::Duration};
 
-use env_logger::Env;
-use image::{GenericImageView, Rgb};
-use mqtt_image_writer::imageutils::merge_colors;
+use image::Rgb;
+use mqtt_image_writer::{
+    imageutils::{blend_onto, BlendSpace},
+    logging,
+};
 use rumqttc::{Client, Event, MqttOptions, Packet, QoS};
 
 const BACKGROUND_COLOR: Rgb<u8> = Rgb([0, 0, 0]);
 
 fn main() -> Result<(), Box<dyn Error>> {
-    env_logger::Builder::from_env(Env::default().default_filter_or("send_one=debug")).init();
+    logging::init("send_one=debug");
 
     // Load image...
     let img =
         image::open("./assets/chrome.png")?.resize(32, 32, image::imageops::FilterType::Nearest);
     let width = img.width();
     let height = img.height();
-    let img = img
-        .pixels()
-        .flat_map(|(_x, _y, rgba)| merge_colors(&rgba, &BACKGROUND_COLOR))
-        .collect::<Vec<_>>();
+    let img = blend_onto(img.to_rgba8().as_raw(), &BACKGROUND_COLOR, BlendSpace::Srgb);
 
     let mut mqttoptions = MqttOptions::new("send-one", "brucebanner.local", 1883);
     mqttoptions.set_max_packet_size(usize::MAX, usize::MAX);
@@ -0,0 +1,124 @@
+// Copyright 2023 André Cipriani Bandarra
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use crate::clock::Clock;
+
+/// Outcome of `ByteBudget::spend`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Spend {
+    /// The bytes fit in the budget. When this ends throttling, `skipped` is the number of
+    /// bytes skipped while throttled.
+    Allowed { skipped: Option<u64> },
+    /// The bytes would exceed the budget. `engaged` is set for the first skip since the
+    /// budget last had room.
+    Skipped { engaged: bool },
+}
+
+/// Caps the bytes published over a rolling window, eg: on a metered link.
+///
+/// Publishes that would exceed the budget are skipped, while smaller ones that still fit
+/// go through, so small panels keep updating when large frames no longer fit.
+#[derive(Debug, Clone)]
+pub struct ByteBudget {
+    max_bytes: u64,
+    window: Duration,
+    // Bytes spent in the window, oldest first.
+    spent: VecDeque<(Instant, u64)>,
+    total: u64,
+    // Bytes skipped since throttling engaged, if it is.
+    skipped: Option<u64>,
+}
+
+impl ByteBudget {
+    pub fn new(max_bytes: u64, window: Duration) -> Self {
+        Self {
+            max_bytes,
+            window,
+            spent: VecDeque::new(),
+            total: 0,
+            skipped: None,
+        }
+    }
+
+    /// Spends `bytes` of the budget if they fit in what's left of the window ending now.
+    pub fn spend(&mut self, bytes: u64, clock: &dyn Clock) -> Spend {
+        let now = clock.now();
+        while let Some(&(spent_at, spent)) = self.spent.front() {
+            if now.saturating_duration_since(spent_at) < self.window {
+                break;
+            }
+            self.spent.pop_front();
+            self.total -= spent;
+        }
+
+        if self.total + bytes > self.max_bytes {
+            let engaged = self.skipped.is_none();
+            *self.skipped.get_or_insert(0) += bytes;
+            return Spend::Skipped { engaged };
+        }
+        self.spent.push_back((now, bytes));
+        self.total += bytes;
+        Spend::Allowed {
+            skipped: self.skipped.take(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{ByteBudget, Spend};
+    use crate::clock::FakeClock;
+
+    #[test]
+    fn skips_publishes_over_budget() {
+        let clock = FakeClock::default();
+        let mut budget = ByteBudget::new(100, Duration::from_secs(60));
+        assert_eq!(budget.spend(60, &clock), Spend::Allowed { skipped: None });
+
+        // A large frame doesn't fit anymore, while a small one still does.
+        assert_eq!(budget.spend(50, &clock), Spend::Skipped { engaged: true });
+        assert_eq!(budget.spend(50, &clock), Spend::Skipped { engaged: false });
+        assert_eq!(
+            budget.spend(30, &clock),
+            Spend::Allowed { skipped: Some(100) }
+        );
+        assert_eq!(budget.spend(20, &clock), Spend::Skipped { engaged: true });
+    }
+
+    #[test]
+    fn frees_budget_as_the_window_rolls() {
+        let clock = FakeClock::default();
+        let mut budget = ByteBudget::new(100, Duration::from_secs(60));
+        assert_eq!(budget.spend(60, &clock), Spend::Allowed { skipped: None });
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(budget.spend(40, &clock), Spend::Allowed { skipped: None });
+        assert_eq!(budget.spend(10, &clock), Spend::Skipped { engaged: true });
+
+        // The first 60 bytes leave the window, the last 40 don't.
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(
+            budget.spend(60, &clock),
+            Spend::Allowed { skipped: Some(10) }
+        );
+        assert_eq!(budget.spend(1, &clock), Spend::Skipped { engaged: true });
+    }
+}
@@ -0,0 +1,210 @@
+// Copyright 2023 André Cipriani Bandarra
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::{collections::VecDeque, sync::Arc};
+
+use image::DynamicImage;
+
+use crate::{emoji::load_emoji_image, error::DaemonError};
+
+/// Least recently used cache of decoded emoji images, so repeated emoji skip the
+/// filesystem and decoding.
+///
+/// Bounded by the number of entries, by the bytes of decoded pixel data, or both: the
+/// least recently used images are evicted until every configured limit holds. Without
+/// any limit nothing is cached.
+#[derive(Debug, Clone, Default)]
+pub struct EmojiCache {
+    max_entries: Option<usize>,
+    max_bytes: Option<usize>,
+    bytes: usize,
+    // Least recently used first.
+    entries: VecDeque<(String, Arc<DynamicImage>)>,
+}
+
+impl EmojiCache {
+    pub fn new(max_entries: Option<usize>, max_bytes: Option<usize>) -> Self {
+        Self {
+            max_entries,
+            max_bytes,
+            ..Default::default()
+        }
+    }
+
+    /// Returns the image for `emoji`, marking it as the most recently used.
+    pub fn get(&mut self, emoji: &str) -> Option<Arc<DynamicImage>> {
+        let index = self.entries.iter().position(|(key, _)| key == emoji)?;
+        let entry = self.entries.remove(index).unwrap();
+        let img = entry.1.clone();
+        self.entries.push_back(entry);
+        Some(img)
+    }
+
+    /// Adds `img` as the most recently used image, evicting others over the limits.
+    ///
+    /// Images larger than the byte budget on their own are not cached.
+    pub fn insert(&mut self, emoji: &str, img: Arc<DynamicImage>) {
+        let size = image_bytes(&img);
+        if (self.max_entries.is_none() && self.max_bytes.is_none())
+            || self.max_entries == Some(0)
+            || self.max_bytes.is_some_and(|max| size > max)
+        {
+            return;
+        }
+
+        if let Some(index) = self.entries.iter().position(|(key, _)| key == emoji) {
+            let (_, old) = self.entries.remove(index).unwrap();
+            self.bytes -= image_bytes(&old);
+        }
+        self.bytes += size;
+        self.entries.push_back((emoji.to_string(), img));
+        while self.max_entries.is_some_and(|max| self.entries.len() > max)
+            || self.max_bytes.is_some_and(|max| self.bytes > max)
+        {
+            let (_, evicted) = self.entries.pop_front().unwrap();
+            self.bytes -= image_bytes(&evicted);
+        }
+    }
+
+    /// Returns the image for `emoji`, loading it from `emoji_directory` when not cached,
+    /// see `load_emoji_image`.
+    pub fn load(
+        &mut self,
+        emoji_directory: &str,
+        emoji: &str,
+        max_pixels: Option<u64>,
+    ) -> Result<Arc<DynamicImage>, DaemonError> {
+        self.load_with(emoji, || {
+            load_emoji_image(emoji_directory, emoji, max_pixels)
+        })
+    }
+
+    /// Returns the image cached under `key`, loading it with `load` when not cached.
+    pub fn load_with(
+        &mut self,
+        key: &str,
+        load: impl FnOnce() -> Result<DynamicImage, DaemonError>,
+    ) -> Result<Arc<DynamicImage>, DaemonError> {
+        if let Some(img) = self.get(key) {
+            return Ok(img);
+        }
+        let img = Arc::new(load()?);
+        self.insert(key, img.clone());
+        Ok(img)
+    }
+
+    /// Forgets every cached image, so updated assets are loaded again.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.bytes = 0;
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Bytes of decoded pixel data held.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+}
+
+fn image_bytes(img: &DynamicImage) -> usize {
+    img.as_bytes().len()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use image::{DynamicImage, RgbaImage};
+
+    use super::EmojiCache;
+
+    // An RGBA image of `side`x`side` pixels, 4 * side * side bytes.
+    fn image(side: u32) -> Arc<DynamicImage> {
+        Arc::new(DynamicImage::ImageRgba8(RgbaImage::new(side, side)))
+    }
+
+    fn keys(cache: &EmojiCache) -> Vec<&str> {
+        cache.entries.iter().map(|(key, _)| key.as_str()).collect()
+    }
+
+    #[test]
+    fn evicts_least_recently_used_over_byte_budget() {
+        // 16x16 images are 1 KiB and the 32x32 one is 4 KiB, in a 6 KiB budget.
+        let mut cache = EmojiCache::new(None, Some(6 * 1024));
+        cache.insert("a", image(16));
+        cache.insert("b", image(16));
+        cache.insert("c", image(16));
+        assert!(cache.get("a").is_some());
+
+        cache.insert("big", image(32));
+        assert_eq!(keys(&cache), vec!["c", "a", "big"]);
+        assert_eq!(cache.bytes(), 6 * 1024);
+
+        // Too large to ever fit, so it's not cached and nothing is evicted for it.
+        cache.insert("huge", image(64));
+        assert_eq!(keys(&cache), vec!["c", "a", "big"]);
+    }
+
+    #[test]
+    fn applies_count_and_byte_limits_together() {
+        let mut cache = EmojiCache::new(Some(2), Some(4 * 1024));
+        cache.insert("a", image(16));
+        cache.insert("b", image(16));
+        cache.insert("c", image(16));
+        assert_eq!(keys(&cache), vec!["b", "c"]);
+
+        cache.insert("big", image(32));
+        assert_eq!(keys(&cache), vec!["big"]);
+        assert_eq!(cache.bytes(), 4 * 1024);
+    }
+
+    #[test]
+    fn picks_up_new_and_updated_assets_after_clearing() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir_str = dir.path().to_str().unwrap();
+        let save = |name: &str, side: u32| {
+            RgbaImage::new(side, side)
+                .save(dir.path().join(name))
+                .unwrap()
+        };
+        save("emoji_u1f44d.png", 1);
+        let mut cache = EmojiCache::new(Some(10), None);
+        assert_eq!(cache.load(dir_str, "👍", None).unwrap().width(), 1);
+        assert!(cache.load(dir_str, "❤", None).is_err());
+
+        save("emoji_u1f44d.png", 2);
+        save("emoji_u2764.png", 1);
+        assert_eq!(cache.load(dir_str, "👍", None).unwrap().width(), 1);
+
+        cache.clear();
+        assert!(cache.is_empty());
+        assert_eq!(cache.load(dir_str, "👍", None).unwrap().width(), 2);
+        assert!(cache.load(dir_str, "❤", None).is_ok());
+    }
+
+    #[test]
+    fn caches_nothing_without_limits() {
+        let mut cache = EmojiCache::new(None, None);
+        cache.insert("a", image(16));
+        assert!(cache.is_empty());
+    }
+}
@@ -0,0 +1,151 @@
+// Copyright 2023 André Cipriani Bandarra
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Time source for the time-based behavior of the daemon.
+//!
+//! Helpers that depend on time (eg: the stall watchdog, reconnect backoff or night mode)
+//! take a `Clock` rather than reading the time or sleeping directly. The daemon passes
+//! `SystemClock`, while tests pass a `FakeClock` and move it forward with `advance`, so
+//! timeouts can be tested deterministically and without waiting.
+
+use std::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, NaiveTime, Utc};
+
+/// Future returned by `Clock::sleep`.
+pub type Sleep<'a> = Pin<Box<dyn Future<Output = ()> + Send + 'a>>;
+
+pub trait Clock: fmt::Debug + Send + Sync {
+    /// Monotonic time, for measuring timeouts.
+    fn now(&self) -> Instant;
+
+    /// Local wall clock time of day, for schedules.
+    fn local_time(&self) -> NaiveTime;
+
+    /// Wall clock date and time, for timestamps and the clock overlay.
+    fn wall_time(&self) -> DateTime<Utc>;
+
+    /// Waits for `duration` to elapse on this clock.
+    fn sleep(&self, duration: Duration) -> Sleep<'_>;
+}
+
+/// The real clock, backed by tokio and the system time zone.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn local_time(&self) -> NaiveTime {
+        chrono::Local::now().time()
+    }
+
+    fn wall_time(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+
+    fn sleep(&self, duration: Duration) -> Sleep<'_> {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+/// Clock that only moves when told to. Sleeping advances it instantly.
+#[cfg(test)]
+#[derive(Debug)]
+pub struct FakeClock {
+    start: Instant,
+    elapsed: std::sync::Mutex<Duration>,
+    local_start: NaiveTime,
+}
+
+#[cfg(test)]
+impl FakeClock {
+    /// Creates a clock starting at `local_time`, with no time elapsed.
+    pub fn new(local_time: NaiveTime) -> Self {
+        Self {
+            start: Instant::now(),
+            elapsed: Default::default(),
+            local_start: local_time,
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.elapsed.lock().unwrap() += duration;
+    }
+
+    /// Time elapsed since the clock was created.
+    pub fn elapsed(&self) -> Duration {
+        *self.elapsed.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+impl Default for FakeClock {
+    fn default() -> Self {
+        Self::new(NaiveTime::MIN)
+    }
+}
+
+#[cfg(test)]
+impl Clock for FakeClock {
+    fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+
+    fn local_time(&self) -> NaiveTime {
+        self.local_start + chrono::Duration::from_std(self.elapsed()).unwrap()
+    }
+
+    // Starts at the Unix epoch, whatever `local_time` starts at.
+    fn wall_time(&self) -> DateTime<Utc> {
+        DateTime::UNIX_EPOCH + chrono::Duration::from_std(self.elapsed()).unwrap()
+    }
+
+    fn sleep(&self, duration: Duration) -> Sleep<'_> {
+        self.advance(duration);
+        Box::pin(std::future::ready(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chrono::NaiveTime;
+
+    use super::{Clock, FakeClock};
+
+    #[tokio::test]
+    async fn fake_clock_moves_on_sleep() {
+        let clock = FakeClock::new(NaiveTime::from_hms_opt(23, 59, 0).unwrap());
+        let start = clock.now();
+
+        clock.sleep(Duration::from_secs(90)).await;
+        assert_eq!(clock.now() - start, Duration::from_secs(90));
+        // The wall clock wraps past midnight.
+        assert_eq!(
+            clock.local_time(),
+            NaiveTime::from_hms_opt(0, 0, 30).unwrap()
+        );
+        assert_eq!(clock.wall_time().timestamp(), 90);
+    }
+}
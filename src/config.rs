@@ -0,0 +1,1328 @@
+// Copyright 2023 André Cipriani Bandarra
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt::Write,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+
+use image::Rgb;
+use rumqttc::{tokio_rustls::rustls::ClientConfig, v5, MqttOptions, TlsConfiguration, Transport};
+
+use crate::{
+    backoff::BackoffKind,
+    chipset::Chipset,
+    emoji::DEFAULT_MAX_EMOJI_CODEPOINTS,
+    encoder::OutputFormat,
+    font::EmojiFont,
+    freeze::FreezePolicy,
+    imageutils::{
+        load_backgrounds, load_lut, parse_color, BlankFramePolicy, BlendSpace, Compression, Dither,
+        Lut, MatrixLayout, PanelShape, ToneMap,
+    },
+    meta::MetaEncoding,
+    mqtt::{
+        check_frame_packet_sizes, MqttVersion, PublishOrder, PublishProperties, PublishSettings,
+        MAX_MQTT_PACKET_BYTES,
+    },
+    payload::PayloadFormat,
+    pixels::OutputMode,
+    playlist::Playlist,
+    render::{parse_size, ResizeMode, ScaleFilter},
+    router::Router,
+    schedule::NightMode,
+    sink::SinkKind,
+    source::{FirebaseSource, MalformedPolicy, DEFAULT_SOURCE_ID},
+    stdin_source::EofPolicy,
+    tls::{self, ClientAuth},
+};
+
+pub const DEFAULT_SIZES: [(u32, u32); 2] = [(32, 32), (128, 128)];
+
+// Frames are published as RGB, with 3 bytes per pixel.
+pub const BYTES_PER_PIXEL: usize = 3;
+
+// Path to Noto Emoji font directory (https://github.com/googlefonts/noto-emoji)
+static ENV_EMOJI_DIRECTORY: &str = "EMOJI_DIRECTORY";
+
+// Path to a color emoji font with bitmap glyphs (eg: NotoColorEmoji.ttf) to rasterize the
+// emoji from, instead of EMOJI_DIRECTORY, which isn't required when this is set.
+static ENV_EMOJI_FONT: &str = "EMOJI_FONT";
+
+// URL to the firebase database record to listen to.
+// eg: 'https://my-firebase-project.firebaseio.com/ledgrids/1.json'
+static ENV_FIREBASE_URL: &str = "FIREBASE_URL";
+
+// Comma separated list of named Firebase records to listen to, as 'id=url'. When set,
+// FIREBASE_URL is not required.
+// eg: 'kitchen=https://my-project.firebaseio.com/ledgrids/1.json,office=https://...'
+static ENV_FIREBASE_SOURCES: &str = "FIREBASE_SOURCES";
+
+// Path to a JSON file with the command to show, in the PAYLOAD_FORMAT shape. The file is
+// read again whenever it changes. When set, FIREBASE_URL is not required.
+static ENV_EVENT_FILE: &str = "EVENT_FILE";
+
+// Where else commands are read from. Only "stdin" is supported, reading one emoji or
+// command per line, see stdin_source::StdinCommand. When set, FIREBASE_URL is not
+// required.
+static ENV_EMOJI_SOURCE: &str = "EMOJI_SOURCE";
+
+// What the stdin source does at the end of the input: "exit" (the default) or "idle",
+// keeping the daemon running.
+static ENV_STDIN_EOF: &str = "STDIN_EOF";
+
+// Path to a named pipe custom renderers write raw frames to, see frame_fifo::FrameFifo.
+// Frames are FRAME_FIFO_SIZE (the first of SIZES by default) RGB, and are routed as the
+// 'fifo' source. When set, FIREBASE_URL is not required.
+static ENV_FRAME_FIFO: &str = "FRAME_FIFO";
+static ENV_FRAME_FIFO_SIZE: &str = "FRAME_FIFO_SIZE";
+
+// Comma separated emoji shown in turn every PLAYLIST_INTERVAL_SECS, looping, and routed
+// as the 'playlist' source. Commands from live sources hold the playlist back for
+// PLAYLIST_RESUME_SECS (one interval by default). When set, FIREBASE_URL is not required.
+// eg: '👍,😀,🎉'
+static ENV_PLAYLIST: &str = "PLAYLIST";
+static ENV_PLAYLIST_INTERVAL_SECS: &str = "PLAYLIST_INTERVAL_SECS";
+static DEFAULT_PLAYLIST_INTERVAL_SECS: u64 = 60;
+static ENV_PLAYLIST_RESUME_SECS: &str = "PLAYLIST_RESUME_SECS";
+
+// Routes from source ids to the topic prefixes of the panels showing them, see
+// Router::parse. eg: 'kitchen=ledmoji/kitchen,office=ledmoji/office,alerts=*'
+static ENV_ROUTES: &str = "ROUTES";
+
+// Where frames go: 'mqtt' (the default) or 'framebuffer:<device>' to draw them on a
+// local display, eg: 'framebuffer:/dev/fb0'. The MQTT settings are not required for a
+// framebuffer, and SIZES must include its resolution.
+static ENV_SINK: &str = "SINK";
+
+// Seconds to wait before starting, eg: for services started alongside the daemon.
+static ENV_STARTUP_DELAY_SECS: &str = "STARTUP_DELAY_SECS";
+// When set, waits up to this many seconds for the MQTT host to accept TCP connections
+// before starting, after STARTUP_DELAY_SECS.
+static ENV_STARTUP_WAIT_TIMEOUT: &str = "STARTUP_WAIT_TIMEOUT";
+// When set, waits for the MQTT broker to accept the connection before starting, exiting
+// after this many failed attempts, or after STARTUP_CONNECT_TIMEOUT seconds (30 by
+// default). Starts right away and keeps trying to connect when not set.
+static ENV_STARTUP_CONNECT_ATTEMPTS: &str = "STARTUP_CONNECT_ATTEMPTS";
+static ENV_STARTUP_CONNECT_TIMEOUT: &str = "STARTUP_CONNECT_TIMEOUT";
+static DEFAULT_STARTUP_CONNECT_TIMEOUT_SECS: u64 = 30;
+
+// MQTT client ID to use.
+static ENV_MQTT_CLIENT_ID: &str = "MQTT_CLIENT_ID";
+static ENV_MQTT_HOST: &str = "MQTT_HOST";
+static ENV_MQTT_PORT: &str = "MQTT_PORT";
+static DEFAULT_MQTT_PORT: u16 = 1883;
+static DEFAULT_MQTT_TLS_PORT: u16 = 8883;
+
+// Connect to the broker over TLS, verifying it against MQTT_CA_CERT when set, or against
+// the system roots otherwise. Implied by MQTT_CLIENT_CERT.
+static ENV_MQTT_TLS: &str = "MQTT_TLS";
+static ENV_MQTT_CA_CERT: &str = "MQTT_CA_CERT";
+
+// PEM certificate chain and private key authenticating the daemon to the broker (mutual
+// TLS, eg: AWS IoT Core). Both must be set together.
+static ENV_MQTT_CLIENT_CERT: &str = "MQTT_CLIENT_CERT";
+static ENV_MQTT_CLIENT_KEY: &str = "MQTT_CLIENT_KEY";
+
+// MQTT protocol version: 4 (3.1.1, the default) or 5. Version 5 falls back to 3.1.1 when
+// the broker doesn't support it.
+static ENV_MQTT_VERSION: &str = "MQTT_VERSION";
+
+// Content type and `key=value,...` user properties of published frames, eg: `panel=1`.
+// Need MQTT_VERSION=5. The content type defaults to the MIME type of OUTPUT_FORMAT, and a
+// `format` property naming OUTPUT_FORMAT is added unless one is set, so panels can tell
+// the byte order.
+static ENV_MQTT_CONTENT_TYPE: &str = "MQTT_CONTENT_TYPE";
+static ENV_MQTT_USER_PROPERTIES: &str = "MQTT_USER_PROPERTIES";
+
+// Reconnect to Firebase when no event (including keep-alives) arrives for this many
+// seconds. When not set, only the chunk timeout applies.
+static ENV_STREAM_STALL_SECS: &str = "STREAM_STALL_SECS";
+
+// Restart a Firebase source that made no progress (no connection attempt, chunk or
+// timeout) for this many seconds, eg: stuck in a request that never returns. Must be
+// more than MIN_WATCHDOG_SECS, the longest a healthy source waits between two of them.
+// Disabled when not set.
+static ENV_WATCHDOG_SECS: &str = "WATCHDOG_SECS";
+static MIN_WATCHDOG_SECS: u64 = 60;
+
+// What to do when Firebase keeps sending malformed lines or payloads: "skip" them (the
+// default) or "reconnect" after MALFORMED_LIMIT (5 by default) of them in a row.
+static ENV_MALFORMED_POLICY: &str = "MALFORMED_POLICY";
+static ENV_MALFORMED_LIMIT: &str = "MALFORMED_LIMIT";
+static DEFAULT_MALFORMED_LIMIT: u32 = 5;
+
+// Shape of the JSON payload: "firebase" (default) for {"data": {"emoji": "..."}} or
+// "flat" for {"emoji_char": "..."}.
+static ENV_PAYLOAD_FORMAT: &str = "PAYLOAD_FORMAT";
+
+// Number of intermediate frames used to crossfade between consecutive emoji, and the
+// rate they are published at. Fading is disabled when FADE_FRAMES is not set or 0.
+static ENV_FADE_FRAMES: &str = "FADE_FRAMES";
+static ENV_FADE_FPS: &str = "FADE_FPS";
+static DEFAULT_FADE_FPS: u32 = 20;
+
+// Comma separated list of panel sizes to publish, eg: '32x32,128x128'.
+static ENV_SIZES: &str = "SIZES";
+
+// Prefix of the variables overriding how frames of a size are published, see
+// PublishSettings::parse. eg: 'TOPIC_SETTINGS_128x128=qos:0,retain:false'.
+static ENV_TOPIC_SETTINGS_PREFIX: &str = "TOPIC_SETTINGS_";
+
+// Largest MQTT packet the broker accepts. Defaults to the MQTT protocol limit.
+static ENV_MAX_PACKET_BYTES: &str = "MAX_PACKET_BYTES";
+
+// Port for the HTTP render preview API. The API is disabled when not set.
+static ENV_RENDER_API_PORT: &str = "RENDER_API_PORT";
+
+// Tone mapping applied to frames to keep detail in bright areas. Only "reinhard" is
+// supported. Disabled when not set.
+static ENV_TONE_MAP: &str = "TONE_MAP";
+
+// What happens when an emoji renders to nothing but background, eg: a transparent asset:
+// "warn" (the default) logs it, "fallback" also shows a placeholder instead.
+static ENV_BLANK_FRAME_POLICY: &str = "BLANK_FRAME_POLICY";
+
+// Dithering applied when reducing frames to DITHER_LEVELS values per channel, for panels
+// with a low color depth. Only "ordered" is supported. Disabled when not set.
+static ENV_DITHER: &str = "DITHER";
+static ENV_DITHER_LEVELS: &str = "DITHER_LEVELS";
+static DEFAULT_DITHER_LEVELS: u8 = 32;
+
+// Path to a file with per-channel color correction tables, see imageutils::parse_lut.
+static ENV_LUT_FILE: &str = "LUT_FILE";
+
+// Path to a JSON file mapping emoji to the hex color they are blended onto, eg:
+// `{"🖤": "#ffffff"}`, instead of the default black. Messages with a background still
+// use theirs.
+static ENV_BACKGROUNDS_FILE: &str = "BACKGROUNDS_FILE";
+
+// LED chipset of the panel, selecting the gamma table applied before publishing:
+// "ws2811", "ws2812" or "apa102". Frames are linear when not set or unknown.
+static ENV_CHIPSET: &str = "CHIPSET";
+
+// Shape of the visible area of the panel: "rectangle" (default) or "circle", which blanks
+// the pixels outside the circle inscribed in the frame.
+static ENV_PANEL_SHAPE: &str = "PANEL_SHAPE";
+
+// Emoji loads failing in a row, while the emoji directory can't be listed, before it is
+// reported unavailable, eg: when a network mount drops (5 by default). It is then
+// checked every ASSET_CHECK_INTERVAL_SECS (30 by default) until it's back.
+static ENV_ASSET_FAILURE_THRESHOLD: &str = "ASSET_FAILURE_THRESHOLD";
+static DEFAULT_ASSET_FAILURE_THRESHOLD: u32 = 5;
+static ENV_ASSET_CHECK_INTERVAL_SECS: &str = "ASSET_CHECK_INTERVAL_SECS";
+static DEFAULT_ASSET_CHECK_INTERVAL_SECS: u64 = 30;
+
+// Decoded emoji images kept in memory, bounded by count, by bytes of pixel data, or both.
+// Nothing is cached when neither is set. eg: EMOJI_CACHE_BYTES=8388608 for 8 MiB.
+static ENV_EMOJI_CACHE_SIZE: &str = "EMOJI_CACHE_SIZE";
+static ENV_EMOJI_CACHE_BYTES: &str = "EMOJI_CACHE_BYTES";
+
+// Skip publishing frames identical to the last one sent to their topic when set to
+// 1/true, eg: when Firebase sends the same record again. Frames are sent again after
+// reconnecting to the broker.
+static ENV_SKIP_DUPLICATES: &str = "SKIP_DUPLICATES";
+
+// With SKIP_DUPLICATES, seconds after which the frame shown is published again, even if
+// unchanged, so that subscribers that missed it eventually get it. Disabled when not set.
+static ENV_KEYFRAME_INTERVAL_SECS: &str = "KEYFRAME_INTERVAL_SECS";
+
+// Skip commands identical to the last one from their source when set to 1/true, eg: the
+// record Firebase sends again after the stream reconnects. Commands are handled again
+// after reconnecting to the broker.
+static ENV_SKIP_REPEATED_EVENTS: &str = "SKIP_REPEATED_EVENTS";
+
+// What happens to the commands received while the panels are frozen with a 'freeze'
+// published to '<prefix>/control': "drop" (the default) or "apply_latest", which shows
+// the latest one after an 'unfreeze'.
+static ENV_FREEZE_POLICY: &str = "FREEZE_POLICY";
+
+// Draws the time in the bottom-right corner of the emoji when set to 1/true, updating it
+// every minute. CLOCK_FORMAT is a strftime format, '%H:%M' by default. The font only has
+// digits, ':', '-' and '.'.
+static ENV_SHOW_CLOCK: &str = "SHOW_CLOCK";
+static ENV_CLOCK_FORMAT: &str = "CLOCK_FORMAT";
+static DEFAULT_CLOCK_FORMAT: &str = "%H:%M";
+
+// Daily window, in local time, during which every published frame is dimmed, eg:
+// '22:00-07:00'. Frames are scaled by NIGHT_BRIGHTNESS, from 0.0 (blank, the default)
+// to 1.0. The frames shown are dimmed and restored on the minute the window starts and
+// ends.
+static ENV_NIGHT_MODE: &str = "NIGHT_MODE";
+static ENV_NIGHT_BRIGHTNESS: &str = "NIGHT_BRIGHTNESS";
+
+// Path to a JSON file counting how many times each emoji was displayed, saved every
+// STATS_INTERVAL_SECS (60 by default) and loaded on startup. The render API lists the
+// most displayed emoji on /stats.
+static ENV_STATS_FILE: &str = "STATS_FILE";
+static ENV_STATS_INTERVAL_SECS: &str = "STATS_INTERVAL_SECS";
+static DEFAULT_STATS_INTERVAL_SECS: u64 = 60;
+
+// Exports tracing spans for receiving, rendering and publishing commands to this OTLP
+// endpoint, eg: 'http://localhost:4318'. Needs the otel feature. The other standard
+// OTEL_* variables, eg: OTEL_SERVICE_NAME, are read by the exporter.
+static ENV_OTEL_EXPORTER_OTLP_ENDPOINT: &str = "OTEL_EXPORTER_OTLP_ENDPOINT";
+
+// Log only 1 in this many "Published" lines, eg: during fades. Errors are always
+// logged. 1 (the default) logs every published frame.
+static ENV_PUBLISH_LOG_SAMPLE: &str = "PUBLISH_LOG_SAMPLE";
+
+// Most bytes of frames published over any minute, eg: on a metered link. Frames that
+// would exceed it are skipped, smallest sizes first getting the room unless
+// PUBLISH_ORDER is set. Unlimited when not set.
+static ENV_MAX_BYTES_PER_MIN: &str = "MAX_BYTES_PER_MIN";
+
+// Order the frames of an emoji are published in: "as_configured" (the order of SIZES,
+// the default), "largest_first" or "smallest_first", by panel area.
+static ENV_PUBLISH_ORDER: &str = "PUBLISH_ORDER";
+
+// Number of recently published frames kept per panel, re-published in order when
+// anything is sent to '{prefix}/replay'. 0 (the default) disables the history.
+static ENV_FRAME_HISTORY: &str = "FRAME_HISTORY";
+// Bytes of pixel data the history of each panel may hold. Defaults to 1 MiB.
+static ENV_FRAME_HISTORY_BYTES: &str = "FRAME_HISTORY_BYTES";
+static DEFAULT_FRAME_HISTORY_BYTES: usize = 1024 * 1024;
+
+// Color of a border painted around the panel contents, as hex, eg: 'ff8000'. Not drawn
+// when unset. BORDER_THICKNESS is its width in pixels, 1 by default.
+static ENV_BORDER_COLOR: &str = "BORDER_COLOR";
+static ENV_BORDER_THICKNESS: &str = "BORDER_THICKNESS";
+static DEFAULT_BORDER_THICKNESS: u32 = 1;
+
+// Render emoji once at the smallest of SIZES and scale that frame up for the other sizes
+// when set to 1/true, so every panel shows the same pixels. Sizes are rendered
+// independently by default.
+static ENV_CONSISTENT_SCALING: &str = "CONSISTENT_SCALING";
+
+// Scale emoji by averaging the source pixels of each LED in linear light when set to
+// 1/true, which keeps more detail on small panels. Nearest neighbor by default.
+static ENV_HIGH_QUALITY_DOWNSCALE: &str = "HIGH_QUALITY_DOWNSCALE";
+
+// Blend the transparent pixels of emoji with the background in linear light when set to
+// 1/true, which avoids dark fringes around their edges over light backgrounds.
+static ENV_LINEAR_BLEND: &str = "LINEAR_BLEND";
+
+// Upscale emoji by whole factors with Scale2x/Scale3x when set to 1/true, which keeps the
+// edges of pixel-art emoji smooth rather than blocky.
+//
+// HIGH_QUALITY_DOWNSCALE, PIXEL_ART_UPSCALE and AUTO_FILTER pick the filter, so only one of
+// them can be set.
+static ENV_PIXEL_ART_UPSCALE: &str = "PIXEL_ART_UPSCALE";
+
+// Scale emoji with nearest neighbor when the scale ratio is a whole number, and with
+// linear interpolation otherwise, when set to 1/true. Nearest neighbor otherwise.
+static ENV_AUTO_FILTER: &str = "AUTO_FILTER";
+
+// How emoji are resized to panels of a different aspect ratio: "fit" (the default),
+// padding them, "fill", cropping their center, or "stretch", distorting them.
+static ENV_RESIZE_MODE: &str = "RESIZE_MODE";
+
+// Strength of the unsharp mask applied to frames right after resizing, before the color
+// corrections. eg: '0.5'. Not applied when unset.
+static ENV_SHARPEN_AMOUNT: &str = "SHARPEN_AMOUNT";
+
+// Lowest value of any color channel, applied when publishing, after every other change
+// to the frames, eg: night mode. Some panels flicker or reset when pixels are fully off.
+static ENV_MIN_BRIGHTNESS: &str = "MIN_BRIGHTNESS";
+
+// Highest value of the red, green and blue channels, applied last when publishing, eg:
+// MAX_B=200 to tame bright blue LEDs. 255 (no cap) by default, and not below
+// MIN_BRIGHTNESS.
+static ENV_MAX_R: &str = "MAX_R";
+static ENV_MAX_G: &str = "MAX_G";
+static ENV_MAX_B: &str = "MAX_B";
+
+// Longest emoji accepted from the network, in codepoints. Longer inputs are rejected
+// before any filesystem work.
+static ENV_MAX_EMOJI_CODEPOINTS: &str = "MAX_EMOJI_CODEPOINTS";
+
+// Largest image decoded from the emoji directory, in pixels, eg: to keep a custom asset
+// pack with a huge image from running the daemon out of memory. Unlimited when not set.
+static ENV_MAX_DECODE_PIXELS: &str = "MAX_DECODE_PIXELS";
+
+// Largest frame rendered at a size chosen by a client, in pixels, through the render API
+// or a size request. Defaults to the largest panel in SIZES.
+static ENV_MAX_RENDER_PIXELS: &str = "MAX_RENDER_PIXELS";
+
+// Retained topic a PNG thumbnail of every emoji shown is published to, eg: for a web UI
+// showing what the panel shows. The thumbnail is ICON_SIZE, 8x8 by default. Disabled when
+// not set.
+static ENV_ICON_TOPIC: &str = "ICON_TOPIC";
+static ENV_ICON_SIZE: &str = "ICON_SIZE";
+static DEFAULT_ICON_SIZE: (u32, u32) = (8, 8);
+
+// Retained topic the daemon publishes the number of available emoji to at startup.
+static ENV_INFO_TOPIC: &str = "INFO_TOPIC";
+static DEFAULT_INFO_TOPIC: &str = "ledmoji/info";
+
+// Encoding of the info topic and of query responses: "json" (the default) or "cbor", see
+// meta::MetaEncoding.
+static ENV_META_ENCODING: &str = "META_ENCODING";
+
+// Delay between reconnect attempts to Firebase and MQTT: "fixed" (default) retries every
+// second, "exponential" and "fibonacci" grow the delay up to a minute.
+static ENV_BACKOFF_STRATEGY: &str = "BACKOFF_STRATEGY";
+
+// Exit after this many consecutive failed attempts to connect to Firebase or MQTT, so a
+// supervisor can restart the daemon. Retries forever when not set.
+static ENV_MAX_RECONNECT_ATTEMPTS: &str = "MAX_RECONNECT_ATTEMPTS";
+
+// Reconnect to MQTT after this many publishes in a row fail, eg: when the client is
+// stuck and stopped delivering. Never reconnects because of failed publishes when not set.
+static ENV_MAX_PUBLISH_FAILURES: &str = "MAX_PUBLISH_FAILURES";
+
+// Publishes blank frames to every panel once connected, so frames retained from a
+// previous run aren't shown until the first event, when set to 1/true.
+static ENV_BLANK_ON_STARTUP: &str = "BLANK_ON_STARTUP";
+
+// Image shown on every panel once connected, eg: a logo, scaled like emoji. With
+// SPLASH_ON_CLEAR set to 1/true, it is also shown instead of blanking when a record is
+// deleted. Panels are blanked instead when it can't be loaded.
+static ENV_SPLASH_IMAGE: &str = "SPLASH_IMAGE";
+static ENV_SPLASH_ON_CLEAR: &str = "SPLASH_ON_CLEAR";
+
+// Shows solid red, green and blue, then blanks every panel once connected, when set to
+// 1/true. SELFTEST_PAUSE_MS is how long each color is shown, 1 second by default.
+static ENV_SELFTEST: &str = "SELFTEST";
+static ENV_SELFTEST_PAUSE_MS: &str = "SELFTEST_PAUSE_MS";
+static DEFAULT_SELFTEST_PAUSE_MS: u64 = 1000;
+
+// Shows a spinner on every panel once connected, until the first emoji is rendered, when
+// set to 1/true, so installers can tell the daemon is running. LOADING_FPS is how many
+// steps it takes per second, 8 by default.
+static ENV_LOADING_ANIMATION: &str = "LOADING_ANIMATION";
+static ENV_LOADING_FPS: &str = "LOADING_FPS";
+static DEFAULT_LOADING_FPS: u32 = 8;
+
+// Slowly rotates the hues of the emoji shown, for an ambient effect, when set to 1/true.
+// HUE_CYCLE_PERIOD_SECS is how long a full turn of the color wheel takes, 60 seconds by
+// default. The cycle starts over from the emoji's own colors whenever one is shown.
+static ENV_HUE_CYCLE: &str = "HUE_CYCLE";
+static ENV_HUE_CYCLE_PERIOD_SECS: &str = "HUE_CYCLE_PERIOD_SECS";
+static DEFAULT_HUE_CYCLE_PERIOD_SECS: u64 = 60;
+
+// Format of the published payload: "rgb" (the default), "grb" or "base64_json", see
+// encoder::OutputFormat. Applied to frames in strip order, before PAYLOAD_COMPRESSION,
+// eg: "rle" for run-length encoding.
+static ENV_OUTPUT_FORMAT: &str = "OUTPUT_FORMAT";
+
+// How frames are published: "frame" (the default), as one message per frame, or
+// "per_pixel", as one 3 byte RGB message per pixel to the frame topic followed by
+// '/pixel/{index}', eg: 'ledmoji/32x32/pixel/5'. OUTPUT_FORMAT and PAYLOAD_COMPRESSION
+// don't apply to pixels.
+static ENV_OUTPUT_MODE: &str = "OUTPUT_MODE";
+
+// Only publishes the pixels that changed since the previous frame when set to 1/true,
+// with OUTPUT_MODE=per_pixel.
+static ENV_PIXEL_DELTA: &str = "PIXEL_DELTA";
+
+// Compresses frames before publishing them: "gzip", "deflate" or "rle", see
+// imageutils::compress_frame and imageutils::rle_encode. Compressed frames are
+// published to the frame topic followed by the encoding, eg: 'ledmoji/32x32/gzip'.
+// Disabled when not set.
+static ENV_PAYLOAD_COMPRESSION: &str = "PAYLOAD_COMPRESSION";
+
+// Draws a counter that changes with every frame published to a panel over its top-left
+// pixels when set to 1/true, to check the panel keeps up, see
+// imageutils::draw_frame_counter.
+static ENV_DEBUG_FRAME_COUNTER: &str = "DEBUG_FRAME_COUNTER";
+
+// Prints every published frame to stdout as ANSI colored text when set to 1/true.
+static ENV_STDOUT_PREVIEW: &str = "STDOUT_PREVIEW";
+
+// Tokio runtime the daemon runs on: "multi_thread" (default) or "current_thread", which
+// avoids the overhead of worker threads on single core boards.
+static ENV_TOKIO_FLAVOR: &str = "TOKIO_FLAVOR";
+
+// How the LED matrix is wired. Origin is one of tl/tr/bl/br, axis is row/column and
+// serpentine is 1/true when every other line runs in the opposite direction.
+static ENV_MATRIX_ORIGIN: &str = "MATRIX_ORIGIN";
+static ENV_MATRIX_AXIS: &str = "MATRIX_AXIS";
+static ENV_MATRIX_SERPENTINE: &str = "MATRIX_SERPENTINE";
+
+/// Reads and parses the environment variable `name`, returning `None` when not set.
+fn parse_env<T>(name: &str) -> Result<Option<T>, Box<dyn Error>>
+where
+    T: FromStr,
+    T::Err: Into<Box<dyn Error>>,
+{
+    match std::env::var(name) {
+        Ok(value) => match value.parse() {
+            Ok(value) => Ok(Some(value)),
+            Err(e) => Err(format!("Invalid {}: {}", name, e.into()).into()),
+        },
+        Err(_) => Ok(None),
+    }
+}
+
+/// Reads a boolean flag, which is set when the variable is "1" or "true".
+fn flag_env(name: &str) -> bool {
+    std::env::var(name).is_ok_and(|value| matches!(value.as_str(), "1" | "true"))
+}
+
+fn required_env(name: &str) -> String {
+    std::env::var(name).unwrap_or_else(|_| {
+        panic!("{} environment variable not set", name);
+    })
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MqttConfig {
+    pub client_id: String,
+    pub server: String,
+    pub port: u16,
+    pub tls: Option<Arc<ClientConfig>>,
+    pub version: MqttVersion,
+    pub publish_properties: PublishProperties,
+}
+
+impl MqttConfig {
+    pub fn from_env() -> Result<Self, Box<dyn Error>> {
+        let ca_cert = std::env::var(ENV_MQTT_CA_CERT).ok().map(PathBuf::from);
+        let client_cert = std::env::var(ENV_MQTT_CLIENT_CERT).ok().map(PathBuf::from);
+        let client_key = std::env::var(ENV_MQTT_CLIENT_KEY).ok().map(PathBuf::from);
+        let client_auth = match (&client_cert, &client_key) {
+            (Some(cert), Some(key)) => Some(ClientAuth { cert, key }),
+            (None, None) => None,
+            _ => {
+                return Err(format!(
+                    "{} and {} must be set together",
+                    ENV_MQTT_CLIENT_CERT, ENV_MQTT_CLIENT_KEY
+                )
+                .into())
+            }
+        };
+
+        let tls = if flag_env(ENV_MQTT_TLS) || client_auth.is_some() {
+            Some(Arc::new(tls::client_config(
+                ca_cert.as_deref(),
+                client_auth,
+            )?))
+        } else {
+            None
+        };
+        let default_port = match tls {
+            Some(_) => DEFAULT_MQTT_TLS_PORT,
+            None => DEFAULT_MQTT_PORT,
+        };
+
+        let version = parse_env(ENV_MQTT_VERSION)?.unwrap_or_default();
+        let content_type = std::env::var(ENV_MQTT_CONTENT_TYPE).ok();
+        let user_properties = std::env::var(ENV_MQTT_USER_PROPERTIES).ok();
+        if version == MqttVersion::V4 {
+            let properties = [
+                (ENV_MQTT_CONTENT_TYPE, &content_type),
+                (ENV_MQTT_USER_PROPERTIES, &user_properties),
+            ];
+            for (env, value) in properties {
+                if value.is_some() {
+                    return Err(format!("{} needs {}=5", env, ENV_MQTT_VERSION).into());
+                }
+            }
+        }
+        let publish_properties = match version {
+            MqttVersion::V4 => PublishProperties::default(),
+            MqttVersion::V5 => PublishProperties::for_format(
+                parse_env(ENV_OUTPUT_FORMAT)?.unwrap_or_default(),
+                parse_env(ENV_PAYLOAD_COMPRESSION)?,
+                content_type,
+                match user_properties {
+                    Some(properties) => PublishProperties::parse_user_properties(&properties)?,
+                    None => Vec::new(),
+                },
+            ),
+        };
+
+        Ok(Self {
+            client_id: required_env(ENV_MQTT_CLIENT_ID),
+            server: required_env(ENV_MQTT_HOST),
+            port: parse_env(ENV_MQTT_PORT)?.unwrap_or(default_port),
+            tls,
+            version,
+            publish_properties,
+        })
+    }
+
+    pub fn options(&self) -> MqttOptions {
+        let mut options = MqttOptions::new(&self.client_id, &self.server, self.port);
+        options.set_keep_alive(Duration::from_secs(5));
+        if let Some(tls) = &self.tls {
+            options.set_transport(Transport::tls_with_config(TlsConfiguration::Rustls(
+                tls.clone(),
+            )));
+        }
+        options
+    }
+
+    /// Options to connect with MQTT v5, see `MQTT_VERSION`.
+    pub fn options_v5(&self) -> v5::MqttOptions {
+        let mut options = v5::MqttOptions::new(&self.client_id, &self.server, self.port);
+        options.set_keep_alive(Duration::from_secs(5));
+        if let Some(tls) = &self.tls {
+            options.set_transport(Transport::tls_with_config(TlsConfiguration::Rustls(
+                tls.clone(),
+            )));
+        }
+        options
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RuntimeFlavor {
+    #[default]
+    MultiThread,
+    CurrentThread,
+}
+
+impl FromStr for RuntimeFlavor {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "multi_thread" => Ok(RuntimeFlavor::MultiThread),
+            "current_thread" => Ok(RuntimeFlavor::CurrentThread),
+            _ => Err(format!("Invalid runtime flavor: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Fade {
+    pub frames: u32,
+    pub fps: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Border {
+    pub color: Rgb<u8>,
+    pub thickness: u32,
+}
+
+/// PNG thumbnail of every emoji shown, see `ICON_TOPIC`.
+#[derive(Debug, Clone)]
+pub struct Icon {
+    pub size: (u32, u32),
+    pub topic: String,
+}
+
+/// Emoji cycled through when no live source sends commands, see `PLAYLIST`.
+#[derive(Debug, Clone)]
+pub struct PlaylistConfig {
+    pub playlist: Playlist,
+    pub interval: Duration,
+    /// How long live commands stay on the panels before the playlist resumes.
+    pub resume_after: Duration,
+}
+
+#[derive(Debug)]
+pub struct Config {
+    pub emoji_directory: String,
+    pub emoji_font: Option<Arc<EmojiFont>>,
+    pub firebase_sources: Vec<FirebaseSource>,
+    pub event_file: Option<PathBuf>,
+    /// Set when commands are read from standard input, with what happens at its end.
+    pub stdin_source: Option<EofPolicy>,
+    pub frame_fifo: Option<PathBuf>,
+    pub frame_fifo_size: (u32, u32),
+    pub router: Router,
+    pub sink: SinkKind,
+    pub startup_delay: Option<Duration>,
+    pub startup_wait_timeout: Option<Duration>,
+    pub startup_connect_attempts: Option<u32>,
+    pub startup_connect_timeout: Duration,
+    pub mqtt: MqttConfig,
+    pub sizes: Vec<(u32, u32)>,
+    pub topic_settings: HashMap<(u32, u32), PublishSettings>,
+    pub max_packet_bytes: usize,
+    pub matrix_layout: MatrixLayout,
+    pub render_api_port: Option<u16>,
+    pub stream_stall_timeout: Option<Duration>,
+    pub watchdog_timeout: Option<Duration>,
+    pub malformed_policy: MalformedPolicy,
+    pub malformed_limit: u32,
+    pub payload_format: PayloadFormat,
+    pub fade: Option<Fade>,
+    pub panel_shape: PanelShape,
+    pub tone_map: Option<ToneMap>,
+    pub blank_frame_policy: BlankFramePolicy,
+    pub dither: Option<Dither>,
+    pub dither_levels: u8,
+    pub lut: Option<Lut>,
+    /// Background of each emoji listed in `BACKGROUNDS_FILE`.
+    pub backgrounds: HashMap<String, Rgb<u8>>,
+    pub chipset: Option<Chipset>,
+    pub min_brightness: Option<u8>,
+    pub channel_max: [u8; 3],
+    pub resize_mode: ResizeMode,
+    pub scale_filter: ScaleFilter,
+    pub blend_space: BlendSpace,
+    pub sharpen_amount: Option<f32>,
+    pub consistent_scaling: bool,
+    pub border: Option<Border>,
+    pub frame_history: usize,
+    pub publish_log_sample: u64,
+    pub max_bytes_per_min: Option<u64>,
+    pub publish_order: PublishOrder,
+    pub otel_enabled: bool,
+    pub stats_file: Option<PathBuf>,
+    pub stats_interval: Duration,
+    /// strftime format of the clock overlay, when enabled.
+    pub clock_format: Option<String>,
+    pub night_mode: Option<NightMode>,
+    pub skip_duplicates: bool,
+    pub keyframe_interval: Option<Duration>,
+    pub skip_repeated_events: bool,
+    pub freeze_policy: FreezePolicy,
+    pub asset_failure_threshold: u32,
+    pub asset_check_interval: Duration,
+    pub emoji_cache_size: Option<usize>,
+    pub emoji_cache_bytes: Option<usize>,
+    pub frame_history_bytes: usize,
+    pub max_emoji_codepoints: usize,
+    pub max_decode_pixels: Option<u64>,
+    /// `MAX_RENDER_PIXELS`, see `render_pixel_limit`.
+    pub max_render_pixels: Option<u64>,
+    pub blank_on_startup: bool,
+    pub splash_image: Option<PathBuf>,
+    pub splash_on_clear: bool,
+    /// How long each self-test color is shown, when the self-test is enabled.
+    pub selftest_pause: Option<Duration>,
+    /// Steps per second of the loading spinner, when it is enabled.
+    pub loading_fps: Option<u32>,
+    /// How long a full hue cycle takes, when hue cycling is enabled.
+    pub hue_cycle_period: Option<Duration>,
+    pub output_format: OutputFormat,
+    pub compression: Option<Compression>,
+    pub output_mode: OutputMode,
+    pub pixel_delta: bool,
+    pub stdout_preview: bool,
+    pub debug_frame_counter: bool,
+    pub backoff: BackoffKind,
+    pub max_reconnect_attempts: Option<u32>,
+    pub max_publish_failures: Option<u32>,
+    pub info_topic: String,
+    pub meta_encoding: MetaEncoding,
+    pub icon: Option<Icon>,
+    pub playlist: Option<PlaylistConfig>,
+    pub runtime_flavor: RuntimeFlavor,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            emoji_directory: String::new(),
+            emoji_font: None,
+            firebase_sources: vec![],
+            event_file: None,
+            stdin_source: None,
+            frame_fifo: None,
+            frame_fifo_size: DEFAULT_SIZES[0],
+            router: Router::default(),
+            sink: SinkKind::default(),
+            startup_delay: None,
+            startup_wait_timeout: None,
+            startup_connect_attempts: None,
+            startup_connect_timeout: Duration::from_secs(DEFAULT_STARTUP_CONNECT_TIMEOUT_SECS),
+            mqtt: MqttConfig::default(),
+            sizes: DEFAULT_SIZES.to_vec(),
+            topic_settings: HashMap::new(),
+            max_packet_bytes: MAX_MQTT_PACKET_BYTES,
+            matrix_layout: MatrixLayout::default(),
+            render_api_port: None,
+            stream_stall_timeout: None,
+            watchdog_timeout: None,
+            malformed_policy: MalformedPolicy::default(),
+            malformed_limit: DEFAULT_MALFORMED_LIMIT,
+            payload_format: PayloadFormat::default(),
+            fade: None,
+            panel_shape: PanelShape::default(),
+            tone_map: None,
+            blank_frame_policy: BlankFramePolicy::default(),
+            dither: None,
+            dither_levels: DEFAULT_DITHER_LEVELS,
+            lut: None,
+            backgrounds: HashMap::new(),
+            chipset: None,
+            min_brightness: None,
+            channel_max: [u8::MAX; 3],
+            resize_mode: ResizeMode::default(),
+            scale_filter: ScaleFilter::default(),
+            blend_space: BlendSpace::default(),
+            sharpen_amount: None,
+            consistent_scaling: false,
+            border: None,
+            frame_history: 0,
+            publish_log_sample: 1,
+            max_bytes_per_min: None,
+            publish_order: PublishOrder::default(),
+            otel_enabled: false,
+            stats_file: None,
+            stats_interval: Duration::from_secs(DEFAULT_STATS_INTERVAL_SECS),
+            clock_format: None,
+            night_mode: None,
+            skip_duplicates: false,
+            keyframe_interval: None,
+            skip_repeated_events: false,
+            freeze_policy: FreezePolicy::default(),
+            asset_failure_threshold: DEFAULT_ASSET_FAILURE_THRESHOLD,
+            asset_check_interval: Duration::from_secs(DEFAULT_ASSET_CHECK_INTERVAL_SECS),
+            emoji_cache_size: None,
+            emoji_cache_bytes: None,
+            frame_history_bytes: DEFAULT_FRAME_HISTORY_BYTES,
+            max_emoji_codepoints: DEFAULT_MAX_EMOJI_CODEPOINTS,
+            max_decode_pixels: None,
+            max_render_pixels: None,
+            blank_on_startup: false,
+            splash_image: None,
+            splash_on_clear: false,
+            selftest_pause: None,
+            loading_fps: None,
+            hue_cycle_period: None,
+            output_format: OutputFormat::default(),
+            compression: None,
+            output_mode: OutputMode::default(),
+            pixel_delta: false,
+            stdout_preview: false,
+            debug_frame_counter: false,
+            backoff: BackoffKind::default(),
+            max_reconnect_attempts: None,
+            max_publish_failures: None,
+            info_topic: DEFAULT_INFO_TOPIC.to_string(),
+            meta_encoding: MetaEncoding::default(),
+            icon: None,
+            playlist: None,
+            runtime_flavor: RuntimeFlavor::default(),
+        }
+    }
+}
+
+impl Config {
+    pub fn from_env() -> Result<Self, Box<dyn Error>> {
+        let sizes = match std::env::var(ENV_SIZES) {
+            Ok(sizes) => sizes
+                .split(',')
+                .map(parse_size)
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| format!("Invalid {}: {}", ENV_SIZES, e))?,
+            Err(_) => DEFAULT_SIZES.to_vec(),
+        };
+
+        let mut topic_settings = HashMap::new();
+        for &(width, height) in &sizes {
+            let name = format!("{}{}x{}", ENV_TOPIC_SETTINGS_PREFIX, width, height);
+            if let Ok(settings) = std::env::var(&name) {
+                let settings = PublishSettings::parse(&settings, PublishSettings::default())
+                    .map_err(|e| format!("Invalid {}: {}", name, e))?;
+                topic_settings.insert((width, height), settings);
+            }
+        }
+
+        let event_file = std::env::var(ENV_EVENT_FILE).ok().map(PathBuf::from);
+        let stdin_source = match std::env::var(ENV_EMOJI_SOURCE) {
+            Ok(source) if source.eq_ignore_ascii_case("stdin") => {
+                Some(parse_env(ENV_STDIN_EOF)?.unwrap_or_default())
+            }
+            Ok(source) => return Err(format!("Invalid emoji source: {}", source).into()),
+            Err(_) => None,
+        };
+        let frame_fifo = std::env::var(ENV_FRAME_FIFO).ok().map(PathBuf::from);
+        let frame_fifo_size = match std::env::var(ENV_FRAME_FIFO_SIZE) {
+            Ok(size) => {
+                parse_size(&size).map_err(|e| format!("Invalid {}: {}", ENV_FRAME_FIFO_SIZE, e))?
+            }
+            Err(_) => sizes[0],
+        };
+        let playlist = match std::env::var(ENV_PLAYLIST) {
+            Ok(playlist) => {
+                let interval = Duration::from_secs(
+                    parse_env(ENV_PLAYLIST_INTERVAL_SECS)?
+                        .unwrap_or(DEFAULT_PLAYLIST_INTERVAL_SECS),
+                );
+                if interval.is_zero() {
+                    return Err(format!("{} must be at least 1", ENV_PLAYLIST_INTERVAL_SECS).into());
+                }
+                Some(PlaylistConfig {
+                    playlist: Playlist::parse(&playlist)
+                        .map_err(|e| format!("Invalid {}: {}", ENV_PLAYLIST, e))?,
+                    interval,
+                    resume_after: parse_env(ENV_PLAYLIST_RESUME_SECS)?
+                        .map(Duration::from_secs)
+                        .unwrap_or(interval),
+                })
+            }
+            Err(_) => None,
+        };
+        let firebase_sources = match std::env::var(ENV_FIREBASE_SOURCES) {
+            Ok(sources) => sources
+                .split(',')
+                .map(|source| match source.split_once('=') {
+                    Some((id, url)) => Ok(FirebaseSource {
+                        id: id.trim().to_string(),
+                        url: url.trim().to_string(),
+                    }),
+                    None => Err(format!("Invalid Firebase source: {}", source)),
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+            // Firebase is optional when commands are read from a file, standard input or
+            // a playlist, or frames from a pipe.
+            Err(_)
+                if (event_file.is_some()
+                    || stdin_source.is_some()
+                    || frame_fifo.is_some()
+                    || playlist.is_some())
+                    && std::env::var(ENV_FIREBASE_URL).is_err() =>
+            {
+                vec![]
+            }
+            Err(_) => vec![FirebaseSource {
+                id: DEFAULT_SOURCE_ID.to_string(),
+                url: required_env(ENV_FIREBASE_URL),
+            }],
+        };
+        if !firebase_sources.is_empty() && !cfg!(feature = "firebase") {
+            return Err("Firebase sources need the firebase feature".into());
+        }
+        if event_file.is_some() && !cfg!(feature = "file-source") {
+            return Err(format!("{} needs the file-source feature", ENV_EVENT_FILE).into());
+        }
+        let malformed_limit = parse_env(ENV_MALFORMED_LIMIT)?.unwrap_or(DEFAULT_MALFORMED_LIMIT);
+        if malformed_limit == 0 {
+            return Err(format!("{} must be at least 1", ENV_MALFORMED_LIMIT).into());
+        }
+        let otel_enabled = std::env::var(ENV_OTEL_EXPORTER_OTLP_ENDPOINT).is_ok();
+        if otel_enabled && !cfg!(feature = "otel") {
+            return Err(
+                format!("{} needs the otel feature", ENV_OTEL_EXPORTER_OTLP_ENDPOINT).into(),
+            );
+        }
+
+        let max_bytes_per_min = parse_env(ENV_MAX_BYTES_PER_MIN)?;
+        let max_decode_pixels = parse_env(ENV_MAX_DECODE_PIXELS)?;
+        if max_decode_pixels == Some(0) {
+            return Err(format!("{} must be at least 1", ENV_MAX_DECODE_PIXELS).into());
+        }
+        let max_render_pixels = parse_env(ENV_MAX_RENDER_PIXELS)?;
+        if max_render_pixels == Some(0) {
+            return Err(format!("{} must be at least 1", ENV_MAX_RENDER_PIXELS).into());
+        }
+        let publish_order = match parse_env(ENV_PUBLISH_ORDER)? {
+            Some(order) => order,
+            None if max_bytes_per_min.is_some() => PublishOrder::SmallestFirst,
+            None => PublishOrder::AsConfigured,
+        };
+        let max_publish_failures = parse_env(ENV_MAX_PUBLISH_FAILURES)?;
+        if max_publish_failures == Some(0) {
+            return Err(format!("{} must be at least 1", ENV_MAX_PUBLISH_FAILURES).into());
+        }
+
+        let router = match std::env::var(ENV_ROUTES) {
+            Ok(routes) => Router::parse(&routes)?,
+            Err(_) => Router::default(),
+        };
+
+        let emoji_font = match std::env::var(ENV_EMOJI_FONT) {
+            Ok(path) => Some(Arc::new(EmojiFont::open(&path)?)),
+            Err(_) => None,
+        };
+        let emoji_directory = match emoji_font {
+            Some(_) => std::env::var(ENV_EMOJI_DIRECTORY).unwrap_or_default(),
+            None => required_env(ENV_EMOJI_DIRECTORY),
+        };
+
+        let max_packet_bytes = parse_env(ENV_MAX_PACKET_BYTES)?.unwrap_or(MAX_MQTT_PACKET_BYTES);
+        let output_format: OutputFormat = parse_env(ENV_OUTPUT_FORMAT)?.unwrap_or_default();
+        check_frame_packet_sizes(
+            &sizes,
+            &router.all_prefixes(),
+            output_format.encoder(),
+            max_packet_bytes,
+        )?;
+
+        let fade_frames = parse_env(ENV_FADE_FRAMES)?.unwrap_or(0);
+        let fade_fps = parse_env(ENV_FADE_FPS)?.unwrap_or(DEFAULT_FADE_FPS);
+        let fade = (fade_frames > 0 && fade_fps > 0).then_some(Fade {
+            frames: fade_frames,
+            fps: fade_fps,
+        });
+
+        let lut = match std::env::var(ENV_LUT_FILE) {
+            Ok(path) => Some(load_lut(Path::new(&path))?),
+            Err(_) => None,
+        };
+        let backgrounds = match std::env::var(ENV_BACKGROUNDS_FILE) {
+            Ok(path) => load_backgrounds(Path::new(&path))?,
+            Err(_) => HashMap::new(),
+        };
+
+        let chipset = match std::env::var(ENV_CHIPSET) {
+            Ok(chipset) => chipset
+                .parse()
+                .map_err(|e| log::warn!("{}, using linear values", e))
+                .ok(),
+            Err(_) => None,
+        };
+
+        let filters = [
+            (ENV_HIGH_QUALITY_DOWNSCALE, ScaleFilter::AreaLinear),
+            (ENV_PIXEL_ART_UPSCALE, ScaleFilter::PixelArt),
+            (ENV_AUTO_FILTER, ScaleFilter::Auto),
+        ]
+        .into_iter()
+        .filter(|(name, _)| flag_env(name))
+        .collect::<Vec<_>>();
+        let scale_filter = match filters[..] {
+            [] => ScaleFilter::Nearest,
+            [(_, filter)] => filter,
+            _ => {
+                let names = filters.iter().map(|(name, _)| *name).collect::<Vec<_>>();
+                return Err(format!("{} can't be set together", names.join(", ")).into());
+            }
+        };
+
+        let border = match std::env::var(ENV_BORDER_COLOR) {
+            Ok(color) => Some(Border {
+                color: parse_color(&color)
+                    .ok_or_else(|| format!("Invalid {}: {}", ENV_BORDER_COLOR, color))?,
+                thickness: parse_env(ENV_BORDER_THICKNESS)?.unwrap_or(DEFAULT_BORDER_THICKNESS),
+            }),
+            Err(_) => None,
+        };
+
+        let icon = match std::env::var(ENV_ICON_TOPIC) {
+            Ok(topic) => Some(Icon {
+                size: match std::env::var(ENV_ICON_SIZE) {
+                    Ok(size) => parse_size(&size)
+                        .map_err(|e| format!("Invalid {}: {}", ENV_ICON_SIZE, e))?,
+                    Err(_) => DEFAULT_ICON_SIZE,
+                },
+                topic,
+            }),
+            Err(_) => None,
+        };
+
+        let clock_format = if flag_env(ENV_SHOW_CLOCK) {
+            let format = std::env::var(ENV_CLOCK_FORMAT)
+                .unwrap_or_else(|_| DEFAULT_CLOCK_FORMAT.to_string());
+            // Formatting fails on invalid specifiers, so check them now.
+            let mut formatted = String::new();
+            write!(formatted, "{}", chrono::Local::now().format(&format))
+                .map_err(|_| format!("Invalid {}: {}", ENV_CLOCK_FORMAT, format))?;
+            Some(format)
+        } else {
+            None
+        };
+
+        let night_mode = match parse_env(ENV_NIGHT_MODE)? {
+            Some(window) => {
+                let brightness = parse_env(ENV_NIGHT_BRIGHTNESS)?.unwrap_or(0.0);
+                if !(0.0..=1.0).contains(&brightness) {
+                    return Err(format!("{} must be from 0 to 1", ENV_NIGHT_BRIGHTNESS).into());
+                }
+                Some(NightMode { window, brightness })
+            }
+            None => None,
+        };
+
+        let stats_interval =
+            parse_env(ENV_STATS_INTERVAL_SECS)?.unwrap_or(DEFAULT_STATS_INTERVAL_SECS);
+        if stats_interval == 0 {
+            return Err(format!("{} must be at least 1", ENV_STATS_INTERVAL_SECS).into());
+        }
+        let stats_interval = Duration::from_secs(stats_interval);
+        let watchdog_timeout = parse_env(ENV_WATCHDOG_SECS)?;
+        if watchdog_timeout.is_some_and(|secs| secs <= MIN_WATCHDOG_SECS) {
+            return Err(format!(
+                "{} must be more than {}",
+                ENV_WATCHDOG_SECS, MIN_WATCHDOG_SECS
+            )
+            .into());
+        }
+        let watchdog_timeout = watchdog_timeout.map(Duration::from_secs);
+        let startup_connect_attempts = parse_env(ENV_STARTUP_CONNECT_ATTEMPTS)?;
+        if startup_connect_attempts == Some(0) {
+            return Err(format!("{} must be at least 1", ENV_STARTUP_CONNECT_ATTEMPTS).into());
+        }
+        let startup_connect_timeout =
+            parse_env(ENV_STARTUP_CONNECT_TIMEOUT)?.unwrap_or(DEFAULT_STARTUP_CONNECT_TIMEOUT_SECS);
+        if startup_connect_timeout == 0 {
+            return Err(format!("{} must be at least 1", ENV_STARTUP_CONNECT_TIMEOUT).into());
+        }
+        let skip_duplicates = flag_env(ENV_SKIP_DUPLICATES);
+        let keyframe_interval = parse_env(ENV_KEYFRAME_INTERVAL_SECS)?;
+        if keyframe_interval == Some(0) {
+            return Err(format!("{} must be at least 1", ENV_KEYFRAME_INTERVAL_SECS).into());
+        }
+        if keyframe_interval.is_some() && !skip_duplicates {
+            return Err(format!(
+                "{} needs {}",
+                ENV_KEYFRAME_INTERVAL_SECS, ENV_SKIP_DUPLICATES
+            )
+            .into());
+        }
+        let keyframe_interval = keyframe_interval.map(Duration::from_secs);
+        let splash_image = std::env::var(ENV_SPLASH_IMAGE).ok().map(PathBuf::from);
+        let splash_on_clear = flag_env(ENV_SPLASH_ON_CLEAR);
+        if splash_on_clear && splash_image.is_none() {
+            return Err(format!("{} needs {}", ENV_SPLASH_ON_CLEAR, ENV_SPLASH_IMAGE).into());
+        }
+        let dither_levels = parse_env(ENV_DITHER_LEVELS)?.unwrap_or(DEFAULT_DITHER_LEVELS);
+        if dither_levels < 2 {
+            return Err(format!("{} must be at least 2", ENV_DITHER_LEVELS).into());
+        }
+        let min_brightness = parse_env(ENV_MIN_BRIGHTNESS)?;
+        let channel_max = [
+            parse_env(ENV_MAX_R)?.unwrap_or(u8::MAX),
+            parse_env(ENV_MAX_G)?.unwrap_or(u8::MAX),
+            parse_env(ENV_MAX_B)?.unwrap_or(u8::MAX),
+        ];
+        // Both limits apply to every pixel, so a cap below the floor can't hold.
+        if let Some(floor) = min_brightness {
+            for (name, max) in [ENV_MAX_R, ENV_MAX_G, ENV_MAX_B]
+                .into_iter()
+                .zip(channel_max)
+            {
+                if max < floor {
+                    return Err(format!("{} must be at least {}", name, ENV_MIN_BRIGHTNESS).into());
+                }
+            }
+        }
+        let asset_check_interval =
+            parse_env(ENV_ASSET_CHECK_INTERVAL_SECS)?.unwrap_or(DEFAULT_ASSET_CHECK_INTERVAL_SECS);
+        if asset_check_interval == 0 {
+            return Err(format!("{} must be at least 1", ENV_ASSET_CHECK_INTERVAL_SECS).into());
+        }
+        let asset_check_interval = Duration::from_secs(asset_check_interval);
+
+        let hue_cycle_period = if flag_env(ENV_HUE_CYCLE) {
+            let secs =
+                parse_env(ENV_HUE_CYCLE_PERIOD_SECS)?.unwrap_or(DEFAULT_HUE_CYCLE_PERIOD_SECS);
+            if secs == 0 {
+                return Err(format!("{} must be at least 1", ENV_HUE_CYCLE_PERIOD_SECS).into());
+            }
+            Some(Duration::from_secs(secs))
+        } else {
+            None
+        };
+        let loading_fps = if flag_env(ENV_LOADING_ANIMATION) {
+            let fps = parse_env(ENV_LOADING_FPS)?.unwrap_or(DEFAULT_LOADING_FPS);
+            if fps == 0 {
+                return Err(format!("{} must be at least 1", ENV_LOADING_FPS).into());
+            }
+            Some(fps)
+        } else {
+            None
+        };
+
+        let selftest_pause = if flag_env(ENV_SELFTEST) {
+            let pause_ms = parse_env(ENV_SELFTEST_PAUSE_MS)?.unwrap_or(DEFAULT_SELFTEST_PAUSE_MS);
+            Some(Duration::from_millis(pause_ms))
+        } else {
+            None
+        };
+
+        let mut matrix_layout = MatrixLayout::default();
+        if let Some(origin) = parse_env(ENV_MATRIX_ORIGIN)? {
+            matrix_layout.origin = origin;
+        }
+        if let Some(axis) = parse_env(ENV_MATRIX_AXIS)? {
+            matrix_layout.axis = axis;
+        }
+        matrix_layout.serpentine = flag_env(ENV_MATRIX_SERPENTINE);
+
+        let sink = parse_env(ENV_SINK)?.unwrap_or_default();
+        let mqtt = match sink {
+            SinkKind::Mqtt => MqttConfig::from_env()?,
+            SinkKind::Framebuffer(_) => MqttConfig::default(),
+        };
+
+        Ok(Self {
+            emoji_directory,
+            emoji_font,
+            firebase_sources,
+            event_file,
+            stdin_source,
+            frame_fifo,
+            frame_fifo_size,
+            router,
+            mqtt,
+            sink,
+            startup_delay: parse_env(ENV_STARTUP_DELAY_SECS)?.map(Duration::from_secs),
+            startup_wait_timeout: parse_env(ENV_STARTUP_WAIT_TIMEOUT)?.map(Duration::from_secs),
+            startup_connect_attempts,
+            startup_connect_timeout: Duration::from_secs(startup_connect_timeout),
+            sizes,
+            topic_settings,
+            max_packet_bytes,
+            matrix_layout,
+            render_api_port: parse_env(ENV_RENDER_API_PORT)?,
+            stream_stall_timeout: parse_env(ENV_STREAM_STALL_SECS)?.map(Duration::from_secs),
+            watchdog_timeout,
+            malformed_policy: parse_env(ENV_MALFORMED_POLICY)?.unwrap_or_default(),
+            malformed_limit,
+            payload_format: parse_env(ENV_PAYLOAD_FORMAT)?.unwrap_or_default(),
+            fade,
+            panel_shape: parse_env(ENV_PANEL_SHAPE)?.unwrap_or_default(),
+            tone_map: parse_env(ENV_TONE_MAP)?,
+            blank_frame_policy: parse_env(ENV_BLANK_FRAME_POLICY)?.unwrap_or_default(),
+            dither: parse_env(ENV_DITHER)?,
+            dither_levels,
+            lut,
+            backgrounds,
+            chipset,
+            min_brightness,
+            channel_max,
+            resize_mode: parse_env(ENV_RESIZE_MODE)?.unwrap_or_default(),
+            scale_filter,
+            blend_space: match flag_env(ENV_LINEAR_BLEND) {
+                true => BlendSpace::Linear,
+                false => BlendSpace::Srgb,
+            },
+            sharpen_amount: parse_env(ENV_SHARPEN_AMOUNT)?,
+            consistent_scaling: flag_env(ENV_CONSISTENT_SCALING),
+            border,
+            frame_history: parse_env(ENV_FRAME_HISTORY)?.unwrap_or(0),
+            publish_log_sample: parse_env(ENV_PUBLISH_LOG_SAMPLE)?.unwrap_or(1),
+            max_bytes_per_min,
+            publish_order,
+            otel_enabled,
+            stats_file: std::env::var(ENV_STATS_FILE).ok().map(PathBuf::from),
+            stats_interval,
+            clock_format,
+            night_mode,
+            skip_duplicates,
+            keyframe_interval,
+            skip_repeated_events: flag_env(ENV_SKIP_REPEATED_EVENTS),
+            freeze_policy: parse_env(ENV_FREEZE_POLICY)?.unwrap_or_default(),
+            asset_failure_threshold: parse_env(ENV_ASSET_FAILURE_THRESHOLD)?
+                .unwrap_or(DEFAULT_ASSET_FAILURE_THRESHOLD),
+            asset_check_interval,
+            emoji_cache_size: parse_env(ENV_EMOJI_CACHE_SIZE)?,
+            emoji_cache_bytes: parse_env(ENV_EMOJI_CACHE_BYTES)?,
+            frame_history_bytes: parse_env(ENV_FRAME_HISTORY_BYTES)?
+                .unwrap_or(DEFAULT_FRAME_HISTORY_BYTES),
+            max_emoji_codepoints: parse_env(ENV_MAX_EMOJI_CODEPOINTS)?
+                .unwrap_or(DEFAULT_MAX_EMOJI_CODEPOINTS),
+            max_decode_pixels,
+            max_render_pixels,
+            blank_on_startup: flag_env(ENV_BLANK_ON_STARTUP),
+            splash_image,
+            splash_on_clear,
+            selftest_pause,
+            loading_fps,
+            hue_cycle_period,
+            output_format,
+            compression: parse_env(ENV_PAYLOAD_COMPRESSION)?,
+            output_mode: parse_env(ENV_OUTPUT_MODE)?.unwrap_or_default(),
+            pixel_delta: flag_env(ENV_PIXEL_DELTA),
+            stdout_preview: flag_env(ENV_STDOUT_PREVIEW),
+            debug_frame_counter: flag_env(ENV_DEBUG_FRAME_COUNTER),
+            backoff: parse_env(ENV_BACKOFF_STRATEGY)?.unwrap_or_default(),
+            max_reconnect_attempts: parse_env(ENV_MAX_RECONNECT_ATTEMPTS)?,
+            max_publish_failures,
+            info_topic: std::env::var(ENV_INFO_TOPIC)
+                .unwrap_or_else(|_| DEFAULT_INFO_TOPIC.to_string()),
+            meta_encoding: parse_env(ENV_META_ENCODING)?.unwrap_or_default(),
+            icon,
+            playlist,
+            runtime_flavor: parse_env(ENV_TOKIO_FLAVOR)?.unwrap_or_default(),
+        })
+    }
+
+    /// How frames of the given size are published, falling back to the defaults for
+    /// sizes without settings.
+    pub fn publish_settings(&self, width: u32, height: u32) -> PublishSettings {
+        self.topic_settings
+            .get(&(width, height))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Largest frame, in pixels, rendered at a size chosen by a client: `MAX_RENDER_PIXELS`,
+    /// or the largest panel in `sizes` when not set.
+    pub fn render_pixel_limit(&self) -> u64 {
+        self.max_render_pixels.unwrap_or_else(|| {
+            let pixels = |&(width, height): &(u32, u32)| width as u64 * height as u64;
+            self.sizes.iter().map(pixels).max().unwrap_or(0)
+        })
+    }
+
+    /// Checks that a `width`x`height` frame chosen by a client is within
+    /// `render_pixel_limit`, before anything is allocated for it.
+    pub fn check_render_size(&self, width: u32, height: u32) -> Result<(), String> {
+        let limit = self.render_pixel_limit();
+        if width as u64 * height as u64 > limit {
+            return Err(format!(
+                "{}x{} is larger than the {} pixels allowed, see {}",
+                width, height, limit, ENV_MAX_RENDER_PIXELS
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn mqtt_options(&self) -> MqttOptions {
+        let mut options = self.mqtt.options();
+        options.set_max_packet_size(self.max_packet_bytes, self.max_packet_bytes);
+        options
+    }
+
+    pub fn mqtt_options_v5(&self) -> v5::MqttOptions {
+        let mut options = self.mqtt.options_v5();
+        // MQTT v5 announces the limit to the broker when connecting.
+        options.set_max_packet_size(Some(self.max_packet_bytes.min(u32::MAX as usize) as u32));
+        options
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rumqttc::QoS;
+
+    use super::Config;
+    use crate::mqtt::PublishSettings;
+
+    #[test]
+    fn publish_settings_fall_back_to_defaults() {
+        let remote = PublishSettings {
+            qos: QoS::AtMostOnce,
+            retain: false,
+        };
+        let mut config = Config::default();
+        config.topic_settings.insert((128, 128), remote);
+
+        assert_eq!(config.publish_settings(128, 128), remote);
+        assert_eq!(config.publish_settings(32, 32), PublishSettings::default());
+    }
+
+    #[test]
+    fn limits_render_sizes_to_the_largest_panel() {
+        let config = Config::default();
+        assert_eq!(config.render_pixel_limit(), 128 * 128);
+        assert!(config.check_render_size(128, 128).is_ok());
+        assert!(config.check_render_size(256, 64).is_ok());
+        assert!(config.check_render_size(129, 128).is_err());
+
+        let config = Config {
+            max_render_pixels: Some(100),
+            ..Default::default()
+        };
+        assert!(config.check_render_size(10, 10).is_ok());
+        assert!(config.check_render_size(11, 10).is_err());
+    }
+}
@@ -0,0 +1,59 @@
+// Copyright 2023 André Cipriani Bandarra
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::str::FromStr;
+
+/// Command published to the control topic, see `mqtt::control_topic`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlCommand {
+    /// Holds the frames shown on the panels under the prefix, ignoring commands and
+    /// pausing animations for them until unfrozen, see `FreezeGate`.
+    Freeze,
+    Unfreeze,
+    /// Picks up the emoji added to or updated in the emoji directory, clearing the
+    /// `EmojiCache`.
+    Reload,
+    /// Publishes the frames shown again, eg: for subscribers that joined since, when
+    /// frames aren't retained.
+    Refresh,
+}
+
+impl FromStr for ControlCommand {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "freeze" | "pause" => Ok(ControlCommand::Freeze),
+            "unfreeze" | "resume" => Ok(ControlCommand::Unfreeze),
+            "reload" => Ok(ControlCommand::Reload),
+            "refresh" => Ok(ControlCommand::Refresh),
+            _ => Err(format!("Invalid control command: {}", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ControlCommand;
+
+    #[test]
+    fn parses_control_commands() {
+        assert_eq!("freeze\n".parse(), Ok(ControlCommand::Freeze));
+        assert_eq!("UNFREEZE".parse(), Ok(ControlCommand::Unfreeze));
+        assert_eq!("reload".parse(), Ok(ControlCommand::Reload));
+        assert_eq!(" Refresh ".parse(), Ok(ControlCommand::Refresh));
+        assert!("reboot".parse::<ControlCommand>().is_err());
+    }
+}
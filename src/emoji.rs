@@ -0,0 +1,530 @@
+// Copyright 2023 André Cipriani Bandarra
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::{
+    error::Error,
+    fs::{self, File},
+    io::{self, BufReader},
+    path::{Path, PathBuf},
+};
+
+use exif::{In, Tag};
+use image::{
+    error::{ImageFormatHint, LimitError, LimitErrorKind, UnsupportedErrorKind},
+    io::Limits,
+    DynamicImage, ImageError,
+};
+
+use crate::error::DaemonError;
+
+// Variation selectors request text (FE0E) or emoji (FE0F) presentation of the
+// preceding character.
+const VARIATION_SELECTORS: [char; 2] = ['\u{fe0e}', '\u{fe0f}'];
+
+// Widest decoded pixel of an emoji asset, RGBA with 16 bits per channel, for capping
+// the memory decoding may allocate, see `open_image`.
+const MAX_BYTES_PER_PIXEL: u64 = 8;
+
+// Flags are pairs of regional indicator symbols, one per letter of the country code.
+const REGIONAL_INDICATORS: std::ops::RangeInclusive<char> = '\u{1f1e6}'..='\u{1f1ff}';
+
+/// Returns whether `emoji` is a country flag, eg: 🇧🇷 (U+1F1E7 U+1F1F7).
+pub fn is_flag(emoji: &str) -> bool {
+    let mut count = 0;
+    for c in emoji.chars() {
+        if !REGIONAL_INDICATORS.contains(&c) {
+            return false;
+        }
+        count += 1;
+    }
+    count == 2
+}
+
+/// Default for the longest emoji accepted, in codepoints. Long enough for ZWJ sequences
+/// like families with skin tones.
+pub const DEFAULT_MAX_EMOJI_CODEPOINTS: usize = 16;
+
+/// Checks that `emoji` has at most `max_codepoints` codepoints.
+///
+/// Only looks at the first `max_codepoints + 1` characters, so huge inputs are rejected
+/// without scanning them.
+pub fn check_emoji_length(emoji: &str, max_codepoints: usize) -> Result<(), String> {
+    if exceeds_length(emoji.chars(), max_codepoints) {
+        return Err(format!(
+            "Emoji longer than {} codepoints ({} bytes)",
+            max_codepoints,
+            emoji.len()
+        ));
+    }
+    Ok(())
+}
+
+/// Returns whether `chars` has more than `max` items, taking at most `max + 1` of them.
+fn exceeds_length(mut chars: impl Iterator<Item = char>, max: usize) -> bool {
+    chars.nth(max).is_some()
+}
+
+/// Parses space-separated hex codepoints into the emoji they spell, eg: `1F44D` is 👍 and
+/// `1F1E7 1F1F7` is 🇧🇷, for clients that can't send emoji characters.
+pub fn parse_codepoints(codepoints: &str) -> Option<String> {
+    let emoji = codepoints
+        .split_whitespace()
+        .map(|hex| char::from_u32(u32::from_str_radix(hex, 16).ok()?))
+        .collect::<Option<String>>()?;
+    (!emoji.is_empty()).then_some(emoji)
+}
+
+/// Builds the Noto Emoji file stem for a sequence of characters, eg: `emoji_u1f44d`.
+fn file_stem(chars: impl Iterator<Item = char>) -> String {
+    let codepoints = chars.map(|c| format!("{:x}", c as u32)).collect::<Vec<_>>();
+    format!("emoji_u{}", codepoints.join("_"))
+}
+
+/// Returns the candidate file stems for `emoji`, in the order they should be tried.
+///
+/// Noto Emoji names its files after the codepoints of the emoji with the variation
+/// selectors removed, so `❤️` (U+2764 U+FE0F) is stored as `emoji_u2764.png`. The name
+/// with the selectors is still tried first, for emoji sets that keep them.
+pub fn candidate_file_stems(emoji: &str) -> Vec<String> {
+    let mut candidates = vec![file_stem(emoji.chars())];
+
+    let stripped = emoji
+        .chars()
+        .filter(|c| !VARIATION_SELECTORS.contains(c))
+        .collect::<Vec<_>>();
+    candidates.push(file_stem(stripped.iter().copied()));
+
+    // Fall back to the emoji without its last codepoint, eg: an unsupported skin tone. A
+    // single regional indicator isn't a meaningful fallback for a flag.
+    if stripped.len() > 1 && !is_flag(emoji) {
+        candidates.push(file_stem(stripped[..stripped.len() - 1].iter().copied()));
+    }
+
+    candidates.dedup();
+    candidates
+}
+
+/// Finds the image file for `emoji` in `emoji_directory`.
+pub fn find_emoji_file(emoji_directory: &str, emoji: &str) -> Option<PathBuf> {
+    candidate_file_stems(emoji)
+        .into_iter()
+        .map(|stem| Path::new(emoji_directory).join(stem + ".png"))
+        .find(|path| path.exists())
+}
+
+/// Counts the emoji images in `emoji_directory` that `find_emoji_file` can resolve.
+pub fn count_emoji_assets(emoji_directory: &Path) -> io::Result<usize> {
+    let mut count = 0;
+    for entry in fs::read_dir(emoji_directory)? {
+        let path = entry?.path();
+        let is_emoji = path.extension().is_some_and(|extension| extension == "png")
+            && path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .is_some_and(|stem| stem.starts_with("emoji_u"));
+        if is_emoji && path.is_file() {
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+/// Loads the image for `emoji` from `emoji_directory`, refusing to decode images larger
+/// than `max_pixels` when set.
+pub fn load_emoji_image(
+    emoji_directory: &str,
+    emoji: &str,
+    max_pixels: Option<u64>,
+) -> Result<DynamicImage, DaemonError> {
+    let Some(filename) = find_emoji_file(emoji_directory, emoji) else {
+        return Err(DaemonError::NotFound(emoji.to_string()));
+    };
+
+    let img = open_image(&filename, max_pixels).map_err(|e| match e.downcast::<ImageError>() {
+        Ok(e) => match *e {
+            ImageError::Limits(_) => DaemonError::TooLarge {
+                path: filename.clone(),
+                max_pixels: max_pixels.unwrap_or_default(),
+            },
+            ImageError::Unsupported(e) if matches!(e.kind(), UnsupportedErrorKind::Format(_)) => {
+                DaemonError::UnsupportedFormat {
+                    path: filename.clone(),
+                    format: format_name(e.format_hint()),
+                }
+            }
+            e => DaemonError::Image(e.into()),
+        },
+        Err(e) => DaemonError::Image(e),
+    })?;
+    log::debug!("Loaded {} as {:?}", filename.display(), img.color());
+    // Blending and resizing expect RGBA, whatever color type the asset pack uses.
+    Ok(DynamicImage::ImageRgba8(img.into_rgba8()))
+}
+
+// Names the format of an image that couldn't be decoded, eg: "Avif".
+fn format_name(hint: ImageFormatHint) -> String {
+    match hint {
+        ImageFormatHint::Exact(format) => format!("{:?}", format),
+        ImageFormatHint::Name(name) => name,
+        ImageFormatHint::PathExtension(extension) => extension.display().to_string(),
+        _ => "an unknown format".to_string(),
+    }
+}
+
+/// Opens the image at `path`, rotated according to its EXIF orientation. Decoding fails
+/// with `ImageError::Limits` when the image is larger than `max_pixels`.
+pub fn open_image(
+    path: &Path,
+    max_pixels: Option<u64>,
+) -> Result<DynamicImage, Box<dyn Error + Send + Sync>> {
+    // Custom assets may be JPEGs saved with a png extension, so sniff the format.
+    let mut reader = image::io::Reader::open(path)?.with_guessed_format()?;
+    if let Some(max_pixels) = max_pixels {
+        // The limit is checked against the image dimensions before anything is
+        // allocated, then caps the memory for decoding that many pixels.
+        let (width, height) = reader.into_dimensions()?;
+        if width as u64 * height as u64 > max_pixels {
+            let error = LimitError::from_kind(LimitErrorKind::DimensionError);
+            return Err(ImageError::Limits(error).into());
+        }
+        reader = image::io::Reader::open(path)?.with_guessed_format()?;
+        let mut limits = Limits::default();
+        limits.max_alloc = Some(max_pixels.saturating_mul(MAX_BYTES_PER_PIXEL));
+        reader.limits(limits);
+    }
+    let img = reader.decode()?;
+    Ok(match read_orientation(path) {
+        Some(orientation) => apply_orientation(img, orientation),
+        None => img,
+    })
+}
+
+/// Reads the EXIF orientation tag of the image at `path`, if it has one.
+pub fn read_orientation(path: &Path) -> Option<u32> {
+    let file = File::open(path).ok()?;
+    let exif = exif::Reader::new()
+        .read_from_container(&mut BufReader::new(file))
+        .ok()?;
+    exif.get_field(Tag::Orientation, In::PRIMARY)?
+        .value
+        .get_uint(0)
+}
+
+/// Transforms `img` so that it's upright, given its EXIF `orientation`.
+///
+/// The `image` crate decodes pixels as stored, ignoring the orientation tag that
+/// cameras and some editors use instead of rotating the pixels.
+pub fn apply_orientation(img: DynamicImage, orientation: u32) -> DynamicImage {
+    match orientation {
+        2 => img.fliph(),
+        3 => img.rotate180(),
+        4 => img.flipv(),
+        5 => img.rotate90().fliph(),
+        6 => img.rotate90(),
+        7 => img.rotate270().fliph(),
+        8 => img.rotate270(),
+        _ => img,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Cursor, path::Path};
+
+    use image::{DynamicImage, GenericImageView, ImageOutputFormat, Rgb, RgbImage};
+
+    fn write_fixture(dir: &Path, name: &str) {
+        image::RgbaImage::new(1, 1).save(dir.join(name)).unwrap();
+    }
+
+    // 2x1 image with a red pixel on the left and a blue pixel on the right.
+    fn red_blue() -> DynamicImage {
+        let mut img = RgbImage::new(2, 1);
+        img.put_pixel(0, 0, Rgb([255, 0, 0]));
+        img.put_pixel(1, 0, Rgb([0, 0, 255]));
+        DynamicImage::ImageRgb8(img)
+    }
+
+    // Encodes `img` as a JPEG with an EXIF APP1 segment holding `orientation`.
+    fn jpeg_with_orientation(img: &DynamicImage, orientation: u8) -> Vec<u8> {
+        let mut jpeg = Vec::new();
+        img.write_to(&mut Cursor::new(&mut jpeg), ImageOutputFormat::Jpeg(100))
+            .unwrap();
+
+        // Big endian TIFF header, followed by an IFD with a single SHORT entry.
+        let mut exif = b"Exif\0\0MM\0\x2a\0\0\0\x08\0\x01".to_vec();
+        exif.extend_from_slice(&[0x01, 0x12, 0x00, 0x03, 0, 0, 0, 1, 0, orientation, 0, 0]);
+        exif.extend_from_slice(&[0, 0, 0, 0]);
+
+        let length = (exif.len() + 2) as u16;
+        let mut segment = vec![0xff, 0xe1];
+        segment.extend_from_slice(&length.to_be_bytes());
+        segment.extend_from_slice(&exif);
+
+        // Insert the segment right after the SOI marker.
+        jpeg.splice(2..2, segment);
+        jpeg
+    }
+
+    #[test]
+    fn loads_indexed_png_as_rgba() {
+        // 2x1 image with a palette of opaque red and half transparent blue.
+        let dir = tempfile::tempdir().unwrap();
+        let file = std::fs::File::create(dir.path().join("emoji_u1f44d.png")).unwrap();
+        let mut encoder = png::Encoder::new(file, 2, 1);
+        encoder.set_color(png::ColorType::Indexed);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.set_palette(vec![255, 0, 0, 0, 0, 255]);
+        encoder.set_trns(vec![255, 128]);
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(&[0, 1]).unwrap();
+        writer.finish().unwrap();
+
+        let img = super::load_emoji_image(dir.path().to_str().unwrap(), "👍", None).unwrap();
+        let img = img.as_rgba8().unwrap();
+        assert_eq!(img.get_pixel(0, 0), &image::Rgba([255, 0, 0, 255]));
+        assert_eq!(img.get_pixel(1, 0), &image::Rgba([0, 0, 255, 128]));
+    }
+
+    #[test]
+    fn reports_formats_missing_from_build() {
+        // An AVIF file type box, a format this build recognizes but can't decode.
+        let dir = tempfile::tempdir().unwrap();
+        let mut avif = b"\0\0\0\x1cftypavif\0\0\0\0avifmif1miaf".to_vec();
+        avif.resize(64, 0);
+        std::fs::write(dir.path().join("emoji_u1f44d.png"), avif).unwrap();
+
+        let result = super::load_emoji_image(dir.path().to_str().unwrap(), "👍", None);
+        match result {
+            Err(e @ crate::error::DaemonError::UnsupportedFormat { .. }) => {
+                assert!(e
+                    .to_string()
+                    .contains("Avif, which is not enabled in this build"));
+            }
+            other => panic!("Unexpected result {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn rejects_images_over_decode_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir_str = dir.path().to_str().unwrap();
+        RgbImage::new(64, 64)
+            .save(dir.path().join("emoji_u1f44d.png"))
+            .unwrap();
+
+        let err = super::load_emoji_image(dir_str, "👍", Some(32 * 32)).unwrap_err();
+        assert!(matches!(
+            err,
+            crate::error::DaemonError::TooLarge {
+                max_pixels: 1024,
+                ..
+            }
+        ));
+        assert!(err.to_string().contains("MAX_DECODE_PIXELS"));
+        assert!(super::load_emoji_image(dir_str, "👍", Some(64 * 64)).is_ok());
+    }
+
+    #[test]
+    fn accepts_emoji_within_length() {
+        assert!(super::check_emoji_length("👍", 4).is_ok());
+        // Family: man, woman, girl, boy joined by ZWJs.
+        assert!(super::check_emoji_length("👨‍👩‍👧‍👦", 7).is_ok());
+        assert!(super::check_emoji_length("👨‍👩‍👧‍👦", 6).is_err());
+    }
+
+    #[test]
+    fn rejects_huge_emoji_without_scanning_them() {
+        let emoji = "👍".repeat(10_000);
+        let result = super::check_emoji_length(&emoji, super::DEFAULT_MAX_EMOJI_CODEPOINTS);
+        assert!(result.is_err());
+
+        // Counts the characters read instead of timing the check, so it can't flake.
+        let mut read = 0;
+        let chars = emoji.chars().inspect(|_| read += 1);
+        assert!(super::exceeds_length(
+            chars,
+            super::DEFAULT_MAX_EMOJI_CODEPOINTS
+        ));
+        assert_eq!(read, super::DEFAULT_MAX_EMOJI_CODEPOINTS + 1);
+    }
+
+    #[test]
+    fn builds_candidates_for_single_codepoint() {
+        assert_eq!(super::candidate_file_stems("👍"), vec!["emoji_u1f44d"]);
+    }
+
+    #[test]
+    fn strips_variation_selectors() {
+        assert_eq!(
+            super::candidate_file_stems("❤️"),
+            vec!["emoji_u2764_fe0f", "emoji_u2764"]
+        );
+        assert_eq!(super::candidate_file_stems("❤"), vec!["emoji_u2764"]);
+    }
+
+    #[test]
+    fn both_heart_forms_resolve_to_same_asset() {
+        let dir = tempfile::tempdir().unwrap();
+        write_fixture(dir.path(), "emoji_u2764.png");
+        let dir_str = dir.path().to_str().unwrap();
+
+        let with_selector = super::find_emoji_file(dir_str, "❤️").unwrap();
+        let without_selector = super::find_emoji_file(dir_str, "❤").unwrap();
+        assert_eq!(with_selector, dir.path().join("emoji_u2764.png"));
+        assert_eq!(with_selector, without_selector);
+    }
+
+    #[test]
+    fn codepoints_resolve_to_same_asset() {
+        let dir = tempfile::tempdir().unwrap();
+        write_fixture(dir.path(), "emoji_u1f44d.png");
+        write_fixture(dir.path(), "emoji_u1f1e7_1f1f7.png");
+        let dir_str = dir.path().to_str().unwrap();
+
+        let emoji = super::parse_codepoints("1F44D").unwrap();
+        assert_eq!(emoji, "👍");
+        assert_eq!(
+            super::find_emoji_file(dir_str, &emoji),
+            super::find_emoji_file(dir_str, "👍")
+        );
+        let flag = super::parse_codepoints(" 1f1e7  1f1f7 ").unwrap();
+        assert_eq!(
+            super::find_emoji_file(dir_str, &flag).unwrap(),
+            dir.path().join("emoji_u1f1e7_1f1f7.png")
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_codepoints() {
+        for codepoints in ["", "  ", "thumbs", "D800", "110000"] {
+            assert_eq!(
+                super::parse_codepoints(codepoints),
+                None,
+                "{:?}",
+                codepoints
+            );
+        }
+    }
+
+    #[test]
+    fn prefers_name_with_selector_when_present() {
+        let dir = tempfile::tempdir().unwrap();
+        write_fixture(dir.path(), "emoji_u2764.png");
+        write_fixture(dir.path(), "emoji_u2764_fe0f.png");
+        let dir_str = dir.path().to_str().unwrap();
+
+        let path = super::find_emoji_file(dir_str, "❤️").unwrap();
+        assert_eq!(path, dir.path().join("emoji_u2764_fe0f.png"));
+    }
+
+    #[test]
+    fn falls_back_to_base_emoji() {
+        let dir = tempfile::tempdir().unwrap();
+        write_fixture(dir.path(), "emoji_u1f44d.png");
+        let dir_str = dir.path().to_str().unwrap();
+
+        // Thumbs up with a skin tone modifier.
+        let path = super::find_emoji_file(dir_str, "👍🏽").unwrap();
+        assert_eq!(path, dir.path().join("emoji_u1f44d.png"));
+        assert!(super::load_emoji_image(dir_str, "👍🏽", None).is_ok());
+    }
+
+    #[test]
+    fn builds_candidates_for_flags() {
+        assert!(super::is_flag("🇧🇷"));
+        assert!(!super::is_flag("🇧"));
+        assert!(!super::is_flag("👍🏽"));
+        assert_eq!(
+            super::candidate_file_stems("🇧🇷"),
+            vec!["emoji_u1f1e7_1f1f7"]
+        );
+        assert_eq!(
+            super::candidate_file_stems("🇯🇵"),
+            vec!["emoji_u1f1ef_1f1f5"]
+        );
+    }
+
+    #[test]
+    fn resolves_flags_and_reports_missing_ones() {
+        let dir = tempfile::tempdir().unwrap();
+        write_fixture(dir.path(), "emoji_u1f1e7_1f1f7.png");
+        write_fixture(dir.path(), "emoji_u1f1e7.png");
+        let dir_str = dir.path().to_str().unwrap();
+
+        let path = super::find_emoji_file(dir_str, "🇧🇷").unwrap();
+        assert_eq!(path, dir.path().join("emoji_u1f1e7_1f1f7.png"));
+
+        // Belgium doesn't fall back to the lone B indicator.
+        assert!(super::find_emoji_file(dir_str, "🇧🇪").is_none());
+        let err = super::load_emoji_image(dir_str, "🇧🇪", None).unwrap_err();
+        assert_eq!(err.to_string(), "Flag asset missing for 🇧🇪");
+    }
+
+    #[test]
+    fn counts_emoji_assets() {
+        let dir = tempfile::tempdir().unwrap();
+        write_fixture(dir.path(), "emoji_u1f44d.png");
+        write_fixture(dir.path(), "emoji_u2764.png");
+        write_fixture(dir.path(), "README.png");
+        std::fs::write(dir.path().join("emoji_u1f600.svg"), "").unwrap();
+        std::fs::create_dir(dir.path().join("emoji_u1f601.png")).unwrap();
+
+        assert_eq!(super::count_emoji_assets(dir.path()).unwrap(), 2);
+        assert!(super::count_emoji_assets(&dir.path().join("missing")).is_err());
+    }
+
+    #[test]
+    fn applies_orientation_transforms() {
+        let img = red_blue();
+        assert_eq!(
+            super::apply_orientation(img.clone(), 1).dimensions(),
+            (2, 1)
+        );
+
+        let flipped = super::apply_orientation(img.clone(), 2).to_rgb8();
+        assert_eq!(flipped.get_pixel(0, 0), &Rgb([0, 0, 255]));
+
+        // Orientation 6 means the image must be rotated 90 degrees clockwise.
+        let rotated = super::apply_orientation(img, 6).to_rgb8();
+        assert_eq!(rotated.dimensions(), (1, 2));
+        assert_eq!(rotated.get_pixel(0, 0), &Rgb([255, 0, 0]));
+    }
+
+    #[test]
+    fn loads_image_respecting_exif_orientation() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut img = RgbImage::from_pixel(16, 8, Rgb([0, 0, 255]));
+        for y in 0..8 {
+            for x in 0..8 {
+                img.put_pixel(x, y, Rgb([255, 0, 0]));
+            }
+        }
+        let jpeg = jpeg_with_orientation(&DynamicImage::ImageRgb8(img), 6);
+        let path = dir.path().join("emoji_u1f44d.png");
+        std::fs::write(&path, jpeg).unwrap();
+
+        assert_eq!(super::read_orientation(&path), Some(6));
+        let loaded = super::load_emoji_image(dir.path().to_str().unwrap(), "👍", None)
+            .unwrap()
+            .to_rgb8();
+        assert_eq!(loaded.dimensions(), (8, 16));
+        // The red left half ends up at the top after rotating clockwise.
+        let top = loaded.get_pixel(4, 2);
+        let bottom = loaded.get_pixel(4, 13);
+        assert!(top[0] > 200 && top[2] < 50, "{:?}", top);
+        assert!(bottom[2] > 200 && bottom[0] < 50, "{:?}", bottom);
+    }
+}
@@ -0,0 +1,166 @@
+// Copyright 2023 André Cipriani Bandarra
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::str::FromStr;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+
+use crate::imageutils::{compress_frame, Compression};
+
+/// Encodes a frame into the payload published for it.
+///
+/// `buf` holds 3 bytes (red, green, blue) per LED, in strip order, for a `width`x`height`
+/// panel. Encoders may be chained, eg: a format followed by a compression.
+pub trait OutputEncoder: Send + Sync {
+    fn encode(&self, buf: &[u8], width: u32, height: u32) -> Vec<u8>;
+}
+
+/// Raw RGB bytes, as rendered.
+pub struct RawRgb;
+
+impl OutputEncoder for RawRgb {
+    fn encode(&self, buf: &[u8], _width: u32, _height: u32) -> Vec<u8> {
+        buf.to_vec()
+    }
+}
+
+/// Raw bytes in green, red, blue order, eg: for WS2812 strips driven directly.
+pub struct RawGrb;
+
+impl OutputEncoder for RawGrb {
+    fn encode(&self, buf: &[u8], _width: u32, _height: u32) -> Vec<u8> {
+        buf.chunks_exact(3)
+            .flat_map(|pixel| [pixel[1], pixel[0], pixel[2]])
+            .collect()
+    }
+}
+
+/// JSON document with the frame size and the raw RGB bytes in base64, for firmwares that
+/// only handle text payloads, eg: `{"width":2,"height":1,"data":"/wAAAP8A"}`.
+pub struct Base64Json;
+
+impl OutputEncoder for Base64Json {
+    fn encode(&self, buf: &[u8], width: u32, height: u32) -> Vec<u8> {
+        serde_json::json!({
+            "width": width,
+            "height": height,
+            "data": STANDARD.encode(buf),
+        })
+        .to_string()
+        .into_bytes()
+    }
+}
+
+impl OutputEncoder for Compression {
+    fn encode(&self, buf: &[u8], _width: u32, _height: u32) -> Vec<u8> {
+        compress_frame(buf, *self)
+    }
+}
+
+/// Payload format expected by the subscribers, selecting its `OutputEncoder`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputFormat {
+    #[default]
+    Rgb,
+    Grb,
+    Base64Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "rgb" => Ok(OutputFormat::Rgb),
+            "grb" => Ok(OutputFormat::Grb),
+            "base64_json" => Ok(OutputFormat::Base64Json),
+            _ => Err(format!("Invalid output format: {}", s)),
+        }
+    }
+}
+
+impl OutputFormat {
+    /// Name of the format, as written in `OUTPUT_FORMAT`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            OutputFormat::Rgb => "rgb",
+            OutputFormat::Grb => "grb",
+            OutputFormat::Base64Json => "base64_json",
+        }
+    }
+
+    /// MIME type of the payloads in this format, before any compression.
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            OutputFormat::Rgb | OutputFormat::Grb => "application/octet-stream",
+            OutputFormat::Base64Json => "application/json",
+        }
+    }
+
+    pub fn encoder(&self) -> &'static dyn OutputEncoder {
+        match self {
+            OutputFormat::Rgb => &RawRgb,
+            OutputFormat::Grb => &RawGrb,
+            OutputFormat::Base64Json => &Base64Json,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{OutputEncoder, OutputFormat};
+    use crate::imageutils::{rle_decode, Compression};
+
+    // 2x1 frame with a red and a green pixel.
+    const FRAME: [u8; 6] = [255, 0, 0, 0, 255, 0];
+
+    #[test]
+    fn encodes_raw_rgb() {
+        assert_eq!(OutputFormat::Rgb.encoder().encode(&FRAME, 2, 1), FRAME);
+    }
+
+    #[test]
+    fn encodes_raw_grb() {
+        assert_eq!(
+            OutputFormat::Grb.encoder().encode(&FRAME, 2, 1),
+            [0, 255, 0, 255, 0, 0]
+        );
+    }
+
+    #[test]
+    fn encodes_base64_json() {
+        let out = OutputFormat::Base64Json.encoder().encode(&FRAME, 2, 1);
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&out).unwrap(),
+            serde_json::json!({"width": 2, "height": 1, "data": "/wAAAP8A"})
+        );
+    }
+
+    #[test]
+    fn compression_is_an_encoder() {
+        let out = Compression::Rle.encode(&FRAME, 2, 1);
+        assert_eq!(out, [1, 255, 0, 0, 1, 0, 255, 0]);
+        assert_eq!(rle_decode(&out).unwrap(), FRAME);
+    }
+
+    #[test]
+    fn parses_output_formats() {
+        assert_eq!("GRB".parse(), Ok(OutputFormat::Grb));
+        assert_eq!("base64_json".parse(), Ok(OutputFormat::Base64Json));
+        assert!("rgbw".parse::<OutputFormat>().is_err());
+        // RLE is a compression, see `PAYLOAD_COMPRESSION`.
+        assert!("rle".parse::<OutputFormat>().is_err());
+    }
+}
@@ -0,0 +1,64 @@
+// Copyright 2023 André Cipriani Bandarra
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::{error::Error, fmt, path::PathBuf};
+
+use crate::emoji::is_flag;
+
+/// Reason an emoji couldn't be rendered.
+#[derive(Debug)]
+pub enum DaemonError {
+    /// The emoji was rejected before any filesystem work, eg: because it's too long.
+    InvalidEmoji(String),
+    /// There is no image for the emoji in the emoji directory.
+    NotFound(String),
+    /// The image for the emoji couldn't be read or decoded.
+    Image(Box<dyn Error + Send + Sync>),
+    /// The image for the emoji is in a format this build can't decode, eg: AVIF.
+    UnsupportedFormat { path: PathBuf, format: String },
+    /// The image for the emoji is larger than `MAX_DECODE_PIXELS` allows.
+    TooLarge { path: PathBuf, max_pixels: u64 },
+    /// Rendering the emoji panicked, with the reason.
+    Panicked(String),
+}
+
+impl fmt::Display for DaemonError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DaemonError::InvalidEmoji(reason) => write!(f, "Invalid emoji: {}", reason),
+            DaemonError::NotFound(emoji) if is_flag(emoji) => {
+                write!(f, "Flag asset missing for {}", emoji)
+            }
+            DaemonError::NotFound(emoji) => write!(f, "No image found for {}", emoji),
+            DaemonError::Image(e) => write!(f, "Failed to load image: {}", e),
+            DaemonError::UnsupportedFormat { path, format } => write!(
+                f,
+                "{} is {}, which is not enabled in this build. Enable the image crate \
+                 feature for it, or convert the emoji directory to PNG",
+                path.display(),
+                format
+            ),
+            DaemonError::TooLarge { path, max_pixels } => write!(
+                f,
+                "{} is larger than the {} pixels allowed by MAX_DECODE_PIXELS",
+                path.display(),
+                max_pixels
+            ),
+            DaemonError::Panicked(reason) => write!(f, "Rendering panicked: {}", reason),
+        }
+    }
+}
+
+impl Error for DaemonError {}
@@ -0,0 +1,127 @@
+// Copyright 2023 André Cipriani Bandarra
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use tokio::sync::mpsc;
+
+use crate::{
+    payload::{PayloadData, PayloadFormat},
+    source::{EventSource, SourceError, SourceEvent, DEFAULT_SOURCE_ID},
+};
+
+// How often the file is checked for changes.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Event source reading commands from a local JSON file, eg: written by a script.
+///
+/// The file holds a single payload in the configured format, and is read again every
+/// time its modification time changes.
+#[derive(Debug, Clone)]
+pub struct FileSource {
+    path: PathBuf,
+    payload_format: PayloadFormat,
+}
+
+impl FileSource {
+    pub fn new(path: PathBuf, payload_format: PayloadFormat) -> Self {
+        Self {
+            path,
+            payload_format,
+        }
+    }
+}
+
+impl EventSource for FileSource {
+    fn id(&self) -> &str {
+        DEFAULT_SOURCE_ID
+    }
+
+    async fn run(self, events: mpsc::Sender<SourceEvent>) -> Result<(), SourceError> {
+        let mut ticks = tokio::time::interval(POLL_INTERVAL);
+        let mut last_modified = None;
+        loop {
+            ticks.tick().await;
+            let Some(modified) = modified_time(&self.path).await else {
+                continue;
+            };
+            if last_modified == Some(modified) {
+                continue;
+            }
+            last_modified = Some(modified);
+
+            let Some(payload) = read_payload(&self.path, self.payload_format).await else {
+                continue;
+            };
+            let event = SourceEvent {
+                source: self.id().to_string(),
+                payload,
+            };
+            if events.send(event).await.is_err() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+async fn modified_time(path: &Path) -> Option<SystemTime> {
+    tokio::fs::metadata(path).await.ok()?.modified().ok()
+}
+
+async fn read_payload(path: &Path, payload_format: PayloadFormat) -> Option<PayloadData> {
+    let data = match tokio::fs::read_to_string(path).await {
+        Ok(data) => data,
+        Err(e) => {
+            log::error!("Failed to read {}: {}", path.display(), e);
+            return None;
+        }
+    };
+    match payload_format.parse(&data) {
+        Ok(payload) => Some(payload),
+        Err(e) => {
+            log::error!("Failed to parse payload {}: {}. Skipping...", data, e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use tokio::sync::mpsc;
+
+    use super::FileSource;
+    use crate::{payload::PayloadFormat, source::EventSource};
+
+    #[tokio::test]
+    async fn sends_payload_from_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path: PathBuf = dir.path().join("event.json");
+        std::fs::write(&path, r#"{"emoji_char":"👍"}"#).unwrap();
+
+        let (events_tx, mut events) = mpsc::channel(1);
+        let source = FileSource::new(path, PayloadFormat::Flat);
+        let task = tokio::spawn(source.run(events_tx));
+
+        let event = events.recv().await.unwrap();
+        assert_eq!(event.source, "default");
+        assert_eq!(event.payload.emoji.as_deref(), Some("👍"));
+        task.abort();
+    }
+}
@@ -0,0 +1,484 @@
+// Copyright 2023 André Cipriani Bandarra
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::{io, sync::Arc, time::Duration};
+
+use reqwest::ClientBuilder;
+use tokio::sync::mpsc;
+
+use crate::{
+    backoff::{wait_next_delay, BackoffKind, BackoffStrategy},
+    clock::Clock,
+    config::Config,
+    payload::{PayloadData, PayloadFormat},
+    source::{EventSource, FirebaseSource, MalformedPolicy, SourceError, SourceEvent},
+    watchdog::{Progress, StallWatchdog},
+};
+
+// Maximum time to wait for a chunk from Firebase before reconnecting.
+const CHUNK_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Event source listening to a Firebase record, with the reconnect settings from the
+/// configuration.
+#[derive(Debug, Clone)]
+pub struct FirebaseListener {
+    source: FirebaseSource,
+    stall_timeout: Option<Duration>,
+    payload_format: PayloadFormat,
+    backoff: BackoffKind,
+    max_reconnect_attempts: Option<u32>,
+    // Malformed lines or payloads in a row after which to reconnect, if any.
+    malformed_limit: Option<u32>,
+    progress: Option<Arc<Progress>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl FirebaseListener {
+    pub fn new(source: FirebaseSource, config: &Config, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            source,
+            stall_timeout: config.stream_stall_timeout,
+            payload_format: config.payload_format,
+            backoff: config.backoff,
+            max_reconnect_attempts: config.max_reconnect_attempts,
+            malformed_limit: (config.malformed_policy == MalformedPolicy::Reconnect)
+                .then_some(config.malformed_limit),
+            progress: None,
+            clock,
+        }
+    }
+
+    /// Records the progress of the listener in `progress`, for the watchdog restarting
+    /// it when it gets stuck.
+    pub fn with_progress(mut self, progress: Arc<Progress>) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+}
+
+impl EventSource for FirebaseListener {
+    fn id(&self) -> &str {
+        &self.source.id
+    }
+
+    async fn run(self, events: mpsc::Sender<SourceEvent>) -> Result<(), SourceError> {
+        run(
+            self.source,
+            self.stall_timeout,
+            self.payload_format,
+            self.backoff.strategy(),
+            self.max_reconnect_attempts,
+            self.malformed_limit,
+            self.progress,
+            &*self.clock,
+            events,
+        )
+        .await
+    }
+}
+
+/// Listens for events on a Firebase record, sending its commands to `events`.
+///
+/// Reconnects whenever the stream fails, waiting as long as `backoff` says after failed
+/// connection attempts, including those answered with an error status, eg: 401 for a
+/// revoked token or 503, and giving up once connecting fails more than
+/// `max_reconnect_attempts` times in a row. Also reconnects after `malformed_limit`
+/// malformed lines or payloads in a row, when set. Records every connection attempt,
+/// timeout and event in `progress`, when set, at the time of `clock`. Returns when
+/// `events` is closed.
+#[allow(clippy::too_many_arguments)]
+pub async fn run(
+    source: FirebaseSource,
+    stall_timeout: Option<Duration>,
+    payload_format: PayloadFormat,
+    mut backoff: Box<dyn BackoffStrategy + Send>,
+    max_reconnect_attempts: Option<u32>,
+    malformed_limit: Option<u32>,
+    progress: Option<Arc<Progress>>,
+    clock: &dyn Clock,
+    events: mpsc::Sender<SourceEvent>,
+) -> Result<(), SourceError> {
+    let http_client = ClientBuilder::new()
+        .build()
+        .map_err(|e| SourceError::Failed(e.into()))?;
+    let record_progress = || {
+        if let Some(progress) = &progress {
+            progress.record(clock);
+        }
+    };
+    let mut failures = 0;
+    loop {
+        record_progress();
+        let mut response = match http_client
+            .get(&source.url)
+            .header("Accept", "text/event-stream")
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+        {
+            Ok(response) => {
+                failures = 0;
+                backoff.reset();
+                response
+            }
+            Err(e) => {
+                failures += 1;
+                if max_reconnect_attempts.is_some_and(|max| failures > max) {
+                    return Err(SourceError::ReconnectLimit {
+                        attempts: failures,
+                        last_error: e.into(),
+                    });
+                }
+                log::error!("Failed to get Firebase URL: {}", e);
+                wait_next_delay(backoff.as_mut(), clock).await;
+                continue;
+            }
+        };
+
+        let mut watchdog = stall_timeout.map(|threshold| StallWatchdog::new(threshold, clock));
+        let mut parser = SseParser::default();
+        // Malformed lines and payloads received in a row, since the last event parsed.
+        let mut malformed = 0;
+        'chunks: loop {
+            let timeout = match &watchdog {
+                Some(watchdog) => watchdog.remaining().min(CHUNK_TIMEOUT),
+                None => CHUNK_TIMEOUT,
+            };
+            let chunk = tokio::time::timeout(timeout, response.chunk()).await;
+            record_progress();
+            let Ok(chunk) = chunk else {
+                match &watchdog {
+                    Some(watchdog) if watchdog.is_stalled() => {
+                        log::error!("No events received from Firebase. Reconnecting...")
+                    }
+                    _ => log::error!("Timed out getting chunk"),
+                }
+                break;
+            };
+
+            let Ok(Some(chunk)) = chunk else {
+                log::error!("Failed to get chunk");
+                break;
+            };
+
+            let too_many_malformed = |malformed| {
+                let reached = malformed_limit.is_some_and(|limit| malformed >= limit);
+                if reached {
+                    log::error!(
+                        "Received {} malformed lines or payloads. Reconnecting...",
+                        malformed
+                    );
+                }
+                reached
+            };
+            for event in parser.push(&chunk) {
+                if let Some(watchdog) = &mut watchdog {
+                    watchdog.record_event();
+                }
+                malformed += event.malformed;
+                if too_many_malformed(malformed) {
+                    break 'chunks;
+                }
+                // Any event parsed, even a keep-alive, ends the run of malformed data.
+                let payload = match parse_event(&event, payload_format) {
+                    Ok(Some(payload)) => payload,
+                    Ok(None) => {
+                        malformed = 0;
+                        continue;
+                    }
+                    Err(e) => {
+                        log::error!("Failed to parse payload {}: {}. Skipping...", event.data, e);
+                        malformed += 1;
+                        if too_many_malformed(malformed) {
+                            break 'chunks;
+                        }
+                        continue;
+                    }
+                };
+                malformed = 0;
+                let event = SourceEvent {
+                    source: source.id.clone(),
+                    payload,
+                };
+                if events.send(event).await.is_err() {
+                    return Ok(());
+                }
+            }
+            malformed += parser.take_malformed();
+            if too_many_malformed(malformed) {
+                break;
+            }
+        }
+    }
+}
+
+/// Event of a server-sent events stream, eg: `event: put` followed by `data: {...}`.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct SseEvent {
+    pub event: String,
+    pub data: String,
+    /// Malformed lines ignored since the previous event, including those among its
+    /// fields.
+    pub malformed: u32,
+}
+
+/// Splits a server-sent events stream into events.
+///
+/// Chunks can end anywhere, even in the middle of a line or of a UTF-8 sequence, so
+/// incomplete lines are kept until the next chunk and fields are collected until the
+/// blank line ending the event.
+#[derive(Debug, Default)]
+pub struct SseParser {
+    line: Vec<u8>,
+    event: SseEvent,
+    malformed: u32,
+}
+
+impl SseParser {
+    /// Adds the next `chunk` of the stream, returning the events it completes.
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<SseEvent> {
+        let mut events = Vec::new();
+        for &byte in chunk {
+            if byte != b'\n' {
+                self.line.push(byte);
+                continue;
+            }
+            let line = String::from_utf8_lossy(&self.line).into_owned();
+            self.line.clear();
+            let line = line.strip_suffix('\r').unwrap_or(&line);
+            if line.is_empty() {
+                if !self.event.event.is_empty() || !self.event.data.is_empty() {
+                    let mut event = std::mem::take(&mut self.event);
+                    event.malformed = std::mem::take(&mut self.malformed);
+                    events.push(event);
+                }
+                continue;
+            }
+            // Lines starting with a colon are comments.
+            let Ok((field, value)) = parse_chunk_line(line) else {
+                log::warn!("Ignoring malformed line {:?}", line);
+                self.malformed += 1;
+                continue;
+            };
+            match field {
+                "event" => self.event.event = value.to_string(),
+                "data" => {
+                    if !self.event.data.is_empty() {
+                        self.event.data.push('\n');
+                    }
+                    self.event.data.push_str(value);
+                }
+                _ => {}
+            }
+        }
+        events
+    }
+
+    /// Returns the number of malformed lines pushed since the last call or event, ie:
+    /// lines that aren't a field, a comment or blank. Those before an event are counted
+    /// in `SseEvent::malformed` instead.
+    pub fn take_malformed(&mut self) -> u32 {
+        std::mem::take(&mut self.malformed)
+    }
+}
+
+// Returns the command carried by a Firebase event, if any, or the error parsing its
+// malformed payload.
+fn parse_event(
+    event: &SseEvent,
+    payload_format: PayloadFormat,
+) -> Result<Option<PayloadData>, serde_json::Error> {
+    match event.event.as_str() {
+        "put" => {
+            log::info!("Received command {}", event.event);
+            payload_format.parse(&event.data).map(Some)
+        }
+        "keep-alive" => {
+            log::debug!("Received keep-alive command");
+            Ok(None)
+        }
+        command => {
+            log::info!("Ignoring unknown command {}", command);
+            Ok(None)
+        }
+    }
+}
+
+fn parse_chunk_line(input: &str) -> io::Result<(&str, &str)> {
+    let parts = input.splitn(2, ':').map(|s| s.trim()).collect::<Vec<_>>();
+
+    if parts.len() < 2 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid input"));
+    }
+
+    Ok(((parts[0]), (parts[1])))
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use tokio::{io::AsyncWriteExt, net::TcpListener, sync::mpsc};
+
+    use crate::{
+        backoff::Fixed, clock::SystemClock, payload::PayloadFormat, source::FirebaseSource,
+    };
+
+    // Answers every connection with the parts of `response`, a little apart so they
+    // arrive as separate chunks, then closes it. Returns the source to connect to.
+    async fn serve(response: &'static [&'static str]) -> FirebaseSource {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/record.json", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                for part in response {
+                    let _ = stream.write_all(part.as_bytes()).await;
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                }
+            }
+        });
+        FirebaseSource {
+            id: "default".to_string(),
+            url,
+        }
+    }
+
+    #[tokio::test]
+    async fn counts_error_statuses_as_failed_attempts() {
+        let source =
+            serve(&["HTTP/1.1 401 Unauthorized\r\ncontent-length: 0\r\nconnection: close\r\n\r\n"])
+                .await;
+        let (events, _events) = mpsc::channel(1);
+        let result = super::run(
+            source,
+            None,
+            PayloadFormat::Firebase,
+            Box::new(Fixed::new(Duration::ZERO)),
+            Some(2),
+            None,
+            None,
+            &SystemClock,
+            events,
+        );
+        let result = tokio::time::timeout(Duration::from_secs(5), result)
+            .await
+            .unwrap();
+        assert!(matches!(
+            result,
+            Err(crate::source::SourceError::ReconnectLimit { attempts: 3, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn resets_malformed_count_on_every_event_parsed() {
+        // Never two malformed lines in a row, so the limit of two isn't reached.
+        let source = serve(&[
+            "HTTP/1.1 200 OK\r\ncontent-type: text/event-stream\r\nconnection: close\r\n\r\n",
+            "garbage\n",
+            "event: keep-alive\ndata: null\n\n",
+            "garbage\n",
+            "event: put\ndata: {\"path\":\"/\",\"data\":{\"emoji\":\"👍\"}}\n\n",
+        ])
+        .await;
+        let (events, mut received) = mpsc::channel(1);
+        tokio::spawn(super::run(
+            source,
+            None,
+            PayloadFormat::Firebase,
+            Box::new(Fixed::new(Duration::ZERO)),
+            None,
+            Some(2),
+            None,
+            &SystemClock,
+            events,
+        ));
+        let event = tokio::time::timeout(Duration::from_secs(5), received.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(event.payload.emoji.as_deref(), Some("👍"));
+    }
+
+    #[test]
+    fn test_parse_chunk_line() {
+        let input = "event: put\ndata: {\"emoji\":\"👍\"}\n\n";
+        let (command, data) = super::parse_chunk_line(input).unwrap();
+        assert_eq!(command, "event");
+        assert_eq!(data, "put\ndata: {\"emoji\":\"👍\"}");
+    }
+
+    #[test]
+    fn parses_event_split_across_chunks() {
+        let stream = "event: put\ndata: {\"emoji\":\"👍\"}\n\n".as_bytes();
+        let mut parser = super::SseParser::default();
+
+        // The event line arrives alone, and the data line is cut in the emoji.
+        let split = stream.len() - 6;
+        assert!(parser.push(&stream[..11]).is_empty());
+        assert!(parser.push(&stream[11..split]).is_empty());
+        let events = parser.push(&stream[split..]);
+        assert_eq!(
+            events,
+            vec![super::SseEvent {
+                event: "put".to_string(),
+                data: "{\"emoji\":\"👍\"}".to_string(),
+                malformed: 0,
+            }]
+        );
+    }
+
+    #[test]
+    fn handles_single_line_chunks() {
+        let mut parser = super::SseParser::default();
+        let events = ["event: put\n", "data: {}", "\n", "\n"]
+            .iter()
+            .flat_map(|chunk| parser.push(chunk.as_bytes()))
+            .collect::<Vec<_>>();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].data, "{}");
+    }
+
+    #[test]
+    fn counts_malformed_lines() {
+        let mut parser = super::SseParser::default();
+        let events = parser.push(b"garbage\n: comment\nevent: put\nmore garbage\ndata: {}\n\n");
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].malformed, 2);
+        assert_eq!(parser.take_malformed(), 0);
+        assert!(parser.push(b"trailing garbage\n").is_empty());
+        assert_eq!(parser.take_malformed(), 1);
+        assert_eq!(parser.take_malformed(), 0);
+
+        let event = super::SseEvent {
+            event: "put".to_string(),
+            data: "{not json".to_string(),
+            ..Default::default()
+        };
+        assert!(super::parse_event(&event, crate::payload::PayloadFormat::Firebase).is_err());
+    }
+
+    #[test]
+    fn parses_several_events_in_one_chunk() {
+        let mut parser = super::SseParser::default();
+        let events =
+            parser.push(b"event: keep-alive\ndata: null\n\nevent: put\r\ndata: {}\r\n\r\n");
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].event, "keep-alive");
+        assert_eq!(
+            (events[1].event.as_str(), events[1].data.as_str()),
+            ("put", "{}")
+        );
+    }
+}
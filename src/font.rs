@@ -0,0 +1,217 @@
+// Copyright 2023 André Cipriani Bandarra
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::{error::Error, fmt, io::Cursor};
+
+use image::{DynamicImage, ImageFormat};
+use rustybuzz::{
+    ttf_parser::{GlyphId, RasterImageFormat},
+    Face, UnicodeBuffer,
+};
+
+use crate::error::DaemonError;
+
+/// Color emoji font the emoji are rasterized from, as an alternative to the emoji
+/// directory, eg: NotoColorEmoji.ttf.
+///
+/// Only fonts with bitmap glyphs (CBDT or sbix tables) are supported. The glyphs are
+/// decoded as is, and scaled to the panels like the images of the emoji directory.
+pub struct EmojiFont {
+    path: String,
+    data: Vec<u8>,
+}
+
+impl fmt::Debug for EmojiFont {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EmojiFont")
+            .field("path", &self.path)
+            .finish_non_exhaustive()
+    }
+}
+
+impl EmojiFont {
+    pub fn open(path: &str) -> Result<Self, Box<dyn Error>> {
+        Self::from_data(path, std::fs::read(path)?)
+    }
+
+    pub fn from_data(path: &str, data: Vec<u8>) -> Result<Self, Box<dyn Error>> {
+        if Face::from_slice(&data, 0).is_none() {
+            return Err(format!("{} is not a TrueType or OpenType font", path).into());
+        }
+        Ok(Self {
+            path: path.to_string(),
+            data,
+        })
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    fn face(&self) -> Face<'_> {
+        Face::from_slice(&self.data, 0).expect("font is checked when opened")
+    }
+
+    /// Rasterizes `emoji` from the bitmap strike closest to `pixels_per_em`.
+    ///
+    /// The emoji is shaped first, so sequences (eg: skin tones or ZWJ sequences) resolve
+    /// to the glyph the font substitutes for them. Sequences the font doesn't have a
+    /// single glyph for are not found.
+    pub fn glyph_image(
+        &self,
+        emoji: &str,
+        pixels_per_em: u16,
+    ) -> Result<DynamicImage, DaemonError> {
+        let face = self.face();
+        let mut buffer = UnicodeBuffer::new();
+        buffer.push_str(emoji);
+        let glyphs = rustybuzz::shape(&face, &[], buffer);
+        // Variation selectors and joiners left over by shaping have no image.
+        let mut images = glyphs.glyph_infos().iter().filter_map(|info| {
+            face.glyph_raster_image(GlyphId(info.glyph_id as u16), pixels_per_em)
+        });
+        let (Some(glyph), None) = (images.next(), images.next()) else {
+            return Err(DaemonError::NotFound(emoji.to_string()));
+        };
+        if glyph.format != RasterImageFormat::PNG {
+            return Err(DaemonError::Image(
+                format!(
+                    "{} has {:?} glyphs, only PNG is supported",
+                    self.path, glyph.format
+                )
+                .into(),
+            ));
+        }
+
+        let img = image::load(Cursor::new(glyph.data), ImageFormat::Png)
+            .map_err(|e| DaemonError::Image(e.into()))?;
+        Ok(DynamicImage::ImageRgba8(img.into_rgba8()))
+    }
+
+    /// Counts the glyphs with a bitmap, including the components of sequences, eg: skin
+    /// tone modifiers.
+    pub fn count(&self) -> usize {
+        let face = self.face();
+        (0..face.number_of_glyphs())
+            .filter(|&id| face.glyph_raster_image(GlyphId(id), u16::MAX).is_some())
+            .count()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use image::{ImageOutputFormat, RgbaImage};
+
+    use super::EmojiFont;
+    use crate::error::DaemonError;
+
+    fn table(tag: &[u8; 4], data: Vec<u8>) -> ([u8; 4], Vec<u8>) {
+        (*tag, data)
+    }
+
+    /// Builds a font with a 4x4 sbix bitmap of `color` for 👍, and a glyph for 🏽 without
+    /// a bitmap, eg: a skin tone only used in sequences.
+    pub(crate) fn thumbs_up_font(color: [u8; 4]) -> EmojiFont {
+        let mut png = Vec::new();
+        image::DynamicImage::ImageRgba8(RgbaImage::from_pixel(4, 4, image::Rgba(color)))
+            .write_to(&mut std::io::Cursor::new(&mut png), ImageOutputFormat::Png)
+            .unwrap();
+
+        let mut head = vec![0; 54];
+        head[..4].copy_from_slice(&0x0001_0000u32.to_be_bytes());
+        head[12..16].copy_from_slice(&0x5F0F_3CF5u32.to_be_bytes());
+        head[18..20].copy_from_slice(&16u16.to_be_bytes());
+        let mut hhea = vec![0; 36];
+        hhea[..4].copy_from_slice(&0x0001_0000u32.to_be_bytes());
+        let maxp = [0x0000_5000u32.to_be_bytes().as_slice(), &3u16.to_be_bytes()].concat();
+
+        // A format 12 subtable mapping 🏽 to glyph 2 and 👍 to glyph 1, sorted by codepoint.
+        let mut cmap = [0u16, 1, 3, 10].map(u16::to_be_bytes).concat();
+        cmap.extend(12u32.to_be_bytes());
+        cmap.extend([12u16, 0].map(u16::to_be_bytes).concat());
+        cmap.extend(
+            [40u32, 0, 2, 0x1F3FD, 0x1F3FD, 2, 0x1F44D, 0x1F44D, 1]
+                .map(u32::to_be_bytes)
+                .concat(),
+        );
+
+        // One strike, where only glyph 1 has data.
+        let glyph = [[0u8; 4].as_slice(), b"png ", &png].concat();
+        let mut sbix = [1u16, 1].map(u16::to_be_bytes).concat();
+        sbix.extend([1u32, 12].map(u32::to_be_bytes).concat());
+        sbix.extend([16u16, 72].map(u16::to_be_bytes).concat());
+        let start = 4 + 4 * 4;
+        let end = start + glyph.len() as u32;
+        sbix.extend([start, start, end, end].map(u32::to_be_bytes).concat());
+        sbix.extend(glyph);
+
+        let tables = [
+            table(b"cmap", cmap),
+            table(b"head", head),
+            table(b"hhea", hhea),
+            table(b"maxp", maxp),
+            table(b"sbix", sbix),
+        ];
+        let mut font = 0x0001_0000u32.to_be_bytes().to_vec();
+        font.extend(
+            [tables.len() as u16, 0, 0, 0]
+                .map(u16::to_be_bytes)
+                .concat(),
+        );
+        let mut offset = 12 + 16 * tables.len();
+        for (tag, data) in &tables {
+            font.extend(tag);
+            font.extend(
+                [0, offset as u32, data.len() as u32]
+                    .map(u32::to_be_bytes)
+                    .concat(),
+            );
+            offset += data.len().next_multiple_of(4);
+        }
+        for (_, data) in tables {
+            font.extend(data);
+            font.resize(font.len().next_multiple_of(4), 0);
+        }
+        EmojiFont::from_data("test.ttf", font).unwrap()
+    }
+
+    #[test]
+    fn rasterizes_emoji_glyphs() {
+        let font = thumbs_up_font([255, 0, 0, 255]);
+        let img = font.glyph_image("👍", 8).unwrap().into_rgba8();
+        assert_eq!(img.dimensions(), (4, 4));
+        assert_eq!(img.get_pixel(0, 0), &image::Rgba([255, 0, 0, 255]));
+        // The presentation selector is ignored.
+        assert!(font.glyph_image("👍\u{fe0f}", 8).is_ok());
+        assert_eq!(font.count(), 1);
+    }
+
+    #[test]
+    fn missing_glyphs_are_not_found() {
+        let font = thumbs_up_font([255, 0, 0, 255]);
+        for emoji in ["😀", "🏽", "👍🏽👍"] {
+            assert!(
+                matches!(font.glyph_image(emoji, 8), Err(DaemonError::NotFound(_))),
+                "{}",
+                emoji
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_files_that_are_not_fonts() {
+        assert!(EmojiFont::from_data("emoji.png", vec![0; 64]).is_err());
+    }
+}
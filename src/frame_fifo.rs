@@ -0,0 +1,130 @@
+// Copyright 2023 André Cipriani Bandarra
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::{io, path::PathBuf};
+
+use image::RgbImage;
+use tokio::{io::AsyncReadExt, sync::mpsc};
+
+use crate::config::BYTES_PER_PIXEL;
+
+/// Source id the frames read from the FIFO are routed as.
+pub const FIFO_SOURCE_ID: &str = "fifo";
+
+/// Splits a byte stream into frames of `frame_len` bytes, keeping partial frames until
+/// the rest of their bytes arrive.
+#[derive(Debug)]
+pub struct FrameAssembler {
+    frame_len: usize,
+    pending: Vec<u8>,
+}
+
+impl FrameAssembler {
+    pub fn new(frame_len: usize) -> Self {
+        Self {
+            frame_len,
+            pending: Vec::with_capacity(frame_len),
+        }
+    }
+
+    /// Adds `bytes` read from the stream, returning the frames they complete.
+    pub fn push(&mut self, mut bytes: &[u8]) -> Vec<Vec<u8>> {
+        let mut frames = Vec::new();
+        while !bytes.is_empty() {
+            let take = (self.frame_len - self.pending.len()).min(bytes.len());
+            self.pending.extend_from_slice(&bytes[..take]);
+            bytes = &bytes[take..];
+            if self.pending.len() == self.frame_len {
+                frames.push(std::mem::replace(
+                    &mut self.pending,
+                    Vec::with_capacity(self.frame_len),
+                ));
+            }
+        }
+        frames
+    }
+
+    /// Drops the bytes of an incomplete frame, eg: when the writer went away.
+    pub fn reset(&mut self) {
+        self.pending.clear();
+    }
+}
+
+/// Reads pre-rendered frames from a named pipe, for custom renderers.
+///
+/// Frames are `width`x`height` RGB, 3 bytes per pixel in image order (rows top to
+/// bottom, pixels left to right), written back to back without any header. They are
+/// published as is, so they should already be color corrected. The pipe is opened
+/// again every time its writer closes it.
+#[derive(Debug, Clone)]
+pub struct FrameFifo {
+    path: PathBuf,
+    width: u32,
+    height: u32,
+}
+
+impl FrameFifo {
+    pub fn new(path: PathBuf, width: u32, height: u32) -> Self {
+        Self {
+            path,
+            width,
+            height,
+        }
+    }
+
+    /// Sends every complete frame read from the pipe to `frames`, until `frames` is
+    /// closed or the pipe can't be opened.
+    pub async fn run(self, frames: mpsc::Sender<RgbImage>) -> io::Result<()> {
+        let frame_len = self.width as usize * self.height as usize * BYTES_PER_PIXEL;
+        let mut assembler = FrameAssembler::new(frame_len);
+        let mut chunk = vec![0; frame_len.clamp(1, 64 * 1024)];
+        loop {
+            // Opening blocks until a writer opens the other end.
+            let mut fifo = tokio::fs::File::open(&self.path).await?;
+            loop {
+                let read = fifo.read(&mut chunk).await?;
+                if read == 0 {
+                    break;
+                }
+                for buf in assembler.push(&chunk[..read]) {
+                    let frame = RgbImage::from_raw(self.width, self.height, buf).unwrap();
+                    if frames.send(frame).await.is_err() {
+                        return Ok(());
+                    }
+                }
+            }
+            assembler.reset();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FrameAssembler;
+
+    #[test]
+    fn accumulates_partial_reads() {
+        let mut assembler = FrameAssembler::new(4);
+        assert!(assembler.push(&[1, 2]).is_empty());
+        assert!(assembler.push(&[3]).is_empty());
+        assert_eq!(
+            assembler.push(&[4, 5, 6, 7, 8, 9]),
+            vec![vec![1, 2, 3, 4], vec![5, 6, 7, 8]]
+        );
+
+        assembler.reset();
+        assert_eq!(assembler.push(&[1, 2, 3, 4]), vec![vec![1, 2, 3, 4]]);
+    }
+}
@@ -0,0 +1,189 @@
+// Copyright 2023 André Cipriani Bandarra
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::{
+    collections::{HashMap, HashSet},
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use crate::source::SourceEvent;
+
+/// What happens to the commands received while frozen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FreezePolicy {
+    /// Commands are dropped.
+    #[default]
+    Drop,
+    /// The latest command is applied when unfreezing, the others are dropped.
+    ApplyLatest,
+}
+
+impl FromStr for FreezePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "drop" => Ok(FreezePolicy::Drop),
+            "apply_latest" => Ok(FreezePolicy::ApplyLatest),
+            _ => Err(format!("Invalid freeze policy: {}", s)),
+        }
+    }
+}
+
+/// Topic prefixes frozen right now, shared with the tasks publishing in the background,
+/// eg: the countdown, so they skip the frozen panels.
+#[derive(Debug, Clone, Default)]
+pub struct FrozenPrefixes(Arc<Mutex<HashSet<String>>>);
+
+impl FrozenPrefixes {
+    pub fn contains(&self, prefix: &str) -> bool {
+        self.0.lock().unwrap().contains(prefix)
+    }
+
+    /// Returns whether the panel of the frame topic `topic`, eg: `ledmoji/32x32`, is
+    /// frozen.
+    pub fn contains_topic(&self, topic: &str) -> bool {
+        topic
+            .rsplit_once('/')
+            .is_some_and(|(prefix, _)| self.contains(prefix))
+    }
+}
+
+/// Holds back the commands from the sources for the topic prefixes frozen, eg: during
+/// maintenance, see `ControlCommand::Freeze`.
+#[derive(Debug, Default)]
+pub struct FreezeGate {
+    policy: FreezePolicy,
+    frozen: FrozenPrefixes,
+    // Latest command held back for each frozen prefix, with `FreezePolicy::ApplyLatest`.
+    pending: HashMap<String, SourceEvent>,
+}
+
+impl FreezeGate {
+    pub fn new(policy: FreezePolicy) -> Self {
+        Self {
+            policy,
+            ..Default::default()
+        }
+    }
+
+    pub fn is_frozen(&self, prefix: &str) -> bool {
+        self.frozen.contains(prefix)
+    }
+
+    /// The frozen prefixes, kept up to date as prefixes are frozen and unfrozen.
+    pub fn frozen(&self) -> FrozenPrefixes {
+        self.frozen.clone()
+    }
+
+    /// Returns the prefixes of `prefixes`, where `event` is routed to, that should show it
+    /// now, holding it back or dropping it for the frozen ones.
+    pub fn admit<'a>(&mut self, event: &SourceEvent, prefixes: Vec<&'a str>) -> Vec<&'a str> {
+        let (frozen, admitted): (Vec<_>, Vec<_>) = prefixes
+            .into_iter()
+            .partition(|prefix| self.is_frozen(prefix));
+        for prefix in frozen {
+            log::info!(
+                "{} is frozen. Dropping command from {}...",
+                prefix,
+                event.source
+            );
+            if self.policy == FreezePolicy::ApplyLatest {
+                self.pending.insert(prefix.to_string(), event.clone());
+            }
+        }
+        admitted
+    }
+
+    pub fn freeze(&mut self, prefix: &str) {
+        log::info!("Freezing the panels of {}", prefix);
+        self.frozen.0.lock().unwrap().insert(prefix.to_string());
+    }
+
+    /// Stops holding back commands for `prefix`, returning the command to show on it now,
+    /// if any.
+    pub fn unfreeze(&mut self, prefix: &str) -> Option<SourceEvent> {
+        log::info!("Unfreezing the panels of {}", prefix);
+        self.frozen.0.lock().unwrap().remove(prefix);
+        self.pending.remove(prefix)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FreezeGate, FreezePolicy};
+    use crate::{payload::PayloadData, source::SourceEvent};
+
+    fn event(emoji: &str) -> SourceEvent {
+        SourceEvent {
+            source: "default".to_string(),
+            payload: PayloadData {
+                emoji: Some(emoji.to_string()),
+                ..Default::default()
+            },
+        }
+    }
+
+    fn emoji(event: Option<SourceEvent>) -> Option<String> {
+        event.and_then(|event| event.payload.emoji)
+    }
+
+    #[test]
+    fn suppresses_commands_while_frozen() {
+        let mut gate = FreezeGate::new(FreezePolicy::Drop);
+        assert_eq!(gate.admit(&event("👍"), vec!["ledmoji"]), vec!["ledmoji"]);
+
+        gate.freeze("ledmoji");
+        assert!(gate.is_frozen("ledmoji"));
+        assert!(gate.admit(&event("❤️"), vec!["ledmoji"]).is_empty());
+
+        // Dropped commands aren't applied when unfreezing.
+        assert!(gate.unfreeze("ledmoji").is_none());
+        assert_eq!(gate.admit(&event("🎉"), vec!["ledmoji"]), vec!["ledmoji"]);
+    }
+
+    #[test]
+    fn freezes_prefixes_separately() {
+        let mut gate = FreezeGate::new(FreezePolicy::ApplyLatest);
+        let frozen = gate.frozen();
+        gate.freeze("kitchen");
+        assert!(frozen.contains_topic("kitchen/32x32"));
+        assert!(!frozen.contains_topic("ledmoji/32x32"));
+
+        // Other panels still show the commands routed to both.
+        let prefixes = gate.admit(&event("👍"), vec!["ledmoji", "kitchen"]);
+        assert_eq!(prefixes, vec!["ledmoji"]);
+        assert!(gate.unfreeze("ledmoji").is_none());
+        assert_eq!(emoji(gate.unfreeze("kitchen")).as_deref(), Some("👍"));
+        assert!(!frozen.contains("kitchen"));
+    }
+
+    #[test]
+    fn applies_latest_command_after_unfreezing() {
+        let mut gate = FreezeGate::new(FreezePolicy::ApplyLatest);
+        gate.freeze("ledmoji");
+        assert!(gate.admit(&event("👍"), vec!["ledmoji"]).is_empty());
+        assert!(gate.admit(&event("❤️"), vec!["ledmoji"]).is_empty());
+        assert_eq!(emoji(gate.unfreeze("ledmoji")).as_deref(), Some("❤️"));
+        assert!(gate.unfreeze("ledmoji").is_none());
+    }
+
+    #[test]
+    fn parses_freeze_policies() {
+        assert_eq!("apply_latest".parse(), Ok(FreezePolicy::ApplyLatest));
+        assert!("queue".parse::<FreezePolicy>().is_err());
+    }
+}
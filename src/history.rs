@@ -0,0 +1,296 @@
+// Copyright 2023 André Cipriani Bandarra
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, VecDeque},
+    hash::{Hash, Hasher},
+    time::{Duration, Instant},
+};
+
+use image::RgbImage;
+
+use crate::{clock::Clock, payload::PayloadData};
+
+/// Ring buffer of the most recently published frames for one panel, for replaying them
+/// when debugging glitches.
+///
+/// Holds at most `capacity` frames, and drops the oldest ones to stay within `max_bytes`
+/// of pixel data. The newest frame is always kept, even when it's larger than
+/// `max_bytes` on its own.
+#[derive(Debug, Clone)]
+pub struct FrameHistory {
+    capacity: usize,
+    max_bytes: usize,
+    bytes: usize,
+    frames: VecDeque<RgbImage>,
+}
+
+impl FrameHistory {
+    pub fn new(capacity: usize, max_bytes: usize) -> Self {
+        Self {
+            capacity,
+            max_bytes,
+            bytes: 0,
+            frames: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// Adds `frame` as the newest frame, evicting the oldest ones over the limits.
+    pub fn push(&mut self, frame: RgbImage) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.bytes += frame.as_raw().len();
+        self.frames.push_back(frame);
+        while self.frames.len() > self.capacity
+            || (self.bytes > self.max_bytes && self.frames.len() > 1)
+        {
+            let evicted = self.frames.pop_front().unwrap();
+            self.bytes -= evicted.as_raw().len();
+        }
+    }
+
+    /// Frames from oldest to newest.
+    pub fn frames(&self) -> impl Iterator<Item = &RgbImage> {
+        self.frames.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Bytes of pixel data held.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+}
+
+/// Remembers a hash of the last frame published to each topic, to skip publishing the
+/// same frame again, see `SKIP_DUPLICATES`.
+///
+/// Frames are tagged with the connection they're sent on, and everything is forgotten
+/// when it changes, so the frames are refreshed after reconnecting. With a keyframe
+/// interval, unchanged frames are let through again once it elapsed since their topic
+/// was last published to, see `KEYFRAME_INTERVAL_SECS`.
+#[derive(Debug, Default)]
+pub struct DuplicateFilter {
+    connection: u64,
+    keyframe_interval: Option<Duration>,
+    // Hash of the last frame published to each topic, and when it was published.
+    hashes: HashMap<String, (u64, Instant)>,
+}
+
+impl DuplicateFilter {
+    pub fn new(keyframe_interval: Option<Duration>) -> Self {
+        Self {
+            keyframe_interval,
+            ..Default::default()
+        }
+    }
+
+    /// Returns whether `buf` is the last frame published to `topic` on `connection`. Frames
+    /// only count as published once `record` is called, after publishing them worked.
+    pub fn is_duplicate(
+        &mut self,
+        topic: &str,
+        buf: &[u8],
+        connection: u64,
+        clock: &dyn Clock,
+    ) -> bool {
+        self.follow(connection);
+        let Some(&(last, published_at)) = self.hashes.get(topic) else {
+            return false;
+        };
+        let keyframe_due = self
+            .keyframe_interval
+            .is_some_and(|interval| clock.now().duration_since(published_at) >= interval);
+        last == frame_hash(buf) && !keyframe_due
+    }
+
+    /// Records `buf` as the last frame published to `topic` on `connection`, now.
+    pub fn record(&mut self, topic: &str, buf: &[u8], connection: u64, clock: &dyn Clock) {
+        self.follow(connection);
+        self.hashes
+            .insert(topic.to_string(), (frame_hash(buf), clock.now()));
+    }
+
+    // Forgets the frames published on a previous connection.
+    fn follow(&mut self, connection: u64) {
+        if connection != self.connection {
+            self.connection = connection;
+            self.hashes.clear();
+        }
+    }
+
+    /// Forgets the frames published so far, eg: after the panels were blanked.
+    pub fn clear(&mut self) {
+        self.hashes.clear();
+    }
+}
+
+fn frame_hash(buf: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    buf.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Remembers the last command received from each source, to skip the ones that repeat
+/// it, see `SKIP_REPEATED_EVENTS`. Firebase sends the current record again whenever the
+/// stream reconnects, and rendering it again makes the panels flicker.
+///
+/// Like `DuplicateFilter`, everything is forgotten when the broker connection changes,
+/// so the panels are refreshed after reconnecting.
+#[derive(Debug, Default)]
+pub struct RepeatFilter {
+    connection: u64,
+    last: HashMap<String, PayloadData>,
+}
+
+impl RepeatFilter {
+    /// Returns whether `payload` is the last command received from `source` on
+    /// `connection`, and records it as the last one otherwise.
+    pub fn is_repeat(&mut self, source: &str, payload: &PayloadData, connection: u64) -> bool {
+        if connection != self.connection {
+            self.connection = connection;
+            self.last.clear();
+        }
+        if self.last.get(source) == Some(payload) {
+            return true;
+        }
+        self.last.insert(source.to_string(), payload.clone());
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgb, RgbImage};
+
+    use std::time::Duration;
+
+    use chrono::NaiveTime;
+
+    use super::{DuplicateFilter, FrameHistory, RepeatFilter};
+    use crate::clock::FakeClock;
+    use crate::payload::PayloadFormat;
+
+    fn frame(value: u8) -> RgbImage {
+        RgbImage::from_pixel(2, 2, Rgb([value; 3]))
+    }
+
+    fn values(history: &FrameHistory) -> Vec<u8> {
+        history.frames().map(|frame| frame.as_raw()[0]).collect()
+    }
+
+    #[test]
+    fn evicts_oldest_frames_over_capacity() {
+        let mut history = FrameHistory::new(3, usize::MAX);
+        for value in 1..=5 {
+            history.push(frame(value));
+        }
+        assert_eq!(values(&history), vec![3, 4, 5]);
+        assert_eq!(history.bytes(), 3 * 12);
+    }
+
+    #[test]
+    fn evicts_oldest_frames_over_byte_budget() {
+        // Each 2x2 frame is 12 bytes, so only two fit.
+        let mut history = FrameHistory::new(10, 30);
+        for value in 1..=4 {
+            history.push(frame(value));
+        }
+        assert_eq!(values(&history), vec![3, 4]);
+        assert_eq!(history.bytes(), 24);
+
+        // A frame over the budget on its own replaces everything else.
+        history.push(RgbImage::new(4, 4));
+        assert_eq!(history.len(), 1);
+    }
+
+    #[test]
+    fn skips_repeated_events() {
+        let event = r#"{"path":"/","data":{"emoji":"👍"}}"#;
+        let mut filter = RepeatFilter::default();
+        let mut rendered = 0;
+        for _ in 0..2 {
+            let payload = PayloadFormat::Firebase.parse(event).unwrap();
+            if !filter.is_repeat("default", &payload, 1) {
+                rendered += 1;
+            }
+        }
+        assert_eq!(rendered, 1);
+
+        let payload = PayloadFormat::Firebase.parse(event).unwrap();
+        assert!(!filter.is_repeat("other", &payload, 1));
+
+        // Reconnecting to the broker forces a refresh.
+        assert!(!filter.is_repeat("default", &payload, 2));
+    }
+
+    #[test]
+    fn detects_repeated_frames_per_topic() {
+        let clock = FakeClock::new(NaiveTime::MIN);
+        let mut filter = DuplicateFilter::default();
+        assert!(!filter.is_duplicate("ledmoji/32x32", &[1, 2, 3], 1, &clock));
+        filter.record("ledmoji/32x32", &[1, 2, 3], 1, &clock);
+        assert!(filter.is_duplicate("ledmoji/32x32", &[1, 2, 3], 1, &clock));
+        assert!(!filter.is_duplicate("ledmoji/128x128", &[1, 2, 3], 1, &clock));
+        assert!(!filter.is_duplicate("ledmoji/32x32", &[3, 2, 1], 1, &clock));
+        filter.record("ledmoji/32x32", &[3, 2, 1], 1, &clock);
+
+        // Reconnecting forces a refresh.
+        assert!(!filter.is_duplicate("ledmoji/32x32", &[3, 2, 1], 2, &clock));
+
+        filter.record("ledmoji/32x32", &[3, 2, 1], 2, &clock);
+        filter.clear();
+        assert!(!filter.is_duplicate("ledmoji/32x32", &[3, 2, 1], 2, &clock));
+    }
+
+    #[test]
+    fn keeps_frames_that_were_not_published() {
+        let clock = FakeClock::new(NaiveTime::MIN);
+        let mut filter = DuplicateFilter::default();
+        // Throttled or failed, so never recorded.
+        assert!(!filter.is_duplicate("ledmoji/32x32", &[1, 2, 3], 1, &clock));
+        assert!(!filter.is_duplicate("ledmoji/32x32", &[1, 2, 3], 1, &clock));
+    }
+
+    #[test]
+    fn lets_keyframes_through_after_interval() {
+        let clock = FakeClock::new(NaiveTime::MIN);
+        let mut filter = DuplicateFilter::new(Some(Duration::from_secs(30)));
+        filter.record("ledmoji/32x32", &[1, 2, 3], 1, &clock);
+
+        clock.advance(Duration::from_secs(29));
+        assert!(filter.is_duplicate("ledmoji/32x32", &[1, 2, 3], 1, &clock));
+
+        // The keyframe restarts the interval.
+        clock.advance(Duration::from_secs(1));
+        assert!(!filter.is_duplicate("ledmoji/32x32", &[1, 2, 3], 1, &clock));
+        filter.record("ledmoji/32x32", &[1, 2, 3], 1, &clock);
+        assert!(filter.is_duplicate("ledmoji/32x32", &[1, 2, 3], 1, &clock));
+    }
+
+    #[test]
+    fn keeps_nothing_when_disabled() {
+        let mut history = FrameHistory::new(0, usize::MAX);
+        history.push(frame(1));
+        assert!(history.is_empty());
+    }
+}
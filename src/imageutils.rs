@@ -0,0 +1,2561 @@
+// Copyright 2023 André Cipriani Bandarra
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt::Write,
+    io::{Cursor, Write as _},
+    path::Path,
+    str::FromStr,
+    sync::Arc,
+};
+
+use flate2::{
+    write::{GzEncoder, ZlibEncoder},
+    Compression as Level,
+};
+use image::{DynamicImage, ImageError, ImageOutputFormat, Rgb, RgbImage, Rgba, RgbaImage};
+use serde::Deserialize;
+
+use crate::{
+    cache::EmojiCache,
+    chipset::apply_gamma,
+    config::{Config, BYTES_PER_PIXEL},
+    emoji::{check_emoji_length, load_emoji_image, open_image},
+    error::DaemonError,
+    font::EmojiFont,
+    render::{render_frame, ResizeMode, ScaleFilter, BACKGROUND},
+};
+
+/// Color space transparent pixels are blended with the background in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BlendSpace {
+    /// The sRGB values are mixed directly, see `merge_colors`.
+    #[default]
+    Srgb,
+    /// The colors are mixed in linear light, see `merge_colors_linear`.
+    Linear,
+}
+
+pub fn merge_colors(foreground: &Rgba<u8>, background: &Rgb<u8>) -> Vec<u8> {
+    blend_pixel(foreground, background).to_vec()
+}
+
+/// Blends `foreground` onto `background` in linear light, as premultiplied colors.
+///
+/// Mixing the sRGB values like `merge_colors` does darkens partly transparent pixels,
+/// which shows as dark fringes around the anti-aliased edges of emoji over light
+/// backgrounds, eg: 50% white over black is 128 rather than the 188 it looks like.
+pub fn merge_colors_linear(foreground: &Rgba<u8>, background: &Rgb<u8>) -> Vec<u8> {
+    blend_pixel_linear(foreground, background).to_vec()
+}
+
+/// Blends the RGBA bytes of an image onto `background` in `space`, returning the RGB
+/// bytes of the frame. Unlike `merge_colors`, nothing is allocated per pixel.
+pub fn blend_onto(rgba: &[u8], background: &Rgb<u8>, space: BlendSpace) -> Vec<u8> {
+    let blend = match space {
+        BlendSpace::Srgb => blend_pixel,
+        BlendSpace::Linear => blend_pixel_linear,
+    };
+    let mut rgb = Vec::with_capacity(rgba.len() / 4 * 3);
+    for pixel in rgba.chunks_exact(4) {
+        let pixel = Rgba([pixel[0], pixel[1], pixel[2], pixel[3]]);
+        rgb.extend_from_slice(&blend(&pixel, background));
+    }
+    rgb
+}
+
+fn blend_pixel_linear(foreground: &Rgba<u8>, background: &Rgb<u8>) -> [u8; 3] {
+    match foreground.0[3] {
+        255 => return [foreground.0[0], foreground.0[1], foreground.0[2]],
+        0 => return background.0,
+        _ => {}
+    }
+    let alpha = foreground.0[3] as f32 / 255.0;
+    let mut color = [0; 3];
+    for (channel, (fg, bg)) in color
+        .iter_mut()
+        .zip(foreground.0.into_iter().zip(background.0))
+    {
+        let premultiplied = srgb_to_linear(fg) * alpha;
+        *channel = linear_to_srgb(premultiplied + srgb_to_linear(bg) * (1.0 - alpha));
+    }
+    color
+}
+
+fn blend_pixel(foreground: &Rgba<u8>, background: &Rgb<u8>) -> [u8; 3] {
+    // Foreground is opaque, just return the color.
+    if foreground.0[3] == 255 {
+        return [foreground.0[0], foreground.0[1], foreground.0[2]];
+    }
+
+    // Convert the factor from u8 to f32, so that 0 is 0.0 and 255 is 1.0.
+    let factor = foreground.0[3] as f32 / 255.0;
+
+    // Function for mixing foreground and background colors.
+    let map_channel =
+        |fg_color: f32, bg_color: f32| (bg_color * (1.0 - factor)) + fg_color * factor;
+
+    // Zip over fb and bg colors, converting to the output color.
+    let mut color = [0; 3];
+    for (channel, (fg, bg)) in color
+        .iter_mut()
+        .zip(foreground.0.into_iter().zip(background.0))
+    {
+        let mixed = map_channel(fg as f32 / 255.0, bg as f32 / 255.0);
+        *channel = (mixed * 255.0).round() as u8;
+    }
+    color
+}
+
+/// Corner of the panel where the first LED of the strip is located.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Origin {
+    #[default]
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+impl FromStr for Origin {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "tl" | "top_left" => Ok(Origin::TopLeft),
+            "tr" | "top_right" => Ok(Origin::TopRight),
+            "bl" | "bottom_left" => Ok(Origin::BottomLeft),
+            "br" | "bottom_right" => Ok(Origin::BottomRight),
+            _ => Err(format!("Invalid matrix origin: {}", s)),
+        }
+    }
+}
+
+/// Direction the strip runs in before wrapping to the next line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Axis {
+    #[default]
+    Row,
+    Column,
+}
+
+impl FromStr for Axis {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "row" | "rows" => Ok(Axis::Row),
+            "column" | "columns" | "col" => Ok(Axis::Column),
+            _ => Err(format!("Invalid matrix axis: {}", s)),
+        }
+    }
+}
+
+/// Describes how the LEDs of a matrix panel are wired.
+///
+/// The default layout (top-left origin, row major, no serpentine) matches the
+/// order of the pixels in an image buffer, so remapping is a no-op.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MatrixLayout {
+    pub origin: Origin,
+    pub axis: Axis,
+    /// When set, every other line runs in the opposite direction.
+    pub serpentine: bool,
+}
+
+/// Reorders an RGB buffer so that pixels are in the order the LEDs are wired.
+///
+/// The n-th pixel of the returned buffer is the color for the n-th LED on the strip.
+pub fn remap(buf: &[u8], width: u32, height: u32, layout: MatrixLayout) -> Vec<u8> {
+    let (width, height) = (width as usize, height as usize);
+    let line_length = match layout.axis {
+        Axis::Row => width,
+        Axis::Column => height,
+    };
+
+    let mut out = Vec::with_capacity(buf.len());
+    for led in 0..width * height {
+        let line = led / line_length;
+        let mut position = led % line_length;
+        if layout.serpentine && line % 2 == 1 {
+            position = line_length - 1 - position;
+        }
+
+        let (mut x, mut y) = match layout.axis {
+            Axis::Row => (position, line),
+            Axis::Column => (line, position),
+        };
+
+        if matches!(layout.origin, Origin::TopRight | Origin::BottomRight) {
+            x = width - 1 - x;
+        }
+        if matches!(layout.origin, Origin::BottomLeft | Origin::BottomRight) {
+            y = height - 1 - y;
+        }
+
+        let index = (y * width + x) * 3;
+        out.extend_from_slice(&buf[index..index + 3]);
+    }
+    out
+}
+
+/// Shape of the visible area of the panel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PanelShape {
+    #[default]
+    Rectangle,
+    Circle,
+}
+
+impl FromStr for PanelShape {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "rectangle" => Ok(PanelShape::Rectangle),
+            "circle" => Ok(PanelShape::Circle),
+            _ => Err(format!("Invalid panel shape: {}", s)),
+        }
+    }
+}
+
+/// Sets the pixels of an RGB buffer outside the circle inscribed in it to `background`,
+/// for round panels.
+pub fn apply_circular_mask(buf: &mut [u8], width: u32, height: u32, background: Rgb<u8>) {
+    let radius = width.min(height) as f32 / 2.0;
+    let (center_x, center_y) = (width as f32 / 2.0, height as f32 / 2.0);
+    for (i, pixel) in buf.chunks_exact_mut(3).enumerate() {
+        let x = (i as u32 % width) as f32 + 0.5 - center_x;
+        let y = (i as u32 / width) as f32 + 0.5 - center_y;
+        if x * x + y * y > radius * radius {
+            pixel.copy_from_slice(&background.0);
+        }
+    }
+}
+
+/// Paints the outer `thickness` pixels of an RGB buffer with `color`, for a frame around
+/// the panel contents.
+pub fn draw_border(buf: &mut [u8], width: u32, height: u32, color: Rgb<u8>, thickness: u32) {
+    for (i, pixel) in buf.chunks_exact_mut(3).enumerate() {
+        let (x, y) = (i as u32 % width, i as u32 / width);
+        let inside =
+            x >= thickness && y >= thickness && x + thickness < width && y + thickness < height;
+        if !inside {
+            pixel.copy_from_slice(&color.0);
+        }
+    }
+}
+
+// Shapes are drawn with pixel centers at whole coordinates: (0.0, 0.0) is the center of
+// the top-left pixel, and x grows to the right and y downwards. Anti-aliased edges blend
+// the color into the buffer by how much of each pixel the shape covers, otherwise pixels
+// whose center is inside the shape are painted. Parts outside of the buffer are clipped.
+
+/// Draws a filled circle of `radius` pixels around `center` into an RGB buffer, eg: a
+/// status dot.
+pub fn draw_circle(
+    buf: &mut [u8],
+    width: u32,
+    height: u32,
+    center: (f32, f32),
+    radius: f32,
+    color: Rgb<u8>,
+    antialias: bool,
+) {
+    let bounds = (center, center, radius);
+    draw_shape(buf, width, height, bounds, color, antialias, |x, y| {
+        radius - distance((x, y), center)
+    });
+}
+
+/// Draws a ring `thickness` pixels wide around `center` into an RGB buffer, centered on
+/// the circle of `radius` pixels.
+#[allow(clippy::too_many_arguments)]
+pub fn draw_ring(
+    buf: &mut [u8],
+    width: u32,
+    height: u32,
+    center: (f32, f32),
+    radius: f32,
+    thickness: f32,
+    color: Rgb<u8>,
+    antialias: bool,
+) {
+    let bounds = (center, center, radius + thickness / 2.0);
+    draw_shape(buf, width, height, bounds, color, antialias, |x, y| {
+        thickness / 2.0 - (distance((x, y), center) - radius).abs()
+    });
+}
+
+/// Draws a 1 pixel wide line from `from` to `to` into an RGB buffer.
+pub fn draw_line(
+    buf: &mut [u8],
+    width: u32,
+    height: u32,
+    from: (f32, f32),
+    to: (f32, f32),
+    color: Rgb<u8>,
+    antialias: bool,
+) {
+    let (dx, dy) = (to.0 - from.0, to.1 - from.1);
+    let length_squared = dx * dx + dy * dy;
+    draw_shape(
+        buf,
+        width,
+        height,
+        (from, to, 0.5),
+        color,
+        antialias,
+        |x, y| {
+            // Distance to the closest point of the segment.
+            let t = match length_squared {
+                0.0 => 0.0,
+                _ => (((x - from.0) * dx + (y - from.1) * dy) / length_squared).clamp(0.0, 1.0),
+            };
+            0.5 - distance((x, y), (from.0 + t * dx, from.1 + t * dy))
+        },
+    );
+}
+
+fn distance(a: (f32, f32), b: (f32, f32)) -> f32 {
+    ((a.0 - b.0).powi(2) + (a.1 - b.1).powi(2)).sqrt()
+}
+
+// Paints the pixels of the box spanning `from` to `to`, grown by `margin`, by how far
+// their center is inside the shape, in pixels, as returned by `inside`.
+fn draw_shape(
+    buf: &mut [u8],
+    width: u32,
+    height: u32,
+    (from, to, margin): ((f32, f32), (f32, f32), f32),
+    color: Rgb<u8>,
+    antialias: bool,
+    inside: impl Fn(f32, f32) -> f32,
+) {
+    // Anti-aliased edges reach half a pixel further out.
+    let margin = margin + 1.0;
+    let clip = |start: f32, end: f32, size: u32| {
+        let low = (start.min(end) - margin).floor().max(0.0) as u32;
+        let high = ((start.max(end) + margin).ceil().max(0.0) as u32).min(size);
+        low..high
+    };
+    for y in clip(from.1, to.1, height) {
+        for x in clip(from.0, to.0, width) {
+            let distance = inside(x as f32, y as f32);
+            let coverage = match antialias {
+                true => (distance + 0.5).clamp(0.0, 1.0),
+                false if distance >= 0.0 => 1.0,
+                false => 0.0,
+            };
+            if coverage == 0.0 {
+                continue;
+            }
+            let index = ((y * width + x) * 3) as usize;
+            for (value, &target) in buf[index..index + 3].iter_mut().zip(&color.0) {
+                *value =
+                    (*value as f32 * (1.0 - coverage) + target as f32 * coverage).round() as u8;
+            }
+        }
+    }
+}
+
+/// Colors of the frame counter pixels, from the lowest bit.
+const FRAME_COUNTER_COLORS: [Rgb<u8>; 3] = [Rgb([255, 0, 0]), Rgb([0, 255, 0]), Rgb([0, 0, 255])];
+
+/// Draws the lowest 3 bits of `counter` as the first 3 pixels of the top row of an RGB
+/// buffer, lit in red, green and blue when set and black otherwise, to check a panel is
+/// keeping up with the published frames. The first pixel blinks every frame.
+pub fn draw_frame_counter(buf: &mut [u8], width: u32, counter: u32) {
+    for (bit, (color, pixel)) in FRAME_COUNTER_COLORS
+        .iter()
+        .zip(buf.chunks_exact_mut(3))
+        .take(width as usize)
+        .enumerate()
+    {
+        let lit = counter & (1 << bit) != 0;
+        pixel.copy_from_slice(if lit { &color.0 } else { &[0, 0, 0] });
+    }
+}
+
+/// Parses a color written as hex, like `ff8000` or `#ff8000`, or in the short form with
+/// one digit per channel, like `#f80`.
+pub fn parse_color(color: &str) -> Option<Rgb<u8>> {
+    let hex = color.strip_prefix('#').unwrap_or(color);
+    if !hex.is_ascii() {
+        return None;
+    }
+    let digits = match hex.len() {
+        3 => 1,
+        6 => 2,
+        _ => return None,
+    };
+    let channel = |i: usize| {
+        let value = u8::from_str_radix(&hex[i * digits..(i + 1) * digits], 16).ok()?;
+        // A single digit is repeated, so `f` is `ff`.
+        Some(if digits == 1 { value * 17 } else { value })
+    };
+    Some(Rgb([channel(0)?, channel(1)?, channel(2)?]))
+}
+
+/// Interpolates between two buffers of the same size, where `t` of 0.0 returns `from`
+/// and 1.0 returns `to`.
+pub fn crossfade(from: &[u8], to: &[u8], t: f32) -> Vec<u8> {
+    let t = t.clamp(0.0, 1.0);
+    from.iter()
+        .zip(to)
+        .map(|(&from, &to)| (from as f32 * (1.0 - t) + to as f32 * t).round() as u8)
+        .collect()
+}
+
+/// Directional transition from the previous emoji to the next one, see
+/// `transition_frames`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Transition {
+    /// Cut directly to the new emoji, without a crossfade either.
+    None,
+    /// The new emoji pushes the old one out to the left.
+    SlideLeft,
+    /// The new emoji pushes the old one out to the top.
+    SlideUp,
+    /// The new emoji is revealed from left to right over the old one.
+    Wipe,
+}
+
+/// Generates the intermediate frames of a `kind` transition from `from` to `to` in
+/// `steps` steps, that is the frames of steps 1 to `steps - 1`, as `from` and `to` are
+/// the panel contents before and after the transition.
+///
+/// Returns no frames for `Transition::None` or when the sizes don't match.
+pub fn transition_frames(
+    from: &RgbImage,
+    to: &RgbImage,
+    kind: Transition,
+    steps: u32,
+) -> Vec<RgbImage> {
+    if kind == Transition::None || from.dimensions() != to.dimensions() {
+        return Vec::new();
+    }
+
+    let (width, height) = to.dimensions();
+    (1..steps)
+        .map(|step| {
+            let t = step as f32 / steps as f32;
+            RgbImage::from_fn(width, height, |x, y| match kind {
+                Transition::SlideLeft => {
+                    let offset = (width as f32 * t).round() as u32;
+                    if x + offset < width {
+                        *from.get_pixel(x + offset, y)
+                    } else {
+                        *to.get_pixel(x + offset - width, y)
+                    }
+                }
+                Transition::SlideUp => {
+                    let offset = (height as f32 * t).round() as u32;
+                    if y + offset < height {
+                        *from.get_pixel(x, y + offset)
+                    } else {
+                        *to.get_pixel(x, y + offset - height)
+                    }
+                }
+                Transition::Wipe => {
+                    if x < (width as f32 * t).round() as u32 {
+                        *to.get_pixel(x, y)
+                    } else {
+                        *from.get_pixel(x, y)
+                    }
+                }
+                Transition::None => unreachable!(),
+            })
+        })
+        .collect()
+}
+
+/// Scales `img` to `width`x`height`, averaging the source pixels each output pixel covers,
+/// weighted by how much of them it covers.
+///
+/// Colors are averaged in linear light and weighted by their alpha, so a checkerboard of
+/// black and white turns into the gray it looks like from afar (188) rather than the
+/// darker arithmetic mean (128), and transparent pixels don't darken their neighbors.
+pub fn downscale_area_linear(img: &DynamicImage, width: u32, height: u32) -> RgbaImage {
+    let src = img.to_rgba8();
+    let x_scale = src.width() as f32 / width as f32;
+    let y_scale = src.height() as f32 / height as f32;
+    let to_linear: Vec<f32> = (0..=255u8).map(srgb_to_linear).collect();
+    RgbaImage::from_fn(width, height, |x, y| {
+        let mut color = [0.0; 3];
+        let mut alpha = 0.0;
+        let mut area = 0.0;
+        for (source_y, y_weight) in coverage(y, y_scale, src.height()) {
+            for (source_x, x_weight) in coverage(x, x_scale, src.width()) {
+                let pixel = src.get_pixel(source_x, source_y);
+                let weight = x_weight * y_weight;
+                let opacity = pixel[3] as f32 / 255.0 * weight;
+                for (sum, value) in color.iter_mut().zip(pixel.0) {
+                    *sum += to_linear[value as usize] * opacity;
+                }
+                alpha += opacity;
+                area += weight;
+            }
+        }
+        if alpha == 0.0 {
+            return Rgba([0, 0, 0, 0]);
+        }
+        let [r, g, b] = color.map(|sum| linear_to_srgb(sum / alpha));
+        Rgba([r, g, b, (alpha / area * 255.0).round() as u8])
+    })
+}
+
+// Source pixels along one axis covered by output pixel `index`, with the fraction of
+// each that is covered.
+fn coverage(index: u32, scale: f32, len: u32) -> impl Iterator<Item = (u32, f32)> {
+    let start = index as f32 * scale;
+    let end = start + scale;
+    (start.floor() as u32..(end.ceil() as u32).min(len)).map(move |source| {
+        let covered = end.min(source as f32 + 1.0) - start.max(source as f32);
+        (source, covered)
+    })
+}
+
+fn srgb_to_linear(value: u8) -> f32 {
+    let value = value as f32 / 255.0;
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(value: f32) -> u8 {
+    let value = if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    };
+    (value * 255.0).round().clamp(0.0, 255.0) as u8
+}
+
+/// Doubles the size of `img` with the Scale2x algorithm, which rounds off the staircase
+/// edges of pixel art instead of turning every pixel into a bigger block.
+///
+/// Every pixel becomes 2x2, taking the color of a neighbor where two neighbors meeting
+/// at that corner match, eg: a 1 pixel diagonal line stays 1 output pixel thin.
+pub fn scale2x(img: &DynamicImage) -> DynamicImage {
+    let src = img.to_rgba8();
+    let neighbor = |x: u32, y: u32, dx: i64, dy: i64| neighbor_pixel(&src, x, y, dx, dy);
+    let output = RgbaImage::from_fn(src.width() * 2, src.height() * 2, |x, y| {
+        let (source_x, source_y) = (x / 2, y / 2);
+        let e = neighbor(source_x, source_y, 0, 0);
+        let b = neighbor(source_x, source_y, 0, -1);
+        let d = neighbor(source_x, source_y, -1, 0);
+        let f = neighbor(source_x, source_y, 1, 0);
+        let h = neighbor(source_x, source_y, 0, 1);
+        if b == h || d == f {
+            return e;
+        }
+        match (x % 2, y % 2) {
+            (0, 0) if d == b => d,
+            (1, 0) if b == f => f,
+            (0, 1) if d == h => d,
+            (1, 1) if h == f => f,
+            _ => e,
+        }
+    });
+    DynamicImage::ImageRgba8(output)
+}
+
+/// Triples the size of `img` with the Scale3x algorithm, the 3x3 counterpart of
+/// `scale2x`.
+pub fn scale3x(img: &DynamicImage) -> DynamicImage {
+    let src = img.to_rgba8();
+    let neighbor = |x: u32, y: u32, dx: i64, dy: i64| neighbor_pixel(&src, x, y, dx, dy);
+    let output = RgbaImage::from_fn(src.width() * 3, src.height() * 3, |x, y| {
+        let (source_x, source_y) = (x / 3, y / 3);
+        let [a, b, c, d, e, f, g, h, i] = [
+            (-1, -1),
+            (0, -1),
+            (1, -1),
+            (-1, 0),
+            (0, 0),
+            (1, 0),
+            (-1, 1),
+            (0, 1),
+            (1, 1),
+        ]
+        .map(|(dx, dy)| neighbor(source_x, source_y, dx, dy));
+        if b == h || d == f {
+            return e;
+        }
+        match (x % 3, y % 3) {
+            (0, 0) if d == b => d,
+            (1, 0) if (d == b && e != c) || (b == f && e != a) => b,
+            (2, 0) if b == f => f,
+            (0, 1) if (d == b && e != g) || (d == h && e != a) => d,
+            (2, 1) if (b == f && e != i) || (h == f && e != c) => f,
+            (0, 2) if d == h => d,
+            (1, 2) if (d == h && e != i) || (h == f && e != g) => h,
+            (2, 2) if h == f => f,
+            _ => e,
+        }
+    });
+    DynamicImage::ImageRgba8(output)
+}
+
+// Pixel `dx`,`dy` away from `x`,`y`, repeating the edge pixels past the borders.
+fn neighbor_pixel(img: &RgbaImage, x: u32, y: u32, dx: i64, dy: i64) -> Rgba<u8> {
+    let x = (x as i64 + dx).clamp(0, img.width() as i64 - 1) as u32;
+    let y = (y as i64 + dy).clamp(0, img.height() as i64 - 1) as u32;
+    *img.get_pixel(x, y)
+}
+
+/// Per-channel lookup tables, mapping each red, green and blue value to its corrected value.
+pub type Lut = [[u8; 256]; 3];
+
+/// Replaces every channel value in an RGB buffer with its entry in the channel's table.
+pub fn apply_lut(buf: &mut [u8], lut: &Lut) {
+    for pixel in buf.chunks_exact_mut(3) {
+        for (channel, value) in pixel.iter_mut().enumerate() {
+            *value = lut[channel][*value as usize];
+        }
+    }
+}
+
+/// Raises every channel value in an RGB buffer below `floor` to `floor`.
+pub fn clamp_min_brightness(buf: &mut [u8], floor: u8) {
+    for value in buf.iter_mut() {
+        *value = (*value).max(floor);
+    }
+}
+
+/// Lowers every red, green and blue value in an RGB buffer above its cap in `max` to the
+/// cap, eg: `[255, 255, 200]` for a strip with bright blue LEDs.
+pub fn clamp_channels_max(buf: &mut [u8], max: [u8; 3]) {
+    for pixel in buf.chunks_exact_mut(3) {
+        for (value, max) in pixel.iter_mut().zip(max) {
+            *value = (*value).min(max);
+        }
+    }
+}
+
+/// Scales every channel value in an RGB buffer by `factor`, eg: 0.5 for half brightness.
+pub fn scale_brightness(buf: &mut [u8], factor: f32) {
+    for value in buf.iter_mut() {
+        *value = (*value as f32 * factor).round() as u8;
+    }
+}
+
+/// Rotates the hue of every pixel of an RGB buffer by `degrees` around the HSV color
+/// wheel, keeping saturation and value, eg: 120 turns red into green. Grays are left
+/// as they are.
+pub fn shift_hue(buf: &mut [u8], degrees: f32) {
+    for pixel in buf.chunks_exact_mut(3) {
+        let [r, g, b] = [pixel[0], pixel[1], pixel[2]].map(|c| c as f32 / 255.0);
+        let max = r.max(g).max(b);
+        let chroma = max - r.min(g).min(b);
+        if chroma == 0.0 {
+            continue;
+        }
+        let hue = if max == r {
+            (g - b) / chroma
+        } else if max == g {
+            (b - r) / chroma + 2.0
+        } else {
+            (r - g) / chroma + 4.0
+        };
+        let hue = (hue * 60.0 + degrees).rem_euclid(360.0) / 60.0;
+        let x = chroma * (1.0 - (hue % 2.0 - 1.0).abs());
+        let (r, g, b) = match hue as u32 {
+            0 => (chroma, x, 0.0),
+            1 => (x, chroma, 0.0),
+            2 => (0.0, chroma, x),
+            3 => (0.0, x, chroma),
+            4 => (x, 0.0, chroma),
+            _ => (chroma, 0.0, x),
+        };
+        let min = max - chroma;
+        for (value, channel) in pixel.iter_mut().zip([r, g, b]) {
+            *value = ((channel + min) * 255.0).round() as u8;
+        }
+    }
+}
+
+/// Radius of the blur the unsharp mask subtracts, see `SHARPEN_AMOUNT`.
+pub const SHARPEN_SIGMA: f32 = 1.0;
+
+/// Sharpens `img` with an unsharp mask: each color channel moves away from a gaussian
+/// blur of radius `sigma` by `amount` times their difference, so edges gain contrast
+/// while flat areas are left as they are. Alpha is not changed.
+pub fn unsharp_mask(img: &mut DynamicImage, sigma: f32, amount: f32) {
+    let blurred = img.blur(sigma).to_rgba8();
+    let mut sharpened = img.to_rgba8();
+    for (pixel, blurred) in sharpened.pixels_mut().zip(blurred.pixels()) {
+        for channel in 0..3 {
+            let value = pixel[channel] as f32;
+            let sharpened = value + amount * (value - blurred[channel] as f32);
+            pixel[channel] = sharpened.round().clamp(0.0, 255.0) as u8;
+        }
+    }
+
+    let sharpened = DynamicImage::ImageRgba8(sharpened);
+    *img = match img {
+        DynamicImage::ImageRgb8(_) => DynamicImage::ImageRgb8(sharpened.into_rgb8()),
+        _ => sharpened,
+    };
+}
+
+/// Tone mapping operator applied to frames before the color corrections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ToneMap {
+    Reinhard,
+}
+
+impl FromStr for ToneMap {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "reinhard" => Ok(ToneMap::Reinhard),
+            _ => Err(format!("Invalid tone map: {}", s)),
+        }
+    }
+}
+
+/// Compresses the highlights of an RGB buffer with the Reinhard operator, L / (1 + L).
+///
+/// The operator is applied to the luminance and every channel of the pixel is scaled by
+/// the same factor, so hues are kept. Dark pixels are nearly unchanged, while the
+/// brightest are halved.
+pub fn tone_map_reinhard(buf: &mut [u8]) {
+    for pixel in buf.chunks_exact_mut(3) {
+        let [r, g, b] = [pixel[0], pixel[1], pixel[2]].map(|c| c as f32 / 255.0);
+        let luminance = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+        if luminance == 0.0 {
+            continue;
+        }
+        let scale = 1.0 / (1.0 + luminance);
+        for value in pixel.iter_mut() {
+            *value = (*value as f32 * scale).round() as u8;
+        }
+    }
+}
+
+/// Dithering applied to frames reduced to fewer levels per channel, see `DITHER`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dither {
+    /// A 4x4 Bayer matrix, so a pixel is dithered the same way in every frame and
+    /// animations don't shimmer.
+    Ordered,
+}
+
+impl FromStr for Dither {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "ordered" => Ok(Dither::Ordered),
+            _ => Err(format!("Invalid dither: {}", s)),
+        }
+    }
+}
+
+/// Thresholds of the 4x4 Bayer matrix, in sixteenths.
+const BAYER_4X4: [[u8; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+
+/// Reduces every channel of an RGB buffer to `levels` evenly spaced values (at least 2),
+/// with ordered dithering.
+///
+/// A value between two levels is rounded up where its position between them is past
+/// the pixel's threshold in the Bayer matrix, so flat areas become a fixed pattern of
+/// both levels that averages to the original value. The outcome only depends on the
+/// pixel's value and position.
+pub fn dither_ordered(buf: &mut [u8], width: u32, height: u32, levels: u8) {
+    let step = 255.0 / (levels.max(2) - 1) as f32;
+    for (index, pixel) in buf
+        .chunks_exact_mut(3)
+        .take(width as usize * height as usize)
+        .enumerate()
+    {
+        let (x, y) = (index % width as usize, index / width as usize);
+        let threshold = (BAYER_4X4[y % 4][x % 4] as f32 + 0.5) / 16.0;
+        for value in pixel.iter_mut() {
+            let scaled = *value as f32 / step;
+            let level = scaled.floor() + if scaled.fract() > threshold { 1.0 } else { 0.0 };
+            *value = (level * step).round().min(255.0) as u8;
+        }
+    }
+}
+
+/// Parses lookup tables from text with one line per channel, in red, green, blue order.
+///
+/// Each line holds exactly 256 values from 0 to 255, separated by whitespace or commas.
+/// Empty lines and lines starting with `#` are ignored.
+pub fn parse_lut(input: &str) -> Result<Lut, String> {
+    let tables = input
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .collect::<Vec<_>>();
+    if tables.len() != 3 {
+        return Err(format!("Expected 3 channel tables, found {}", tables.len()));
+    }
+
+    let mut lut = [[0; 256]; 3];
+    for (channel, line) in tables.into_iter().enumerate() {
+        let values = line
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|value| !value.is_empty())
+            .map(|value| value.parse::<u8>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("Invalid value in channel {} table: {}", channel, e))?;
+        lut[channel] = values.try_into().map_err(|values: Vec<u8>| {
+            format!(
+                "Expected 256 entries in channel {} table, found {}",
+                channel,
+                values.len()
+            )
+        })?;
+    }
+    Ok(lut)
+}
+
+pub fn load_lut(path: &Path) -> Result<Lut, Box<dyn Error>> {
+    Ok(parse_lut(&std::fs::read_to_string(path)?)?)
+}
+
+/// Parses per-emoji backgrounds, written as a JSON object mapping emoji to hex colors,
+/// eg: `{"🖤": "#ffffff"}`. Emoji are keyed without U+FE0F, see `background_key`.
+pub fn parse_backgrounds(input: &str) -> Result<HashMap<String, Rgb<u8>>, String> {
+    let colors = serde_json::from_str::<HashMap<String, String>>(input)
+        .map_err(|e| format!("Invalid backgrounds: {}", e))?;
+    colors
+        .into_iter()
+        .map(|(emoji, color)| match parse_color(&color) {
+            Some(color) => Ok((background_key(&emoji), color)),
+            None => Err(format!("Invalid background {:?} for {}", color, emoji)),
+        })
+        .collect()
+}
+
+pub fn load_backgrounds(path: &Path) -> Result<HashMap<String, Rgb<u8>>, Box<dyn Error>> {
+    Ok(parse_backgrounds(&std::fs::read_to_string(path)?)?)
+}
+
+// Key of `emoji` in `Config::backgrounds`, without the emoji presentation selector, so
+// `❤` and `❤️` share a background.
+fn background_key(emoji: &str) -> String {
+    emoji.chars().filter(|&c| c != '\u{fe0f}').collect()
+}
+
+// Background `emoji` is blended onto: the one from the message when set, otherwise the
+// one configured for the emoji in `BACKGROUNDS_FILE`, otherwise `BACKGROUND`.
+fn emoji_background(config: &Config, emoji: &str, background: Option<Rgb<u8>>) -> Rgb<u8> {
+    background
+        .or_else(|| config.backgrounds.get(&background_key(emoji)).copied())
+        .unwrap_or(BACKGROUND)
+}
+
+/// Largest difference from the background, in any channel, for a pixel to still count
+/// as background, see `shows_only_background`.
+pub const BLANK_TOLERANCE: u8 = 8;
+
+/// What happens when an emoji renders to a frame with nothing but background, see
+/// `BLANK_FRAME_POLICY`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BlankFramePolicy {
+    /// Logs a warning and shows the frame anyway.
+    #[default]
+    Warn,
+    /// Logs a warning and shows a placeholder instead.
+    Fallback,
+}
+
+impl FromStr for BlankFramePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "warn" => Ok(BlankFramePolicy::Warn),
+            "fallback" => Ok(BlankFramePolicy::Fallback),
+            _ => Err(format!("Invalid blank frame policy: {}", s)),
+        }
+    }
+}
+
+/// Whether every channel of `frame` is within `tolerance` of the same channel of
+/// `blank`. Both are RGB buffers of the same size.
+pub fn is_effectively_blank(frame: &[u8], blank: &[u8], tolerance: u8) -> bool {
+    frame.len() == blank.len()
+        && frame
+            .iter()
+            .zip(blank)
+            .all(|(value, blank)| value.abs_diff(*blank) <= tolerance)
+}
+
+/// Whether a corrected `width`x`height` frame rendered for `emoji` shows nothing but the
+/// background it was blended onto, eg: when the asset is fully transparent, or its
+/// details vanish at small sizes. `background` is the one the emoji was rendered with.
+pub fn shows_only_background(
+    config: &Config,
+    emoji: &str,
+    background: Option<Rgb<u8>>,
+    width: u32,
+    height: u32,
+    frame: &[u8],
+) -> bool {
+    let background = emoji_background(config, emoji, background);
+    let mut blank = RgbImage::from_pixel(width, height, background).into_raw();
+    apply_corrections(&mut blank, width, height, config);
+    is_effectively_blank(frame, &blank, BLANK_TOLERANCE)
+}
+
+pub const GLYPH_WIDTH: u32 = 3;
+pub const GLYPH_HEIGHT: u32 = 5;
+
+// Rows of a 3x5 bitmap font, top to bottom. The most significant of the 3 bits is
+// the leftmost pixel.
+fn glyph(c: char) -> [u8; GLYPH_HEIGHT as usize] {
+    match c {
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b111, 0b001, 0b111, 0b100, 0b111],
+        '3' => [0b111, 0b001, 0b111, 0b001, 0b111],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b111, 0b001, 0b111],
+        '6' => [0b111, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b001, 0b001, 0b001],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b111],
+        ':' => [0b000, 0b010, 0b000, 0b010, 0b000],
+        '-' => [0b000, 0b000, 0b111, 0b000, 0b000],
+        '.' => [0b000, 0b000, 0b000, 0b000, 0b010],
+        '?' => [0b111, 0b001, 0b010, 0b000, 0b010],
+        _ => [0; GLYPH_HEIGHT as usize],
+    }
+}
+
+/// Width in pixels of `text` drawn at `scale`, including a 1 pixel gap between glyphs.
+pub fn text_width(text: &str, scale: u32) -> u32 {
+    let glyphs = text.chars().count() as u32;
+    if glyphs == 0 {
+        return 0;
+    }
+    (glyphs * (GLYPH_WIDTH + 1) - 1) * scale
+}
+
+/// Draws `text` into an RGB buffer with its top-left corner at `x`, `y`.
+///
+/// Each font pixel is drawn as a `scale`x`scale` block. Pixels outside of the buffer
+/// are clipped. Only digits and `:`, `-`, `.` and `?` are supported, the latter for
+/// the placeholder of `BLANK_FRAME_POLICY`. Other characters are drawn as blanks.
+#[allow(clippy::too_many_arguments)]
+pub fn draw_text(
+    buf: &mut [u8],
+    width: u32,
+    height: u32,
+    x: u32,
+    y: u32,
+    text: &str,
+    color: Rgb<u8>,
+    scale: u32,
+) {
+    for (i, c) in text.chars().enumerate() {
+        let glyph_x = x + i as u32 * (GLYPH_WIDTH + 1) * scale;
+        for (row, bits) in glyph(c).into_iter().enumerate() {
+            for column in 0..GLYPH_WIDTH {
+                if bits & (1 << (GLYPH_WIDTH - 1 - column)) == 0 {
+                    continue;
+                }
+                for dy in 0..scale {
+                    for dx in 0..scale {
+                        let px = glyph_x + column * scale + dx;
+                        let py = y + row as u32 * scale + dy;
+                        if px >= width || py >= height {
+                            continue;
+                        }
+                        let index = ((py * width + px) * 3) as usize;
+                        buf[index..index + 3].copy_from_slice(&color.0);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Draws `text` in the bottom-right corner of an RGB buffer, over a `background` box with
+/// a 1 pixel margin so it stays legible over the emoji, eg: for the clock overlay.
+pub fn draw_corner_text(
+    buf: &mut [u8],
+    width: u32,
+    height: u32,
+    text: &str,
+    foreground: Rgb<u8>,
+    background: Rgb<u8>,
+) {
+    let left = width.saturating_sub(text_width(text, 1) + 2);
+    let top = height.saturating_sub(GLYPH_HEIGHT + 2);
+    for y in top..height {
+        for x in left..width {
+            let index = ((y * width + x) * 3) as usize;
+            buf[index..index + 3].copy_from_slice(&background.0);
+        }
+    }
+    draw_text(buf, width, height, left + 1, top + 1, text, foreground, 1);
+}
+
+/// Renders `text` centered on a `width`x`height` frame, at the largest scale that fits.
+pub fn render_text(
+    text: &str,
+    width: u32,
+    height: u32,
+    foreground: Rgb<u8>,
+    background: Rgb<u8>,
+) -> Vec<u8> {
+    let mut buf = background.0.repeat((width * height) as usize);
+    let unscaled_width = text_width(text, 1);
+    if unscaled_width == 0 {
+        return buf;
+    }
+
+    let scale = (width / unscaled_width).min(height / GLYPH_HEIGHT).max(1);
+    let x = width.saturating_sub(text_width(text, scale)) / 2;
+    let y = height.saturating_sub(GLYPH_HEIGHT * scale) / 2;
+    draw_text(&mut buf, width, height, x, y, text, foreground, scale);
+    buf
+}
+
+/// Number of dots around the loading spinner, and of steps before it repeats.
+pub const SPINNER_STEPS: u32 = 8;
+
+/// Renders step `step` of a spinner centered on a `width`x`height` frame: a ring of dots
+/// where the brightest one moves clockwise every step, followed by two fading ones.
+pub fn render_spinner(
+    step: u32,
+    width: u32,
+    height: u32,
+    foreground: Rgb<u8>,
+    background: Rgb<u8>,
+) -> Vec<u8> {
+    let mut buf = background.0.repeat((width * height) as usize);
+    let radius = (width.min(height) as f32 / 2.0 - 1.5).max(0.0);
+    let (center_x, center_y) = ((width as f32 - 1.0) / 2.0, (height as f32 - 1.0) / 2.0);
+    for dot in 0..SPINNER_STEPS {
+        // How many steps ago the head of the spinner was on this dot.
+        let age = (step % SPINNER_STEPS + SPINNER_STEPS - dot) % SPINNER_STEPS;
+        let brightness = match age {
+            0 => 1.0,
+            1 => 0.5,
+            2 => 0.25,
+            _ => 0.1,
+        };
+        // Dot 0 is at the top.
+        let angle = dot as f32 / SPINNER_STEPS as f32 * std::f32::consts::TAU;
+        let x = (center_x + radius * angle.sin()).round() as u32;
+        let y = (center_y - radius * angle.cos()).round() as u32;
+        if x >= width || y >= height {
+            continue;
+        }
+        let color = foreground
+            .0
+            .map(|value| (value as f32 * brightness).round() as u8);
+        let index = ((y * width + x) * 3) as usize;
+        buf[index..index + 3].copy_from_slice(&color);
+    }
+    buf
+}
+
+/// Encoding of the frames published to MQTT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// gzip stream (RFC 1952).
+    Gzip,
+    /// zlib stream (RFC 1950), which has a smaller header than gzip.
+    Deflate,
+    /// Run-length encoding, see `rle_encode`.
+    Rle,
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "gzip" => Ok(Compression::Gzip),
+            "deflate" => Ok(Compression::Deflate),
+            "rle" => Ok(Compression::Rle),
+            _ => Err(format!("Invalid payload compression: {}", s)),
+        }
+    }
+}
+
+impl Compression {
+    /// Name of the encoding, appended to the frame topic, eg: `ledmoji/32x32/gzip`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Compression::Gzip => "gzip",
+            Compression::Deflate => "deflate",
+            Compression::Rle => "rle",
+        }
+    }
+}
+
+/// Compresses a frame before publishing.
+///
+/// The compressed stream decompresses to exactly the bytes of the uncompressed payload,
+/// by default 3 bytes (red, green, blue) per LED, in strip order (see
+/// `encoder::OutputFormat`). The frame size is not included, as it's in the topic.
+pub fn compress_frame(buf: &[u8], method: Compression) -> Vec<u8> {
+    // Writing to a Vec can't fail.
+    match method {
+        Compression::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), Level::best());
+            encoder.write_all(buf).unwrap();
+            encoder.finish().unwrap()
+        }
+        Compression::Deflate => {
+            let mut encoder = ZlibEncoder::new(Vec::new(), Level::best());
+            encoder.write_all(buf).unwrap();
+            encoder.finish().unwrap()
+        }
+        Compression::Rle => rle_encode(buf),
+    }
+}
+
+/// Run-length encodes an RGB buffer, for subscribers too small to decompress gzip.
+///
+/// The output is a sequence of 4 byte runs, `[count][r][g][b]`, each one meaning `count`
+/// consecutive pixels of color `r, g, b`. `count` is from 1 to 255, so longer runs are
+/// split. Decoding appends the color `count` times for every run, until the end of the
+/// payload.
+pub fn rle_encode(buf: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for pixel in buf.chunks_exact(3) {
+        let len = out.len();
+        if len >= 4 && out[len - 4] < u8::MAX && out[len - 3..] == *pixel {
+            out[len - 4] += 1;
+        } else {
+            out.push(1);
+            out.extend_from_slice(pixel);
+        }
+    }
+    out
+}
+
+/// Decodes a buffer encoded by `rle_encode`.
+pub fn rle_decode(buf: &[u8]) -> Result<Vec<u8>, String> {
+    if !buf.len().is_multiple_of(4) {
+        return Err(format!(
+            "RLE payload length {} is not a multiple of 4",
+            buf.len()
+        ));
+    }
+    let mut out = Vec::new();
+    for run in buf.chunks_exact(4) {
+        for _ in 0..run[0] {
+            out.extend_from_slice(&run[1..]);
+        }
+    }
+    Ok(out)
+}
+
+/// Renders an RGB buffer as ANSI truecolor text, for previewing frames in a terminal.
+///
+/// Each character is an upper half block showing two rows of pixels: the top one as the
+/// foreground color and the bottom one as the background. The last row of an odd height
+/// buffer is drawn over the terminal's default background.
+pub fn to_ansi(buf: &[u8], width: u32, height: u32) -> String {
+    let pixel = |x: u32, y: u32| {
+        let i = ((y * width + x) * 3) as usize;
+        (buf[i], buf[i + 1], buf[i + 2])
+    };
+
+    let mut out = String::new();
+    for y in (0..height).step_by(2) {
+        for x in 0..width {
+            let (r, g, b) = pixel(x, y);
+            let _ = write!(out, "\x1b[38;2;{};{};{}m", r, g, b);
+            if y + 1 < height {
+                let (r, g, b) = pixel(x, y + 1);
+                let _ = write!(out, "\x1b[48;2;{};{};{}m", r, g, b);
+            } else {
+                out.push_str("\x1b[49m");
+            }
+            out.push('\u{2580}');
+        }
+        out.push_str("\x1b[0m\n");
+    }
+    out
+}
+
+/// Applies the corrections configured in `config` to an RGB frame, in this order:
+///
+/// 1. The panel shape mask, so pixels outside the panel are background.
+/// 2. Tone mapping, on the colors of the image.
+/// 3. The border, so it keeps its configured color through tone mapping.
+/// 4. The color correction tables, which calibrate the panel.
+/// 5. The gamma table of the LED chipset, see `Chipset::gamma_table`.
+/// 6. Dithering down to the levels the panel shows, see `DITHER`.
+///
+/// Frames are still in image order, the matrix layout (eg: serpentine wiring) is applied
+/// afterwards, when publishing, as are the limits of `apply_output_limits`.
+pub fn apply_corrections(buf: &mut [u8], width: u32, height: u32, config: &Config) {
+    if config.panel_shape == PanelShape::Circle {
+        apply_circular_mask(buf, width, height, BACKGROUND);
+    }
+    if let Some(ToneMap::Reinhard) = config.tone_map {
+        tone_map_reinhard(buf);
+    }
+    if let Some(border) = config.border {
+        draw_border(buf, width, height, border.color, border.thickness);
+    }
+    if let Some(lut) = &config.lut {
+        apply_lut(buf, lut);
+    }
+    if let Some(chipset) = config.chipset {
+        apply_gamma(buf, chipset.gamma_table());
+    }
+    if let Some(Dither::Ordered) = config.dither {
+        dither_ordered(buf, width, height, config.dither_levels);
+    }
+}
+
+/// Applies the limits configured in `config` to a frame about to be shown, after every
+/// other change to its pixels, eg: night mode or the clock overlay: the minimum
+/// brightness, so that no pixel is ever fully off, then the channel caps, so no LED is
+/// ever driven above its cap. The floor is never above a cap, so both hold.
+pub fn apply_output_limits(buf: &mut [u8], config: &Config) {
+    if let Some(floor) = config.min_brightness {
+        clamp_min_brightness(buf, floor);
+    }
+    if config.channel_max != [u8::MAX; 3] {
+        clamp_channels_max(buf, config.channel_max);
+    }
+}
+
+/// Returns whether `config` limits the frames shown, see `apply_output_limits`.
+pub fn has_output_limits(config: &Config) -> bool {
+    config.min_brightness.is_some() || config.channel_max != [u8::MAX; 3]
+}
+
+/// Renders `emoji` for every size in `config.sizes`, see `render_emoji_sizes`.
+pub fn render_emoji(config: &Config, emoji: &str) -> Result<Vec<(u32, u32, Vec<u8>)>, DaemonError> {
+    render_emoji_sizes(config, emoji, &config.sizes)
+}
+
+/// Renders `emoji` into one RGB frame per size, returned as (width, height, bytes).
+///
+/// The image is loaded from the emoji directory, resized and blended onto the background
+/// configured for the emoji, or `BACKGROUND` (see `render_frame`), sharpened when
+/// `config.sharpen_amount` is set, and corrected with `apply_corrections`. Frames are in
+/// image order, as the matrix layout only matters when publishing.
+pub fn render_emoji_sizes(
+    config: &Config,
+    emoji: &str,
+    sizes: &[(u32, u32)],
+) -> Result<Vec<(u32, u32, Vec<u8>)>, DaemonError> {
+    check_emoji_length(emoji, config.max_emoji_codepoints).map_err(DaemonError::InvalidEmoji)?;
+    let background = emoji_background(config, emoji, None);
+    let frames = match &config.emoji_font {
+        Some(font) => render_font_sizes(config, font, None, emoji, sizes, background, None)?,
+        None => {
+            let img = load_emoji_image(&config.emoji_directory, emoji, config.max_decode_pixels)?;
+            render_image_sizes(config, &img, sizes, background)
+        }
+    };
+    Ok(corrected(config, frames))
+}
+
+/// Like `render_emoji_sizes`, but loading the image through `cache`. The emoji is
+/// blended onto `background` when set, eg: the color picked for a message, otherwise
+/// onto the background configured for the emoji, or `BACKGROUND`. With `opacity`, the
+/// emoji is faded toward the background first, see `fade_alpha`.
+pub fn render_emoji_sizes_cached(
+    config: &Config,
+    cache: &mut EmojiCache,
+    emoji: &str,
+    sizes: &[(u32, u32)],
+    background: Option<Rgb<u8>>,
+    opacity: Option<u8>,
+) -> Result<Vec<(u32, u32, Vec<u8>)>, DaemonError> {
+    let frames = render_emoji_sizes_uncorrected(config, cache, emoji, sizes, background, opacity)?;
+    Ok(corrected(config, frames))
+}
+
+/// Like `render_emoji_sizes_cached`, but leaving the frames uncorrected, eg: so the halves
+/// of a split panel are corrected once they're joined, see `corrected`.
+pub fn render_emoji_sizes_uncorrected(
+    config: &Config,
+    cache: &mut EmojiCache,
+    emoji: &str,
+    sizes: &[(u32, u32)],
+    background: Option<Rgb<u8>>,
+    opacity: Option<u8>,
+) -> Result<Vec<(u32, u32, Vec<u8>)>, DaemonError> {
+    check_emoji_length(emoji, config.max_emoji_codepoints).map_err(DaemonError::InvalidEmoji)?;
+    let background = emoji_background(config, emoji, background);
+    if let Some(font) = &config.emoji_font {
+        return render_font_sizes(config, font, Some(cache), emoji, sizes, background, opacity);
+    }
+    let img = cache.load(&config.emoji_directory, emoji, config.max_decode_pixels)?;
+    Ok(render_image_sizes(
+        config,
+        &faded(img, opacity),
+        sizes,
+        background,
+    ))
+}
+
+/// Applies `apply_corrections` to every frame of `frames`.
+pub fn corrected(
+    config: &Config,
+    mut frames: Vec<(u32, u32, Vec<u8>)>,
+) -> Vec<(u32, u32, Vec<u8>)> {
+    for (width, height, buf) in &mut frames {
+        apply_corrections(buf, *width, *height, config);
+    }
+    frames
+}
+
+/// Multiplies the alpha of every pixel of `img` by `opacity` percent, clamped to 100, so
+/// the emoji blends partly into the background.
+pub fn fade_alpha(img: &DynamicImage, opacity: u8) -> DynamicImage {
+    let opacity = opacity.min(100) as f32 / 100.0;
+    let mut faded = img.to_rgba8();
+    for pixel in faded.pixels_mut() {
+        pixel[3] = (pixel[3] as f32 * opacity).round() as u8;
+    }
+    DynamicImage::ImageRgba8(faded)
+}
+
+// Applies `fade_alpha` when `opacity` is set.
+fn faded(img: Arc<DynamicImage>, opacity: Option<u8>) -> Arc<DynamicImage> {
+    match opacity {
+        Some(opacity) => Arc::new(fade_alpha(&img, opacity)),
+        None => img,
+    }
+}
+
+/// Renders `left` and `right` side by side for each size in `sizes`, each on its own
+/// half of the panel, eg: a 64x32 panel split into two 32x32 ones. The right half is a
+/// column wider on panels with an odd width. The joined frame is corrected as a whole,
+/// so the panel gets a single border and shape mask.
+pub fn render_split_sizes_cached(
+    config: &Config,
+    cache: &mut EmojiCache,
+    left: &str,
+    right: &str,
+    sizes: &[(u32, u32)],
+    background: Option<Rgb<u8>>,
+    opacity: Option<u8>,
+) -> Result<Vec<(u32, u32, Vec<u8>)>, DaemonError> {
+    let frames =
+        render_split_sizes_uncorrected(config, cache, left, right, sizes, background, opacity)?;
+    Ok(corrected(config, frames))
+}
+
+/// Like `render_split_sizes_cached`, but leaving the joined frames uncorrected.
+pub fn render_split_sizes_uncorrected(
+    config: &Config,
+    cache: &mut EmojiCache,
+    left: &str,
+    right: &str,
+    sizes: &[(u32, u32)],
+    background: Option<Rgb<u8>>,
+    opacity: Option<u8>,
+) -> Result<Vec<(u32, u32, Vec<u8>)>, DaemonError> {
+    if let Some((width, height)) = sizes.iter().find(|(width, _)| *width < 2) {
+        return Err(DaemonError::InvalidEmoji(format!(
+            "{}x{} panels are too narrow to split",
+            width, height
+        )));
+    }
+    let halves = |right_half: bool| {
+        sizes
+            .iter()
+            .map(|&(width, height)| match right_half {
+                true => (width - width / 2, height),
+                false => (width / 2, height),
+            })
+            .collect::<Vec<_>>()
+    };
+    let lefts =
+        render_emoji_sizes_uncorrected(config, cache, left, &halves(false), background, opacity)?;
+    let rights =
+        render_emoji_sizes_uncorrected(config, cache, right, &halves(true), background, opacity)?;
+    let joined = sizes
+        .iter()
+        .zip(lefts.iter().zip(&rights))
+        .map(
+            |(&(width, height), ((left_width, _, left), (_, _, right)))| {
+                let buf = join_side_by_side(left, *left_width, right, width, height);
+                (width, height, buf)
+            },
+        )
+        .collect();
+    Ok(joined)
+}
+
+// Joins the RGB frames of two halves into a `width` x `height` frame, the left half
+// being `left_width` wide.
+fn join_side_by_side(
+    left: &[u8],
+    left_width: u32,
+    right: &[u8],
+    width: u32,
+    height: u32,
+) -> Vec<u8> {
+    let left_row = left_width as usize * BYTES_PER_PIXEL;
+    let right_row = (width - left_width) as usize * BYTES_PER_PIXEL;
+    let mut buf = Vec::with_capacity(width as usize * height as usize * BYTES_PER_PIXEL);
+    for (left, right) in left.chunks(left_row).zip(right.chunks(right_row)) {
+        buf.extend_from_slice(left);
+        buf.extend_from_slice(right);
+    }
+    buf
+}
+
+// Renders `emoji` from the emoji font, rasterizing the glyph for each size, and caching
+// the glyphs per emoji and size when `cache` is set.
+fn render_font_sizes(
+    config: &Config,
+    font: &EmojiFont,
+    mut cache: Option<&mut EmojiCache>,
+    emoji: &str,
+    sizes: &[(u32, u32)],
+    background: Rgb<u8>,
+    opacity: Option<u8>,
+) -> Result<Vec<(u32, u32, Vec<u8>)>, DaemonError> {
+    let mut glyph = |(width, height): (u32, u32)| {
+        let pixels_per_em = width.max(height).min(u16::MAX as u32) as u16;
+        let glyph = match cache.as_deref_mut() {
+            Some(cache) => cache.load_with(&format!("{}@{}", emoji, pixels_per_em), || {
+                font.glyph_image(emoji, pixels_per_em)
+            }),
+            None => font.glyph_image(emoji, pixels_per_em).map(Arc::new),
+        };
+        glyph.map(|glyph| faded(glyph, opacity))
+    };
+
+    // Consistent scaling renders every size from the smallest, see `render_image_sizes`.
+    let smallest = sizes.iter().min_by_key(|(width, height)| width * height);
+    if let (true, Some(&smallest)) = (config.consistent_scaling, smallest) {
+        let img = glyph(smallest)?;
+        return Ok(render_image_sizes(config, &img, sizes, background));
+    }
+    let mut frames = Vec::with_capacity(sizes.len());
+    for &size in sizes {
+        let img = glyph(size)?;
+        frames.extend(render_image_sizes(config, &img, &[size], background));
+    }
+    Ok(frames)
+}
+
+// Renders the uncorrected frames of `img`. With `config.consistent_scaling`, the image is
+// rendered once at the smallest size, and that frame is stretched to the other sizes
+// with the nearest pixel, whatever the configured filter and resize mode, so every
+// panel shows the same pixel art in blocks.
+fn render_image_sizes(
+    config: &Config,
+    img: &DynamicImage,
+    sizes: &[(u32, u32)],
+    background: Rgb<u8>,
+) -> Vec<(u32, u32, Vec<u8>)> {
+    let base = config
+        .consistent_scaling
+        .then(|| sizes.iter().min_by_key(|(width, height)| width * height))
+        .flatten()
+        .map(|&(width, height)| {
+            DynamicImage::ImageRgb8(render_sharpened(config, img, width, height, background))
+        });
+
+    sizes
+        .iter()
+        .map(|&(width, height)| {
+            let frame = match &base {
+                Some(base) => render_frame(
+                    base,
+                    width,
+                    height,
+                    ResizeMode::Stretch,
+                    ScaleFilter::Nearest,
+                    background,
+                    config.blend_space,
+                ),
+                None => render_sharpened(config, img, width, height, background),
+            };
+            (width, height, frame.into_raw())
+        })
+        .collect()
+}
+
+/// Renders the image at `path` for every size in `sizes`, scaled and corrected like
+/// emoji, eg: the `SPLASH_IMAGE`.
+pub fn render_splash(
+    config: &Config,
+    path: &Path,
+    sizes: &[(u32, u32)],
+) -> Result<HashMap<(u32, u32), RgbImage>, Box<dyn Error + Send + Sync>> {
+    let img = open_image(path, config.max_decode_pixels)?;
+    let frames = render_image_sizes(config, &img, sizes, BACKGROUND);
+    Ok(corrected(config, frames)
+        .into_iter()
+        .map(|(width, height, buf)| {
+            let frame = RgbImage::from_raw(width, height, buf).unwrap();
+            ((width, height), frame)
+        })
+        .collect())
+}
+
+/// Encodes an RGB frame as PNG, eg: for showing it in a web page.
+pub fn encode_png(frame: RgbImage) -> Result<Vec<u8>, ImageError> {
+    let mut png = Vec::new();
+    DynamicImage::ImageRgb8(frame).write_to(&mut Cursor::new(&mut png), ImageOutputFormat::Png)?;
+    Ok(png)
+}
+
+/// Renders `emoji` as a PNG thumbnail of `size`, see `ICON_TOPIC`. The thumbnail gets
+/// the same corrections as the frames, so it looks like the panel.
+pub fn render_icon(
+    config: &Config,
+    cache: &mut EmojiCache,
+    emoji: &str,
+    size: (u32, u32),
+    background: Option<Rgb<u8>>,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let (width, height, buf) =
+        render_emoji_sizes_cached(config, cache, emoji, &[size], background, None)?.remove(0);
+    let frame = RgbImage::from_raw(width, height, buf).unwrap();
+    Ok(encode_png(frame)?)
+}
+
+fn render_sharpened(
+    config: &Config,
+    img: &DynamicImage,
+    width: u32,
+    height: u32,
+    background: Rgb<u8>,
+) -> RgbImage {
+    let frame = render_frame(
+        img,
+        width,
+        height,
+        config.resize_mode,
+        config.scale_filter,
+        background,
+        config.blend_space,
+    );
+    let mut frame = DynamicImage::ImageRgb8(frame);
+    if let Some(amount) = config.sharpen_amount {
+        unsharp_mask(&mut frame, SHARPEN_SIGMA, amount);
+    }
+    frame.into_rgb8()
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgba;
+
+    use super::BlendSpace;
+
+    use crate::{config::Config, error::DaemonError, render::ScaleFilter};
+
+    #[test]
+    fn merges_colors_correctly() {
+        let fg = image::Rgba([255, 0, 0, 128]);
+        let bg = image::Rgb([0, 255, 0]);
+        let result = super::merge_colors(&fg, &bg);
+        assert_eq!(result, vec![128, 127, 0]);
+    }
+
+    #[test]
+    fn blends_buffers_like_single_pixels() {
+        // Alternating opaque and translucent pixels.
+        let pixels = (0..1027)
+            .map(|i| Rgba([255, 0, 0, if i % 2 == 0 { 255 } else { 128 }]))
+            .collect::<Vec<_>>();
+        let rgba = pixels.iter().flat_map(|pixel| pixel.0).collect::<Vec<_>>();
+        let bg = image::Rgb([0, 255, 0]);
+
+        let expected = pixels
+            .iter()
+            .flat_map(|pixel| super::merge_colors(pixel, &bg))
+            .collect::<Vec<_>>();
+        assert_eq!(super::blend_onto(&rgba, &bg, BlendSpace::Srgb), expected);
+    }
+
+    #[test]
+    fn blends_edges_in_linear_light() {
+        // An anti-aliased edge pixel of a white emoji over a dark background.
+        let edge = Rgba([255, 255, 255, 128]);
+        let bg = image::Rgb([16, 16, 16]);
+        let srgb = super::merge_colors(&edge, &bg);
+        let linear = super::merge_colors_linear(&edge, &bg);
+        assert_eq!(srgb, vec![136, 136, 136]);
+        // Linear blending keeps the edge as bright as it looks, rather than darkened.
+        assert_eq!(linear, vec![188, 188, 188]);
+
+        // Opaque and fully transparent pixels are the same either way.
+        let opaque = Rgba([10, 20, 30, 255]);
+        assert_eq!(super::merge_colors_linear(&opaque, &bg), vec![10, 20, 30]);
+        let transparent = Rgba([255, 255, 255, 0]);
+        assert_eq!(
+            super::merge_colors_linear(&transparent, &bg),
+            vec![16, 16, 16]
+        );
+
+        let rgba = [edge.0, opaque.0].concat();
+        let blended = super::blend_onto(&rgba, &bg, BlendSpace::Linear);
+        assert_eq!(blended, [linear, vec![10, 20, 30]].concat());
+    }
+
+    // 3x2 buffer where each pixel's channels hold its index:
+    // 0 1 2
+    // 3 4 5
+    fn indexed_buffer() -> Vec<u8> {
+        (0..6).flat_map(|i| [i, i, i]).collect()
+    }
+
+    fn led_order(buf: &[u8]) -> Vec<u8> {
+        buf.chunks(3).map(|pixel| pixel[0]).collect()
+    }
+
+    #[test]
+    fn remap_default_layout_is_identity() {
+        let buf = indexed_buffer();
+        let result = super::remap(&buf, 3, 2, super::MatrixLayout::default());
+        assert_eq!(result, buf);
+    }
+
+    #[test]
+    fn remaps_row_serpentine() {
+        let layout = super::MatrixLayout {
+            serpentine: true,
+            ..Default::default()
+        };
+        let result = super::remap(&indexed_buffer(), 3, 2, layout);
+        assert_eq!(led_order(&result), vec![0, 1, 2, 5, 4, 3]);
+    }
+
+    #[test]
+    fn remaps_column_major() {
+        let layout = super::MatrixLayout {
+            axis: super::Axis::Column,
+            ..Default::default()
+        };
+        let result = super::remap(&indexed_buffer(), 3, 2, layout);
+        assert_eq!(led_order(&result), vec![0, 3, 1, 4, 2, 5]);
+    }
+
+    #[test]
+    fn remaps_bottom_left_rows() {
+        let layout = super::MatrixLayout {
+            origin: super::Origin::BottomLeft,
+            ..Default::default()
+        };
+        let result = super::remap(&indexed_buffer(), 3, 2, layout);
+        assert_eq!(led_order(&result), vec![3, 4, 5, 0, 1, 2]);
+    }
+
+    #[test]
+    fn remaps_bottom_right_column_serpentine() {
+        let layout = super::MatrixLayout {
+            origin: super::Origin::BottomRight,
+            axis: super::Axis::Column,
+            serpentine: true,
+        };
+        let result = super::remap(&indexed_buffer(), 3, 2, layout);
+        assert_eq!(led_order(&result), vec![5, 2, 1, 4, 3, 0]);
+    }
+
+    #[test]
+    fn masks_pixels_outside_circle() {
+        let mut buf = vec![255; 4 * 4 * 3];
+        super::apply_circular_mask(&mut buf, 4, 4, super::Rgb([0, 0, 0]));
+        assert_eq!(ascii_art(&buf, 4), vec![".##.", "####", "####", ".##."]);
+    }
+
+    #[test]
+    fn slides_left_one_column_per_step() {
+        // Columns of the old frame are 1, 2, 3, 4 and of the new one 11, 12, 13, 14.
+        let from = image::RgbImage::from_fn(4, 1, |x, _| image::Rgb([x as u8 + 1; 3]));
+        let to = image::RgbImage::from_fn(4, 1, |x, _| image::Rgb([x as u8 + 11; 3]));
+        let frames = super::transition_frames(&from, &to, super::Transition::SlideLeft, 4);
+
+        let columns = frames
+            .iter()
+            .map(|frame| frame.pixels().map(|pixel| pixel[0]).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            columns,
+            vec![vec![2, 3, 4, 11], vec![3, 4, 11, 12], vec![4, 11, 12, 13]]
+        );
+    }
+
+    #[test]
+    fn wipes_and_slides_up() {
+        let from = image::RgbImage::from_pixel(2, 2, image::Rgb([0, 0, 0]));
+        let to = image::RgbImage::from_pixel(2, 2, image::Rgb([255, 255, 255]));
+
+        let wipe = super::transition_frames(&from, &to, super::Transition::Wipe, 2);
+        assert_eq!(wipe.len(), 1);
+        assert_eq!(wipe[0].as_raw(), &[255, 255, 255, 0, 0, 0].repeat(2));
+
+        let slide = super::transition_frames(&from, &to, super::Transition::SlideUp, 2);
+        assert_eq!(slide[0].as_raw(), &[[0; 6], [255; 6]].concat());
+
+        assert!(super::transition_frames(&from, &to, super::Transition::None, 2).is_empty());
+    }
+
+    #[test]
+    fn crossfades_between_buffers() {
+        let from = [0, 100, 255];
+        let to = [255, 200, 0];
+        assert_eq!(super::crossfade(&from, &to, 0.0), from);
+        assert_eq!(super::crossfade(&from, &to, 0.5), vec![128, 150, 128]);
+        assert_eq!(super::crossfade(&from, &to, 1.0), to);
+    }
+
+    fn lut_text(map: impl Fn(u32) -> u32) -> String {
+        let table = (0..256).map(|v| map(v).to_string()).collect::<Vec<_>>();
+        format!("# red\n{0}\n# green\n{0}\n# blue\n{0}\n", table.join(" "))
+    }
+
+    #[test]
+    fn identity_lut_keeps_buffer() {
+        let lut = super::parse_lut(&lut_text(|v| v)).unwrap();
+        let mut buf = vec![0, 1, 2, 128, 200, 255];
+        super::apply_lut(&mut buf, &lut);
+        assert_eq!(buf, vec![0, 1, 2, 128, 200, 255]);
+    }
+
+    #[test]
+    fn inverting_lut_inverts_buffer() {
+        let lut = super::parse_lut(&lut_text(|v| 255 - v)).unwrap();
+        let mut buf = vec![0, 1, 2, 128, 200, 255];
+        super::apply_lut(&mut buf, &lut);
+        assert_eq!(buf, vec![255, 254, 253, 127, 55, 0]);
+    }
+
+    #[test]
+    fn draws_corner_text_over_box() {
+        let (width, height) = (12, 8);
+        let mut buf = vec![50; (width * height * 3) as usize];
+        super::draw_corner_text(
+            &mut buf,
+            width,
+            height,
+            "1",
+            image::Rgb([255, 255, 255]),
+            image::Rgb([0, 0, 0]),
+        );
+
+        // A 3x5 glyph in a 5x7 box.
+        for (i, pixel) in buf.chunks_exact(3).enumerate() {
+            let (x, y) = (i as u32 % width, i as u32 / width);
+            if x < 7 || y < 1 {
+                assert_eq!(pixel, [50, 50, 50], "pixel {},{}", x, y);
+            }
+        }
+        let pixel = |x: u32, y: u32| buf[((y * width + x) * 3) as usize];
+        assert_eq!(pixel(7, 1), 0);
+        // Top of the 1 glyph, which is its middle column.
+        assert_eq!(pixel(9, 2), 255);
+        assert_eq!(pixel(8, 2), 0);
+    }
+
+    #[test]
+    fn draws_border_on_outer_pixels_only() {
+        let (width, height) = (5, 4);
+        let mut buf = vec![10; (width * height * 3) as usize];
+        super::draw_border(&mut buf, width, height, image::Rgb([255, 0, 0]), 1);
+
+        for (i, pixel) in buf.chunks_exact(3).enumerate() {
+            let (x, y) = (i as u32 % width, i as u32 / width);
+            let border = x == 0 || y == 0 || x == width - 1 || y == height - 1;
+            let expected = if border { [255, 0, 0] } else { [10, 10, 10] };
+            assert_eq!(pixel, expected, "pixel {},{}", x, y);
+        }
+    }
+
+    #[test]
+    fn draws_circles() {
+        let (width, height) = (7, 7);
+        let red = image::Rgb([255, 0, 0]);
+        let pixel = |buf: &[u8], x: u32, y: u32| {
+            let index = ((y * width + x) * 3) as usize;
+            buf[index]
+        };
+
+        let mut buf = vec![0; (width * height * 3) as usize];
+        super::draw_circle(&mut buf, width, height, (3.0, 3.0), 2.0, red, false);
+        assert_eq!(pixel(&buf, 3, 3), 255);
+        assert_eq!(pixel(&buf, 3, 1), 255);
+        assert_eq!(pixel(&buf, 1, 1), 0);
+        assert_eq!(pixel(&buf, 3, 0), 0);
+
+        // The edge is blended by how much of the pixel the circle covers.
+        let mut buf = vec![0; (width * height * 3) as usize];
+        super::draw_circle(&mut buf, width, height, (3.0, 3.0), 2.0, red, true);
+        assert_eq!(pixel(&buf, 3, 3), 255);
+        assert_eq!(pixel(&buf, 3, 1), 128);
+        assert_eq!(pixel(&buf, 3, 0), 0);
+
+        // Circles are clipped to the buffer.
+        super::draw_circle(&mut buf, width, height, (0.0, 0.0), 10.0, red, true);
+        assert!(buf.chunks_exact(3).all(|pixel| pixel == [255, 0, 0]));
+    }
+
+    #[test]
+    fn draws_rings_and_lines() {
+        let (width, height) = (5, 5);
+        let blue = image::Rgb([0, 0, 255]);
+        let lit = |buf: &[u8]| {
+            buf.chunks_exact(3)
+                .map(|pixel| u8::from(pixel[2] == 255))
+                .collect::<Vec<_>>()
+        };
+
+        let mut buf = vec![0; (width * height * 3) as usize];
+        super::draw_ring(&mut buf, width, height, (2.0, 2.0), 2.0, 1.0, blue, false);
+        #[rustfmt::skip]
+        assert_eq!(lit(&buf), [
+            0, 1, 1, 1, 0,
+            1, 0, 0, 0, 1,
+            1, 0, 0, 0, 1,
+            1, 0, 0, 0, 1,
+            0, 1, 1, 1, 0,
+        ]);
+
+        let mut buf = vec![0; (width * height * 3) as usize];
+        super::draw_line(&mut buf, width, height, (0.0, 0.0), (4.0, 4.0), blue, false);
+        super::draw_line(&mut buf, width, height, (0.0, 4.0), (1.0, 4.0), blue, true);
+        #[rustfmt::skip]
+        assert_eq!(lit(&buf), [
+            1, 0, 0, 0, 0,
+            0, 1, 0, 0, 0,
+            0, 0, 1, 0, 0,
+            0, 0, 0, 1, 0,
+            1, 1, 0, 0, 1,
+        ]);
+    }
+
+    #[test]
+    fn parses_hex_colors() {
+        assert_eq!(
+            super::parse_color("#ff8000"),
+            Some(image::Rgb([255, 128, 0]))
+        );
+        assert_eq!(super::parse_color("00ff00"), Some(image::Rgb([0, 255, 0])));
+        assert_eq!(super::parse_color("red"), None);
+        assert_eq!(super::parse_color("#ff80"), None);
+        assert_eq!(super::parse_color("#f80"), Some(image::Rgb([255, 136, 0])));
+        assert_eq!(super::parse_color("+f0"), None);
+    }
+
+    #[test]
+    fn scales_brightness() {
+        let mut buf = vec![0, 100, 255];
+        super::scale_brightness(&mut buf, 0.1);
+        assert_eq!(buf, vec![0, 10, 26]);
+        super::scale_brightness(&mut buf, 0.0);
+        assert_eq!(buf, vec![0, 0, 0]);
+    }
+
+    #[test]
+    fn clamps_channels_to_min_brightness() {
+        let mut buf = vec![0, 3, 4, 200, 0, 255];
+        super::clamp_min_brightness(&mut buf, 4);
+        assert_eq!(buf, vec![4, 4, 4, 200, 4, 255]);
+    }
+
+    #[test]
+    fn clamps_channels_to_their_max() {
+        let mut buf = vec![255, 255, 255, 10, 220, 201];
+        super::clamp_channels_max(&mut buf, [255, 255, 200]);
+        assert_eq!(buf, vec![255, 255, 200, 10, 220, 200]);
+    }
+
+    #[test]
+    fn sharpening_increases_edge_contrast() {
+        // A soft edge from dark to light, as left by downscaling.
+        let ramp = [50, 50, 50, 50, 100, 150, 200, 200, 200, 200];
+        let img = image::RgbImage::from_fn(ramp.len() as u32, 4, |x, _| {
+            image::Rgb([ramp[x as usize]; 3])
+        });
+        let mut img = image::DynamicImage::ImageRgb8(img);
+        super::unsharp_mask(&mut img, 1.0, 1.0);
+
+        let img = img.as_rgb8().unwrap();
+        let dark = img.get_pixel(3, 1)[0];
+        let light = img.get_pixel(6, 1)[0];
+        assert!(dark < 50 && light > 200, "{} {}", dark, light);
+        // Flat areas away from the edge are unchanged.
+        assert_eq!(img.get_pixel(0, 1)[0], 50);
+        assert_eq!(img.get_pixel(9, 1)[0], 200);
+    }
+
+    #[test]
+    fn shifts_hues() {
+        let mut buf = vec![255, 0, 0, 0, 128, 255, 90, 90, 90];
+        super::shift_hue(&mut buf, 120.0);
+        // Red turns green, azure turns pink, and gray stays gray.
+        assert_eq!(&buf[0..3], [0, 255, 0]);
+        assert_eq!(&buf[3..6], [255, 0, 128]);
+        assert_eq!(&buf[6..9], [90, 90, 90]);
+
+        let mut turned = vec![200, 100, 0];
+        super::shift_hue(&mut turned, 360.0);
+        assert_eq!(turned, vec![200, 100, 0]);
+    }
+
+    #[test]
+    fn tone_maps_highlights() {
+        let mut buf = vec![250, 250, 250, 255, 240, 10, 20, 20, 20, 0, 0, 0];
+        super::tone_map_reinhard(&mut buf);
+        // Bright pixels are brought down, keeping their hue.
+        assert_eq!(&buf[0..3], &[126, 126, 126]);
+        assert!(buf[3] < 160 && buf[4] < 150 && buf[3] > buf[4] && buf[4] > buf[5]);
+        // Dark pixels are roughly preserved.
+        assert_eq!(&buf[6..9], &[19, 19, 19]);
+        assert_eq!(&buf[9..12], &[0, 0, 0]);
+    }
+
+    #[test]
+    fn dithers_flat_areas_into_bayer_pattern() {
+        // 128 is halfway between the two levels, so half the pixels of every 4x4 tile
+        // are rounded up, always the same ones.
+        let (width, height) = (8, 4);
+        let mut buf = vec![128; width * height * 3];
+        super::dither_ordered(&mut buf, width as u32, height as u32, 2);
+        for (index, pixel) in buf.chunks_exact(3).enumerate() {
+            let (x, y) = (index % width, index / width);
+            let expected = if super::BAYER_4X4[y % 4][x % 4] < 8 {
+                255
+            } else {
+                0
+            };
+            assert_eq!(pixel, [expected; 3], "pixel {},{}", x, y);
+        }
+
+        // Values on a level are left as they are.
+        let mut buf = vec![0, 85, 170, 255, 255, 255];
+        super::dither_ordered(&mut buf, 2, 1, 4);
+        assert_eq!(buf, vec![0, 85, 170, 255, 255, 255]);
+    }
+
+    #[test]
+    fn dithers_the_same_way_every_frame() {
+        let gradient: Vec<u8> = (0..16 * 16)
+            .flat_map(|i| [i as u8, 255 - i as u8, 100])
+            .collect();
+        let mut first = gradient.clone();
+        let mut second = gradient.clone();
+        super::dither_ordered(&mut first, 16, 16, 5);
+        super::dither_ordered(&mut second, 16, 16, 5);
+        assert_eq!(first, second);
+        assert!(first.iter().all(|v| [0, 64, 128, 191, 255].contains(v)));
+    }
+
+    #[test]
+    fn rejects_lut_with_wrong_entry_count() {
+        let table = (0..255)
+            .map(|v| v.to_string())
+            .collect::<Vec<_>>()
+            .join(",");
+        let input = format!("{0}\n{0}\n{0}\n", table);
+        let err = super::parse_lut(&input).unwrap_err();
+        assert!(err.contains("found 255"), "{}", err);
+
+        let two_tables = lut_text(|v| v)
+            .lines()
+            .take(4)
+            .collect::<Vec<_>>()
+            .join("\n");
+        assert!(super::parse_lut(&two_tables).is_err());
+        assert!(super::parse_lut(&lut_text(|v| v + 1)).is_err());
+    }
+
+    const WHITE: super::Rgb<u8> = super::Rgb([255, 255, 255]);
+    const BLACK: super::Rgb<u8> = super::Rgb([0, 0, 0]);
+
+    // Converts an RGB buffer to rows of '#' (lit) and '.' (unlit) pixels.
+    fn ascii_art(buf: &[u8], width: u32) -> Vec<String> {
+        buf.chunks(3)
+            .map(|pixel| if pixel[0] > 0 { '#' } else { '.' })
+            .collect::<Vec<_>>()
+            .chunks(width as usize)
+            .map(|row| row.iter().collect())
+            .collect()
+    }
+
+    #[test]
+    fn measures_text_width() {
+        assert_eq!(super::text_width("", 1), 0);
+        assert_eq!(super::text_width("1", 1), 3);
+        assert_eq!(super::text_width("10", 1), 7);
+        assert_eq!(super::text_width("10", 2), 14);
+    }
+
+    #[test]
+    fn renders_centered_text() {
+        let buf = super::render_text("1", 5, 7, WHITE, BLACK);
+        assert_eq!(
+            ascii_art(&buf, 5),
+            vec![".....", "..#..", ".##..", "..#..", "..#..", ".###.", "....."]
+        );
+    }
+
+    #[test]
+    fn scales_text_to_fit() {
+        let buf = super::render_text("7", 6, 10, WHITE, BLACK);
+        assert_eq!(
+            ascii_art(&buf, 6),
+            vec![
+                "######", "######", "....##", "....##", "....##", "....##", "....##", "....##",
+                "....##", "....##",
+            ]
+        );
+    }
+
+    #[test]
+    fn clips_text_outside_buffer() {
+        let mut buf = vec![0; 2 * 2 * 3];
+        super::draw_text(&mut buf, 2, 2, 1, 1, "8", WHITE, 1);
+        assert_eq!(ascii_art(&buf, 2), vec!["..", ".#"]);
+    }
+
+    fn decompress(buf: &[u8], method: super::Compression) -> Vec<u8> {
+        use std::io::Read;
+
+        let mut out = Vec::new();
+        match method {
+            super::Compression::Gzip => flate2::read::GzDecoder::new(buf)
+                .read_to_end(&mut out)
+                .unwrap(),
+            super::Compression::Deflate => flate2::read::ZlibDecoder::new(buf)
+                .read_to_end(&mut out)
+                .unwrap(),
+            super::Compression::Rle => return super::rle_decode(buf).unwrap(),
+        };
+        out
+    }
+
+    #[test]
+    fn compressed_frames_round_trip() {
+        let mut buf = [0, 0, 0].repeat(128 * 128);
+        for (i, value) in buf.iter_mut().enumerate().step_by(7) {
+            *value = i as u8;
+        }
+        for method in [super::Compression::Gzip, super::Compression::Deflate] {
+            let compressed = super::compress_frame(&buf, method);
+            assert!(compressed.len() < buf.len());
+            assert_eq!(decompress(&compressed, method), buf);
+        }
+    }
+
+    #[test]
+    fn rle_round_trips() {
+        let buf = [[1, 2, 3].repeat(300), vec![4, 5, 6], [1, 2, 3].repeat(2)].concat();
+        let encoded = super::rle_encode(&buf);
+        assert_eq!(
+            encoded,
+            vec![255, 1, 2, 3, 45, 1, 2, 3, 1, 4, 5, 6, 2, 1, 2, 3]
+        );
+        assert_eq!(super::rle_decode(&encoded).unwrap(), buf);
+        assert!(super::rle_decode(&encoded[..3]).is_err());
+    }
+
+    #[test]
+    fn rle_compresses_solid_frames() {
+        let buf = [0, 0, 0].repeat(128 * 128);
+        let encoded = super::rle_encode(&buf);
+        // 16384 pixels need 65 runs.
+        assert_eq!(encoded.len(), 65 * 4);
+        assert_eq!(super::rle_decode(&encoded).unwrap(), buf);
+    }
+
+    #[test]
+    fn downscales_in_linear_light() {
+        // Black and white, which average to 128 when ignoring gamma.
+        let mut img = image::RgbaImage::from_pixel(2, 2, Rgba([0, 0, 0, 255]));
+        img.put_pixel(1, 0, Rgba([255, 255, 255, 255]));
+        img.put_pixel(0, 1, Rgba([255, 255, 255, 255]));
+        let img = image::DynamicImage::ImageRgba8(img);
+        let scaled = super::downscale_area_linear(&img, 1, 1);
+        assert_eq!(scaled.get_pixel(0, 0), &Rgba([188, 188, 188, 255]));
+
+        // Transparent pixels only lower the alpha.
+        let mut img = image::RgbaImage::from_pixel(2, 1, Rgba([255, 0, 0, 255]));
+        img.put_pixel(1, 0, Rgba([0, 0, 0, 0]));
+        let scaled = super::downscale_area_linear(&image::DynamicImage::ImageRgba8(img), 1, 1);
+        assert_eq!(scaled.get_pixel(0, 0), &Rgba([255, 0, 0, 128]));
+    }
+
+    #[test]
+    fn spinner_frames_differ_across_steps() {
+        let white = image::Rgb([255, 255, 255]);
+        let black = image::Rgb([0, 0, 0]);
+        let frames = (0..super::SPINNER_STEPS)
+            .map(|step| super::render_spinner(step, 8, 8, white, black))
+            .collect::<Vec<_>>();
+        for (i, frame) in frames.iter().enumerate() {
+            assert_eq!(frame.len(), 8 * 8 * 3);
+            // One dot at full brightness.
+            assert_eq!(frame.chunks_exact(3).filter(|p| p == &[255; 3]).count(), 1);
+            for other in &frames[i + 1..] {
+                assert_ne!(frame, other);
+            }
+        }
+        // And it loops, however many steps it took.
+        assert_eq!(
+            super::render_spinner(super::SPINNER_STEPS, 8, 8, white, black),
+            frames[0]
+        );
+        assert_eq!(
+            super::render_spinner(u32::MAX, 8, 8, white, black),
+            frames[7]
+        );
+    }
+
+    #[test]
+    fn frame_counter_changes_every_frame() {
+        let mut previous = vec![255; 4 * 2 * 3];
+        super::draw_frame_counter(&mut previous, 4, 0);
+        for counter in 1..10 {
+            let mut buf = vec![255; 4 * 2 * 3];
+            super::draw_frame_counter(&mut buf, 4, counter);
+            assert_ne!(buf[..3], previous[..3], "frame {}", counter);
+            // Only the 3 counter pixels are drawn over.
+            assert!(buf[9..].iter().all(|&value| value == 255));
+            previous = buf;
+        }
+        assert_eq!(previous[..9], [255, 0, 0, 0, 0, 0, 0, 0, 0]);
+    }
+
+    // Draws `img` as rows of `#` for black and `.` for white.
+    fn pixel_art(img: &image::DynamicImage) -> Vec<String> {
+        let img = img.to_rgba8();
+        img.rows()
+            .map(|row| {
+                row.map(|pixel| if pixel[0] == 0 { '#' } else { '.' })
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn diagonal() -> image::DynamicImage {
+        let mut img = image::RgbaImage::from_pixel(3, 3, Rgba([255, 255, 255, 255]));
+        for i in 0..3 {
+            img.put_pixel(i, i, Rgba([0, 0, 0, 255]));
+        }
+        image::DynamicImage::ImageRgba8(img)
+    }
+
+    #[test]
+    fn scale2x_preserves_diagonal_edges() {
+        assert_eq!(
+            pixel_art(&super::scale2x(&diagonal())),
+            ["##....", "#.#...", ".###..", "..###.", "...#.#", "....##"]
+        );
+    }
+
+    #[test]
+    fn scale3x_preserves_diagonal_edges() {
+        assert_eq!(
+            pixel_art(&super::scale3x(&diagonal())),
+            [
+                "###......",
+                "##.#.....",
+                "#..#.....",
+                ".#####...",
+                "...###...",
+                "...#####.",
+                ".....#..#",
+                ".....#.##",
+                "......###",
+            ]
+        );
+    }
+
+    #[test]
+    fn renders_ansi_half_blocks() {
+        // 1x2: red above blue.
+        let ansi = super::to_ansi(&[255, 0, 0, 0, 0, 255], 1, 2);
+        assert_eq!(
+            ansi,
+            "\x1b[38;2;255;0;0m\x1b[48;2;0;0;255m\u{2580}\x1b[0m\n"
+        );
+    }
+
+    #[test]
+    fn renders_ansi_with_odd_height() {
+        let buf = [10, 20, 30].repeat(2 * 3);
+        let ansi = super::to_ansi(&buf, 2, 3);
+        let lines = ansi.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0].matches('\u{2580}').count(), 2);
+        assert_eq!(lines[1].matches("\x1b[49m").count(), 2);
+        assert!(!lines[1].contains("\x1b[48;2"));
+    }
+
+    fn emoji_config(dir: &tempfile::TempDir) -> Config {
+        image::RgbaImage::from_pixel(4, 4, Rgba([200, 100, 0, 255]))
+            .save(dir.path().join("emoji_u1f44d.png"))
+            .unwrap();
+        Config {
+            emoji_directory: dir.path().to_str().unwrap().to_string(),
+            sizes: vec![(2, 2), (4, 2)],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn renders_emoji_for_every_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let frames = super::render_emoji(&emoji_config(&dir), "👍").unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0], (2, 2, [200, 100, 0].repeat(4)));
+        let (width, height, buf) = &frames[1];
+        assert_eq!((*width, *height), (4, 2));
+        assert_eq!(ascii_art(buf, 4), vec![".##.", ".##."]);
+    }
+
+    #[test]
+    fn consistent_scaling_replicates_smallest_render() {
+        let dir = tempfile::tempdir().unwrap();
+        // Noise, so a separate render at 128x128 would differ from the upscale.
+        image::RgbaImage::from_fn(100, 100, |x, y| {
+            Rgba([(x * 7 + y * 13) as u8, (x * y) as u8, (x ^ y) as u8, 255])
+        })
+        .save(dir.path().join("emoji_u1f44d.png"))
+        .unwrap();
+        let config = Config {
+            emoji_directory: dir.path().to_str().unwrap().to_string(),
+            sizes: vec![(128, 128), (32, 32)],
+            consistent_scaling: true,
+            ..Default::default()
+        };
+
+        let frames = super::render_emoji(&config, "👍").unwrap();
+        let (large, small) = (&frames[0].2, &frames[1].2);
+        for y in 0..128 {
+            for x in 0..128 {
+                let large_index = (y * 128 + x) * 3;
+                let small_index = ((y / 4) * 32 + x / 4) * 3;
+                assert_eq!(
+                    large[large_index..large_index + 3],
+                    small[small_index..small_index + 3],
+                    "pixel {},{}",
+                    x,
+                    y
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn consistent_scaling_replicates_blocks_with_any_filter() {
+        let dir = tempfile::tempdir().unwrap();
+        image::RgbaImage::from_fn(4, 4, |x, y| Rgba([(x * 60) as u8, (y * 60) as u8, 0, 255]))
+            .save(dir.path().join("emoji_u1f44d.png"))
+            .unwrap();
+        for scale_filter in [
+            ScaleFilter::Triangle,
+            ScaleFilter::PixelArt,
+            ScaleFilter::Auto,
+        ] {
+            let config = Config {
+                emoji_directory: dir.path().to_str().unwrap().to_string(),
+                sizes: vec![(12, 12), (4, 4)],
+                consistent_scaling: true,
+                scale_filter,
+                ..Default::default()
+            };
+
+            let frames = super::render_emoji(&config, "👍").unwrap();
+            let (large, small) = (&frames[0].2, &frames[1].2);
+            for y in 0..12 {
+                for x in 0..12 {
+                    let large_index = (y * 12 + x) * 3;
+                    let small_index = ((y / 3) * 4 + x / 3) * 3;
+                    assert_eq!(
+                        large[large_index..large_index + 3],
+                        small[small_index..small_index + 3],
+                        "{:?} pixel {},{}",
+                        scale_filter,
+                        x,
+                        y
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn renders_onto_message_background() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = emoji_config(&dir);
+        let mut cache = crate::cache::EmojiCache::new(None, None);
+        let mut padding = |background| {
+            let frames = super::render_emoji_sizes_cached(
+                &config,
+                &mut cache,
+                "👍",
+                &[(4, 2)],
+                background,
+                None,
+            )
+            .unwrap();
+            (frames[0].2[0..3].to_vec(), frames[0].2[3..6].to_vec())
+        };
+
+        // The padding around the 2x2 emoji takes the background, the emoji doesn't.
+        let navy = super::parse_color("#001f3f").unwrap();
+        assert_eq!(padding(Some(navy)), (vec![0, 31, 63], vec![200, 100, 0]));
+        assert_eq!(padding(None), (vec![0, 0, 0], vec![200, 100, 0]));
+    }
+
+    #[test]
+    fn renders_split_panels() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = emoji_config(&dir);
+        image::RgbaImage::from_pixel(4, 4, Rgba([0, 0, 255, 255]))
+            .save(dir.path().join("emoji_u1f600.png"))
+            .unwrap();
+        let mut cache = crate::cache::EmojiCache::new(None, None);
+        let frames = super::render_split_sizes_cached(
+            &config,
+            &mut cache,
+            "👍",
+            "😀",
+            &[(4, 2)],
+            None,
+            None,
+        )
+        .unwrap();
+
+        // Each row is the left emoji on the first two pixels, the right one after them.
+        let row = [[200, 100, 0].repeat(2), [0, 0, 255].repeat(2)].concat();
+        assert_eq!(frames, vec![(4, 2, row.repeat(2))]);
+
+        let err = super::render_split_sizes_cached(
+            &config,
+            &mut cache,
+            "👍",
+            "😀",
+            &[(1, 2)],
+            None,
+            None,
+        )
+        .unwrap_err();
+        assert!(matches!(err, DaemonError::InvalidEmoji(_)));
+    }
+
+    #[test]
+    fn corrects_split_panels_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            border: Some(crate::config::Border {
+                color: image::Rgb([255, 255, 255]),
+                thickness: 1,
+            }),
+            ..emoji_config(&dir)
+        };
+        image::RgbaImage::from_pixel(4, 4, Rgba([0, 0, 255, 255]))
+            .save(dir.path().join("emoji_u1f600.png"))
+            .unwrap();
+        let mut cache = crate::cache::EmojiCache::new(None, None);
+        let frames = super::render_split_sizes_cached(
+            &config,
+            &mut cache,
+            "👍",
+            "😀",
+            &[(6, 4)],
+            None,
+            None,
+        )
+        .unwrap();
+        let frame = image::RgbImage::from_raw(6, 4, frames[0].2.clone()).unwrap();
+
+        // The border runs around the panel, not between the halves.
+        assert_eq!(frame.get_pixel(0, 1), &image::Rgb([255, 255, 255]));
+        assert_eq!(frame.get_pixel(5, 1), &image::Rgb([255, 255, 255]));
+        assert_eq!(frame.get_pixel(2, 1), &image::Rgb([200, 100, 0]));
+        assert_eq!(frame.get_pixel(3, 1), &image::Rgb([0, 0, 255]));
+    }
+
+    #[test]
+    fn detects_frames_showing_only_background() {
+        let dir = tempfile::tempdir().unwrap();
+        image::RgbaImage::from_pixel(4, 4, Rgba([255, 0, 0, 0]))
+            .save(dir.path().join("emoji_u1f600.png"))
+            .unwrap();
+        let config = Config {
+            min_brightness: Some(20),
+            ..emoji_config(&dir)
+        };
+
+        // Fully transparent assets render to the background, with the corrections.
+        let transparent = super::render_emoji_sizes(&config, "😀", &config.sizes).unwrap();
+        for (width, height, buf) in transparent {
+            assert!(super::shows_only_background(
+                &config, "😀", None, width, height, &buf
+            ));
+        }
+        let visible = super::render_emoji_sizes(&config, "👍", &config.sizes).unwrap();
+        for (width, height, buf) in visible {
+            assert!(!super::shows_only_background(
+                &config, "👍", None, width, height, &buf
+            ));
+        }
+
+        assert!(super::is_effectively_blank(&[8, 0, 3], &[0, 0, 0], 8));
+        assert!(!super::is_effectively_blank(&[9, 0, 3], &[0, 0, 0], 8));
+    }
+
+    #[test]
+    fn fades_emoji_by_opacity() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = emoji_config(&dir);
+        let mut cache = crate::cache::EmojiCache::new(None, None);
+        let mut render = |opacity| {
+            super::render_emoji_sizes_cached(&config, &mut cache, "👍", &[(2, 2)], None, opacity)
+                .unwrap()
+                .remove(0)
+                .2
+        };
+
+        // Half opacity lands halfway between the emoji and the black background.
+        let opaque = render(Some(100));
+        assert_eq!(opaque, render(None));
+        assert_eq!(&opaque[0..3], [200, 100, 0]);
+        assert_eq!(&render(Some(50))[0..3], [100, 50, 0]);
+        assert_eq!(&render(Some(0))[0..3], [0, 0, 0]);
+        // Values above 100 are clamped.
+        assert_eq!(render(Some(250)), opaque);
+    }
+
+    #[test]
+    fn renders_onto_emoji_background() {
+        let dir = tempfile::tempdir().unwrap();
+        image::RgbaImage::from_pixel(4, 4, Rgba([0, 0, 255, 255]))
+            .save(dir.path().join("emoji_u1f600.png"))
+            .unwrap();
+        let navy = super::parse_color("#001f3f").unwrap();
+        let config = Config {
+            backgrounds: super::parse_backgrounds(r##"{"👍": "#001f3f"}"##).unwrap(),
+            ..emoji_config(&dir)
+        };
+        let padding = |emoji| {
+            super::render_emoji_sizes(&config, emoji, &[(4, 2)]).unwrap()[0].2[0..3].to_vec()
+        };
+
+        // Mapped emoji take their background, the others the global one.
+        assert_eq!(padding("👍"), navy.0);
+        assert_eq!(padding("😀"), super::BACKGROUND.0);
+
+        // The background of the message wins over the mapped one.
+        let mut cache = crate::cache::EmojiCache::new(None, None);
+        let red = Some(image::Rgb([255, 0, 0]));
+        let frames =
+            super::render_emoji_sizes_cached(&config, &mut cache, "👍", &[(4, 2)], red, None)
+                .unwrap();
+        assert_eq!(frames[0].2[0..3], [255, 0, 0]);
+
+        assert!(super::parse_backgrounds(r#"{"👍": "navy"}"#).is_err());
+    }
+
+    #[test]
+    fn matches_backgrounds_with_or_without_variation_selectors() {
+        let navy = super::parse_color("#001f3f").unwrap();
+        for (mapped, shown) in [("❤\u{fe0f}", "❤"), ("❤", "❤\u{fe0f}")] {
+            let config = Config {
+                backgrounds: super::parse_backgrounds(&format!(r##"{{"{}": "#001f3f"}}"##, mapped))
+                    .unwrap(),
+                ..Config::default()
+            };
+            assert_eq!(super::emoji_background(&config, shown, None), navy);
+        }
+    }
+
+    #[test]
+    fn renders_emoji_from_font() {
+        let font = crate::font::tests::thumbs_up_font([0, 0, 255, 255]);
+        let config = Config {
+            emoji_font: Some(std::sync::Arc::new(font)),
+            ..Default::default()
+        };
+        let mut cache = crate::cache::EmojiCache::new(Some(8), None);
+        let frames = super::render_emoji_sizes_cached(
+            &config,
+            &mut cache,
+            "👍",
+            &[(2, 2), (8, 8)],
+            None,
+            None,
+        )
+        .unwrap();
+        assert_eq!(frames[0], (2, 2, [0, 0, 255].repeat(4)));
+        assert_eq!(frames[1].2.len(), 8 * 8 * 3);
+        // Glyphs are cached per size.
+        assert_eq!(cache.len(), 2);
+
+        let err = super::render_emoji_sizes(&config, "😀", &[(2, 2)]).unwrap_err();
+        assert!(matches!(err, DaemonError::NotFound(_)));
+    }
+
+    #[test]
+    fn renders_icon_as_png() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = emoji_config(&dir);
+        let mut cache = crate::cache::EmojiCache::new(None, None);
+        let png = super::render_icon(&config, &mut cache, "👍", (8, 8), None).unwrap();
+        assert_eq!(image::guess_format(&png).unwrap(), image::ImageFormat::Png);
+        let icon = image::load_from_memory(&png).unwrap().into_rgb8();
+        assert_eq!(icon.dimensions(), (8, 8));
+        assert_eq!(icon.get_pixel(0, 0), &super::Rgb([200, 100, 0]));
+    }
+
+    #[test]
+    fn applies_corrections_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            lut: Some([[0; 256], [0; 256], [200; 256]]),
+            min_brightness: Some(10),
+            channel_max: [255, 255, 100],
+            ..emoji_config(&dir)
+        };
+        let mut frames = super::render_emoji_sizes(&config, "👍", &[(1, 1)]).unwrap();
+        assert_eq!(frames, vec![(1, 1, vec![0, 0, 200])]);
+        // The limits apply after the tables turned red and green off.
+        super::apply_output_limits(&mut frames[0].2, &config);
+        assert_eq!(frames[0].2, vec![10, 10, 100]);
+    }
+
+    #[test]
+    fn reports_render_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = emoji_config(&dir);
+        assert!(matches!(
+            super::render_emoji(&config, "😀"),
+            Err(DaemonError::NotFound(_))
+        ));
+        assert!(matches!(
+            super::render_emoji(&config, &"👍".repeat(100)),
+            Err(DaemonError::InvalidEmoji(_))
+        ));
+    }
+}
@@ -0,0 +1,156 @@
+// Copyright 2023 André Cipriani Bandarra
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::{
+    io::Write,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use env_logger::{fmt::Formatter, Env};
+use log::{
+    kv::{Error, Key, Value, VisitSource},
+    Record,
+};
+use serde_json::{Map, Value as JsonValue};
+
+// Log output format: "text" (default) for human readable lines, or "json" for one JSON
+// object per line, for log aggregation.
+static ENV_LOG_FORMAT: &str = "LOG_FORMAT";
+
+/// Initializes the logger, filtered by RUST_LOG or `default_filter` when not set.
+pub fn init(default_filter: &str) {
+    let mut builder =
+        env_logger::Builder::from_env(Env::default().default_filter_or(default_filter));
+    if std::env::var(ENV_LOG_FORMAT).is_ok_and(|format| format.eq_ignore_ascii_case("json")) {
+        builder.format(format_json);
+    }
+    builder.init();
+}
+
+/// Counts occurrences of a frequent log message, to log only some of them.
+#[derive(Debug, Default)]
+pub struct LogSampler {
+    count: AtomicU64,
+}
+
+impl LogSampler {
+    pub const fn new() -> Self {
+        Self {
+            count: AtomicU64::new(0),
+        }
+    }
+
+    /// Counts one occurrence, returning whether it's one of the 1 in `every` that should
+    /// be logged, starting with the first. Every occurrence is logged when `every` is 0
+    /// or 1.
+    pub fn sample(&self, every: u64) -> bool {
+        let count = self.count.fetch_add(1, Ordering::Relaxed);
+        every <= 1 || count.is_multiple_of(every)
+    }
+}
+
+fn format_json(buf: &mut Formatter, record: &Record) -> std::io::Result<()> {
+    let line = json_line(&buf.timestamp_millis().to_string(), record);
+    writeln!(buf, "{}", line)
+}
+
+/// Builds the JSON object logged for `record`, with the key-values attached to it as
+/// extra fields.
+pub fn json_line(timestamp: &str, record: &Record) -> JsonValue {
+    let mut fields = Map::new();
+    fields.insert("timestamp".to_string(), timestamp.into());
+    fields.insert("level".to_string(), record.level().as_str().into());
+    fields.insert("target".to_string(), record.target().into());
+    fields.insert("message".to_string(), record.args().to_string().into());
+
+    let mut visitor = FieldVisitor(Map::new());
+    // The visitor never fails.
+    let _ = record.key_values().visit(&mut visitor);
+    if !visitor.0.is_empty() {
+        fields.insert("fields".to_string(), JsonValue::Object(visitor.0));
+    }
+    JsonValue::Object(fields)
+}
+
+struct FieldVisitor(Map<String, JsonValue>);
+
+impl<'kvs> VisitSource<'kvs> for FieldVisitor {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), Error> {
+        let value = if let Some(value) = value.to_i64() {
+            value.into()
+        } else if let Some(value) = value.to_bool() {
+            value.into()
+        } else {
+            value.to_string().into()
+        };
+        self.0.insert(key.to_string(), value);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use log::Level;
+    use serde_json::json;
+
+    #[test]
+    fn samples_one_in_every_n() {
+        let sampler = super::LogSampler::new();
+        let logged = (0..7).map(|_| sampler.sample(3)).collect::<Vec<_>>();
+        assert_eq!(logged, [true, false, false, true, false, false, true]);
+
+        let sampler = super::LogSampler::new();
+        assert!((0..5).all(|_| sampler.sample(1)));
+    }
+
+    #[test]
+    fn formats_record_as_json() {
+        let line = super::json_line(
+            "2024-01-01T00:00:00.000Z",
+            &log::Record::builder()
+                .level(Level::Warn)
+                .target("daemon")
+                .args(format_args!("Rejected emoji from {}", "kitchen"))
+                .build(),
+        );
+        assert_eq!(
+            line,
+            json!({
+                "timestamp": "2024-01-01T00:00:00.000Z",
+                "level": "WARN",
+                "target": "daemon",
+                "message": "Rejected emoji from kitchen",
+            })
+        );
+    }
+
+    #[test]
+    fn includes_structured_fields() {
+        let fields = [("source", "kitchen"), ("topic", "ledmoji/32x32")];
+        let line = super::json_line(
+            "2024-01-01T00:00:00.000Z",
+            &log::Record::builder()
+                .level(Level::Info)
+                .target("daemon")
+                .args(format_args!("Published"))
+                .key_values(&fields)
+                .build(),
+        );
+        assert_eq!(
+            line["fields"],
+            json!({"source": "kitchen", "topic": "ledmoji/32x32"})
+        );
+    }
+}
@@ -0,0 +1,98 @@
+// Copyright 2023 André Cipriani Bandarra
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+/// Encoding of the messages published to the metadata topics, eg: the info topic and
+/// query responses. Frames aren't affected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MetaEncoding {
+    #[default]
+    Json,
+    /// CBOR (RFC 8949), which is cheaper to parse on embedded subscribers.
+    Cbor,
+}
+
+impl FromStr for MetaEncoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "json" => Ok(MetaEncoding::Json),
+            "cbor" => Ok(MetaEncoding::Cbor),
+            _ => Err(format!("Invalid meta encoding: {}", s)),
+        }
+    }
+}
+
+impl MetaEncoding {
+    pub fn encode<T: Serialize>(&self, message: &T) -> Vec<u8> {
+        match self {
+            // The messages are plain structs, which always serialize.
+            MetaEncoding::Json => serde_json::to_vec(message).unwrap(),
+            MetaEncoding::Cbor => {
+                let mut out = Vec::new();
+                ciborium::into_writer(message, &mut out).unwrap();
+                out
+            }
+        }
+    }
+}
+
+/// Published to the info topic on startup, so operators can check the right asset pack
+/// is mounted.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Info {
+    pub emoji_count: usize,
+    pub emoji_directory: String,
+    pub emoji_font: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Info, MetaEncoding};
+
+    fn info() -> Info {
+        Info {
+            emoji_count: 3731,
+            emoji_directory: "/emoji".to_string(),
+            emoji_font: Some("/fonts/NotoColorEmoji.ttf".to_string()),
+        }
+    }
+
+    #[test]
+    fn round_trips_cbor() {
+        let encoded = MetaEncoding::Cbor.encode(&info());
+        let decoded: Info = ciborium::from_reader(&encoded[..]).unwrap();
+        assert_eq!(decoded, info());
+    }
+
+    #[test]
+    fn encodes_json_by_default() {
+        let encoded = MetaEncoding::default().encode(&info());
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&encoded).unwrap(),
+            serde_json::json!({
+                "emoji_count": 3731,
+                "emoji_directory": "/emoji",
+                "emoji_font": "/fonts/NotoColorEmoji.ttf",
+            })
+        );
+        assert_eq!("CBOR".parse(), Ok(MetaEncoding::Cbor));
+        assert!("msgpack".parse::<MetaEncoding>().is_err());
+    }
+}
@@ -0,0 +1,1771 @@
+// Copyright 2023 André Cipriani Bandarra
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::{
+    error::Error,
+    fmt,
+    future::Future,
+    io,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use rumqttc::{
+    v5::{
+        self,
+        mqttbytes::{
+            v5::{
+                ConnectReturnCode as V5ConnectReturnCode, Packet as V5Packet,
+                PublishProperties as V5PublishProperties,
+                SubscribeReasonCode as V5SubscribeReasonCode,
+            },
+            QoS as V5QoS,
+        },
+        StateError as V5StateError,
+    },
+    AsyncClient, ClientError, ConnAck, ConnectReturnCode, ConnectionError, Event, EventLoop,
+    Incoming, MqttOptions, Outgoing, PubAck, PubComp, PubRec, PubRel, Publish, QoS, SubAck,
+    SubscribeReasonCode, UnsubAck,
+};
+use tokio::{
+    sync::{mpsc, watch, Notify},
+    task::JoinHandle,
+};
+
+use crate::{
+    backoff::{wait_next_delay, BackoffStrategy},
+    clock::Clock,
+    config::BYTES_PER_PIXEL,
+    encoder::{OutputEncoder, OutputFormat},
+    imageutils::Compression,
+};
+
+/// Largest packet allowed by the MQTT protocol: a 256MB remaining length, plus the
+/// fixed header.
+pub const MAX_MQTT_PACKET_BYTES: usize = 268_435_455 + 5;
+
+// Longest a publish waits for room in the request channel before failing. The channel
+// stays full when the event loop can't send, eg: on a stalled connection.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Connections closed in a row by the broker before it answers, see V5EventLoop, after
+// which it is taken to only speak MQTT 3.1.1.
+const V5_ATTEMPTS_BEFORE_FALLBACK: u32 = 3;
+
+/// How frames of a size are published.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PublishSettings {
+    pub qos: QoS,
+    pub retain: bool,
+}
+
+impl Default for PublishSettings {
+    fn default() -> Self {
+        Self {
+            qos: QoS::AtLeastOnce,
+            retain: true,
+        }
+    }
+}
+
+impl PublishSettings {
+    /// Parses settings like `qos:0,retain:false`, taking the settings that are not
+    /// mentioned from `defaults`.
+    pub fn parse(settings: &str, defaults: PublishSettings) -> Result<Self, String> {
+        let mut parsed = defaults;
+        for setting in settings.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let invalid = || format!("Invalid publish setting: {}", setting);
+            let (key, value) = setting.split_once(':').ok_or_else(invalid)?;
+            match (key.trim(), value.trim()) {
+                ("qos", "0") => parsed.qos = QoS::AtMostOnce,
+                ("qos", "1") => parsed.qos = QoS::AtLeastOnce,
+                ("qos", "2") => parsed.qos = QoS::ExactlyOnce,
+                ("retain", "true" | "1") => parsed.retain = true,
+                ("retain", "false" | "0") => parsed.retain = false,
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(parsed)
+    }
+}
+
+/// Order the frames of an emoji are published in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PublishOrder {
+    /// The order of `SIZES`.
+    #[default]
+    AsConfigured,
+    /// Largest panels first, as they're the most visible.
+    LargestFirst,
+    /// Smallest panels first, eg: so they get the room left when bandwidth is capped.
+    SmallestFirst,
+}
+
+impl FromStr for PublishOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "as_configured" => Ok(PublishOrder::AsConfigured),
+            "largest_first" => Ok(PublishOrder::LargestFirst),
+            "smallest_first" => Ok(PublishOrder::SmallestFirst),
+            _ => Err(format!("Invalid publish order: {}", s)),
+        }
+    }
+}
+
+impl PublishOrder {
+    /// Sorts `items` by the area of their `size`. Items of the same area keep their order.
+    pub fn sort<T>(&self, items: &mut [T], size: impl Fn(&T) -> (u32, u32)) {
+        let area = |item: &T| {
+            let (width, height) = size(item);
+            width as u64 * height as u64
+        };
+        match self {
+            PublishOrder::AsConfigured => {}
+            PublishOrder::LargestFirst => items.sort_by_key(|item| std::cmp::Reverse(area(item))),
+            PublishOrder::SmallestFirst => items.sort_by_key(area),
+        }
+    }
+}
+
+/// Version of the MQTT protocol used to connect to the broker, see `MQTT_VERSION`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MqttVersion {
+    /// MQTT 3.1.1, which is protocol level 4.
+    #[default]
+    V4,
+    /// MQTT 5, which adds properties to published messages. Falls back to 3.1.1 when the
+    /// broker doesn't support it.
+    V5,
+}
+
+impl FromStr for MqttVersion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "4" | "3.1.1" => Ok(MqttVersion::V4),
+            "5" => Ok(MqttVersion::V5),
+            _ => Err(format!("Invalid MQTT version: {}", s)),
+        }
+    }
+}
+
+/// Properties attached to published frames, see `MQTT_CONTENT_TYPE` and
+/// `MQTT_USER_PROPERTIES`. MQTT 3.1.1 has no properties, so they're dropped on
+/// connections that fell back to it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PublishProperties {
+    pub content_type: Option<String>,
+    pub user_properties: Vec<(String, String)>,
+}
+
+impl PublishProperties {
+    /// Properties of frames encoded in `format`, then compressed with `compression` when
+    /// set: `content_type`, or the MIME type of the payload when not set, and
+    /// `user_properties` with a `format` property naming the format unless they have one.
+    pub fn for_format(
+        format: OutputFormat,
+        compression: Option<Compression>,
+        content_type: Option<String>,
+        mut user_properties: Vec<(String, String)>,
+    ) -> Self {
+        let content_type = content_type.unwrap_or_else(|| match compression {
+            Some(_) => "application/octet-stream".to_string(),
+            None => format.content_type().to_string(),
+        });
+        if !user_properties.iter().any(|(key, _)| key == "format") {
+            user_properties.push(("format".to_string(), format.name().to_string()));
+        }
+        Self {
+            content_type: Some(content_type),
+            user_properties,
+        }
+    }
+
+    /// Parses user properties in the `key=value,key=value` format, eg: `format=grb`.
+    pub fn parse_user_properties(properties: &str) -> Result<Vec<(String, String)>, String> {
+        properties
+            .split(',')
+            .map(str::trim)
+            .filter(|property| !property.is_empty())
+            .map(|property| match property.split_once('=') {
+                Some((key, value)) if !key.trim().is_empty() => {
+                    Ok((key.trim().to_string(), value.trim().to_string()))
+                }
+                _ => Err(format!("Invalid user property: {}", property)),
+            })
+            .collect()
+    }
+
+    fn to_v5(&self) -> V5PublishProperties {
+        V5PublishProperties {
+            content_type: self.content_type.clone(),
+            user_properties: self.user_properties.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Topic frames of the given size are published to, under the panel's topic prefix.
+pub fn frame_topic(prefix: &str, width: u32, height: u32) -> String {
+    format!("{}/{}x{}", prefix, width, height)
+}
+
+/// Topic subscribers publish size requests to, under the panel's topic prefix.
+pub fn request_topic(prefix: &str) -> String {
+    format!("{}/request", prefix)
+}
+
+/// Topic subscribers publish to for replaying the recent frames, see `FRAME_HISTORY`.
+pub fn replay_topic(prefix: &str) -> String {
+    format!("{}/replay", prefix)
+}
+
+/// Topic subscribers publish to for asking which emoji is shown, see
+/// `query::query_response`.
+pub fn query_topic(prefix: &str) -> String {
+    format!("{}/query", prefix)
+}
+
+/// Topic answers to queries are published to.
+pub fn query_response_topic(prefix: &str) -> String {
+    format!("{}/query/response", prefix)
+}
+
+/// Topic operators publish control commands to, eg: `freeze`, see `control::ControlCommand`.
+pub fn control_topic(prefix: &str) -> String {
+    format!("{}/control", prefix)
+}
+
+/// Size in bytes of a QoS 1 or 2 PUBLISH packet for `topic` with a `payload_len` payload.
+pub fn publish_packet_size(topic: &str, payload_len: usize) -> usize {
+    // Topic length prefix, topic and packet identifier.
+    let remaining_length = 2 + topic.len() + 2 + payload_len;
+    let length_bytes = match remaining_length {
+        0..=127 => 1,
+        128..=16_383 => 2,
+        16_384..=2_097_151 => 3,
+        _ => 4,
+    };
+    1 + length_bytes + remaining_length
+}
+
+/// Checks that the frames for every size and topic prefix fit in packets of at most
+/// `max_packet_bytes` once encoded by `encoder`, which may grow them, eg: base64.
+pub fn check_frame_packet_sizes(
+    sizes: &[(u32, u32)],
+    prefixes: &[&str],
+    encoder: &dyn OutputEncoder,
+    max_packet_bytes: usize,
+) -> Result<(), String> {
+    for &(width, height) in sizes {
+        let blank = vec![0; width as usize * height as usize * BYTES_PER_PIXEL];
+        let payload_len = encoder.encode(&blank, width, height).len();
+        for prefix in prefixes {
+            let topic = frame_topic(prefix, width, height);
+            let packet_size = publish_packet_size(&topic, payload_len);
+            if packet_size > max_packet_bytes {
+                return Err(format!(
+                    "Frames for {} need {} byte packets, more than the maximum of {} bytes",
+                    topic, packet_size, max_packet_bytes
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    Connecting,
+    Connected,
+    Disconnected,
+    /// The event loop gave up after too many consecutive connection errors.
+    Failed,
+}
+
+/// Source of MQTT events. Implemented by rumqttc's `EventLoop` and by fakes in tests.
+pub trait EventStream {
+    fn poll(&mut self) -> impl Future<Output = Result<Event, ConnectionError>> + Send;
+
+    /// Drops the connection, so the next poll connects again, returning the client that
+    /// sends requests to the new connection.
+    fn reconnect(&mut self) -> Client;
+
+    /// Client to use from now on, when the stream replaced its connection on its own
+    /// since the last call.
+    fn replaced_client(&mut self) -> Option<Client> {
+        None
+    }
+}
+
+impl EventStream for EventLoop {
+    fn poll(&mut self) -> impl Future<Output = Result<Event, ConnectionError>> + Send {
+        EventLoop::poll(self)
+    }
+
+    // The connection and the request channel of an event loop can't be reset from the
+    // outside, so this replaces both. Requests still queued are dropped.
+    fn reconnect(&mut self) -> Client {
+        let capacity = self.mqtt_options.request_channel_capacity();
+        let (client, event_loop) = AsyncClient::new(self.mqtt_options.clone(), capacity);
+        *self = event_loop;
+        Client::V4(client)
+    }
+}
+
+/// Sends requests to the event loop of a connection of either protocol version.
+#[derive(Clone)]
+pub enum Client {
+    V4(AsyncClient),
+    V5(v5::AsyncClient),
+}
+
+impl From<AsyncClient> for Client {
+    fn from(client: AsyncClient) -> Self {
+        Client::V4(client)
+    }
+}
+
+impl Client {
+    async fn publish(
+        &self,
+        topic: &str,
+        qos: QoS,
+        retain: bool,
+        payload: Vec<u8>,
+        properties: &PublishProperties,
+    ) -> Result<(), RequestError> {
+        match self {
+            Client::V4(client) => Ok(client.publish(topic, qos, retain, payload).await?),
+            Client::V5(client) => Ok(client
+                .publish_with_properties(topic, v5_qos(qos), retain, payload, properties.to_v5())
+                .await?),
+        }
+    }
+
+    fn try_subscribe(&self, filter: &str, qos: QoS) -> Result<(), RequestError> {
+        match self {
+            Client::V4(client) => Ok(client.try_subscribe(filter, qos)?),
+            Client::V5(client) => Ok(client.try_subscribe(filter, v5_qos(qos))?),
+        }
+    }
+
+    async fn subscribe(&self, filter: &str, qos: QoS) -> Result<(), RequestError> {
+        match self {
+            Client::V4(client) => Ok(client.subscribe(filter, qos).await?),
+            Client::V5(client) => Ok(client.subscribe(filter, v5_qos(qos)).await?),
+        }
+    }
+
+    pub fn try_disconnect(&self) -> Result<(), RequestError> {
+        match self {
+            Client::V4(client) => Ok(client.try_disconnect()?),
+            Client::V5(client) => Ok(client.try_disconnect()?),
+        }
+    }
+}
+
+/// The request couldn't be sent to the event loop.
+#[derive(Debug)]
+pub enum RequestError {
+    V4(ClientError),
+    V5(Box<v5::ClientError>),
+    /// The request channel stayed full for this long.
+    TimedOut(Duration),
+}
+
+impl From<ClientError> for RequestError {
+    fn from(e: ClientError) -> Self {
+        RequestError::V4(e)
+    }
+}
+
+impl From<v5::ClientError> for RequestError {
+    fn from(e: v5::ClientError) -> Self {
+        RequestError::V5(Box::new(e))
+    }
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RequestError::V4(e) => write!(f, "{}", e),
+            RequestError::V5(e) => write!(f, "{}", e),
+            RequestError::TimedOut(timeout) => {
+                write!(f, "Request channel full for {:?}", timeout)
+            }
+        }
+    }
+}
+
+impl Error for RequestError {}
+
+fn v5_qos(qos: QoS) -> V5QoS {
+    match qos {
+        QoS::AtMostOnce => V5QoS::AtMostOnce,
+        QoS::AtLeastOnce => V5QoS::AtLeastOnce,
+        QoS::ExactlyOnce => V5QoS::ExactlyOnce,
+    }
+}
+
+fn v4_qos(qos: V5QoS) -> QoS {
+    match qos {
+        V5QoS::AtMostOnce => QoS::AtMostOnce,
+        V5QoS::AtLeastOnce => QoS::AtLeastOnce,
+        V5QoS::ExactlyOnce => QoS::ExactlyOnce,
+    }
+}
+
+/// Translates an event of an MQTT v5 event loop to its MQTT 3.1.1 equivalent, dropping
+/// the properties. `None` for packets only clients send.
+pub fn v4_event(event: v5::Event) -> Option<Event> {
+    let packet = match event {
+        v5::Event::Outgoing(outgoing) => return Some(Event::Outgoing(outgoing)),
+        v5::Event::Incoming(packet) => packet,
+    };
+    let incoming = match packet {
+        V5Packet::ConnAck(ack) => {
+            let code = match ack.code {
+                V5ConnectReturnCode::Success => ConnectReturnCode::Success,
+                V5ConnectReturnCode::RefusedProtocolVersion
+                | V5ConnectReturnCode::UnsupportedProtocolVersion => {
+                    ConnectReturnCode::RefusedProtocolVersion
+                }
+                V5ConnectReturnCode::BadClientId
+                | V5ConnectReturnCode::ClientIdentifierNotValid => ConnectReturnCode::BadClientId,
+                V5ConnectReturnCode::BadUserNamePassword => ConnectReturnCode::BadUserNamePassword,
+                V5ConnectReturnCode::NotAuthorized => ConnectReturnCode::NotAuthorized,
+                _ => ConnectReturnCode::ServiceUnavailable,
+            };
+            Incoming::ConnAck(ConnAck::new(code, ack.session_present))
+        }
+        V5Packet::Publish(publish) => {
+            let mut v4 = Publish::from_bytes(
+                String::from_utf8_lossy(&publish.topic),
+                v4_qos(publish.qos),
+                publish.payload,
+            );
+            v4.dup = publish.dup;
+            v4.retain = publish.retain;
+            v4.pkid = publish.pkid;
+            Incoming::Publish(v4)
+        }
+        V5Packet::PubAck(ack) => Incoming::PubAck(PubAck::new(ack.pkid)),
+        V5Packet::PubRec(rec) => Incoming::PubRec(PubRec::new(rec.pkid)),
+        V5Packet::PubRel(rel) => Incoming::PubRel(PubRel::new(rel.pkid)),
+        V5Packet::PubComp(comp) => Incoming::PubComp(PubComp::new(comp.pkid)),
+        V5Packet::SubAck(ack) => {
+            let codes = ack
+                .return_codes
+                .into_iter()
+                .map(|code| match code {
+                    V5SubscribeReasonCode::Success(qos) => {
+                        SubscribeReasonCode::Success(v4_qos(qos))
+                    }
+                    _ => SubscribeReasonCode::Failure,
+                })
+                .collect();
+            Incoming::SubAck(SubAck::new(ack.pkid, codes))
+        }
+        V5Packet::UnsubAck(ack) => Incoming::UnsubAck(UnsubAck::new(ack.pkid)),
+        V5Packet::PingResp(_) => Incoming::PingResp,
+        V5Packet::Disconnect(_) => Incoming::Disconnect,
+        V5Packet::Connect(..)
+        | V5Packet::PingReq(_)
+        | V5Packet::Subscribe(_)
+        | V5Packet::Unsubscribe(_) => return None,
+    };
+    Some(Event::Incoming(incoming))
+}
+
+fn v4_error(e: v5::ConnectionError) -> ConnectionError {
+    match e {
+        v5::ConnectionError::Io(e) => ConnectionError::Io(e),
+        v5::ConnectionError::Timeout(_) => ConnectionError::NetworkTimeout,
+        e => ConnectionError::Io(io::Error::other(e.to_string())),
+    }
+}
+
+/// Whether the broker refused MQTT v5, either with a v5 CONNACK, from brokers that know
+/// v5, or with a 3.1.1 CONNACK, from older ones, which fails to parse as v5.
+fn refuses_v5(e: &v5::ConnectionError) -> bool {
+    matches!(
+        e,
+        v5::ConnectionError::ConnectionRefused(V5ConnectReturnCode::UnsupportedProtocolVersion)
+            | v5::ConnectionError::MqttState(V5StateError::Deserialization(_))
+    )
+}
+
+/// Whether the broker closed the connection. Brokers only speaking MQTT 3.1.1 disconnect
+/// right after their CONNACK, which is all the v5 client sees, as it is too short to be
+/// parsed. Brokers restarting, or a flaky network, look the same.
+fn is_closed(e: &v5::ConnectionError) -> bool {
+    match e {
+        v5::ConnectionError::Io(e) => matches!(
+            e.kind(),
+            io::ErrorKind::ConnectionAborted | io::ErrorKind::ConnectionReset
+        ),
+        _ => false,
+    }
+}
+
+/// Event stream of an MQTT v5 connection, see `v4_event`. Connects with MQTT 3.1.1
+/// instead when the broker doesn't support v5, replacing the client: when it refuses
+/// v5, or closes `V5_ATTEMPTS_BEFORE_FALLBACK` connections in a row before answering.
+pub struct V5EventLoop {
+    connection: VersionedEventLoop,
+    // Options to connect with MQTT 3.1.1 instead.
+    fallback: MqttOptions,
+    // Whether the broker ever accepted a v5 connection, after which it is not retried
+    // with 3.1.1.
+    accepted: bool,
+    // Connections the broker closed in a row before answering.
+    closed_attempts: u32,
+    replaced_client: Option<Client>,
+}
+
+// Boxed, as the event loops are large.
+enum VersionedEventLoop {
+    V5(Box<v5::EventLoop>),
+    V4(Box<EventLoop>),
+}
+
+impl V5EventLoop {
+    pub fn new(event_loop: v5::EventLoop, fallback: MqttOptions) -> Self {
+        Self {
+            connection: VersionedEventLoop::V5(Box::new(event_loop)),
+            fallback,
+            accepted: false,
+            closed_attempts: 0,
+            replaced_client: None,
+        }
+    }
+
+    /// Whether the connection failing with `e` shows the broker doesn't support v5,
+    /// counting the connections it closed.
+    fn is_unsupported_version(&mut self, e: &v5::ConnectionError) -> bool {
+        if self.accepted {
+            return false;
+        }
+        if !is_closed(e) {
+            self.closed_attempts = 0;
+            return refuses_v5(e);
+        }
+        self.closed_attempts += 1;
+        self.closed_attempts >= V5_ATTEMPTS_BEFORE_FALLBACK
+    }
+
+    async fn poll(&mut self) -> Result<Event, ConnectionError> {
+        loop {
+            let event_loop = match &mut self.connection {
+                VersionedEventLoop::V5(event_loop) => event_loop,
+                VersionedEventLoop::V4(event_loop) => return event_loop.poll().await,
+            };
+            match event_loop.poll().await {
+                Ok(event) => {
+                    if let v5::Event::Incoming(V5Packet::ConnAck(_)) = event {
+                        self.accepted = true;
+                    }
+                    if let Some(event) = v4_event(event) {
+                        return Ok(event);
+                    }
+                }
+                Err(e) if self.is_unsupported_version(&e) => {
+                    log::warn!(
+                        "Broker doesn't support MQTT v5, falling back to 3.1.1: {}",
+                        e
+                    );
+                    let capacity = self.fallback.request_channel_capacity();
+                    let (client, event_loop) = AsyncClient::new(self.fallback.clone(), capacity);
+                    self.connection = VersionedEventLoop::V4(Box::new(event_loop));
+                    self.replaced_client = Some(Client::V4(client));
+                    return Err(v4_error(e));
+                }
+                Err(e) => return Err(v4_error(e)),
+            }
+        }
+    }
+}
+
+impl EventStream for V5EventLoop {
+    fn poll(&mut self) -> impl Future<Output = Result<Event, ConnectionError>> + Send {
+        V5EventLoop::poll(self)
+    }
+
+    fn reconnect(&mut self) -> Client {
+        match &mut self.connection {
+            VersionedEventLoop::V5(event_loop) => {
+                let capacity = event_loop.options.request_channel_capacity();
+                let (client, new) = v5::AsyncClient::new(event_loop.options.clone(), capacity);
+                **event_loop = new;
+                Client::V5(client)
+            }
+            VersionedEventLoop::V4(event_loop) => event_loop.reconnect(),
+        }
+    }
+
+    fn replaced_client(&mut self) -> Option<Client> {
+        self.replaced_client.take()
+    }
+}
+
+/// Outcome of waiting for the first connection after `failed_attempts` attempts to
+/// connect failed, with the client now in `state`. `None` while it should keep waiting.
+pub fn first_connection_status(
+    state: ConnectionState,
+    failed_attempts: u32,
+    max_attempts: u32,
+) -> Option<Result<(), ConnectError>> {
+    match state {
+        ConnectionState::Connected => Some(Ok(())),
+        ConnectionState::Failed => Some(Err(ConnectError::Unreachable(failed_attempts))),
+        _ if failed_attempts >= max_attempts => {
+            Some(Err(ConnectError::Unreachable(failed_attempts)))
+        }
+        _ => None,
+    }
+}
+
+/// Computes the connection state after receiving `event` from the event loop.
+pub fn next_state(
+    current: ConnectionState,
+    event: &Result<Event, ConnectionError>,
+) -> ConnectionState {
+    match event {
+        Ok(Event::Incoming(Incoming::ConnAck(ack))) if ack.code == ConnectReturnCode::Success => {
+            ConnectionState::Connected
+        }
+        Ok(Event::Incoming(Incoming::ConnAck(_) | Incoming::Disconnect)) => {
+            ConnectionState::Disconnected
+        }
+        Ok(Event::Outgoing(Outgoing::Disconnect)) => ConnectionState::Disconnected,
+        Ok(_) => current,
+        Err(_) => ConnectionState::Disconnected,
+    }
+}
+
+/// Topic filters the client should be subscribed to, and where to send the messages
+/// matching them. The session is not persistent, so the broker forgets the subscriptions
+/// on every reconnect, and they are issued again from here.
+#[derive(Debug, Default)]
+struct Subscriptions {
+    subscribers: Mutex<Vec<(String, mpsc::UnboundedSender<Publish>)>>,
+}
+
+impl Subscriptions {
+    /// Sends the messages matching `filter` to `messages`, returning whether the client
+    /// needs to subscribe to it, ie: no other subscriber asked for the same filter.
+    fn add(&self, filter: &str, messages: mpsc::UnboundedSender<Publish>) -> bool {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.retain(|(_, tx)| !tx.is_closed());
+        let subscribed = subscribers.iter().any(|(other, _)| other == filter);
+        subscribers.push((filter.to_string(), messages));
+        !subscribed
+    }
+
+    /// Sends `publish` to the subscribers whose filter matches its topic, forgetting the
+    /// subscribers that went away.
+    fn dispatch(&self, publish: &Publish) {
+        self.subscribers.lock().unwrap().retain(|(filter, tx)| {
+            !rumqttc::matches(&publish.topic, filter) || tx.send(publish.clone()).is_ok()
+        });
+    }
+
+    /// Distinct filters of the subscribers still listening, in the order first asked for.
+    fn filters(&self) -> Vec<String> {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.retain(|(_, tx)| !tx.is_closed());
+        let mut filters: Vec<String> = Vec::new();
+        for (filter, _) in subscribers.iter() {
+            if !filters.contains(filter) {
+                filters.push(filter.clone());
+            }
+        }
+        filters
+    }
+
+    /// Subscribes to every filter once, needed after every reconnect.
+    fn resubscribe(&self, client: &Client) {
+        for filter in self.filters() {
+            // The event loop task can't wait for room in the request queue, as it's the
+            // one emptying it.
+            if let Err(e) = client.try_subscribe(&filter, QoS::AtLeastOnce) {
+                log::error!("Failed to subscribe to {}: {}", filter, e);
+            }
+        }
+    }
+}
+
+/// Counts consecutive failed publishes, to tell when the client looks wedged.
+#[derive(Debug, Default)]
+struct PublishFailures {
+    max: Option<u32>,
+    count: AtomicU32,
+}
+
+impl PublishFailures {
+    fn new(max: Option<u32>) -> Self {
+        Self {
+            max,
+            ..Default::default()
+        }
+    }
+
+    /// Records the outcome of a publish, returning whether it was failure number `max` in
+    /// a row. The count starts over after a success, or after reaching `max`.
+    fn record(&self, succeeded: bool) -> bool {
+        let Some(max) = self.max else {
+            return false;
+        };
+        if succeeded {
+            self.count.store(0, Ordering::Relaxed);
+            return false;
+        }
+        let failures = self.count.fetch_add(1, Ordering::Relaxed) + 1;
+        if failures < max {
+            return false;
+        }
+        self.count.store(0, Ordering::Relaxed);
+        true
+    }
+}
+
+/// Polls `stream` until it fails more than `max_reconnect_attempts` times in a row, or
+/// forever when not set, waiting between failed attempts on `clock`. Drops the connection
+/// and connects again when `reconnect` is notified, replacing `client`.
+#[allow(clippy::too_many_arguments)]
+async fn run_event_loop<S: EventStream>(
+    mut stream: S,
+    client: Arc<Mutex<Client>>,
+    subscriptions: Arc<Subscriptions>,
+    state: watch::Sender<ConnectionState>,
+    connections: Arc<AtomicU64>,
+    failed_attempts: Arc<AtomicU32>,
+    reconnect: Arc<Notify>,
+    mut backoff: Box<dyn BackoffStrategy + Send>,
+    max_reconnect_attempts: Option<u32>,
+    clock: Arc<dyn Clock>,
+) {
+    let mut failures = 0;
+    loop {
+        let notification = tokio::select! {
+            notification = stream.poll() => notification,
+            _ = reconnect.notified() => {
+                log::warn!("Reconnecting to MQTT...");
+                *client.lock().unwrap() = stream.reconnect();
+                state.send_replace(ConnectionState::Disconnected);
+                continue;
+            }
+        };
+        if let Some(replaced) = stream.replaced_client() {
+            *client.lock().unwrap() = replaced;
+        }
+        let current = *state.borrow();
+        let next = next_state(current, &notification);
+        if notification.is_err() {
+            failures += 1;
+            failed_attempts.store(failures, Ordering::Relaxed);
+        }
+        if next != current {
+            log::info!("MQTT connection state changed to {:?}", next);
+        }
+        // Failed attempts are announced even without a change, so waiters can count them.
+        if next != current || notification.is_err() {
+            state.send_replace(next);
+        }
+
+        match notification {
+            Ok(Event::Incoming(Incoming::PingResp) | Event::Outgoing(Outgoing::PingReq)) => {
+                continue
+            }
+            Ok(Event::Incoming(Incoming::ConnAck(ack))) => {
+                if ack.code == ConnectReturnCode::Success {
+                    failures = 0;
+                    failed_attempts.store(0, Ordering::Relaxed);
+                    backoff.reset();
+                    connections.fetch_add(1, Ordering::Relaxed);
+                    let client = client.lock().unwrap().clone();
+                    subscriptions.resubscribe(&client);
+                }
+                log::info!("Notification = {:?}", Incoming::ConnAck(ack));
+            }
+            Ok(Event::Incoming(Incoming::Publish(publish))) => {
+                log::debug!("Received message on {}", publish.topic);
+                subscriptions.dispatch(&publish);
+            }
+            Ok(notification) => log::info!("Notification = {:?}", notification),
+            Err(e) => {
+                if max_reconnect_attempts.is_some_and(|max| failures > max) {
+                    log::error!(
+                        "Giving up on MQTT after {} failed connection attempts: {}",
+                        failures,
+                        e
+                    );
+                    state.send_replace(ConnectionState::Failed);
+                    return;
+                }
+                log::error!("Error = {:?}", e);
+                // The event loop reconnects on the next poll, so wait to prevent a tight
+                // reconnect loop.
+                wait_next_delay(backoff.as_mut(), &*clock).await;
+            }
+        }
+    }
+}
+
+/// Reason the client failed to connect to the broker.
+#[derive(Debug)]
+pub enum ConnectError {
+    /// The broker answered with a CONNACK refusing the connection.
+    Refused(ConnectReturnCode),
+    /// The connection failed before a CONNACK was received.
+    Connection(ConnectionError),
+    TimedOut(Duration),
+    /// The broker couldn't be reached after this many attempts.
+    Unreachable(u32),
+}
+
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectError::Refused(code) => write!(f, "Connection refused by broker: {:?}", code),
+            ConnectError::Connection(e) => write!(f, "Connection failed: {}", e),
+            ConnectError::TimedOut(timeout) => {
+                write!(f, "No CONNACK received after {:?}", timeout)
+            }
+            ConnectError::Unreachable(attempts) => {
+                write!(
+                    f,
+                    "Broker unreachable after {} connection attempts",
+                    attempts
+                )
+            }
+        }
+    }
+}
+
+impl Error for ConnectError {}
+
+/// Polls `stream` until the broker acknowledges the connection, or fails to connect.
+pub async fn wait_for_connack<S: EventStream>(
+    stream: &mut S,
+    timeout: Duration,
+) -> Result<(), ConnectError> {
+    let wait = async {
+        loop {
+            match stream.poll().await {
+                Ok(Event::Incoming(Incoming::ConnAck(ack))) => {
+                    return match ack.code {
+                        ConnectReturnCode::Success => Ok(()),
+                        code => Err(ConnectError::Refused(code)),
+                    };
+                }
+                Ok(_) => continue,
+                Err(e) => return Err(ConnectError::Connection(e)),
+            }
+        }
+    };
+
+    tokio::time::timeout(timeout, wait)
+        .await
+        .unwrap_or(Err(ConnectError::TimedOut(timeout)))
+}
+
+/// Publishes messages to an MQTT broker, keeping the connection alive in the background.
+///
+/// The event loop is polled on its own task, which reconnects after errors. Publishing
+/// waits until the client is connected, so callers don't need to track the connection.
+/// When `max_reconnect_attempts` is set, the task stops after that many consecutive
+/// reconnects fail, and the state becomes `Failed`. When `max_publish_failures` is set,
+/// the client reconnects after that many publishes in a row fail, in case it is wedged,
+/// counting those that found the request channel full for `REQUEST_TIMEOUT`.
+pub struct MqttPublisher {
+    client: Arc<Mutex<Client>>,
+    subscriptions: Arc<Subscriptions>,
+    state: watch::Receiver<ConnectionState>,
+    connections: Arc<AtomicU64>,
+    failed_attempts: Arc<AtomicU32>,
+    publish_failures: PublishFailures,
+    reconnect: Arc<Notify>,
+    event_loop: JoinHandle<()>,
+}
+
+impl MqttPublisher {
+    pub fn new(
+        mut options: MqttOptions,
+        cap: usize,
+        backoff: Box<dyn BackoffStrategy + Send>,
+        max_reconnect_attempts: Option<u32>,
+        max_publish_failures: Option<u32>,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        // Reconnecting creates a new request channel with the capacity from the options.
+        options.set_request_channel_capacity(cap);
+        let (client, event_loop) = AsyncClient::new(options, cap);
+        Self::with_event_stream(
+            client,
+            event_loop,
+            backoff,
+            max_reconnect_attempts,
+            max_publish_failures,
+            clock,
+        )
+    }
+
+    /// Creates a publisher connecting with MQTT v5, or with `fallback` when the broker
+    /// doesn't support it, see `V5EventLoop`.
+    pub fn new_v5(
+        mut options: v5::MqttOptions,
+        mut fallback: MqttOptions,
+        cap: usize,
+        backoff: Box<dyn BackoffStrategy + Send>,
+        max_reconnect_attempts: Option<u32>,
+        max_publish_failures: Option<u32>,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        options.set_request_channel_capacity(cap);
+        fallback.set_request_channel_capacity(cap);
+        let (client, event_loop) = v5::AsyncClient::new(options, cap);
+        Self::with_event_stream(
+            Client::V5(client),
+            V5EventLoop::new(event_loop, fallback),
+            backoff,
+            max_reconnect_attempts,
+            max_publish_failures,
+            clock,
+        )
+    }
+
+    /// Creates a publisher driven by a custom event stream.
+    pub fn with_event_stream<S>(
+        client: impl Into<Client>,
+        stream: S,
+        backoff: Box<dyn BackoffStrategy + Send>,
+        max_reconnect_attempts: Option<u32>,
+        max_publish_failures: Option<u32>,
+        clock: Arc<dyn Clock>,
+    ) -> Self
+    where
+        S: EventStream + Send + 'static,
+    {
+        let (state_tx, state) = watch::channel(ConnectionState::Connecting);
+        let client = Arc::new(Mutex::new(client.into()));
+        let subscriptions = Arc::new(Subscriptions::default());
+        let connections = Arc::new(AtomicU64::new(0));
+        let failed_attempts = Arc::new(AtomicU32::new(0));
+        let reconnect = Arc::new(Notify::new());
+        let event_loop = tokio::spawn(run_event_loop(
+            stream,
+            client.clone(),
+            subscriptions.clone(),
+            state_tx,
+            connections.clone(),
+            failed_attempts.clone(),
+            reconnect.clone(),
+            backoff,
+            max_reconnect_attempts,
+            clock,
+        ));
+        Self {
+            client,
+            subscriptions,
+            state,
+            connections,
+            failed_attempts,
+            publish_failures: PublishFailures::new(max_publish_failures),
+            reconnect,
+            event_loop,
+        }
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        *self.state.borrow()
+    }
+
+    /// Number of times the client connected to the broker, which changes on every
+    /// reconnect.
+    pub fn connection_count(&self) -> u64 {
+        self.connections.load(Ordering::Relaxed)
+    }
+
+    /// Waits until the client is connected to the broker, or has given up reconnecting.
+    pub async fn wait_connected(&self) {
+        let mut state = self.state.clone();
+        // The sender lives as long as the event loop task, which sets the state to
+        // Failed before stopping.
+        let _ = state
+            .wait_for(|state| matches!(state, ConnectionState::Connected | ConnectionState::Failed))
+            .await;
+    }
+
+    /// Waits until the client connects to the broker for the first time, failing when it
+    /// doesn't within `timeout`, or after `max_attempts` attempts fail, see
+    /// `first_connection_status`.
+    pub async fn wait_first_connection(
+        &self,
+        timeout: Duration,
+        max_attempts: u32,
+    ) -> Result<(), ConnectError> {
+        let mut state = self.state.clone();
+        let wait = async {
+            loop {
+                let current = *state.borrow_and_update();
+                let failed_attempts = self.failed_attempts.load(Ordering::Relaxed);
+                if let Some(result) =
+                    first_connection_status(current, failed_attempts, max_attempts)
+                {
+                    return result;
+                }
+                // The event loop sets the state to Failed before stopping.
+                if state.changed().await.is_err() {
+                    return Err(ConnectError::Unreachable(failed_attempts));
+                }
+            }
+        };
+        tokio::time::timeout(timeout, wait)
+            .await
+            .unwrap_or(Err(ConnectError::TimedOut(timeout)))
+    }
+
+    /// Waits until the event loop gives up reconnecting. Never returns when there is no
+    /// reconnect limit.
+    pub async fn wait_failed(&self) {
+        let mut state = self.state.clone();
+        if state
+            .wait_for(|state| *state == ConnectionState::Failed)
+            .await
+            .is_err()
+        {
+            std::future::pending::<()>().await;
+        }
+    }
+
+    pub async fn publish(
+        &self,
+        topic: &str,
+        qos: QoS,
+        retain: bool,
+        payload: Vec<u8>,
+    ) -> Result<(), RequestError> {
+        self.publish_with_properties(topic, qos, retain, payload, &PublishProperties::default())
+            .await
+    }
+
+    /// Publishes `payload` with `properties`, which are dropped on MQTT 3.1.1 connections.
+    pub async fn publish_with_properties(
+        &self,
+        topic: &str,
+        qos: QoS,
+        retain: bool,
+        payload: Vec<u8>,
+        properties: &PublishProperties,
+    ) -> Result<(), RequestError> {
+        self.wait_connected().await;
+        let client = self.client.lock().unwrap().clone();
+        let publish = client.publish(topic, qos, retain, payload, properties);
+        let result = tokio::time::timeout(REQUEST_TIMEOUT, publish)
+            .await
+            .unwrap_or(Err(RequestError::TimedOut(REQUEST_TIMEOUT)));
+        if self.publish_failures.record(result.is_ok()) {
+            log::warn!("Too many failed publishes in a row");
+            self.reconnect.notify_one();
+        }
+        result
+    }
+
+    /// Sends the messages published to topics matching `filter` to `messages`, for as
+    /// long as it is open. The subscription is renewed every time the client connects, once
+    /// per filter however many subscribers share it.
+    pub async fn subscribe(
+        &self,
+        filter: &str,
+        messages: mpsc::UnboundedSender<Publish>,
+    ) -> Result<(), RequestError> {
+        if self.subscriptions.add(filter, messages) && self.state() == ConnectionState::Connected {
+            let client = self.client.lock().unwrap().clone();
+            client.subscribe(filter, QoS::AtLeastOnce).await?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for MqttPublisher {
+    fn drop(&mut self) {
+        self.event_loop.abort();
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::result_large_err)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use rumqttc::{
+        AsyncClient, ConnAck, ConnectReturnCode, ConnectionError, Event, EventLoop, Incoming,
+        MqttOptions, Publish, QoS, Request,
+    };
+    use tokio::sync::mpsc;
+
+    use super::{
+        Client, ConnectionState, EventStream, MqttPublisher, PublishOrder, PublishProperties,
+        PublishSettings,
+    };
+    use crate::{
+        backoff::Fixed, clock::SystemClock, encoder::OutputFormat, imageutils::Compression,
+    };
+
+    struct FakeEventStream {
+        events: mpsc::UnboundedReceiver<Result<Event, ConnectionError>>,
+        // Keeps the request channel of the clients returned by reconnect open.
+        event_loops: Vec<EventLoop>,
+    }
+
+    impl FakeEventStream {
+        fn new(events: mpsc::UnboundedReceiver<Result<Event, ConnectionError>>) -> Self {
+            Self {
+                events,
+                event_loops: Vec::new(),
+            }
+        }
+    }
+
+    impl EventStream for FakeEventStream {
+        async fn poll(&mut self) -> Result<Event, ConnectionError> {
+            match self.events.recv().await {
+                Some(event) => event,
+                None => std::future::pending().await,
+            }
+        }
+
+        fn reconnect(&mut self) -> Client {
+            let (client, event_loop) =
+                AsyncClient::new(MqttOptions::new("test", "localhost", 1883), 10);
+            self.event_loops.push(event_loop);
+            Client::V4(client)
+        }
+    }
+
+    fn connack() -> Result<Event, ConnectionError> {
+        Ok(Event::Incoming(Incoming::ConnAck(ConnAck {
+            session_present: false,
+            code: ConnectReturnCode::Success,
+        })))
+    }
+
+    fn connection_error() -> Result<Event, ConnectionError> {
+        Err(ConnectionError::Io(std::io::Error::new(
+            std::io::ErrorKind::ConnectionReset,
+            "connection reset",
+        )))
+    }
+
+    // The real event loop is returned so the client's request channel stays open.
+    fn fake_publisher(
+        max_reconnect_attempts: Option<u32>,
+        max_publish_failures: Option<u32>,
+    ) -> (
+        MqttPublisher,
+        mpsc::UnboundedSender<Result<Event, ConnectionError>>,
+        EventLoop,
+    ) {
+        let (client, event_loop) =
+            AsyncClient::new(MqttOptions::new("test", "localhost", 1883), 10);
+        let (events_tx, events) = mpsc::unbounded_channel();
+        let publisher = MqttPublisher::with_event_stream(
+            client,
+            FakeEventStream::new(events),
+            Box::new(Fixed::new(Duration::ZERO)),
+            max_reconnect_attempts,
+            max_publish_failures,
+            Arc::new(SystemClock),
+        );
+        (publisher, events_tx, event_loop)
+    }
+
+    #[test]
+    fn calculates_publish_packet_size() {
+        // 1 byte header, 1 byte length, 2 + 5 byte topic, 2 byte packet id, 3 byte payload.
+        assert_eq!(super::publish_packet_size("a/2x1", 3), 14);
+        // 32x32 RGB frame needs a 2 byte remaining length.
+        assert_eq!(
+            super::publish_packet_size("ledmoji/32x32", 3072),
+            1 + 2 + 2 + 13 + 2 + 3072
+        );
+    }
+
+    #[test]
+    fn parses_user_properties() {
+        let properties =
+            PublishProperties::parse_user_properties("format=grb, panel = 1,").unwrap();
+        assert_eq!(
+            properties,
+            vec![
+                ("format".to_string(), "grb".to_string()),
+                ("panel".to_string(), "1".to_string()),
+            ]
+        );
+        assert_eq!(
+            PublishProperties::parse_user_properties("").unwrap(),
+            vec![]
+        );
+        assert!(PublishProperties::parse_user_properties("format").is_err());
+        assert!(PublishProperties::parse_user_properties("=grb").is_err());
+    }
+
+    #[test]
+    fn derives_publish_properties_from_output_format() {
+        let properties =
+            PublishProperties::for_format(OutputFormat::Base64Json, None, None, vec![]);
+        assert_eq!(properties.content_type.as_deref(), Some("application/json"));
+        assert_eq!(
+            properties.user_properties,
+            vec![("format".to_string(), "base64_json".to_string())]
+        );
+
+        // Compressed payloads are binary, whatever the format.
+        let properties = PublishProperties::for_format(
+            OutputFormat::Base64Json,
+            Some(Compression::Rle),
+            None,
+            vec![],
+        );
+        assert_eq!(
+            properties.content_type.as_deref(),
+            Some("application/octet-stream")
+        );
+
+        // The configured properties win.
+        let configured = vec![("format".to_string(), "custom".to_string())];
+        let properties = PublishProperties::for_format(
+            OutputFormat::Grb,
+            None,
+            Some("application/x-led".to_string()),
+            configured.clone(),
+        );
+        assert_eq!(
+            properties.content_type.as_deref(),
+            Some("application/x-led")
+        );
+        assert_eq!(properties.user_properties, configured);
+    }
+
+    #[test]
+    fn translates_v5_events() {
+        use rumqttc::v5::{
+            self,
+            mqttbytes::{
+                v5::{
+                    ConnAck as V5ConnAck, ConnectReturnCode as V5Code, Packet, Publish as V5Publish,
+                },
+                QoS as V5QoS,
+            },
+        };
+
+        let connack = v5::Event::Incoming(Packet::ConnAck(V5ConnAck {
+            session_present: true,
+            code: V5Code::Success,
+            properties: None,
+        }));
+        assert_eq!(
+            super::v4_event(connack),
+            Some(Event::Incoming(Incoming::ConnAck(ConnAck {
+                session_present: true,
+                code: ConnectReturnCode::Success,
+            })))
+        );
+
+        let mut publish = V5Publish::new("ledmoji/request", V5QoS::AtLeastOnce, "48x48", None);
+        publish.retain = true;
+        let Some(Event::Incoming(Incoming::Publish(publish))) =
+            super::v4_event(v5::Event::Incoming(Packet::Publish(publish)))
+        else {
+            panic!("expected a publish");
+        };
+        assert_eq!(publish.topic, "ledmoji/request");
+        assert_eq!(publish.qos, QoS::AtLeastOnce);
+        assert!(publish.retain);
+        assert_eq!(&publish.payload[..], b"48x48");
+
+        let ping = v5::Event::Outgoing(rumqttc::Outgoing::PingReq);
+        assert_eq!(
+            super::v4_event(ping),
+            Some(Event::Outgoing(rumqttc::Outgoing::PingReq))
+        );
+    }
+
+    #[test]
+    fn parses_publish_settings() {
+        let defaults = PublishSettings::default();
+        assert_eq!(
+            PublishSettings::parse("qos:0,retain:false", defaults),
+            Ok(PublishSettings {
+                qos: QoS::AtMostOnce,
+                retain: false,
+            })
+        );
+        assert_eq!(
+            PublishSettings::parse("qos:2", defaults),
+            Ok(PublishSettings {
+                qos: QoS::ExactlyOnce,
+                retain: true,
+            })
+        );
+        assert_eq!(PublishSettings::parse("", defaults), Ok(defaults));
+        assert!(PublishSettings::parse("qos:3", defaults).is_err());
+        assert!(PublishSettings::parse("retain", defaults).is_err());
+        assert!(PublishSettings::parse("dup:true", defaults).is_err());
+    }
+
+    #[test]
+    fn sorts_sizes_by_publish_order() {
+        let sizes = [(32, 32), (8, 8), (64, 16), (128, 64), (16, 16)];
+        let sorted = |order: PublishOrder| {
+            let mut sizes = sizes;
+            order.sort(&mut sizes, |size| *size);
+            sizes
+        };
+        assert_eq!(sorted(PublishOrder::AsConfigured), sizes);
+        // 32x32 and 64x16 have the same area, so they keep their order.
+        assert_eq!(
+            sorted(PublishOrder::LargestFirst),
+            [(128, 64), (32, 32), (64, 16), (16, 16), (8, 8)]
+        );
+        assert_eq!(
+            sorted(PublishOrder::SmallestFirst),
+            [(8, 8), (16, 16), (32, 32), (64, 16), (128, 64)]
+        );
+        assert_eq!("LARGEST_FIRST".parse(), Ok(PublishOrder::LargestFirst));
+        assert!("random".parse::<PublishOrder>().is_err());
+    }
+
+    #[test]
+    fn rejects_frames_larger_than_max_packet() {
+        let sizes = [(32, 32), (128, 128)];
+        let prefixes = ["ledmoji"];
+        let max = super::MAX_MQTT_PACKET_BYTES;
+        let rgb = OutputFormat::Rgb.encoder();
+        assert!(super::check_frame_packet_sizes(&sizes, &prefixes, rgb, max).is_ok());
+        assert!(super::check_frame_packet_sizes(&sizes, &prefixes, rgb, 49_175).is_ok());
+
+        let err = super::check_frame_packet_sizes(&sizes, &prefixes, rgb, 49_174).unwrap_err();
+        assert!(err.contains("128x128"), "{}", err);
+
+        // Base64 grows the payload by a third, so the same limit is too small.
+        let base64 = OutputFormat::Base64Json.encoder();
+        let err = super::check_frame_packet_sizes(&sizes, &prefixes, base64, 49_175).unwrap_err();
+        assert!(err.contains("128x128"), "{}", err);
+        assert!(super::check_frame_packet_sizes(&sizes, &prefixes, base64, 65_600).is_ok());
+    }
+
+    #[test]
+    fn next_state_tracks_connack_and_errors() {
+        let state = super::next_state(ConnectionState::Connecting, &connack());
+        assert_eq!(state, ConnectionState::Connected);
+
+        let state = super::next_state(state, &connection_error());
+        assert_eq!(state, ConnectionState::Disconnected);
+
+        let refused = Ok(Event::Incoming(Incoming::ConnAck(ConnAck {
+            session_present: false,
+            code: ConnectReturnCode::NotAuthorized,
+        })));
+        let state = super::next_state(ConnectionState::Connecting, &refused);
+        assert_eq!(state, ConnectionState::Disconnected);
+
+        let ping = Ok(Event::Incoming(Incoming::PingResp));
+        let state = super::next_state(ConnectionState::Connected, &ping);
+        assert_eq!(state, ConnectionState::Connected);
+    }
+
+    // Broker only speaking MQTT 3.1.1, which answers v5 connections with a 3.1.1 CONNACK
+    // refusing the protocol version, like older brokers do. Returns its port.
+    async fn v4_only_broker() -> u16 {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    // Fixed header and remaining length, short enough for a single byte.
+                    let mut header = [0; 2];
+                    socket.read_exact(&mut header).await.unwrap();
+                    // Protocol name, then the level.
+                    let mut connect = vec![0; header[1] as usize];
+                    socket.read_exact(&mut connect).await.unwrap();
+                    if connect[6] == 5 {
+                        // Refuses the protocol level, then disconnects.
+                        socket.write_all(&[0x20, 2, 0, 1]).await.unwrap();
+                        return;
+                    }
+                    socket.write_all(&[0x20, 2, 0, 0]).await.unwrap();
+                    let _ = socket.read_to_end(&mut Vec::new()).await;
+                });
+            }
+        });
+        port
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_mqtt_3_when_v5_is_rejected() {
+        use rumqttc::v5;
+
+        let port = v4_only_broker().await;
+        let (_client, event_loop) =
+            v5::AsyncClient::new(v5::MqttOptions::new("test", "127.0.0.1", port), 10);
+        let fallback = MqttOptions::new("test", "127.0.0.1", port);
+        let mut stream = super::V5EventLoop::new(event_loop, fallback);
+        let timeout = Duration::from_secs(5);
+
+        // The broker closes the connection after its 3.1.1 CONNACK, like on restarts, so
+        // it takes a few attempts.
+        for _ in 1..super::V5_ATTEMPTS_BEFORE_FALLBACK {
+            let closed = tokio::time::timeout(timeout, stream.poll()).await.unwrap();
+            assert!(closed.is_err());
+            assert!(stream.replaced_client().is_none());
+        }
+        let rejected = tokio::time::timeout(timeout, stream.poll()).await.unwrap();
+        assert!(rejected.is_err());
+        assert!(matches!(stream.replaced_client(), Some(Client::V4(_))));
+        assert!(stream.replaced_client().is_none());
+
+        // The next connection uses 3.1.1, which the broker accepts.
+        let connected = tokio::time::timeout(timeout, stream.poll()).await.unwrap();
+        assert_eq!(connected.unwrap(), connack().unwrap());
+    }
+
+    #[tokio::test]
+    async fn keeps_mqtt_5_when_the_connection_is_reset() {
+        use rumqttc::v5;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // Broker closing the first connection, as if restarting, then accepting v5.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            drop(listener.accept().await.unwrap());
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut header = [0; 2];
+            socket.read_exact(&mut header).await.unwrap();
+            socket
+                .read_exact(&mut vec![0; header[1] as usize])
+                .await
+                .unwrap();
+            // No session, success and no properties.
+            socket.write_all(&[0x20, 3, 0, 0, 0]).await.unwrap();
+            let _ = socket.read_to_end(&mut Vec::new()).await;
+        });
+
+        let (_client, event_loop) =
+            v5::AsyncClient::new(v5::MqttOptions::new("test", "127.0.0.1", port), 10);
+        let fallback = MqttOptions::new("test", "127.0.0.1", port);
+        let mut stream = super::V5EventLoop::new(event_loop, fallback);
+        let timeout = Duration::from_secs(5);
+
+        let reset = tokio::time::timeout(timeout, stream.poll()).await.unwrap();
+        assert!(reset.is_err());
+        assert!(stream.replaced_client().is_none());
+        let connected = tokio::time::timeout(timeout, stream.poll()).await.unwrap();
+        assert!(matches!(
+            connected,
+            Ok(Event::Incoming(Incoming::ConnAck(_)))
+        ));
+        assert!(stream.replaced_client().is_none());
+    }
+
+    #[tokio::test]
+    async fn waits_for_connack() {
+        let (events_tx, events) = mpsc::unbounded_channel();
+        let mut stream = FakeEventStream::new(events);
+        events_tx
+            .send(Ok(Event::Outgoing(rumqttc::Outgoing::PingReq)))
+            .unwrap();
+        events_tx.send(connack()).unwrap();
+
+        let result = super::wait_for_connack(&mut stream, Duration::from_secs(1)).await;
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn reports_connect_failures() {
+        let (events_tx, events) = mpsc::unbounded_channel();
+        let mut stream = FakeEventStream::new(events);
+        let timeout = Duration::from_millis(10);
+
+        events_tx
+            .send(Ok(Event::Incoming(Incoming::ConnAck(ConnAck {
+                session_present: false,
+                code: ConnectReturnCode::BadUserNamePassword,
+            }))))
+            .unwrap();
+        let result = super::wait_for_connack(&mut stream, timeout).await;
+        assert!(matches!(
+            result,
+            Err(super::ConnectError::Refused(
+                ConnectReturnCode::BadUserNamePassword
+            ))
+        ));
+
+        events_tx.send(connection_error()).unwrap();
+        let result = super::wait_for_connack(&mut stream, timeout).await;
+        assert!(matches!(result, Err(super::ConnectError::Connection(_))));
+
+        let result = super::wait_for_connack(&mut stream, timeout).await;
+        assert!(matches!(result, Err(super::ConnectError::TimedOut(_))));
+    }
+
+    #[tokio::test]
+    async fn reconnects_after_error() {
+        let (publisher, events, _event_loop) = fake_publisher(None, None);
+        let mut state = publisher.state.clone();
+        assert_eq!(publisher.state(), ConnectionState::Connecting);
+
+        events.send(connack()).unwrap();
+        state.changed().await.unwrap();
+        assert_eq!(*state.borrow_and_update(), ConnectionState::Connected);
+
+        events.send(connection_error()).unwrap();
+        state.changed().await.unwrap();
+        assert_eq!(*state.borrow_and_update(), ConnectionState::Disconnected);
+
+        events.send(connack()).unwrap();
+        state.changed().await.unwrap();
+        assert_eq!(*state.borrow_and_update(), ConnectionState::Connected);
+    }
+
+    #[test]
+    fn first_connection_status_counts_failed_attempts() {
+        use super::{first_connection_status, ConnectError};
+
+        assert!(first_connection_status(ConnectionState::Connecting, 0, 3).is_none());
+        assert!(first_connection_status(ConnectionState::Disconnected, 2, 3).is_none());
+        assert!(matches!(
+            first_connection_status(ConnectionState::Disconnected, 3, 3),
+            Some(Err(ConnectError::Unreachable(3)))
+        ));
+        assert!(matches!(
+            first_connection_status(ConnectionState::Connected, 2, 3),
+            Some(Ok(()))
+        ));
+        assert!(matches!(
+            first_connection_status(ConnectionState::Failed, 1, 3),
+            Some(Err(ConnectError::Unreachable(1)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn waits_for_first_connection() {
+        let timeout = Duration::from_secs(1);
+        let (publisher, events, _event_loop) = fake_publisher(None, None);
+        events.send(connection_error()).unwrap();
+        events.send(connack()).unwrap();
+        publisher.wait_first_connection(timeout, 2).await.unwrap();
+
+        let (publisher, events, _event_loop) = fake_publisher(None, None);
+        for _ in 0..2 {
+            events.send(connection_error()).unwrap();
+        }
+        let result = publisher.wait_first_connection(timeout, 2).await;
+        assert!(matches!(result, Err(super::ConnectError::Unreachable(2))));
+
+        let (publisher, _events, _event_loop) = fake_publisher(None, None);
+        let result = publisher
+            .wait_first_connection(Duration::from_millis(10), 2)
+            .await;
+        assert!(matches!(result, Err(super::ConnectError::TimedOut(_))));
+    }
+
+    #[tokio::test]
+    async fn publish_waits_for_connection() {
+        let (publisher, events, _event_loop) = fake_publisher(None, None);
+        events.send(connection_error()).unwrap();
+
+        let publish = publisher.publish("ledmoji/32x32", QoS::AtLeastOnce, true, vec![0; 3]);
+        tokio::pin!(publish);
+        let pending = tokio::time::timeout(Duration::from_millis(50), &mut publish).await;
+        assert!(pending.is_err(), "publish should wait while disconnected");
+
+        events.send(connack()).unwrap();
+        publish.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_reconnect_attempts() {
+        let (publisher, events, _event_loop) = fake_publisher(Some(2), None);
+        // The counter resets after connecting.
+        for _ in 0..2 {
+            for event in [connection_error(), connection_error(), connack()] {
+                events.send(event).unwrap();
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(publisher.state(), ConnectionState::Connected);
+
+        for _ in 0..3 {
+            events.send(connection_error()).unwrap();
+        }
+
+        tokio::time::timeout(Duration::from_secs(1), publisher.wait_failed())
+            .await
+            .unwrap();
+        assert_eq!(publisher.state(), ConnectionState::Failed);
+    }
+
+    #[test]
+    fn counts_consecutive_publish_failures() {
+        let failures = super::PublishFailures::new(Some(3));
+        assert!(!failures.record(false));
+        assert!(!failures.record(false));
+        // A success starts the count over.
+        assert!(!failures.record(true));
+        assert!(!failures.record(false));
+        assert!(!failures.record(false));
+        assert!(failures.record(false));
+        // And so does reaching the threshold.
+        assert!(!failures.record(false));
+
+        let unlimited = super::PublishFailures::new(None);
+        assert!((0..100).all(|_| !unlimited.record(false)));
+    }
+
+    #[tokio::test]
+    async fn reconnects_after_max_publish_failures() {
+        let (publisher, events, event_loop) = fake_publisher(None, Some(2));
+        events.send(connack()).unwrap();
+        publisher.wait_connected().await;
+        // Publishes fail once nothing reads the request channel anymore.
+        drop(event_loop);
+
+        let mut state = publisher.state.clone();
+        state.borrow_and_update();
+        for _ in 0..2 {
+            let result = publisher.publish("ledmoji/32x32", QoS::AtLeastOnce, true, vec![0; 3]);
+            assert!(result.await.is_err());
+        }
+        state.changed().await.unwrap();
+        assert_eq!(*state.borrow_and_update(), ConnectionState::Disconnected);
+
+        // Publishing works again with the client of the new connection.
+        events.send(connack()).unwrap();
+        publisher
+            .publish("ledmoji/32x32", QoS::AtLeastOnce, true, vec![0; 3])
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn counts_publishes_to_a_full_request_channel_as_failures() {
+        let (publisher, events, _event_loop) = fake_publisher(None, Some(1));
+        events.send(connack()).unwrap();
+        publisher.wait_connected().await;
+
+        // Nothing reads the request channel, so it fills up.
+        let mut state = publisher.state.clone();
+        state.borrow_and_update();
+        let publish = || publisher.publish("ledmoji/32x32", QoS::AtLeastOnce, true, vec![0; 3]);
+        for _ in 0..10 {
+            publish().await.unwrap();
+        }
+        assert!(matches!(
+            publish().await,
+            Err(super::RequestError::TimedOut(_))
+        ));
+        state.changed().await.unwrap();
+        assert_eq!(*state.borrow_and_update(), ConnectionState::Disconnected);
+    }
+
+    #[tokio::test]
+    async fn dispatches_messages_to_subscribers() {
+        let (publisher, events, _event_loop) = fake_publisher(None, None);
+        let (requests_tx, mut requests) = mpsc::unbounded_channel();
+        publisher
+            .subscribe("ledmoji/request", requests_tx)
+            .await
+            .unwrap();
+
+        for topic in ["ledmoji/32x32", "ledmoji/request"] {
+            let publish = Publish::new(topic, QoS::AtLeastOnce, "48x48");
+            events
+                .send(Ok(Event::Incoming(Incoming::Publish(publish))))
+                .unwrap();
+        }
+
+        let message = requests.recv().await.unwrap();
+        assert_eq!(message.topic, "ledmoji/request");
+        assert_eq!(&message.payload[..], b"48x48");
+        assert!(requests.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn resubscribes_after_reconnect() {
+        // Reads the requests of the client, to see which filters it subscribes to.
+        let (requests_tx, requests) = flume::bounded(10);
+        let (events, events_rx) = mpsc::unbounded_channel();
+        let publisher = MqttPublisher::with_event_stream(
+            AsyncClient::from_senders(requests_tx),
+            FakeEventStream::new(events_rx),
+            Box::new(Fixed::new(Duration::ZERO)),
+            None,
+            None,
+            Arc::new(SystemClock),
+        );
+        let (commands_tx, _commands) = mpsc::unbounded_channel();
+        let (alerts_tx, _alerts) = mpsc::unbounded_channel();
+        for (filter, tx) in [
+            ("ledmoji/request", &commands_tx),
+            ("ledmoji/alert", &alerts_tx),
+            ("ledmoji/request", &alerts_tx),
+        ] {
+            publisher.subscribe(filter, tx.clone()).await.unwrap();
+        }
+
+        let mut subscribed = Vec::new();
+        for event in [connack(), connection_error(), connack()] {
+            events.send(event).unwrap();
+        }
+        let resubscribe = async {
+            while subscribed.len() < 4 {
+                match requests.recv_async().await.unwrap() {
+                    Request::Subscribe(subscribe) => {
+                        subscribed.extend(subscribe.filters.into_iter().map(|filter| filter.path))
+                    }
+                    request => panic!("Unexpected request {:?}", request),
+                }
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), resubscribe)
+            .await
+            .expect("Timed out waiting for the subscriptions");
+        // Each filter once per connection, even when shared by several subscribers.
+        assert_eq!(
+            subscribed,
+            [
+                "ledmoji/request",
+                "ledmoji/alert",
+                "ledmoji/request",
+                "ledmoji/alert"
+            ]
+        );
+        assert!(requests.try_recv().is_err());
+    }
+}
@@ -0,0 +1,254 @@
+// Copyright 2023 André Cipriani Bandarra
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::str::FromStr;
+
+use image::Rgb;
+use serde::{de::Error as _, Deserialize};
+use serde_json::Value;
+
+use crate::{
+    emoji::parse_codepoints,
+    imageutils::{parse_color, Transition},
+    priority::Priority,
+};
+
+/// Command sent by the backend, eg: the emoji to display.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct PayloadData {
+    pub emoji: Option<String>,
+    /// Space-separated hex codepoints of the emoji, eg: `1F44D`, for clients that can't
+    /// send emoji characters. Parsing sets `emoji` from them when it isn't set.
+    pub codepoint: Option<String>,
+    /// Emoji shown on the left and right halves of the panels, eg: for two-player
+    /// games. Both must be set, and take precedence over `emoji`.
+    pub emoji_left: Option<String>,
+    pub emoji_right: Option<String>,
+    pub countdown_secs: Option<u64>,
+    /// How the emoji replaces the previous one. A crossfade, when configured, otherwise.
+    pub transition: Option<Transition>,
+    /// Hex color the emoji is rendered onto, eg: `#001f3f`, instead of the default one.
+    pub background: Option<String>,
+    /// How opaque the emoji is, in percent, faded toward the background below 100.
+    /// Values above 100 are treated as 100.
+    pub opacity: Option<u8>,
+    /// `high` to handle the command ahead of the normal ones waiting, see `CommandQueue`.
+    #[serde(default)]
+    pub priority: Priority,
+    /// Set when the record was deleted, asking for the panel to be blanked.
+    #[serde(skip)]
+    pub clear: bool,
+}
+
+impl PayloadData {
+    pub fn clear() -> Self {
+        Self {
+            clear: true,
+            ..Default::default()
+        }
+    }
+
+    /// Returns the left and right emoji when the message splits the panels.
+    pub fn split_emoji(&self) -> Option<(&str, &str)> {
+        match (&self.emoji_left, &self.emoji_right) {
+            (Some(left), Some(right)) => Some((left, right)),
+            _ => None,
+        }
+    }
+
+    /// Parses the `background` of the message, returning `Err` with the value when it
+    /// isn't a valid hex color.
+    pub fn background_color(&self) -> Result<Option<Rgb<u8>>, &str> {
+        match &self.background {
+            Some(background) => parse_color(background).map(Some).ok_or(background),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Shape of the JSON documents received from the backend.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PayloadFormat {
+    /// Firebase event, eg: `{"path": "/", "data": {"emoji": "👍"}}`.
+    #[default]
+    Firebase,
+    /// Flat record, eg: `{"emoji_char": "👍"}`.
+    Flat,
+}
+
+impl FromStr for PayloadFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "firebase" => Ok(PayloadFormat::Firebase),
+            "flat" => Ok(PayloadFormat::Flat),
+            _ => Err(format!("Invalid payload format: {}", s)),
+        }
+    }
+}
+
+impl PayloadFormat {
+    /// Parses the JSON `data` of an event into the command it carries.
+    ///
+    /// A null record, which Firebase sends when the record is deleted, is a clear command.
+    /// A `codepoint` field is turned into the `emoji` it spells.
+    pub fn parse(&self, data: &str) -> Result<PayloadData, serde_json::Error> {
+        let value = serde_json::from_str::<Value>(data)?;
+        let value = match self {
+            PayloadFormat::Firebase => match value.get("data") {
+                Some(Value::Null) => return Ok(PayloadData::clear()),
+                Some(data) => data.clone(),
+                None => Value::Null,
+            },
+            PayloadFormat::Flat if value.is_null() => return Ok(PayloadData::clear()),
+            PayloadFormat::Flat => {
+                let mut value = value;
+                if let Some(object) = value.as_object_mut() {
+                    if let Some(emoji) = object.remove("emoji_char") {
+                        object.insert("emoji".to_string(), emoji);
+                    }
+                }
+                value
+            }
+        };
+        let mut payload = PayloadData::deserialize(value)?;
+        if let (None, Some(codepoint)) = (&payload.emoji, &payload.codepoint) {
+            let emoji = parse_codepoints(codepoint).ok_or_else(|| {
+                serde_json::Error::custom(format!("invalid codepoint {:?}", codepoint))
+            })?;
+            payload.emoji = Some(emoji);
+        }
+        Ok(payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PayloadData, PayloadFormat};
+    use crate::{imageutils::Transition, priority::Priority};
+
+    #[test]
+    fn parses_firebase_payload() {
+        let data = r#"{"path":"/","data":{"emoji":"👍"}}"#;
+        let payload = PayloadFormat::Firebase.parse(data).unwrap();
+        assert_eq!(payload.emoji.as_deref(), Some("👍"));
+    }
+
+    #[test]
+    fn parses_flat_payload() {
+        let data = r#"{"emoji_char":"👍"}"#;
+        let payload = PayloadFormat::Flat.parse(data).unwrap();
+        assert_eq!(payload.emoji.as_deref(), Some("👍"));
+    }
+
+    #[test]
+    fn parses_codepoint_payload() {
+        let data = r#"{"data":{"codepoint":"1F44D"}}"#;
+        let payload = PayloadFormat::Firebase.parse(data).unwrap();
+        assert_eq!(payload.emoji.as_deref(), Some("👍"));
+        assert!(PayloadFormat::Firebase
+            .parse(r#"{"data":{"codepoint":"thumbs up"}}"#)
+            .is_err());
+    }
+
+    #[test]
+    fn parses_split_payload() {
+        let data = r#"{"data":{"emoji_left":"👍","emoji_right":"😀"}}"#;
+        let payload = PayloadFormat::Firebase.parse(data).unwrap();
+        assert_eq!(payload.split_emoji(), Some(("👍", "😀")));
+
+        // Both halves are needed to split the panels.
+        let data = r#"{"data":{"emoji":"👍","emoji_left":"👍"}}"#;
+        let payload = PayloadFormat::Firebase.parse(data).unwrap();
+        assert_eq!(payload.split_emoji(), None);
+    }
+
+    #[test]
+    fn parses_countdown_payload() {
+        let data = r#"{"data":{"countdown_secs":60}}"#;
+        let payload = PayloadFormat::Firebase.parse(data).unwrap();
+        assert_eq!(
+            payload,
+            PayloadData {
+                countdown_secs: Some(60),
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn parses_transition() {
+        let data = r#"{"data":{"emoji":"👍","transition":"slide_left"}}"#;
+        let payload = PayloadFormat::Firebase.parse(data).unwrap();
+        assert_eq!(payload.transition, Some(Transition::SlideLeft));
+        assert!(PayloadFormat::Firebase
+            .parse(r#"{"data":{"emoji":"👍","transition":"spin"}}"#)
+            .is_err());
+    }
+
+    #[test]
+    fn parses_priority() {
+        let data = r#"{"data":{"emoji":"🚨","priority":"high"}}"#;
+        let payload = PayloadFormat::Firebase.parse(data).unwrap();
+        assert_eq!(payload.priority, Priority::High);
+        let payload = PayloadFormat::Firebase
+            .parse(r#"{"data":{"emoji":"👍"}}"#)
+            .unwrap();
+        assert_eq!(payload.priority, Priority::Normal);
+        assert!(PayloadFormat::Firebase
+            .parse(r#"{"data":{"emoji":"👍","priority":"urgent"}}"#)
+            .is_err());
+    }
+
+    #[test]
+    fn parses_background() {
+        let data = r##"{"data":{"emoji":"👍","background":"#001f3f"}}"##;
+        let payload = PayloadFormat::Firebase.parse(data).unwrap();
+        assert_eq!(
+            payload.background_color(),
+            Ok(Some(image::Rgb([0, 31, 63])))
+        );
+
+        let payload = PayloadFormat::Firebase
+            .parse(r#"{"data":{"emoji":"👍"}}"#)
+            .unwrap();
+        assert_eq!(payload.background_color(), Ok(None));
+
+        let data = r#"{"data":{"emoji":"👍","background":"navy"}}"#;
+        let payload = PayloadFormat::Firebase.parse(data).unwrap();
+        assert_eq!(payload.background_color(), Err("navy"));
+    }
+
+    #[test]
+    fn deleted_record_is_clear_command() {
+        let payload = PayloadFormat::Firebase
+            .parse(r#"{"path":"/","data":null}"#)
+            .unwrap();
+        assert_eq!(payload, PayloadData::clear());
+        assert_eq!(
+            PayloadFormat::Flat.parse("null").unwrap(),
+            PayloadData::clear()
+        );
+    }
+
+    #[test]
+    fn rejects_payload_in_wrong_format() {
+        assert!(PayloadFormat::Firebase
+            .parse(r#"{"emoji_char":"👍"}"#)
+            .is_err());
+        assert!("xml".parse::<PayloadFormat>().is_err());
+    }
+}
@@ -0,0 +1,162 @@
+// Copyright 2023 André Cipriani Bandarra
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::{collections::BTreeMap, str::FromStr};
+
+/// How frames are published over MQTT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputMode {
+    /// Every frame is a single message with the whole buffer.
+    #[default]
+    Frame,
+    /// Every pixel is its own message, see `pixel_topic`, for bridges driving addressable
+    /// LEDs one at a time.
+    PerPixel,
+}
+
+impl FromStr for OutputMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "frame" => Ok(OutputMode::Frame),
+            "per_pixel" => Ok(OutputMode::PerPixel),
+            _ => Err(format!("Invalid output mode: {}", s)),
+        }
+    }
+}
+
+/// Topic pixel `index` of the frames published to `topic` is published to, eg:
+/// `ledmoji/32x32/pixel/5`. Indexes are in strip order, see `imageutils::remap`.
+pub fn pixel_topic(topic: &str, index: usize) -> String {
+    format!("{}/pixel/{}", topic, index)
+}
+
+/// Index and color of the pixels of an RGB buffer that differ from `previous`, or of
+/// every pixel when there is no previous buffer of the same size.
+pub fn changed_pixels(previous: Option<&[u8]>, buf: &[u8]) -> Vec<(usize, [u8; 3])> {
+    let pixels = buf
+        .chunks_exact(3)
+        .map(|pixel| [pixel[0], pixel[1], pixel[2]]);
+    match previous.filter(|previous| previous.len() == buf.len()) {
+        Some(previous) => pixels
+            .zip(previous.chunks_exact(3))
+            .enumerate()
+            .filter(|(_, (pixel, previous))| pixel[..] != previous[..])
+            .map(|(index, (pixel, _))| (index, pixel))
+            .collect(),
+        None => pixels.enumerate().collect(),
+    }
+}
+
+/// Last buffer published pixel by pixel to each frame topic, so only the pixels that
+/// changed since are published.
+///
+/// Like `history::DuplicateFilter`, buffers are tagged with the connection they're sent
+/// on, and everything is forgotten when it changes, so every pixel is published again
+/// after reconnecting.
+#[derive(Debug, Default)]
+pub struct PixelHistory {
+    connection: u64,
+    frames: BTreeMap<String, Vec<u8>>,
+}
+
+impl PixelHistory {
+    pub const fn new() -> Self {
+        Self {
+            connection: 0,
+            frames: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the pixels of `buf` that changed since the last buffer published to
+    /// `topic` on `connection`. Buffers only count as published once `commit` is called,
+    /// after publishing their pixels worked.
+    pub fn changes(&mut self, topic: &str, buf: &[u8], connection: u64) -> Vec<(usize, [u8; 3])> {
+        self.follow(connection);
+        changed_pixels(self.frames.get(topic).map(Vec::as_slice), buf)
+    }
+
+    /// Records `buf` as the last buffer published to `topic` on `connection`.
+    pub fn commit(&mut self, topic: &str, buf: &[u8], connection: u64) {
+        self.follow(connection);
+        self.frames.insert(topic.to_string(), buf.to_vec());
+    }
+
+    /// Forgets the last buffer published to `topic`, so every pixel of the next one is
+    /// published, eg: when only some of the pixels were.
+    pub fn forget(&mut self, topic: &str) {
+        self.frames.remove(topic);
+    }
+
+    // Forgets the buffers published on a previous connection.
+    fn follow(&mut self, connection: u64) {
+        if connection != self.connection {
+            self.connection = connection;
+            self.frames.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{OutputMode, PixelHistory};
+
+    #[test]
+    fn publishes_only_changed_pixels_in_delta_mode() {
+        let mut history = PixelHistory::new();
+        let first = [255, 0, 0, 0, 255, 0, 0, 0, 255];
+        // Every pixel is new the first time.
+        assert_eq!(
+            history.changes("ledmoji/3x1", &first, 1),
+            [(0, [255, 0, 0]), (1, [0, 255, 0]), (2, [0, 0, 255])]
+        );
+        history.commit("ledmoji/3x1", &first, 1);
+
+        let second = [255, 0, 0, 9, 9, 9, 0, 0, 255];
+        assert_eq!(history.changes("ledmoji/3x1", &second, 1), [(1, [9, 9, 9])]);
+        history.commit("ledmoji/3x1", &second, 1);
+        assert!(history.changes("ledmoji/3x1", &second, 1).is_empty());
+
+        // Topics are tracked separately.
+        assert_eq!(history.changes("ledmoji/1x3", &second, 1).len(), 3);
+    }
+
+    #[test]
+    fn publishes_every_pixel_again_unless_committed() {
+        let mut history = PixelHistory::new();
+        let frame = [1, 2, 3, 4, 5, 6];
+        history.commit("ledmoji/2x1", &frame, 1);
+        assert!(history.changes("ledmoji/2x1", &frame, 1).is_empty());
+
+        // Changes that weren't published are published again.
+        let next = [1, 2, 3, 7, 8, 9];
+        assert_eq!(history.changes("ledmoji/2x1", &next, 1).len(), 1);
+        assert_eq!(history.changes("ledmoji/2x1", &next, 1).len(), 1);
+
+        // Reconnecting or forgetting the topic publishes every pixel.
+        assert_eq!(history.changes("ledmoji/2x1", &frame, 2).len(), 2);
+        history.commit("ledmoji/2x1", &frame, 2);
+        history.forget("ledmoji/2x1");
+        assert_eq!(history.changes("ledmoji/2x1", &frame, 2).len(), 2);
+    }
+
+    #[test]
+    fn parses_output_modes() {
+        assert_eq!("PER_PIXEL".parse(), Ok(OutputMode::PerPixel));
+        assert_eq!("frame".parse(), Ok(OutputMode::Frame));
+        assert!("pixel".parse::<OutputMode>().is_err());
+    }
+}
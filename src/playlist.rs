@@ -0,0 +1,155 @@
+// Copyright 2023 André Cipriani Bandarra
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::time::{Duration, Instant};
+
+use tokio::sync::mpsc;
+
+use crate::{
+    clock::Clock,
+    payload::PayloadData,
+    source::{EventSource, SourceError, SourceEvent},
+};
+
+/// Source id of the playlist, for routing its emoji.
+pub const PLAYLIST_SOURCE_ID: &str = "playlist";
+
+/// Fixed list of emoji shown in turn, looping back to the first after the last.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Playlist {
+    emoji: Vec<String>,
+    next: usize,
+}
+
+impl Playlist {
+    /// Parses a comma separated list, eg: `👍,😀,🎉`.
+    pub fn parse(list: &str) -> Result<Self, String> {
+        let emoji = list
+            .split(',')
+            .map(str::trim)
+            .filter(|emoji| !emoji.is_empty())
+            .map(str::to_string)
+            .collect::<Vec<_>>();
+        if emoji.is_empty() {
+            return Err(format!("Playlist has no emoji: {:?}", list));
+        }
+        Ok(Self { emoji, next: 0 })
+    }
+
+    /// Returns the emoji to show now, moving on to the next one.
+    pub fn advance(&mut self) -> &str {
+        let index = self.next;
+        self.next = (self.next + 1) % self.emoji.len();
+        &self.emoji[index]
+    }
+}
+
+/// Event source showing the emoji of a playlist, one every `interval`.
+#[derive(Debug, Clone)]
+pub struct PlaylistSource {
+    playlist: Playlist,
+    interval: Duration,
+}
+
+impl PlaylistSource {
+    pub fn new(playlist: Playlist, interval: Duration) -> Self {
+        Self { playlist, interval }
+    }
+}
+
+impl EventSource for PlaylistSource {
+    fn id(&self) -> &str {
+        PLAYLIST_SOURCE_ID
+    }
+
+    async fn run(mut self, events: mpsc::Sender<SourceEvent>) -> Result<(), SourceError> {
+        let mut ticks = tokio::time::interval(self.interval);
+        loop {
+            ticks.tick().await;
+            let event = SourceEvent {
+                source: PLAYLIST_SOURCE_ID.to_string(),
+                payload: PayloadData {
+                    emoji: Some(self.playlist.advance().to_string()),
+                    ..Default::default()
+                },
+            };
+            if events.send(event).await.is_err() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Holds back the playlist for a while after each command from a live source, so live
+/// commands stay on the panels before the playlist resumes.
+#[derive(Debug, Clone)]
+pub struct LiveOverride {
+    duration: Duration,
+    live_until: Option<Instant>,
+}
+
+impl LiveOverride {
+    pub fn new(duration: Duration) -> Self {
+        Self {
+            duration,
+            live_until: None,
+        }
+    }
+
+    /// Returns whether the event from `source` should be shown.
+    pub fn admit(&mut self, source: &str, clock: &dyn Clock) -> bool {
+        let now = clock.now();
+        if source != PLAYLIST_SOURCE_ID {
+            self.live_until = Some(now + self.duration);
+            return true;
+        }
+        self.live_until.is_none_or(|until| now >= until)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{LiveOverride, Playlist, PLAYLIST_SOURCE_ID};
+    use crate::clock::FakeClock;
+
+    #[test]
+    fn advances_and_wraps_around() {
+        let mut playlist = Playlist::parse("👍, 😀,🎉,").unwrap();
+        let shown = (0..5)
+            .map(|_| playlist.advance().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(shown, ["👍", "😀", "🎉", "👍", "😀"]);
+    }
+
+    #[test]
+    fn rejects_empty_playlists() {
+        assert!(Playlist::parse(" , ").is_err());
+    }
+
+    #[test]
+    fn live_commands_hold_back_the_playlist() {
+        let clock = FakeClock::default();
+        let mut live = LiveOverride::new(Duration::from_secs(60));
+        assert!(live.admit(PLAYLIST_SOURCE_ID, &clock));
+
+        assert!(live.admit("default", &clock));
+        clock.advance(Duration::from_secs(59));
+        assert!(!live.admit(PLAYLIST_SOURCE_ID, &clock));
+        clock.advance(Duration::from_secs(1));
+        assert!(live.admit(PLAYLIST_SOURCE_ID, &clock));
+    }
+}
@@ -0,0 +1,179 @@
+// Copyright 2023 André Cipriani Bandarra
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::collections::VecDeque;
+
+use serde::Deserialize;
+
+use crate::{router::Router, source::SourceEvent};
+
+/// How urgent a command is, see `CommandQueue`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Priority {
+    #[default]
+    Normal,
+    /// Handled before the normal commands waiting, and instead of those only for its panels.
+    High,
+}
+
+/// Commands received while an earlier one was being handled, in the order to handle
+/// them.
+///
+/// Normal commands are handled in the order they arrived. A high priority command drops
+/// the normal ones waiting that are routed only to its panels, as it would replace what
+/// they show anyway, and is handled right after the high priority commands before it.
+/// Clearing a panel is never dropped, as the record it showed is gone, and a high
+/// priority command for that panel waits for it rather than being blanked by it.
+#[derive(Debug, Default)]
+pub struct CommandQueue {
+    pending: VecDeque<SourceEvent>,
+}
+
+impl CommandQueue {
+    /// Queues `event`, with `router` telling the panels each command is shown on.
+    pub fn push(&mut self, event: SourceEvent, router: &Router) {
+        if event.payload.priority == Priority::Normal {
+            self.pending.push_back(event);
+            return;
+        }
+
+        let prefixes = router.route(&event.source);
+        let replaced = |pending: &SourceEvent| {
+            pending.payload.priority == Priority::Normal
+                && !pending.payload.clear
+                && router
+                    .route(&pending.source)
+                    .iter()
+                    .all(|prefix| prefixes.contains(prefix))
+        };
+        let waiting = self.pending.len();
+        self.pending.retain(|pending| !replaced(pending));
+        let dropped = waiting - self.pending.len();
+        if dropped > 0 {
+            log::info!(
+                "High priority command from {}. Dropping {} waiting command(s)...",
+                event.source,
+                dropped
+            );
+        }
+        let ahead = |pending: &SourceEvent| {
+            pending.payload.priority == Priority::High
+                || pending.payload.clear
+                    && router
+                        .route(&pending.source)
+                        .iter()
+                        .any(|prefix| prefixes.contains(prefix))
+        };
+        let position = self.pending.iter().rposition(ahead).map_or(0, |i| i + 1);
+        self.pending.insert(position, event);
+    }
+
+    /// Takes the next command to handle.
+    pub fn pop(&mut self) -> Option<SourceEvent> {
+        self.pending.pop_front()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CommandQueue, Priority};
+    use crate::{payload::PayloadData, router::Router, source::SourceEvent};
+
+    fn event(emoji: &str, priority: Priority) -> SourceEvent {
+        event_from("default", emoji, priority)
+    }
+
+    fn event_from(source: &str, emoji: &str, priority: Priority) -> SourceEvent {
+        SourceEvent {
+            source: source.to_string(),
+            payload: PayloadData {
+                emoji: Some(emoji.to_string()),
+                priority,
+                ..Default::default()
+            },
+        }
+    }
+
+    fn drain(queue: &mut CommandQueue) -> Vec<String> {
+        std::iter::from_fn(|| queue.pop())
+            .filter_map(|event| event.payload.emoji)
+            .collect()
+    }
+
+    #[test]
+    fn keeps_normal_commands_in_order() {
+        let router = Router::default();
+        let mut queue = CommandQueue::default();
+        assert!(queue.is_empty());
+        queue.push(event("👍", Priority::Normal), &router);
+        queue.push(event("❤️", Priority::Normal), &router);
+        assert_eq!(drain(&mut queue), vec!["👍", "❤️"]);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn high_priority_preempts_queued_normal_commands() {
+        let router = Router::default();
+        let mut queue = CommandQueue::default();
+        queue.push(event("👍", Priority::Normal), &router);
+        queue.push(event("🚨", Priority::High), &router);
+        queue.push(event("❤️", Priority::Normal), &router);
+        queue.push(event("🔥", Priority::High), &router);
+        // Normal commands arriving after a high priority one are kept, until the next.
+        assert_eq!(drain(&mut queue), vec!["🚨", "🔥"]);
+    }
+
+    #[test]
+    fn keeps_clears_and_commands_for_other_panels() {
+        let router = Router::parse("kitchen=ledmoji/kitchen,office=ledmoji/office").unwrap();
+        let mut queue = CommandQueue::default();
+        let clear = SourceEvent {
+            source: "kitchen".to_string(),
+            payload: PayloadData::clear(),
+        };
+        queue.push(clear, &router);
+        queue.push(event_from("kitchen", "👍", Priority::Normal), &router);
+        queue.push(event_from("office", "❤️", Priority::Normal), &router);
+        queue.push(event_from("kitchen", "🚨", Priority::High), &router);
+
+        let pending = std::iter::from_fn(|| queue.pop())
+            .map(|event| (event.source, event.payload.clear, event.payload.emoji))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            pending,
+            vec![
+                ("kitchen".to_string(), true, None),
+                ("kitchen".to_string(), false, Some("🚨".to_string())),
+                ("office".to_string(), false, Some("❤️".to_string())),
+            ]
+        );
+    }
+
+    #[test]
+    fn high_priority_goes_ahead_of_commands_for_other_panels() {
+        let router = Router::parse("kitchen=ledmoji/kitchen,office=ledmoji/office").unwrap();
+        let mut queue = CommandQueue::default();
+        queue.push(event_from("office", "❤️", Priority::Normal), &router);
+        queue.push(event_from("kitchen", "🚨", Priority::High), &router);
+        queue.push(event_from("office", "👍", Priority::Normal), &router);
+        queue.push(event_from("kitchen", "🔥", Priority::High), &router);
+        assert_eq!(drain(&mut queue), vec!["🚨", "🔥", "❤️", "👍"]);
+    }
+}
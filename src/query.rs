@@ -0,0 +1,102 @@
+// Copyright 2023 André Cipriani Bandarra
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use image::Rgb;
+use serde::{Deserialize, Serialize};
+
+use crate::meta::MetaEncoding;
+
+/// Emoji shown under a topic prefix, for rendering requested sizes and answering
+/// queries, see `mqtt::query_topic`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShownEmoji {
+    pub emoji: String,
+    /// Left and right emoji when the panels are split, `emoji` labeling both.
+    pub split: Option<(String, String)>,
+    /// Background of the message the emoji came with, if any.
+    pub background: Option<Rgb<u8>>,
+    /// Opacity of the emoji in percent, when the message set one.
+    pub opacity: Option<u8>,
+    pub shown_at: DateTime<Utc>,
+}
+
+/// Answer to a query for the emoji shown.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueryResponse {
+    pub emoji: String,
+    /// When the emoji was shown, in RFC 3339.
+    pub timestamp: String,
+}
+
+/// Answers a query for the emoji shown with `encoding`, eg:
+/// `{"emoji":"👍","timestamp":"2023-05-01T12:00:00Z"}` as JSON, or `null` when nothing
+/// was shown yet.
+pub fn query_response(shown: Option<&ShownEmoji>, encoding: MetaEncoding) -> Vec<u8> {
+    let response = shown.map(|shown| QueryResponse {
+        emoji: shown.emoji.clone(),
+        timestamp: shown.shown_at.to_rfc3339_opts(SecondsFormat::Secs, true),
+    });
+    encoding.encode(&response)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use super::{QueryResponse, ShownEmoji};
+    use crate::meta::MetaEncoding;
+
+    fn shown() -> ShownEmoji {
+        ShownEmoji {
+            emoji: "👍".to_string(),
+            split: None,
+            background: None,
+            opacity: None,
+            shown_at: Utc.with_ymd_and_hms(2023, 5, 1, 12, 0, 0).unwrap(),
+        }
+    }
+
+    #[test]
+    fn answers_with_the_shown_emoji() {
+        let response = super::query_response(Some(&shown()), MetaEncoding::Json);
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&response).unwrap(),
+            serde_json::json!({"emoji": "👍", "timestamp": "2023-05-01T12:00:00Z"})
+        );
+    }
+
+    #[test]
+    fn answers_null_before_any_emoji() {
+        assert_eq!(super::query_response(None, MetaEncoding::Json), b"null");
+    }
+
+    #[test]
+    fn answers_in_cbor() {
+        let response = super::query_response(Some(&shown()), MetaEncoding::Cbor);
+        let decoded: Option<QueryResponse> = ciborium::from_reader(&response[..]).unwrap();
+        assert_eq!(
+            decoded,
+            Some(QueryResponse {
+                emoji: "👍".to_string(),
+                timestamp: "2023-05-01T12:00:00Z".to_string(),
+            })
+        );
+
+        let response = super::query_response(None, MetaEncoding::Cbor);
+        let decoded: Option<QueryResponse> = ciborium::from_reader(&response[..]).unwrap();
+        assert_eq!(decoded, None);
+    }
+}
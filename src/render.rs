@@ -0,0 +1,442 @@
+// Copyright 2023 André Cipriani Bandarra
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::{error::Error, fmt, str::FromStr};
+
+use image::{imageops, imageops::FilterType, DynamicImage, Rgb, RgbImage, RgbaImage};
+
+use crate::imageutils::{blend_onto, downscale_area_linear, scale2x, scale3x, BlendSpace};
+
+/// Default color of the transparent parts of the emoji and of the padding around it.
+pub const BACKGROUND: Rgb<u8> = Rgb([0, 0, 0]);
+
+/// How an image is resized to a panel with a different aspect ratio.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ResizeMode {
+    /// Preserves the aspect ratio, fitting the whole image with padding around it.
+    #[default]
+    Fit,
+    /// Preserves the aspect ratio, covering the whole panel and cropping the center.
+    Fill,
+    /// Distorts the image to the panel size.
+    Stretch,
+}
+
+impl FromStr for ResizeMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "fit" => Ok(ResizeMode::Fit),
+            "fill" => Ok(ResizeMode::Fill),
+            "stretch" => Ok(ResizeMode::Stretch),
+            _ => Err(format!("Invalid resize mode: {}", s)),
+        }
+    }
+}
+
+/// How the pixels of an image are sampled when it is scaled to a panel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ScaleFilter {
+    /// Takes the nearest source pixel, keeping hard pixel-art edges.
+    #[default]
+    Nearest,
+    /// Averages the source pixels covered by each panel pixel in linear light, see
+    /// `imageutils::downscale_area_linear`.
+    AreaLinear,
+    /// Upscales by whole factors with Scale2x and Scale3x, rounding off the stair steps
+    /// of pixel-art emoji, see `imageutils::scale2x`. Takes the nearest source pixel for
+    /// the other sizes.
+    PixelArt,
+    /// Interpolates linearly between the nearest source pixels, softening the uneven
+    /// pixel sizes nearest neighbor gives when the scale ratio isn't a whole number.
+    Triangle,
+    /// Picks `Nearest` or `Triangle` depending on the scale ratio, see `auto_filter`.
+    Auto,
+}
+
+/// Filter `Auto` picks for scaling from `from` to `to` pixels: `Nearest` when each axis
+/// is scaled up or down by a whole factor, as every source pixel then maps to the same
+/// number of panel pixels, and `Triangle` otherwise, with the reason for the choice.
+pub fn auto_filter(from: (u32, u32), to: (u32, u32)) -> (ScaleFilter, &'static str) {
+    let whole = |from: u32, to: u32| to.is_multiple_of(from) || from.is_multiple_of(to);
+    if whole(from.0, to.0) && whole(from.1, to.1) {
+        (ScaleFilter::Nearest, "the scale ratio is a whole number")
+    } else {
+        (
+            ScaleFilter::Triangle,
+            "the scale ratio isn't a whole number",
+        )
+    }
+}
+
+/// Resizes an image to exactly `width`x`height` with `mode`, sampling with `filter`.
+/// Padding added to fit the image is transparent.
+pub fn resize(
+    img: &DynamicImage,
+    width: u32,
+    height: u32,
+    mode: ResizeMode,
+    filter: ScaleFilter,
+) -> RgbaImage {
+    let scale = |width, height| scale(img, width, height, filter);
+    match mode {
+        ResizeMode::Fit => {
+            let (scaled_width, scaled_height) = scaled_size(img, width, height, false);
+            let resized = scale(scaled_width, scaled_height);
+            let mut frame = RgbaImage::new(width, height);
+            let left = (width - resized.width()) / 2;
+            let top = (height - resized.height()) / 2;
+            imageops::replace(&mut frame, &resized, left as i64, top as i64);
+            frame
+        }
+        ResizeMode::Fill => {
+            let (scaled_width, scaled_height) = scaled_size(img, width, height, true);
+            let resized = scale(scaled_width, scaled_height);
+            let left = (scaled_width - width) / 2;
+            let top = (scaled_height - height) / 2;
+            imageops::crop_imm(&resized, left, top, width, height).to_image()
+        }
+        ResizeMode::Stretch => scale(width, height),
+    }
+}
+
+// Scales `img` to exactly `width`x`height` with `filter`.
+fn scale(img: &DynamicImage, width: u32, height: u32, filter: ScaleFilter) -> RgbaImage {
+    match filter {
+        ScaleFilter::Nearest => img
+            .resize_exact(width, height, FilterType::Nearest)
+            .to_rgba8(),
+        ScaleFilter::AreaLinear => downscale_area_linear(img, width, height),
+        ScaleFilter::PixelArt => upscale_pixel_art(img, width, height),
+        ScaleFilter::Triangle => img
+            .resize_exact(width, height, FilterType::Triangle)
+            .to_rgba8(),
+        ScaleFilter::Auto => {
+            let from = (img.width(), img.height());
+            let (filter, reason) = auto_filter(from, (width, height));
+            log::debug!(
+                "Scaling {}x{} to {}x{} with {:?}, as {}",
+                from.0,
+                from.1,
+                width,
+                height,
+                filter,
+                reason
+            );
+            scale(img, width, height, filter)
+        }
+    }
+}
+
+// Scales `img` to `width`x`height` with Scale3x and Scale2x as long as the remaining
+// factor is a multiple of 3 or 2, taking the nearest pixel for what is left, eg: 6x is
+// Scale3x then Scale2x, 5x or 1.5x is nearest neighbor.
+fn upscale_pixel_art(img: &DynamicImage, width: u32, height: u32) -> RgbaImage {
+    let mut scaled = img.clone();
+    let factor = width / img.width();
+    if factor > 1 && width == img.width() * factor && height == img.height() * factor {
+        let mut factor = factor;
+        while factor.is_multiple_of(3) {
+            scaled = scale3x(&scaled);
+            factor /= 3;
+        }
+        while factor.is_multiple_of(2) {
+            scaled = scale2x(&scaled);
+            factor /= 2;
+        }
+    }
+    if (scaled.width(), scaled.height()) == (width, height) {
+        return scaled.to_rgba8();
+    }
+    scaled
+        .resize_exact(width, height, FilterType::Nearest)
+        .to_rgba8()
+}
+
+// Size of `img` scaled to fit within, or to cover when `fill`, `width`x`height` keeping
+// its aspect ratio. Rounds like `DynamicImage::resize`.
+fn scaled_size(img: &DynamicImage, width: u32, height: u32, fill: bool) -> (u32, u32) {
+    let width_ratio = width as f64 / img.width() as f64;
+    let height_ratio = height as f64 / img.height() as f64;
+    let ratio = if fill {
+        width_ratio.max(height_ratio)
+    } else {
+        width_ratio.min(height_ratio)
+    };
+    let scaled = |size: u32| ((size as f64 * ratio).round() as u32).max(1);
+    (scaled(img.width()), scaled(img.height()))
+}
+
+/// Renders an emoji image into the RGB frame that is published for a panel.
+///
+/// The image is resized to `width`x`height` with `mode` and `filter`, and blended onto
+/// `background` in `blend`, eg: a square emoji fit on a 64x16 panel is 16x16 with 24 columns of
+/// padding on each side. The returned frame is in image order; matrix remapping happens
+/// when publishing.
+pub fn render_frame(
+    img: &DynamicImage,
+    width: u32,
+    height: u32,
+    mode: ResizeMode,
+    filter: ScaleFilter,
+    background: Rgb<u8>,
+    blend: BlendSpace,
+) -> RgbImage {
+    let resized = resize(img, width, height, mode, filter);
+    RgbImage::from_raw(width, height, blend_onto(&resized, &background, blend)).unwrap()
+}
+
+/// Reason a panel size couldn't be parsed, with the input that was rejected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseSizeError {
+    /// There is no `x` between the width and the height, eg: `32`.
+    MissingSeparator(String),
+    /// The width or the height isn't a number, eg: `axb`.
+    NotANumber(String),
+    /// The width or the height is 0, eg: `0x32`.
+    ZeroDimension(String),
+}
+
+impl fmt::Display for ParseSizeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseSizeError::MissingSeparator(size) => {
+                write!(
+                    f,
+                    "Invalid size {:?}: expected WIDTHxHEIGHT, eg: 32x32",
+                    size
+                )
+            }
+            ParseSizeError::NotANumber(size) => {
+                write!(
+                    f,
+                    "Invalid size {:?}: width and height must be numbers",
+                    size
+                )
+            }
+            ParseSizeError::ZeroDimension(size) => {
+                write!(
+                    f,
+                    "Invalid size {:?}: width and height must be at least 1",
+                    size
+                )
+            }
+        }
+    }
+}
+
+impl Error for ParseSizeError {}
+
+/// Parses a panel size like `32x32` into its width and height, ignoring surrounding
+/// whitespace.
+pub fn parse_size(size: &str) -> Result<(u32, u32), ParseSizeError> {
+    let size = size.trim();
+    let Some((width, height)) = size.split_once('x') else {
+        return Err(ParseSizeError::MissingSeparator(size.to_string()));
+    };
+    let (Ok(width), Ok(height)) = (width.parse::<u32>(), height.parse::<u32>()) else {
+        return Err(ParseSizeError::NotANumber(size.to_string()));
+    };
+    if width == 0 || height == 0 {
+        return Err(ParseSizeError::ZeroDimension(size.to_string()));
+    }
+    Ok((width, height))
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, Rgb, Rgba, RgbaImage};
+
+    use super::{ResizeMode, ScaleFilter};
+    use crate::imageutils::BlendSpace;
+
+    #[test]
+    fn centers_square_emoji_on_wide_panel() {
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_pixel(8, 8, Rgba([255, 0, 0, 255])));
+        let frame = super::render_frame(
+            &img,
+            64,
+            16,
+            ResizeMode::Fit,
+            ScaleFilter::Nearest,
+            super::BACKGROUND,
+            BlendSpace::Srgb,
+        );
+        assert_eq!(frame.dimensions(), (64, 16));
+
+        // The emoji is scaled to 16x16 and centered, with 24 columns on each side.
+        for y in 0..16 {
+            assert_eq!(frame.get_pixel(23, y), &Rgb([0, 0, 0]));
+            assert_eq!(frame.get_pixel(24, y), &Rgb([255, 0, 0]));
+            assert_eq!(frame.get_pixel(39, y), &Rgb([255, 0, 0]));
+            assert_eq!(frame.get_pixel(40, y), &Rgb([0, 0, 0]));
+        }
+        assert_eq!(frame.get_pixel(0, 0), &Rgb([0, 0, 0]));
+        assert_eq!(frame.get_pixel(63, 15), &Rgb([0, 0, 0]));
+    }
+
+    #[test]
+    fn blends_transparent_pixels_with_background() {
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_pixel(2, 2, Rgba([255, 255, 255, 0])));
+        let frame = super::render_frame(
+            &img,
+            2,
+            2,
+            ResizeMode::Fit,
+            ScaleFilter::Nearest,
+            super::BACKGROUND,
+            BlendSpace::Srgb,
+        );
+        assert!(frame.pixels().all(|pixel| pixel == &Rgb([0, 0, 0])));
+
+        let frame = super::render_frame(
+            &img,
+            2,
+            2,
+            ResizeMode::Fit,
+            ScaleFilter::Nearest,
+            Rgb([0, 31, 63]),
+            BlendSpace::Srgb,
+        );
+        assert!(frame.pixels().all(|pixel| pixel == &Rgb([0, 31, 63])));
+    }
+
+    // 4x2 image with a red left half and a blue right half.
+    fn red_blue() -> DynamicImage {
+        let mut img = RgbaImage::from_pixel(4, 2, Rgba([255, 0, 0, 255]));
+        for y in 0..2 {
+            for x in 2..4 {
+                img.put_pixel(x, y, Rgba([0, 0, 255, 255]));
+            }
+        }
+        DynamicImage::ImageRgba8(img)
+    }
+
+    fn colors(img: &RgbaImage) -> Vec<[u8; 4]> {
+        img.pixels().map(|pixel| pixel.0).collect()
+    }
+
+    const RED: [u8; 4] = [255, 0, 0, 255];
+    const BLUE: [u8; 4] = [0, 0, 255, 255];
+    const CLEAR: [u8; 4] = [0, 0, 0, 0];
+
+    #[test]
+    fn fits_image_with_padding() {
+        let resized = super::resize(&red_blue(), 2, 3, ResizeMode::Fit, ScaleFilter::Nearest);
+        assert_eq!(colors(&resized), [CLEAR, CLEAR, RED, BLUE, CLEAR, CLEAR]);
+    }
+
+    #[test]
+    fn fills_panel_cropping_center() {
+        let resized = super::resize(&red_blue(), 2, 2, ResizeMode::Fill, ScaleFilter::Nearest);
+        assert_eq!(colors(&resized), [RED, BLUE, RED, BLUE]);
+        let resized = super::resize(&red_blue(), 2, 4, ResizeMode::Fill, ScaleFilter::Nearest);
+        assert_eq!(resized.dimensions(), (2, 4));
+        assert!(colors(&resized).iter().all(|pixel| *pixel != CLEAR));
+    }
+
+    #[test]
+    fn stretches_image() {
+        let resized = super::resize(&red_blue(), 2, 4, ResizeMode::Stretch, ScaleFilter::Nearest);
+        assert_eq!(colors(&resized), [RED, BLUE].repeat(4));
+    }
+
+    #[test]
+    fn upscales_pixel_art_by_whole_factors() {
+        // A 2x2 checkerboard of red and clear, whose corners Scale2x rounds off.
+        let mut img = RgbaImage::from_pixel(2, 2, Rgba(CLEAR));
+        img.put_pixel(0, 0, Rgba(RED));
+        img.put_pixel(1, 1, Rgba(RED));
+        let img = DynamicImage::ImageRgba8(img);
+
+        let resized = super::resize(&img, 4, 4, ResizeMode::Fit, ScaleFilter::PixelArt);
+        let expected = super::scale2x(&img).to_rgba8();
+        assert_eq!(colors(&resized), colors(&expected));
+        assert_ne!(
+            resized,
+            super::resize(&img, 4, 4, ResizeMode::Fit, ScaleFilter::Nearest)
+        );
+
+        // Other factors fall back to the nearest pixel.
+        let resized = super::resize(&img, 5, 5, ResizeMode::Fit, ScaleFilter::PixelArt);
+        assert_eq!(
+            resized,
+            super::resize(&img, 5, 5, ResizeMode::Fit, ScaleFilter::Nearest)
+        );
+    }
+
+    #[test]
+    fn auto_filter_smooths_non_integer_ratios() {
+        let filter = |from, to| super::auto_filter(from, to).0;
+        // Whole factors, up or down, or none.
+        assert_eq!(filter((16, 16), (64, 64)), ScaleFilter::Nearest);
+        assert_eq!(filter((128, 128), (32, 32)), ScaleFilter::Nearest);
+        assert_eq!(filter((32, 32), (32, 32)), ScaleFilter::Nearest);
+        assert_eq!(filter((16, 16), (32, 48)), ScaleFilter::Nearest);
+
+        assert_eq!(filter((136, 128), (32, 32)), ScaleFilter::Triangle);
+        assert_eq!(filter((16, 16), (24, 24)), ScaleFilter::Triangle);
+        assert_eq!(filter((16, 16), (32, 24)), ScaleFilter::Triangle);
+
+        // The image is scaled with the chosen filter.
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_fn(2, 1, |x, _| {
+            Rgba(if x == 0 { RED } else { BLUE })
+        }));
+        let auto = super::resize(&img, 3, 1, ResizeMode::Stretch, ScaleFilter::Auto);
+        let triangle = super::resize(&img, 3, 1, ResizeMode::Stretch, ScaleFilter::Triangle);
+        assert_eq!(auto, triangle);
+        assert_ne!(
+            auto,
+            super::resize(&img, 3, 1, ResizeMode::Stretch, ScaleFilter::Nearest)
+        );
+    }
+
+    #[test]
+    fn parses_resize_modes() {
+        assert_eq!("FILL".parse(), Ok(ResizeMode::Fill));
+        assert!("crop".parse::<ResizeMode>().is_err());
+    }
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(super::parse_size("32x32"), Ok((32, 32)));
+        assert_eq!(super::parse_size(" 64x16\n"), Ok((64, 16)));
+    }
+
+    #[test]
+    fn reports_invalid_sizes() {
+        use super::ParseSizeError;
+
+        assert_eq!(
+            super::parse_size("64"),
+            Err(ParseSizeError::MissingSeparator("64".to_string()))
+        );
+        for size in ["axb", "32x", "x32", "-1x32", "32x32x32"] {
+            assert_eq!(
+                super::parse_size(size),
+                Err(ParseSizeError::NotANumber(size.to_string()))
+            );
+        }
+        assert_eq!(
+            super::parse_size("0x16"),
+            Err(ParseSizeError::ZeroDimension("0x16".to_string()))
+        );
+        assert_eq!(
+            super::parse_size("0x16").unwrap_err().to_string(),
+            "Invalid size \"0x16\": width and height must be at least 1"
+        );
+    }
+}
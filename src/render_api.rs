@@ -0,0 +1,313 @@
+// Copyright 2023 André Cipriani Bandarra
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::{
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use hyper::{
+    header::CONTENT_TYPE,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use image::RgbImage;
+
+use crate::{
+    config::Config,
+    error::DaemonError,
+    imageutils::{apply_output_limits, encode_png, render_emoji_sizes},
+    render::parse_size,
+    stats::EmojiStats,
+};
+
+// Number of emoji listed by `/stats` when the request doesn't say.
+const DEFAULT_STATS_TOP: usize = 10;
+
+/// Response produced by the render API, before being converted into an HTTP response.
+#[derive(Debug)]
+pub struct RenderResponse {
+    pub status: StatusCode,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+impl RenderResponse {
+    fn error(status: StatusCode, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            content_type: "text/plain",
+            body: message.into().into_bytes(),
+        }
+    }
+}
+
+/// Handles the query string of a `/render` request, rendering with `config`, including
+/// its color corrections. Blocks while rendering, see `serve`.
+///
+/// Supported parameters are `emoji`, `size` (eg: `32x32`, at most
+/// `Config::render_pixel_limit` pixels) and an optional `format`, which is either `rgb`
+/// (the default, raw RGB bytes) or `png`.
+pub fn handle_render(query: &str, config: &Config) -> RenderResponse {
+    let mut emoji = None;
+    let mut size = None;
+    let mut format = "rgb".to_string();
+    for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+        match key.as_ref() {
+            "emoji" => emoji = Some(value.into_owned()),
+            "size" => size = Some(value.into_owned()),
+            "format" => format = value.into_owned(),
+            _ => {}
+        }
+    }
+
+    let Some(emoji) = emoji else {
+        return RenderResponse::error(StatusCode::BAD_REQUEST, "Missing emoji parameter");
+    };
+    let Some(size) = size else {
+        return RenderResponse::error(StatusCode::BAD_REQUEST, "Missing size parameter");
+    };
+    let (width, height) = match parse_size(&size) {
+        Ok(size) => size,
+        Err(e) => return RenderResponse::error(StatusCode::BAD_REQUEST, e.to_string()),
+    };
+    if let Err(e) = config.check_render_size(width, height) {
+        return RenderResponse::error(StatusCode::BAD_REQUEST, e);
+    }
+    if format != "rgb" && format != "png" {
+        return RenderResponse::error(StatusCode::BAD_REQUEST, "Invalid format parameter");
+    }
+
+    let mut buf = match render_emoji_sizes(config, &emoji, &[(width, height)]) {
+        Ok(mut rendered) => rendered.remove(0).2,
+        Err(e @ DaemonError::InvalidEmoji(_)) => {
+            return RenderResponse::error(StatusCode::BAD_REQUEST, e.to_string());
+        }
+        Err(e @ DaemonError::NotFound(_)) => {
+            return RenderResponse::error(StatusCode::NOT_FOUND, e.to_string());
+        }
+        Err(e) => {
+            log::error!("Failed to render {}: {}", emoji, e);
+            return RenderResponse::error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
+        }
+    };
+
+    apply_output_limits(&mut buf, config);
+    let frame = RgbImage::from_raw(width, height, buf).unwrap();
+    if format == "rgb" {
+        return RenderResponse {
+            status: StatusCode::OK,
+            content_type: "application/octet-stream",
+            body: frame.into_raw(),
+        };
+    }
+
+    let body = match encode_png(frame) {
+        Ok(body) => body,
+        Err(e) => return RenderResponse::error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    };
+    RenderResponse {
+        status: StatusCode::OK,
+        content_type: "image/png",
+        body,
+    }
+}
+
+/// Handles the query string of a `/stats` request, listing the most displayed emoji as
+/// JSON, eg: `[{"emoji":"👍","count":12}]`.
+///
+/// The optional `top` parameter is how many emoji to list, 10 by default.
+pub fn handle_stats(query: &str, stats: Option<&Mutex<EmojiStats>>) -> RenderResponse {
+    let Some(stats) = stats else {
+        return RenderResponse::error(StatusCode::NOT_FOUND, "Statistics are disabled");
+    };
+    let mut top = DEFAULT_STATS_TOP;
+    for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+        if key == "top" {
+            match value.parse() {
+                Ok(value) => top = value,
+                Err(_) => {
+                    return RenderResponse::error(StatusCode::BAD_REQUEST, "Invalid top parameter")
+                }
+            }
+        }
+    }
+
+    let stats = stats.lock().unwrap();
+    let body = stats
+        .top(top)
+        .into_iter()
+        .map(|(emoji, count)| serde_json::json!({"emoji": emoji, "count": count}))
+        .collect::<Vec<_>>();
+    RenderResponse {
+        status: StatusCode::OK,
+        content_type: "application/json",
+        body: serde_json::Value::Array(body).to_string().into_bytes(),
+    }
+}
+
+async fn handle(
+    req: Request<Body>,
+    config: Arc<Config>,
+    stats: Option<&Mutex<EmojiStats>>,
+) -> Response<Body> {
+    let query = req.uri().query().unwrap_or("").to_string();
+    let response = match (req.method(), req.uri().path()) {
+        // Rendered on a blocking thread, so large panels don't stall the other tasks.
+        (&Method::GET, "/render") => {
+            tokio::task::spawn_blocking(move || handle_render(&query, &config))
+                .await
+                .unwrap_or_else(|e| {
+                    log::error!("Rendering panicked: {}", e);
+                    RenderResponse::error(StatusCode::INTERNAL_SERVER_ERROR, "Rendering failed")
+                })
+        }
+        (&Method::GET, "/stats") => handle_stats(&query, stats),
+        _ => RenderResponse::error(StatusCode::NOT_FOUND, "Not found"),
+    };
+
+    Response::builder()
+        .status(response.status)
+        .header(CONTENT_TYPE, response.content_type)
+        .body(Body::from(response.body))
+        .unwrap()
+}
+
+/// Serves the render API on `port` until the server fails. `/stats` lists the most
+/// displayed emoji from `stats`, when statistics are enabled.
+pub async fn serve(
+    port: u16,
+    config: Arc<Config>,
+    stats: Option<Arc<Mutex<EmojiStats>>>,
+) -> Result<(), hyper::Error> {
+    let addr = SocketAddr::from(([0, 0, 0, 0], port));
+    let make_service = make_service_fn(move |_| {
+        let config = config.clone();
+        let stats = stats.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let config = config.clone();
+                let stats = stats.clone();
+                async move { Ok::<_, Infallible>(handle(req, config, stats.as_deref()).await) }
+            }))
+        }
+    });
+
+    log::info!("Render API listening on {}", addr);
+    Server::bind(&addr).serve(make_service).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use hyper::StatusCode;
+
+    use crate::{config::Config, stats::EmojiStats};
+
+    fn fixture_dir() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        image::RgbaImage::from_pixel(4, 4, image::Rgba([255, 0, 0, 255]))
+            .save(dir.path().join("emoji_u1f44d.png"))
+            .unwrap();
+        dir
+    }
+
+    fn config(dir: &tempfile::TempDir) -> Config {
+        Config {
+            emoji_directory: dir.path().to_str().unwrap().to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn renders_raw_rgb() {
+        let dir = fixture_dir();
+        let response = super::handle_render("emoji=%F0%9F%91%8D&size=2x2", &config(&dir));
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(response.body, [255, 0, 0].repeat(4));
+    }
+
+    #[test]
+    fn renders_png() {
+        let dir = fixture_dir();
+        let response =
+            super::handle_render("emoji=%F0%9F%91%8D&size=2x2&format=png", &config(&dir));
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(response.content_type, "image/png");
+        let img = image::load_from_memory(&response.body).unwrap();
+        assert_eq!((img.width(), img.height()), (2, 2));
+    }
+
+    #[test]
+    fn unknown_emoji_is_not_found() {
+        let dir = fixture_dir();
+        let response = super::handle_render("emoji=%F0%9F%98%80&size=2x2", &config(&dir));
+        assert_eq!(response.status, StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn oversized_render_is_bad_request() {
+        let dir = fixture_dir();
+        let config = Config {
+            max_render_pixels: Some(64),
+            ..config(&dir)
+        };
+        let response = super::handle_render("emoji=%F0%9F%91%8D&size=8x8", &config);
+        assert_eq!(response.status, StatusCode::OK);
+        for size in ["9x8", "60000x60000"] {
+            let query = format!("emoji=%F0%9F%91%8D&size={}", size);
+            let response = super::handle_render(&query, &config);
+            assert_eq!(response.status, StatusCode::BAD_REQUEST, "size {:?}", size);
+        }
+    }
+
+    #[test]
+    fn invalid_size_is_bad_request() {
+        let dir = fixture_dir();
+        let config = config(&dir);
+        for size in ["", "32", "0x32", "axb"] {
+            let query = format!("emoji=%F0%9F%91%8D&size={}", size);
+            let response = super::handle_render(&query, &config);
+            assert_eq!(response.status, StatusCode::BAD_REQUEST, "size {:?}", size);
+        }
+    }
+
+    #[test]
+    fn lists_top_emoji() {
+        let stats = Mutex::new(EmojiStats::default());
+        for emoji in ["👍", "❤️", "👍"] {
+            stats.lock().unwrap().record(emoji);
+        }
+        let response = super::handle_stats("top=1", Some(&stats));
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&response.body).unwrap(),
+            serde_json::json!([{"emoji": "👍", "count": 2}])
+        );
+
+        let response = super::handle_stats("", None);
+        assert_eq!(response.status, StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn overlong_emoji_is_bad_request() {
+        let dir = fixture_dir();
+        let query = format!("emoji={}&size=2x2", "%F0%9F%91%8D".repeat(100));
+        let response = super::handle_render(&query, &config(&dir));
+        assert_eq!(response.status, StatusCode::BAD_REQUEST);
+    }
+}
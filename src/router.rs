@@ -0,0 +1,145 @@
+// Copyright 2023 André Cipriani Bandarra
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::collections::HashMap;
+
+/// Topic prefix frames are published under when no routes are configured.
+pub const DEFAULT_TOPIC_PREFIX: &str = "ledmoji";
+
+// Route target that sends frames to every configured panel.
+const BROADCAST: &str = "*";
+
+/// Decides which panels receive the frames rendered for each source.
+///
+/// Panels are identified by a topic prefix, and frames are published to
+/// `{prefix}/{width}x{height}`. Sources without a route go to the default prefix.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Router {
+    routes: HashMap<String, Vec<String>>,
+    broadcast_sources: Vec<String>,
+    default_prefixes: Vec<String>,
+}
+
+impl Default for Router {
+    fn default() -> Self {
+        Self {
+            routes: HashMap::new(),
+            broadcast_sources: vec![],
+            default_prefixes: vec![DEFAULT_TOPIC_PREFIX.to_string()],
+        }
+    }
+}
+
+impl Router {
+    /// Parses routes like `kitchen=ledmoji/kitchen,office=ledmoji/office|ledmoji/hall,alerts=*`.
+    ///
+    /// Each route maps a source to the `|` separated topic prefixes of its panels. A `*`
+    /// target broadcasts the source to every panel named in the other routes.
+    pub fn parse(routes: &str) -> Result<Self, String> {
+        let mut router = Router::default();
+        for route in routes.split(',').map(str::trim).filter(|r| !r.is_empty()) {
+            let Some((source, targets)) = route.split_once('=') else {
+                return Err(format!("Invalid route: {}", route));
+            };
+            let source = source.trim().to_string();
+            if source.is_empty() {
+                return Err(format!("Invalid route: {}", route));
+            }
+
+            if targets.trim() == BROADCAST {
+                router.broadcast_sources.push(source);
+                continue;
+            }
+
+            let prefixes = targets
+                .split('|')
+                .map(|prefix| prefix.trim().trim_end_matches('/').to_string())
+                .collect::<Vec<_>>();
+            if prefixes.iter().any(|prefix| prefix.is_empty()) {
+                return Err(format!("Invalid route: {}", route));
+            }
+            router.routes.entry(source).or_default().extend(prefixes);
+        }
+        Ok(router)
+    }
+
+    /// Every topic prefix that frames can be routed to.
+    pub fn all_prefixes(&self) -> Vec<&str> {
+        let mut prefixes = self
+            .routes
+            .values()
+            .flatten()
+            .map(String::as_str)
+            .collect::<Vec<_>>();
+        if prefixes.is_empty() {
+            prefixes = self.default_prefixes.iter().map(String::as_str).collect();
+        }
+        prefixes.sort_unstable();
+        prefixes.dedup();
+        prefixes
+    }
+
+    /// Topic prefixes of the panels that receive frames from `source`.
+    pub fn route(&self, source: &str) -> Vec<&str> {
+        if self.broadcast_sources.iter().any(|s| s == source) {
+            return self.all_prefixes();
+        }
+        match self.routes.get(source) {
+            Some(prefixes) => prefixes.iter().map(String::as_str).collect(),
+            None => self.default_prefixes.iter().map(String::as_str).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Router;
+
+    #[test]
+    fn routes_everything_to_default_prefix_without_routes() {
+        let router = Router::parse("").unwrap();
+        assert_eq!(router.route("default"), vec!["ledmoji"]);
+        assert_eq!(router.all_prefixes(), vec!["ledmoji"]);
+    }
+
+    #[test]
+    fn routes_sources_to_their_panels() {
+        let router =
+            Router::parse("kitchen=ledmoji/kitchen,office=ledmoji/office|ledmoji/hall").unwrap();
+        assert_eq!(router.route("kitchen"), vec!["ledmoji/kitchen"]);
+        assert_eq!(
+            router.route("office"),
+            vec!["ledmoji/office", "ledmoji/hall"]
+        );
+        assert_eq!(router.route("unknown"), vec!["ledmoji"]);
+    }
+
+    #[test]
+    fn broadcasts_to_all_panels() {
+        let router =
+            Router::parse("kitchen=ledmoji/kitchen,office=ledmoji/office,alerts=*").unwrap();
+        assert_eq!(
+            router.route("alerts"),
+            vec!["ledmoji/kitchen", "ledmoji/office"]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_routes() {
+        assert!(Router::parse("kitchen").is_err());
+        assert!(Router::parse("=ledmoji/kitchen").is_err());
+        assert!(Router::parse("kitchen=ledmoji/kitchen|").is_err());
+    }
+}
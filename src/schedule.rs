@@ -0,0 +1,115 @@
+// Copyright 2023 André Cipriani Bandarra
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::str::FromStr;
+
+use chrono::NaiveTime;
+
+/// Daily window of time, eg: `22:00-07:00`. The start is included and the end isn't,
+/// and a window ending before it starts wraps past midnight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeWindow {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl FromStr for TimeWindow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |time: &str| NaiveTime::parse_from_str(time.trim(), "%H:%M").ok();
+        s.split_once('-')
+            .and_then(|(start, end)| {
+                Some(TimeWindow {
+                    start: parse(start)?,
+                    end: parse(end)?,
+                })
+            })
+            .ok_or_else(|| format!("Invalid time window: {}", s))
+    }
+}
+
+impl TimeWindow {
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+/// Dims every published frame to `brightness` during `window`, see `NIGHT_MODE`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NightMode {
+    pub window: TimeWindow,
+    /// Factor the channels are scaled by, from 0.0 (blank) to 1.0 (unchanged).
+    pub brightness: f32,
+}
+
+impl NightMode {
+    /// Returns the brightness frames are shown with at `time`, if they're dimmed.
+    pub fn brightness_at(&self, time: NaiveTime) -> Option<f32> {
+        self.window.contains(time).then_some(self.brightness)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveTime;
+
+    use super::{NightMode, TimeWindow};
+
+    fn time(hour: u32, minute: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(hour, minute, 0).unwrap()
+    }
+
+    #[test]
+    fn contains_times_within_the_day() {
+        let window = "12:30-14:00".parse::<TimeWindow>().unwrap();
+        assert!(!window.contains(time(12, 29)));
+        assert!(window.contains(time(12, 30)));
+        assert!(window.contains(time(13, 59)));
+        assert!(!window.contains(time(14, 0)));
+    }
+
+    #[test]
+    fn wraps_past_midnight() {
+        let window = "22:00-07:00".parse::<TimeWindow>().unwrap();
+        assert!(!window.contains(time(21, 59)));
+        assert!(window.contains(time(22, 0)));
+        assert!(window.contains(time(0, 0)));
+        assert!(window.contains(time(6, 59)));
+        assert!(!window.contains(time(7, 0)));
+        assert!(!window.contains(time(12, 0)));
+    }
+
+    #[test]
+    fn dims_only_within_the_window() {
+        let night = NightMode {
+            window: "22:00-07:00".parse().unwrap(),
+            brightness: 0.1,
+        };
+        assert_eq!(night.brightness_at(time(23, 0)), Some(0.1));
+        assert_eq!(night.brightness_at(time(8, 0)), None);
+    }
+
+    #[test]
+    fn rejects_invalid_windows() {
+        for window in ["", "22:00", "22:00-25:00", "10pm-7am"] {
+            assert!(window.parse::<TimeWindow>().is_err(), "{:?}", window);
+        }
+    }
+}
@@ -0,0 +1,267 @@
+// Copyright 2023 André Cipriani Bandarra
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::{
+    fmt,
+    fs::{self, File, OpenOptions},
+    io,
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use image::RgbImage;
+
+/// Where rendered frames go.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum SinkKind {
+    /// Published to the MQTT broker, for the panels subscribed to it.
+    #[default]
+    Mqtt,
+    /// Written to a local framebuffer device, eg: `/dev/fb0`, without using MQTT.
+    Framebuffer(PathBuf),
+}
+
+impl FromStr for SinkKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s.eq_ignore_ascii_case("mqtt") => Ok(SinkKind::Mqtt),
+            Some((kind, path)) if kind.eq_ignore_ascii_case("framebuffer") && !path.is_empty() => {
+                Ok(SinkKind::Framebuffer(PathBuf::from(path)))
+            }
+            _ => Err(format!("Invalid sink: {}", s)),
+        }
+    }
+}
+
+/// Reason a frame couldn't be written to a sink.
+#[derive(Debug)]
+pub enum SinkError {
+    /// The device uses a pixel format frames can't be converted to.
+    UnsupportedFormat(String),
+    Io(io::Error),
+}
+
+impl fmt::Display for SinkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SinkError::UnsupportedFormat(format) => {
+                write!(f, "Unsupported pixel format: {}", format)
+            }
+            SinkError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for SinkError {}
+
+impl From<io::Error> for SinkError {
+    fn from(e: io::Error) -> Self {
+        SinkError::Io(e)
+    }
+}
+
+/// Local display frames are written to, instead of being published over MQTT.
+pub trait FrameSink: Send {
+    /// Size of the display, frames of other sizes are ignored.
+    fn size(&self) -> (u32, u32);
+
+    /// Shows `frame`, which is in image order and already corrected.
+    fn write_frame(&mut self, frame: &RgbImage) -> Result<(), SinkError>;
+}
+
+/// Geometry and pixel format of a framebuffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FramebufferInfo {
+    pub width: u32,
+    pub height: u32,
+    pub bits_per_pixel: u32,
+    /// Bytes from the start of one row to the start of the next.
+    pub stride: usize,
+}
+
+impl FramebufferInfo {
+    /// Reads the information of the framebuffer `name` (eg: `fb0`) from sysfs.
+    pub fn from_sysfs(name: &str) -> Result<Self, SinkError> {
+        let dir = Path::new("/sys/class/graphics").join(name);
+        let read = |attribute: &str| -> Result<String, SinkError> {
+            let value = fs::read_to_string(dir.join(attribute))?;
+            Ok(value.trim().to_string())
+        };
+        let invalid = |attribute: &str, value: &str| {
+            SinkError::Io(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid {} for {}: {}", attribute, name, value),
+            ))
+        };
+
+        let size = read("virtual_size")?;
+        let (width, height) = size
+            .split_once(',')
+            .and_then(|(width, height)| Some((width.parse().ok()?, height.parse().ok()?)))
+            .ok_or_else(|| invalid("virtual_size", &size))?;
+        let bits_per_pixel = read("bits_per_pixel")?;
+        let stride = read("stride")?;
+        Ok(Self {
+            width,
+            height,
+            bits_per_pixel: bits_per_pixel
+                .parse()
+                .map_err(|_| invalid("bits_per_pixel", &bits_per_pixel))?,
+            stride: stride.parse().map_err(|_| invalid("stride", &stride))?,
+        })
+    }
+}
+
+/// Writes frames to a Linux framebuffer device, converting them to its pixel format.
+///
+/// Supports the formats drivers use by default: RGB565 (16 bits per pixel), BGR888 (24)
+/// and XRGB8888 (32), with rows `stride` bytes apart.
+#[derive(Debug)]
+pub struct FramebufferSink {
+    device: File,
+    info: FramebufferInfo,
+}
+
+impl FramebufferSink {
+    /// Opens the framebuffer device at `path`, reading its format from sysfs.
+    pub fn open(path: &Path) -> Result<Self, SinkError> {
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| SinkError::Io(io::Error::from(io::ErrorKind::NotFound)))?;
+        let info = FramebufferInfo::from_sysfs(name)?;
+        let device = OpenOptions::new().write(true).open(path)?;
+        Self::new(device, info)
+    }
+
+    /// Writes to `device`, which has the geometry and format in `info`.
+    pub fn new(device: File, info: FramebufferInfo) -> Result<Self, SinkError> {
+        bytes_per_pixel(info.bits_per_pixel)?;
+        Ok(Self { device, info })
+    }
+}
+
+impl FrameSink for FramebufferSink {
+    fn size(&self) -> (u32, u32) {
+        (self.info.width, self.info.height)
+    }
+
+    fn write_frame(&mut self, frame: &RgbImage) -> Result<(), SinkError> {
+        if frame.dimensions() != self.size() {
+            return Ok(());
+        }
+        let bytes_per_pixel = bytes_per_pixel(self.info.bits_per_pixel)?;
+        let mut row = Vec::with_capacity(frame.width() as usize * bytes_per_pixel);
+        for (y, pixels) in frame.rows().enumerate() {
+            row.clear();
+            for pixel in pixels {
+                let [r, g, b] = pixel.0;
+                match self.info.bits_per_pixel {
+                    16 => {
+                        let rgb565 = (r as u16 >> 3) << 11 | (g as u16 >> 2) << 5 | b as u16 >> 3;
+                        row.extend_from_slice(&rgb565.to_le_bytes());
+                    }
+                    24 => row.extend_from_slice(&[b, g, r]),
+                    _ => row.extend_from_slice(&[b, g, r, 0]),
+                }
+            }
+            self.device
+                .write_all_at(&row, (y * self.info.stride) as u64)?;
+        }
+        Ok(())
+    }
+}
+
+fn bytes_per_pixel(bits_per_pixel: u32) -> Result<usize, SinkError> {
+    match bits_per_pixel {
+        16 => Ok(2),
+        24 => Ok(3),
+        32 => Ok(4),
+        bits => Err(SinkError::UnsupportedFormat(format!(
+            "{} bits per pixel",
+            bits
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use image::{Rgb, RgbImage};
+
+    use super::{FrameSink, FramebufferInfo, FramebufferSink, SinkError, SinkKind};
+
+    fn write(bits_per_pixel: u32, stride: usize, frame: &RgbImage) -> Vec<u8> {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let info = FramebufferInfo {
+            width: frame.width(),
+            height: frame.height(),
+            bits_per_pixel,
+            stride,
+        };
+        let mut sink = FramebufferSink::new(file.reopen().unwrap(), info).unwrap();
+        sink.write_frame(frame).unwrap();
+        fs::read(file.path()).unwrap()
+    }
+
+    #[test]
+    fn writes_rows_at_stride() {
+        let frame = RgbImage::from_fn(2, 2, |x, y| Rgb([x as u8 + 1, y as u8 + 1, 9]));
+        let written = write(32, 12, &frame);
+        assert_eq!(
+            written,
+            vec![
+                9, 1, 1, 0, 9, 1, 2, 0, 0, 0, 0, 0, //
+                9, 2, 1, 0, 9, 2, 2, 0,
+            ]
+        );
+    }
+
+    #[test]
+    fn converts_to_rgb565() {
+        let frame = RgbImage::from_pixel(1, 1, Rgb([255, 0, 255]));
+        assert_eq!(write(16, 2, &frame), 0xf81fu16.to_le_bytes());
+    }
+
+    #[test]
+    fn rejects_unsupported_formats() {
+        let file = tempfile::tempfile().unwrap();
+        let info = FramebufferInfo {
+            width: 1,
+            height: 1,
+            bits_per_pixel: 8,
+            stride: 1,
+        };
+        assert!(matches!(
+            FramebufferSink::new(file, info),
+            Err(SinkError::UnsupportedFormat(_))
+        ));
+    }
+
+    #[test]
+    fn parses_sinks() {
+        assert_eq!("mqtt".parse(), Ok(SinkKind::Mqtt));
+        assert_eq!(
+            "framebuffer:/dev/fb0".parse(),
+            Ok(SinkKind::Framebuffer(PathBuf::from("/dev/fb0")))
+        );
+        assert!("framebuffer".parse::<SinkKind>().is_err());
+        assert!("hdmi:/dev/fb0".parse::<SinkKind>().is_err());
+    }
+}
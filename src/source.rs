@@ -0,0 +1,102 @@
+// Copyright 2023 André Cipriani Bandarra
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::{error::Error, fmt, future::Future, str::FromStr};
+
+use tokio::sync::mpsc;
+
+use crate::payload::PayloadData;
+
+/// Source id used for the Firebase record configured with `FIREBASE_URL`.
+pub const DEFAULT_SOURCE_ID: &str = "default";
+
+/// Firebase database record to listen to, identified by `id` for routing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FirebaseSource {
+    pub id: String,
+    pub url: String,
+}
+
+/// What a source does when it keeps receiving malformed data, eg: lines that aren't
+/// server-sent event fields or payloads that aren't JSON.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MalformedPolicy {
+    /// Malformed data is logged and skipped.
+    #[default]
+    Skip,
+    /// The source reconnects after a number of malformed lines or payloads in a row.
+    Reconnect,
+}
+
+impl FromStr for MalformedPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "skip" => Ok(MalformedPolicy::Skip),
+            "reconnect" => Ok(MalformedPolicy::Reconnect),
+            _ => Err(format!("Invalid malformed data policy: {}", s)),
+        }
+    }
+}
+
+/// Command received from one of the configured sources.
+#[derive(Debug, Clone)]
+pub struct SourceEvent {
+    pub source: String,
+    pub payload: PayloadData,
+}
+
+/// Reason a source stopped producing events.
+#[derive(Debug)]
+pub enum SourceError {
+    /// Connecting failed more than the allowed number of times in a row.
+    ReconnectLimit {
+        attempts: u32,
+        last_error: Box<dyn Error + Send + Sync>,
+    },
+    Failed(Box<dyn Error + Send + Sync>),
+}
+
+impl fmt::Display for SourceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SourceError::ReconnectLimit {
+                attempts,
+                last_error,
+            } => write!(
+                f,
+                "Gave up after {} failed connection attempts: {}",
+                attempts, last_error
+            ),
+            SourceError::Failed(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for SourceError {}
+
+/// Produces the commands shown on the panels, eg: a Firebase record.
+pub trait EventSource: Send + 'static {
+    /// Id of the source, used for routing its events.
+    fn id(&self) -> &str;
+
+    /// Sends the commands from the source to `events`, until `events` is closed or the
+    /// source fails.
+    fn run(
+        self,
+        events: mpsc::Sender<SourceEvent>,
+    ) -> impl Future<Output = Result<(), SourceError>> + Send;
+}
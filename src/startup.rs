@@ -0,0 +1,85 @@
+// Copyright 2023 André Cipriani Bandarra
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::{io, time::Duration};
+
+use tokio::{net::TcpStream, time::Instant};
+
+// Time allowed for each connection attempt, and between failed attempts.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+const PROBE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Waits until a TCP connection to `host`:`port` succeeds, trying again every second,
+/// for at most `timeout` in total. The connection is closed right away.
+///
+/// Used at startup to wait for services started alongside the daemon, like the broker.
+pub async fn wait_for_tcp(host: &str, port: u16, timeout: Duration) -> io::Result<()> {
+    let deadline = Instant::now() + timeout;
+    let mut attempt = 1;
+    loop {
+        log::info!("Checking {}:{} is up (attempt {})...", host, port, attempt);
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let error = match tokio::time::timeout(
+            PROBE_TIMEOUT.min(remaining),
+            TcpStream::connect((host, port)),
+        )
+        .await
+        {
+            Ok(Ok(_)) => return Ok(()),
+            Ok(Err(e)) => e,
+            Err(_) => io::Error::from(io::ErrorKind::TimedOut),
+        };
+
+        if Instant::now() + PROBE_INTERVAL >= deadline {
+            return Err(io::Error::new(
+                error.kind(),
+                format!(
+                    "{}:{} not reachable after {:?}: {}",
+                    host, port, timeout, error
+                ),
+            ));
+        }
+        tokio::time::sleep(PROBE_INTERVAL).await;
+        attempt += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn returns_once_port_accepts_connections() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let result = super::wait_for_tcp("127.0.0.1", port, Duration::from_secs(1)).await;
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn fails_after_timeout() {
+        // Bind and drop a listener to get a port nothing listens on.
+        let port = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let result = super::wait_for_tcp("127.0.0.1", port, Duration::from_millis(100)).await;
+        assert!(result.is_err());
+    }
+}
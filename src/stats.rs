@@ -0,0 +1,108 @@
+// Copyright 2023 André Cipriani Bandarra
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::{collections::HashMap, fs, io, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+/// Number of times each emoji was displayed, saved as a JSON object from emoji to count,
+/// eg: `{"👍": 12, "❤️": 3}`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct EmojiStats {
+    counts: HashMap<String, u64>,
+}
+
+impl EmojiStats {
+    /// Loads the counts saved at `path`, or no counts when the file doesn't exist yet.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(data) => serde_json::from_str(&data).map_err(io::Error::from),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Saves the counts to `path`, through a temporary file so a crash while writing
+    /// doesn't lose the previous counts.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec(self)?)?;
+        fs::rename(&tmp, path)
+    }
+
+    /// Counts one display of `emoji`.
+    pub fn record(&mut self, emoji: &str) {
+        *self.counts.entry(emoji.to_string()).or_default() += 1;
+    }
+
+    pub fn count(&self, emoji: &str) -> u64 {
+        self.counts.get(emoji).copied().unwrap_or(0)
+    }
+
+    /// The `n` most displayed emoji with their counts, most displayed first. Ties are
+    /// ordered by emoji, so the order is stable.
+    pub fn top(&self, n: usize) -> Vec<(&str, u64)> {
+        let mut counts = self
+            .counts
+            .iter()
+            .map(|(emoji, count)| (emoji.as_str(), *count))
+            .collect::<Vec<_>>();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        counts.truncate(n);
+        counts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::EmojiStats;
+
+    #[test]
+    fn persists_counts_across_restarts() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("stats.json");
+        assert_eq!(EmojiStats::load(&path).unwrap(), EmojiStats::default());
+
+        let mut stats = EmojiStats::default();
+        stats.record("👍");
+        stats.record("👍");
+        stats.record("❤️");
+        stats.save(&path).unwrap();
+
+        let mut loaded = EmojiStats::load(&path).unwrap();
+        assert_eq!(loaded, stats);
+        loaded.record("👍");
+        assert_eq!(loaded.count("👍"), 3);
+    }
+
+    #[test]
+    fn ranks_most_displayed_first() {
+        let mut stats = EmojiStats::default();
+        for emoji in ["b", "a", "c", "c", "a", "c"] {
+            stats.record(emoji);
+        }
+        assert_eq!(stats.top(2), vec![("c", 3), ("a", 2)]);
+        assert_eq!(stats.top(10).len(), 3);
+    }
+
+    #[test]
+    fn rejects_corrupt_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("stats.json");
+        std::fs::write(&path, "not json").unwrap();
+        assert!(EmojiStats::load(&path).is_err());
+    }
+}
@@ -0,0 +1,216 @@
+// Copyright 2023 André Cipriani Bandarra
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::str::FromStr;
+
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, BufReader, Stdin},
+    sync::mpsc,
+};
+
+use crate::{
+    imageutils::parse_color,
+    payload::PayloadData,
+    source::{EventSource, SourceError, SourceEvent},
+};
+
+/// Source id of standard input, for routing its commands.
+pub const STDIN_SOURCE_ID: &str = "stdin";
+
+/// What the stdin source does once standard input is closed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EofPolicy {
+    /// The source stops, and the daemon exits once no other source is left.
+    #[default]
+    Exit,
+    /// The source keeps running, leaving the last command on the panels.
+    Idle,
+}
+
+impl FromStr for EofPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "exit" => Ok(EofPolicy::Exit),
+            "idle" => Ok(EofPolicy::Idle),
+            _ => Err(format!("Invalid end of input policy: {}", s)),
+        }
+    }
+}
+
+/// Command read from a line of standard input.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StdinCommand {
+    /// Shows the emoji, eg: `👍`.
+    Show(String),
+    /// Blanks the panels: `clear`.
+    Clear,
+    /// Renders the emoji shown after it onto a hex color: `color #001f3f` or `color #fff`.
+    Color(String),
+}
+
+impl FromStr for StdinCommand {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s == "clear" {
+            return Ok(StdinCommand::Clear);
+        }
+        match s.strip_prefix("color") {
+            Some(color) if color.is_empty() || color.starts_with(char::is_whitespace) => {
+                let color = color.trim();
+                match parse_color(color) {
+                    Some(_) => Ok(StdinCommand::Color(color.to_string())),
+                    None => Err(format!("Invalid color: {:?}", color)),
+                }
+            }
+            _ => Ok(StdinCommand::Show(s.to_string())),
+        }
+    }
+}
+
+/// Event source reading newline-delimited commands from standard input, eg: `echo 👍 |
+/// daemon`, see `StdinCommand`. Blank lines are skipped.
+pub struct StdinSource<R = BufReader<Stdin>> {
+    reader: R,
+    on_eof: EofPolicy,
+}
+
+impl StdinSource {
+    pub fn new(on_eof: EofPolicy) -> Self {
+        Self::with_reader(BufReader::new(tokio::io::stdin()), on_eof)
+    }
+}
+
+impl<R> StdinSource<R> {
+    /// Reads the commands from `reader` instead of standard input, eg: in tests.
+    pub fn with_reader(reader: R, on_eof: EofPolicy) -> Self {
+        Self { reader, on_eof }
+    }
+}
+
+impl<R: AsyncBufRead + Unpin + Send + 'static> EventSource for StdinSource<R> {
+    fn id(&self) -> &str {
+        STDIN_SOURCE_ID
+    }
+
+    async fn run(self, events: mpsc::Sender<SourceEvent>) -> Result<(), SourceError> {
+        let mut lines = self.reader.lines();
+        let mut background = None;
+        while let Some(line) = lines
+            .next_line()
+            .await
+            .map_err(|e| SourceError::Failed(e.into()))?
+        {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let payload = match line.parse() {
+                Ok(StdinCommand::Show(emoji)) => PayloadData {
+                    emoji: Some(emoji),
+                    background: background.clone(),
+                    ..Default::default()
+                },
+                Ok(StdinCommand::Clear) => PayloadData::clear(),
+                Ok(StdinCommand::Color(color)) => {
+                    background = Some(color);
+                    continue;
+                }
+                Err(e) => {
+                    log::warn!("Rejected line {:?} from standard input: {}", line, e);
+                    continue;
+                }
+            };
+            let event = SourceEvent {
+                source: STDIN_SOURCE_ID.to_string(),
+                payload,
+            };
+            if events.send(event).await.is_err() {
+                return Ok(());
+            }
+        }
+
+        match self.on_eof {
+            EofPolicy::Exit => log::info!("Standard input closed. Stopping..."),
+            EofPolicy::Idle => {
+                log::info!("Standard input closed. Idling...");
+                events.closed().await;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use tokio::sync::mpsc;
+
+    use super::{EofPolicy, StdinCommand, StdinSource};
+    use crate::source::EventSource;
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!("👍".parse(), Ok(StdinCommand::Show("👍".to_string())));
+        assert_eq!(" clear ".parse(), Ok(StdinCommand::Clear));
+        assert_eq!(
+            "color #001f3f".parse(),
+            Ok(StdinCommand::Color("#001f3f".to_string()))
+        );
+        assert_eq!(
+            "color #fff".parse(),
+            Ok(StdinCommand::Color("#fff".to_string()))
+        );
+        assert!("color".parse::<StdinCommand>().is_err());
+        assert!("color navy".parse::<StdinCommand>().is_err());
+    }
+
+    #[tokio::test]
+    async fn sends_commands_from_lines() {
+        let input = Cursor::new("👍\n\ncolor #001f3f\n😀\nclear\n");
+        let (events_tx, mut events) = mpsc::channel(4);
+        let source = StdinSource::with_reader(input, EofPolicy::Exit);
+        source.run(events_tx).await.unwrap();
+
+        let event = events.recv().await.unwrap();
+        assert_eq!(event.source, "stdin");
+        assert_eq!(event.payload.emoji.as_deref(), Some("👍"));
+        assert_eq!(event.payload.background, None);
+
+        // The color applies to the emoji after it.
+        let event = events.recv().await.unwrap();
+        assert_eq!(event.payload.emoji.as_deref(), Some("😀"));
+        assert_eq!(event.payload.background.as_deref(), Some("#001f3f"));
+
+        assert!(events.recv().await.unwrap().payload.clear);
+        // The source stopped at the end of the input.
+        assert!(events.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn idles_after_end_of_input() {
+        let (events_tx, events) = mpsc::channel(1);
+        let source = StdinSource::with_reader(Cursor::new(""), EofPolicy::Idle);
+        let task = tokio::spawn(source.run(events_tx));
+        tokio::task::yield_now().await;
+        assert!(!task.is_finished());
+
+        drop(events);
+        task.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,154 @@
+// Copyright 2023 André Cipriani Bandarra
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Tracing spans around the stages of the pipeline, exported with OTLP when the `otel`
+//! feature is enabled and `OTEL_EXPORTER_OTLP_ENDPOINT` is set. Without the feature,
+//! spans do nothing.
+
+use std::future::Future;
+
+#[cfg(feature = "otel")]
+use std::error::Error;
+
+#[cfg(feature = "otel")]
+use tracing::{field::Empty, Instrument};
+
+/// Span around one stage of the pipeline.
+#[derive(Debug, Clone)]
+pub struct Span {
+    #[cfg(feature = "otel")]
+    inner: tracing::Span,
+}
+
+impl Span {
+    /// Span for handling a command received from `source`.
+    pub fn receive_event(source: &str, emoji: Option<&str>) -> Self {
+        #[cfg(not(feature = "otel"))]
+        let _ = (source, emoji);
+        Self {
+            #[cfg(feature = "otel")]
+            inner: tracing::info_span!("receive_event", source, emoji),
+        }
+    }
+
+    /// Span for rendering `emoji` for every size.
+    pub fn render(emoji: &str) -> Self {
+        #[cfg(not(feature = "otel"))]
+        let _ = emoji;
+        Self {
+            #[cfg(feature = "otel")]
+            inner: tracing::info_span!("render", emoji),
+        }
+    }
+
+    /// Span for publishing a frame of `emoji` (or what the frame shows, eg: `blank`) to
+    /// `topic`, see `record_publish`.
+    pub fn publish(topic: &str, emoji: &str) -> Self {
+        #[cfg(not(feature = "otel"))]
+        let _ = (topic, emoji);
+        Self {
+            #[cfg(feature = "otel")]
+            inner: tracing::info_span!(
+                "publish",
+                topic,
+                emoji,
+                bytes = Empty,
+                success = Empty,
+                error = Empty
+            ),
+        }
+    }
+
+    /// Records the outcome of a publish span, with the size of the payload.
+    pub fn record_publish(&self, bytes: usize, error: Option<&dyn std::fmt::Display>) {
+        #[cfg(not(feature = "otel"))]
+        let _ = (bytes, error);
+        #[cfg(feature = "otel")]
+        {
+            self.inner.record("bytes", bytes);
+            self.inner.record("success", error.is_none());
+            if let Some(error) = error {
+                self.inner.record("error", tracing::field::display(error));
+            }
+        }
+    }
+
+    /// Runs `f` within the span.
+    pub fn in_scope<T>(&self, f: impl FnOnce() -> T) -> T {
+        #[cfg(feature = "otel")]
+        return self.inner.in_scope(f);
+        #[cfg(not(feature = "otel"))]
+        f()
+    }
+
+    /// Runs `future` within the span.
+    pub async fn instrument<F: Future>(&self, future: F) -> F::Output {
+        #[cfg(feature = "otel")]
+        return future.instrument(self.inner.clone()).await;
+        #[cfg(not(feature = "otel"))]
+        future.await
+    }
+}
+
+/// Flushes the exported spans when dropped.
+#[cfg(feature = "otel")]
+pub struct TelemetryGuard {
+    provider: opentelemetry_sdk::trace::SdkTracerProvider,
+}
+
+#[cfg(feature = "otel")]
+impl Drop for TelemetryGuard {
+    fn drop(&mut self) {
+        if let Err(e) = self.provider.shutdown() {
+            log::error!("Failed to flush spans: {}", e);
+        }
+    }
+}
+
+/// Exports the spans with OTLP over HTTP. The exporter reads its settings from the
+/// standard variables, eg: `OTEL_EXPORTER_OTLP_ENDPOINT` and `OTEL_SERVICE_NAME`.
+///
+/// Must be called from within the Tokio runtime.
+#[cfg(feature = "otel")]
+pub fn init() -> Result<TelemetryGuard, Box<dyn Error>> {
+    use opentelemetry::trace::TracerProvider;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .build()?;
+    let provider = opentelemetry_sdk::trace::SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .build();
+    let layer = tracing_opentelemetry::layer().with_tracer(provider.tracer("ledmoji"));
+    tracing::subscriber::set_global_default(tracing_subscriber::registry().with(layer))?;
+    Ok(TelemetryGuard { provider })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Span;
+
+    #[tokio::test]
+    async fn runs_stages_within_spans() {
+        let span = Span::receive_event("default", Some("👍"));
+        assert_eq!(span.in_scope(|| Span::render("👍").in_scope(|| 1 + 1)), 2);
+
+        let publish = Span::publish("ledmoji/32x32", "👍");
+        let bytes = span.instrument(async { 3 * 32 * 32 }).await;
+        publish.record_publish(bytes, None);
+        publish.record_publish(0, Some(&"Connection closed"));
+    }
+}
@@ -0,0 +1,176 @@
+// Copyright 2023 André Cipriani Bandarra
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::{error::Error, fs::File, io::BufReader, path::Path};
+
+use rumqttc::tokio_rustls::rustls::{
+    sign, Certificate, ClientConfig, PrivateKey, RootCertStore, SignatureScheme,
+};
+use rustls_pemfile::Item;
+
+// Message signed with the client key to check it belongs to the client certificate.
+const KEY_PROBE: &[u8] = b"ledmoji client key check";
+
+// Signature schemes tried when checking the client key, with the matching algorithm used
+// to verify the signature against the certificate.
+static KEY_CHECK_SCHEMES: &[(SignatureScheme, &webpki::SignatureAlgorithm)] = &[
+    (
+        SignatureScheme::ECDSA_NISTP256_SHA256,
+        &webpki::ECDSA_P256_SHA256,
+    ),
+    (
+        SignatureScheme::ECDSA_NISTP384_SHA384,
+        &webpki::ECDSA_P384_SHA384,
+    ),
+    (SignatureScheme::ED25519, &webpki::ED25519),
+    (
+        SignatureScheme::RSA_PKCS1_SHA256,
+        &webpki::RSA_PKCS1_2048_8192_SHA256,
+    ),
+];
+
+/// Client certificate and key used to authenticate to the broker (mutual TLS).
+#[derive(Debug, Clone, Copy)]
+pub struct ClientAuth<'a> {
+    pub cert: &'a Path,
+    pub key: &'a Path,
+}
+
+/// Builds the TLS configuration used to connect to the broker.
+///
+/// The broker certificate is verified against `ca_cert` when given, or against the
+/// system roots otherwise. With `client_auth`, the certificate chain and key are loaded
+/// and checked to belong together, so a mismatch is reported at startup rather than as
+/// a handshake failure.
+pub fn client_config(
+    ca_cert: Option<&Path>,
+    client_auth: Option<ClientAuth>,
+) -> Result<ClientConfig, Box<dyn Error>> {
+    let mut roots = RootCertStore::empty();
+    match ca_cert {
+        Some(path) => {
+            for cert in load_certs(path)? {
+                roots.add(&cert)?;
+            }
+        }
+        None => {
+            for cert in rustls_native_certs::load_native_certs()? {
+                // Skip the system roots rustls can't parse rather than failing.
+                let _ = roots.add(&Certificate(cert.0));
+            }
+        }
+    }
+
+    let builder = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots);
+    let Some(client_auth) = client_auth else {
+        return Ok(builder.with_no_client_auth());
+    };
+
+    let certs = load_certs(client_auth.cert)?;
+    let key = load_key(client_auth.key)?;
+    check_key_matches(&certs[0], &key).map_err(|e| {
+        format!(
+            "{} does not match {}: {}",
+            client_auth.key.display(),
+            client_auth.cert.display(),
+            e
+        )
+    })?;
+    Ok(builder.with_client_auth_cert(certs, key)?)
+}
+
+fn load_certs(path: &Path) -> Result<Vec<Certificate>, Box<dyn Error>> {
+    let mut reader = BufReader::new(open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader)?;
+    if certs.is_empty() {
+        return Err(format!("No certificate found in {}", path.display()).into());
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_key(path: &Path) -> Result<PrivateKey, Box<dyn Error>> {
+    let mut reader = BufReader::new(open(path)?);
+    while let Some(item) = rustls_pemfile::read_one(&mut reader)? {
+        match item {
+            Item::RSAKey(key) | Item::PKCS8Key(key) | Item::ECKey(key) => {
+                return Ok(PrivateKey(key))
+            }
+            _ => continue,
+        }
+    }
+    Err(format!("No private key found in {}", path.display()).into())
+}
+
+fn open(path: &Path) -> Result<File, Box<dyn Error>> {
+    File::open(path).map_err(|e| format!("Failed to open {}: {}", path.display(), e).into())
+}
+
+// Signs a probe message with `key` and verifies it with the public key in `cert`.
+fn check_key_matches(cert: &Certificate, key: &PrivateKey) -> Result<(), String> {
+    let signing_key = sign::any_supported_type(key).map_err(|e| e.to_string())?;
+    let cert = webpki::EndEntityCert::try_from(cert.0.as_slice()).map_err(|e| e.to_string())?;
+    for (scheme, algorithm) in KEY_CHECK_SCHEMES {
+        let Some(signer) = signing_key.choose_scheme(&[*scheme]) else {
+            continue;
+        };
+        let signature = signer.sign(KEY_PROBE).map_err(|e| e.to_string())?;
+        return cert
+            .verify_signature(algorithm, KEY_PROBE, &signature)
+            .map_err(|_| "the key was not issued for the certificate".to_string());
+    }
+    Err("unsupported key type".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use super::ClientAuth;
+
+    fn write_identity(dir: &Path, name: &str) -> (std::path::PathBuf, std::path::PathBuf) {
+        let identity = rcgen::generate_simple_self_signed(vec!["ledmoji".to_string()]).unwrap();
+        let cert = dir.join(format!("{}.crt", name));
+        let key = dir.join(format!("{}.key", name));
+        fs::write(&cert, identity.serialize_pem().unwrap()).unwrap();
+        fs::write(&key, identity.serialize_private_key_pem()).unwrap();
+        (cert, key)
+    }
+
+    #[test]
+    fn loads_matching_client_identity() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert, key) = write_identity(dir.path(), "client");
+        let client_auth = ClientAuth {
+            cert: &cert,
+            key: &key,
+        };
+        assert!(super::client_config(Some(&cert), Some(client_auth)).is_ok());
+    }
+
+    #[test]
+    fn rejects_key_for_another_certificate() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert, _) = write_identity(dir.path(), "client");
+        let (_, other_key) = write_identity(dir.path(), "other");
+        let client_auth = ClientAuth {
+            cert: &cert,
+            key: &other_key,
+        };
+        let error = super::client_config(Some(&cert), Some(client_auth)).unwrap_err();
+        assert!(error.to_string().contains("does not match"), "{}", error);
+    }
+}
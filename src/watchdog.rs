@@ -0,0 +1,142 @@
+// Copyright 2023 André Cipriani Bandarra
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use crate::clock::Clock;
+
+/// Detects a stream that stopped delivering events without reporting an error.
+///
+/// The time is read from `clock`, so the detection timing can be tested with a
+/// `FakeClock` without waiting.
+#[derive(Debug, Clone)]
+pub struct StallWatchdog<'a> {
+    threshold: Duration,
+    last_event: Instant,
+    clock: &'a dyn Clock,
+}
+
+impl<'a> StallWatchdog<'a> {
+    pub fn new(threshold: Duration, clock: &'a dyn Clock) -> Self {
+        Self {
+            threshold,
+            last_event: clock.now(),
+            clock,
+        }
+    }
+
+    /// Records that an event (data or keep-alive) was received now.
+    pub fn record_event(&mut self) {
+        self.last_event = self.clock.now();
+    }
+
+    /// Time left before the stream is considered stalled.
+    pub fn remaining(&self) -> Duration {
+        self.threshold
+            .saturating_sub(self.clock.now().saturating_duration_since(self.last_event))
+    }
+
+    pub fn is_stalled(&self) -> bool {
+        self.remaining().is_zero()
+    }
+}
+
+/// Last time a task made progress, shared between the task and the supervisor restarting
+/// it when it stops making progress, eg: a source stuck in a call that never returns,
+/// see `WATCHDOG_SECS`.
+#[derive(Debug)]
+pub struct Progress {
+    start: Instant,
+    // Milliseconds from `start` to the last progress.
+    last: AtomicU64,
+}
+
+impl Progress {
+    pub fn new(clock: &dyn Clock) -> Self {
+        Self {
+            start: clock.now(),
+            last: AtomicU64::new(0),
+        }
+    }
+
+    /// Records that the task made progress now.
+    pub fn record(&self, clock: &dyn Clock) {
+        let elapsed = clock.now().saturating_duration_since(self.start);
+        self.last
+            .store(elapsed.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// Whether the task made no progress for `threshold`.
+    pub fn is_stalled(&self, threshold: Duration, clock: &dyn Clock) -> bool {
+        let last = self.start + Duration::from_millis(self.last.load(Ordering::Relaxed));
+        clock.now().saturating_duration_since(last) >= threshold
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{Progress, StallWatchdog};
+    use crate::clock::FakeClock;
+
+    #[test]
+    fn detects_stall_after_threshold() {
+        let clock = FakeClock::default();
+        let watchdog = StallWatchdog::new(Duration::from_secs(30), &clock);
+
+        assert!(!watchdog.is_stalled());
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(watchdog.remaining(), Duration::from_secs(20));
+        clock.advance(Duration::from_secs(19));
+        assert!(!watchdog.is_stalled());
+        clock.advance(Duration::from_secs(1));
+        assert!(watchdog.is_stalled());
+        clock.advance(Duration::from_secs(60));
+        assert!(watchdog.is_stalled());
+    }
+
+    #[test]
+    fn events_reset_the_timer() {
+        let clock = FakeClock::default();
+        let mut watchdog = StallWatchdog::new(Duration::from_secs(30), &clock);
+
+        clock.advance(Duration::from_secs(25));
+        watchdog.record_event();
+        clock.advance(Duration::from_secs(25));
+        assert!(!watchdog.is_stalled());
+        assert_eq!(watchdog.remaining(), Duration::from_secs(5));
+        clock.advance(Duration::from_secs(5));
+        assert!(watchdog.is_stalled());
+    }
+
+    #[test]
+    fn detects_tasks_without_progress() {
+        let clock = FakeClock::default();
+        let progress = Progress::new(&clock);
+        let threshold = Duration::from_secs(120);
+
+        clock.advance(Duration::from_secs(119));
+        assert!(!progress.is_stalled(threshold, &clock));
+        progress.record(&clock);
+        clock.advance(Duration::from_secs(119));
+        assert!(!progress.is_stalled(threshold, &clock));
+        clock.advance(Duration::from_secs(1));
+        assert!(progress.is_stalled(threshold, &clock));
+    }
+}